@@ -0,0 +1,139 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashSet},
+    env,
+    sync::Arc,
+};
+use teloxide::types::{ChatId, UserId};
+use tokio::sync::Mutex;
+
+// Users whose commands are ignored. Their ordinary messages are still stored
+// so summaries of the chat stay complete.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    users: HashSet<UserId>,
+}
+
+impl Blocklist {
+    pub fn from_env() -> Self {
+        let users = match env::var("BLOCKED_USER_IDS") {
+            Ok(value) => parse_user_ids(&value),
+            Err(_) => HashSet::new(),
+        };
+        Self { users }
+    }
+
+    pub fn is_blocked(&self, user_id: UserId) -> bool {
+        self.users.contains(&user_id)
+    }
+
+    // Returns false if the user was already blocked
+    pub fn block(&mut self, user_id: UserId) -> bool {
+        self.users.insert(user_id)
+    }
+
+    // Returns false if the user wasn't blocked
+    pub fn unblock(&mut self, user_id: UserId) -> bool {
+        self.users.remove(&user_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+pub type BlocklistType = Arc<Mutex<Blocklist>>;
+
+// Parse a comma-separated list of user ids, skipping invalid entries
+pub fn parse_user_ids(value: &str) -> HashSet<UserId> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse::<u64>() {
+            Ok(id) => Some(UserId(id)),
+            Err(_) => {
+                warn!(target: "config", "Ignoring invalid user id '{}'", entry);
+                None
+            }
+        })
+        .collect()
+}
+
+// Parse a comma-separated list of chat ids, skipping invalid entries
+pub fn parse_chat_ids(value: &str) -> HashSet<ChatId> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse::<i64>() {
+            Ok(id) => Some(ChatId(id)),
+            Err(_) => {
+                warn!(target: "config", "Ignoring invalid chat id '{}'", entry);
+                None
+            }
+        })
+        .collect()
+}
+
+pub const CHAT_ACCESS_META_KEY: &str = "chat_access";
+
+// Chats let in or shut out with /allowchat and /blockchat, on top of
+// ALLOWED_CHAT_IDS. A chat is in at most one of the two sets.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatOverrides {
+    pub allowed: BTreeSet<i64>,
+    pub blocked: BTreeSet<i64>,
+}
+
+impl ChatOverrides {
+    // Returns false if the chat was already allowed
+    pub fn allow(&mut self, chat_id: ChatId) -> bool {
+        self.blocked.remove(&chat_id.0);
+        self.allowed.insert(chat_id.0)
+    }
+
+    // Returns false if the chat was already blocked
+    pub fn block(&mut self, chat_id: ChatId) -> bool {
+        self.allowed.remove(&chat_id.0);
+        self.blocked.insert(chat_id.0)
+    }
+}
+
+// Whether the bot works in a chat. The owner's private chat always does, so
+// a bad list can be fixed from there; then come the runtime overrides, then
+// ALLOWED_CHAT_IDS, and without that list every chat is allowed.
+pub fn is_chat_allowed(
+    chat_id: ChatId,
+    listed: Option<&HashSet<ChatId>>,
+    overrides: &ChatOverrides,
+    owner: Option<UserId>,
+) -> bool {
+    if owner.is_some_and(|owner| ChatId::from(owner) == chat_id) {
+        return true;
+    }
+    if overrides.blocked.contains(&chat_id.0) {
+        return false;
+    }
+    if overrides.allowed.contains(&chat_id.0) {
+        return true;
+    }
+    listed.is_none_or(|listed| listed.contains(&chat_id))
+}
+
+// Resolve the user an admin command targets: an explicit id wins, otherwise the
+// sender of the replied-to message
+pub fn resolve_target_user(arg: &str, reply_sender: Option<UserId>) -> Option<UserId> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        reply_sender
+    } else {
+        arg.parse::<u64>().ok().map(UserId)
+    }
+}
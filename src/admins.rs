@@ -0,0 +1,225 @@
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, PoisonError},
+};
+use teloxide::{
+    prelude::*,
+    types::{ChatId, Message, UserId},
+};
+
+// How long a fetched administrator list is trusted, unless ADMIN_CACHE_TTL_SECS
+// says otherwise
+pub const DEFAULT_ADMIN_TTL_SECS: i64 = 300;
+// Chats whose lists are refreshed ahead of time on each prefetch round
+pub const PREFETCH_CHATS: usize = 10;
+pub const PREFETCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone)]
+struct AdminList {
+    ids: Arc<HashSet<UserId>>,
+    fetched_at: DateTime<Utc>,
+}
+
+// Administrator lists per chat. get_chat_administrators takes long enough in
+// big groups to be felt on every admin-only command, so lists are kept for a
+// while, concurrent misses for one chat share a single request, and the
+// busiest chats are refreshed in the background before anyone waits on them.
+#[derive(Debug)]
+pub struct AdminCache {
+    ttl: Duration,
+    lists: Mutex<HashMap<ChatId, AdminList>>,
+    // Held while a chat's list is being fetched; later callers wait on it and
+    // then find the list cached
+    fetching: Mutex<HashMap<ChatId, Arc<tokio::sync::Mutex<()>>>>,
+    // Admin checks per chat since the last prefetch round
+    checks: Mutex<HashMap<ChatId, u64>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminCacheDebug {
+    pub cached_chats: usize,
+    // Lists older than the TTL, kept as a fallback
+    pub stale_chats: usize,
+    pub fetches_in_progress: usize,
+}
+
+pub type AdminCacheType = Arc<AdminCache>;
+
+impl AdminCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            lists: Mutex::default(),
+            fetching: Mutex::default(),
+            checks: Mutex::default(),
+        }
+    }
+
+    fn fresh(&self, chat_id: ChatId, now: DateTime<Utc>) -> Option<Arc<HashSet<UserId>>> {
+        self.lists
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&chat_id)
+            .filter(|list| now - list.fetched_at < self.ttl)
+            .map(|list| list.ids.clone())
+    }
+
+    fn stale(&self, chat_id: ChatId) -> Option<Arc<HashSet<UserId>>> {
+        self.lists
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&chat_id)
+            .map(|list| list.ids.clone())
+    }
+
+    fn store(
+        &self,
+        chat_id: ChatId,
+        ids: HashSet<UserId>,
+        now: DateTime<Utc>,
+    ) -> Arc<HashSet<UserId>> {
+        let ids = Arc::new(ids);
+        self.lists
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                chat_id,
+                AdminList {
+                    ids: ids.clone(),
+                    fetched_at: now,
+                },
+            );
+        ids
+    }
+
+    // The chat's administrators, from the cache if fresh. Otherwise `fetch` is
+    // called, once no matter how many callers miss at the same time. A failed
+    // fetch falls back to the last known list if there is one.
+    pub async fn admins_with<F, Fut>(
+        &self,
+        chat_id: ChatId,
+        fetch: F,
+    ) -> ResponseResult<Arc<HashSet<UserId>>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = ResponseResult<HashSet<UserId>>>,
+    {
+        if let Some(ids) = self.fresh(chat_id, Utc::now()) {
+            return Ok(ids);
+        }
+
+        let gate = self
+            .fetching
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(chat_id)
+            .or_default()
+            .clone();
+        let _turn = gate.lock().await;
+        // Someone else may have fetched it while this caller waited
+        if let Some(ids) = self.fresh(chat_id, Utc::now()) {
+            return Ok(ids);
+        }
+
+        let started = std::time::Instant::now();
+        let result = fetch().await;
+        self.fetching
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&chat_id);
+        match result {
+            Ok(ids) => {
+                debug!(target: "admins", "Fetched {} administrators of chat {} in {:?}", ids.len(), chat_id, started.elapsed());
+                Ok(self.store(chat_id, ids, Utc::now()))
+            }
+            Err(e) => match self.stale(chat_id) {
+                Some(ids) => {
+                    warn!(target: "admins", "Failed to fetch administrators of chat {}, using the last known list: {}", chat_id, e);
+                    Ok(ids)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    pub async fn admins(&self, bot: &Bot, chat_id: ChatId) -> ResponseResult<Arc<HashSet<UserId>>> {
+        self.admins_with(chat_id, || fetch(bot, chat_id)).await
+    }
+
+    // Count a check towards the chat's share of background refreshes
+    pub fn record_check(&self, chat_id: ChatId) {
+        *self
+            .checks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(chat_id)
+            .or_default() += 1;
+    }
+
+    // The chats with the most checks since the last call, busiest first.
+    // Counting starts over, so chats that went quiet drop out.
+    pub fn take_busiest(&self, limit: usize) -> Vec<ChatId> {
+        let mut checks: Vec<(ChatId, u64)> =
+            std::mem::take(&mut *self.checks.lock().unwrap_or_else(PoisonError::into_inner))
+                .into_iter()
+                .collect();
+        checks.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        checks
+            .into_iter()
+            .take(limit)
+            .map(|(chat_id, _)| chat_id)
+            .collect()
+    }
+
+    pub fn debug_snapshot(&self) -> AdminCacheDebug {
+        let now = Utc::now();
+        let lists = self.lists.lock().unwrap_or_else(PoisonError::into_inner);
+        AdminCacheDebug {
+            cached_chats: lists.len(),
+            stale_chats: lists
+                .values()
+                .filter(|list| now - list.fetched_at >= self.ttl)
+                .count(),
+            fetches_in_progress: self
+                .fetching
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .len(),
+        }
+    }
+
+    // Fetch a chat's list now, regardless of its age
+    pub async fn refresh(&self, bot: &Bot, chat_id: ChatId) -> ResponseResult<()> {
+        let ids = fetch(bot, chat_id).await?;
+        self.store(chat_id, ids, Utc::now());
+        Ok(())
+    }
+}
+
+async fn fetch(bot: &Bot, chat_id: ChatId) -> ResponseResult<HashSet<UserId>> {
+    let admins = bot.get_chat_administrators(chat_id).await?;
+    Ok(admins.into_iter().map(|member| member.user.id).collect())
+}
+
+// Private chats need no check; in groups the sender must be an administrator,
+// or post anonymously on behalf of the group (which only admins can do)
+pub async fn is_chat_admin(bot: &Bot, admins: &AdminCache, msg: &Message) -> ResponseResult<bool> {
+    if msg.chat.is_private() {
+        return Ok(true);
+    }
+    if msg
+        .sender_chat
+        .as_ref()
+        .is_some_and(|chat| chat.id == msg.chat.id)
+    {
+        return Ok(true);
+    }
+    let Some(user) = &msg.from else {
+        return Ok(false);
+    };
+    admins.record_check(msg.chat.id);
+    Ok(admins.admins(bot, msg.chat.id).await?.contains(&user.id))
+}
@@ -0,0 +1,81 @@
+use crate::store::SavedMessage;
+use chrono::Duration;
+use std::collections::HashMap;
+use teloxide::types::ThreadId;
+
+// Identical posts by the same sender within this window count as one cross-post
+pub const CROSS_POST_WINDOW_MINUTES: i64 = 10;
+
+// Normalize text for equality checks: case-folded, whitespace runs collapsed
+pub fn normalize_text(text: &str) -> String {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn topic_label(thread_id: Option<ThreadId>) -> String {
+    match thread_id {
+        Some(thread) => format!("#{}", thread.0.0),
+        None => "General".to_string(),
+    }
+}
+
+struct Entry {
+    message: SavedMessage,
+    topics: Vec<Option<ThreadId>>,
+}
+
+// Collapse a message cross-posted to several topics of a forum into the first
+// copy, annotated with every topic it appeared in. Messages must be in arrival
+// order. Repeats inside a single topic are left alone.
+pub fn collapse_cross_posts(
+    messages: Vec<(Option<ThreadId>, SavedMessage)>,
+    window: Duration,
+) -> Vec<SavedMessage> {
+    let mut entries: Vec<Entry> = Vec::with_capacity(messages.len());
+    let mut seen: HashMap<(String, String), usize> = HashMap::new();
+
+    for (thread_id, message) in messages {
+        let Some(sender) = message.from_user.clone() else {
+            entries.push(Entry {
+                message,
+                topics: vec![thread_id],
+            });
+            continue;
+        };
+        // Rendered, so two uncaptioned photos match but a photo and a sticker don't
+        let key = (sender, normalize_text(&message.kind.render(&message.text)));
+
+        if let Some(&index) = seen.get(&key) {
+            let entry = &mut entries[index];
+            let within_window = message.timestamp - entry.message.timestamp <= window;
+            if within_window && !entry.topics.contains(&thread_id) {
+                entry.topics.push(thread_id);
+                continue;
+            }
+        }
+
+        seen.insert(key, entries.len());
+        entries.push(Entry {
+            message,
+            topics: vec![thread_id],
+        });
+    }
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            let mut message = entry.message;
+            if entry.topics.len() > 1 {
+                let topics: Vec<String> = entry.topics.into_iter().map(topic_label).collect();
+                message.text = format!(
+                    "[cross-posted to topics {}] {}",
+                    topics.join(", "),
+                    message.text
+                );
+            }
+            message
+        })
+        .collect()
+}
@@ -0,0 +1,76 @@
+use log::warn;
+use std::time::Duration;
+use teloxide::{ApiError, RequestError};
+use tokio::time::Instant;
+
+// Capped exponential backoff that keeps retrying until a time window runs
+// out, for calls the bot can't start without (get_me, command registration,
+// webhook registration)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max_delay: Duration,
+    // No attempt starts later than this after the first one
+    pub window: Duration,
+}
+
+impl Backoff {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            window,
+        }
+    }
+
+    // Wait before attempt `attempt + 1`, doubling from `initial`
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay)
+    }
+
+    // Call until it succeeds, fails with an error `is_transient` rejects, or
+    // the window runs out. Returns the last error and how many attempts were
+    // made in the failure case.
+    pub async fn retry<T, E, Fut>(
+        &self,
+        what: &str,
+        is_transient: impl Fn(&E) -> bool,
+        mut call: impl FnMut() -> Fut,
+    ) -> Result<T, (E, u32)>
+    where
+        E: std::fmt::Display,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let started = Instant::now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let error = match call().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            let delay = self.delay(attempt);
+            if !is_transient(&error) || started.elapsed() + delay > self.window {
+                return Err((error, attempt));
+            }
+            warn!(target: "startup", "Failed to {} (attempt {}), retrying in {}s: {}", what, attempt, delay.as_secs(), error);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// Failures that can clear up on their own, like the network not being up yet.
+// A rejected token or request won't get better by asking again.
+pub fn is_transient(error: &RequestError) -> bool {
+    match error {
+        RequestError::Network(_)
+        | RequestError::Io(_)
+        | RequestError::RetryAfter(_)
+        | RequestError::InvalidJson { .. } => true,
+        // Gateway errors in front of the Bot API aren't known API errors
+        RequestError::Api(ApiError::Unknown(_)) => true,
+        _ => false,
+    }
+}
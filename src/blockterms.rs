@@ -0,0 +1,165 @@
+use crate::glossary;
+use std::collections::BTreeSet;
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+pub const MAX_TERMS: usize = 50;
+const MAX_TERM_CHARS: usize = 100;
+pub const REDACTED: &str = "[redacted]";
+
+// Why a term was rejected, worded for the admin who sent it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TermError {
+    Empty,
+    TooLong,
+    Full,
+}
+
+impl std::fmt::Display for TermError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TermError::Empty => write!(f, "Usage: /settings blockterm add <term>"),
+            TermError::TooLong => {
+                write!(
+                    f,
+                    "Blocked terms can be up to {} characters.",
+                    MAX_TERM_CHARS
+                )
+            }
+            TermError::Full => write!(
+                f,
+                "This chat already blocks {} terms. Remove one first.",
+                MAX_TERMS
+            ),
+        }
+    }
+}
+
+// Text as terms are matched against it: compatibility forms folded (so
+// full-width letters and ligatures match their plain spelling), accents
+// dropped, lowercased. Each byte of the result records the byte range of the
+// original character it came from.
+struct Folded {
+    text: String,
+    origin: Vec<(usize, usize)>,
+}
+
+fn fold(text: &str) -> Folded {
+    let mut folded = Folded {
+        text: String::with_capacity(text.len()),
+        origin: Vec::with_capacity(text.len()),
+    };
+    for (start, c) in text.char_indices() {
+        let end = start + c.len_utf8();
+        for decomposed in std::iter::once(c).nfkd().filter(|c| !is_combining_mark(*c)) {
+            for lower in decomposed.to_lowercase() {
+                folded.text.push(lower);
+                folded
+                    .origin
+                    .extend(std::iter::repeat_n((start, end), lower.len_utf8()));
+            }
+        }
+    }
+    folded
+}
+
+// The blocked terms of a chat, folded once for matching
+#[derive(Debug, Clone, Default)]
+pub struct Matcher {
+    terms: Vec<String>,
+}
+
+impl Matcher {
+    pub fn new(terms: &BTreeSet<String>) -> Self {
+        Self {
+            terms: terms
+                .iter()
+                .map(|term| fold(term).text)
+                .filter(|term| !term.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        if self.terms.is_empty() {
+            return false;
+        }
+        let folded = fold(text).text;
+        self.terms.iter().any(|term| folded.contains(term.as_str()))
+    }
+
+    // Replace every occurrence of a term with REDACTED. Returns the text and
+    // how many spans were replaced.
+    pub fn redact(&self, text: &str) -> (String, usize) {
+        if self.terms.is_empty() {
+            return (text.to_string(), 0);
+        }
+        let folded = fold(text);
+        let mut spans: Vec<(usize, usize)> = self
+            .terms
+            .iter()
+            .flat_map(|term| {
+                folded
+                    .text
+                    .match_indices(term.as_str())
+                    .map(|(position, found)| {
+                        (
+                            folded.origin[position].0,
+                            folded.origin[position + found.len() - 1].1,
+                        )
+                    })
+            })
+            .collect();
+        if spans.is_empty() {
+            return (text.to_string(), 0);
+        }
+
+        // Overlapping matches of different terms become one redaction
+        spans.sort();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+        for (start, end) in spans {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let mut redacted = String::with_capacity(text.len());
+        let mut copied = 0;
+        for (start, end) in &merged {
+            redacted.push_str(&text[copied..*start]);
+            redacted.push_str(REDACTED);
+            copied = *end;
+        }
+        redacted.push_str(&text[copied..]);
+        (redacted, merged.len())
+    }
+}
+
+// Add a term unless an equivalent one is already blocked. Returns whether the
+// list changed.
+pub fn insert(terms: &mut BTreeSet<String>, term: &str) -> Result<bool, TermError> {
+    let term = glossary::sanitize(term);
+    if fold(&term).text.is_empty() {
+        return Err(TermError::Empty);
+    }
+    if term.chars().count() > MAX_TERM_CHARS {
+        return Err(TermError::TooLong);
+    }
+    if find(terms, &term).is_some() {
+        return Ok(false);
+    }
+    if terms.len() >= MAX_TERMS {
+        return Err(TermError::Full);
+    }
+    Ok(terms.insert(term))
+}
+
+// The stored spelling of a term, matched the way messages are
+pub fn find<'a>(terms: &'a BTreeSet<String>, term: &str) -> Option<&'a String> {
+    let folded = fold(&glossary::sanitize(term)).text;
+    terms.iter().find(|existing| fold(existing).text == folded)
+}
@@ -0,0 +1,219 @@
+use crate::{llm::Usage, usage::CostRates};
+use chrono::{DateTime, Datelike, Utc};
+use chrono_tz::Tz;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{env, sync::Arc};
+use tokio::sync::Mutex;
+
+const DEFAULT_WARN_PERCENT: f64 = 80.0;
+// Prices while COST_PER_MTOK_INPUT and COST_PER_MTOK_OUTPUT are unset: Groq's
+// llama-3.3-70b output price, used for input too to stay on the safe side
+pub const DEFAULT_RATES: CostRates = CostRates {
+    input: 0.79,
+    output: 0.79,
+};
+// Rough average for the languages the bot sees; good enough for a spending cap
+pub const CHARS_PER_TOKEN: usize = 4;
+
+// A calendar month as (year, month)
+pub type BudgetMonth = (i32, u32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BudgetConfig {
+    pub cap_usd: f64,
+    pub warn_percent: f64,
+}
+
+impl BudgetConfig {
+    // Enabled by MONTHLY_BUDGET_USD; the other variables only tune it
+    pub fn from_env() -> Option<Self> {
+        let cap_usd = env::var("MONTHLY_BUDGET_USD").ok()?;
+        let Some(cap_usd) = cap_usd.trim().parse::<f64>().ok().filter(|cap| *cap > 0.0) else {
+            warn!(target: "config", "Ignoring invalid MONTHLY_BUDGET_USD '{}'", cap_usd);
+            return None;
+        };
+        let parse = |name: &str, default: f64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .filter(|value| *value >= 0.0)
+                .unwrap_or(default)
+        };
+
+        if env::var("LLM_USD_PER_MILLION_TOKENS").is_ok() {
+            warn!(target: "config", "LLM_USD_PER_MILLION_TOKENS is no longer used; the budget follows COST_PER_MTOK_INPUT and COST_PER_MTOK_OUTPUT");
+        }
+
+        let config = Self {
+            cap_usd,
+            warn_percent: parse("BUDGET_WARN_PERCENT", DEFAULT_WARN_PERCENT),
+        };
+        info!(target: "config", "Monthly budget of ${:.2} enabled (warning at {}%)", config.cap_usd, config.warn_percent);
+        Some(config)
+    }
+
+    fn warn_usd(&self) -> f64 {
+        self.cap_usd * self.warn_percent / 100.0
+    }
+}
+
+pub fn estimate_tokens(chars: usize) -> u64 {
+    chars.div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+// What a provider call cost: the tokens the server counted, or, if it didn't
+// report them, the characters sent and received at the higher of the prices
+pub fn call_cost(usage: Option<Usage>, chars: usize, rates: CostRates) -> f64 {
+    match usage {
+        Some(usage) => {
+            (usage.prompt_tokens as f64 * rates.input
+                + usage.completion_tokens as f64 * rates.output)
+                / 1_000_000.0
+        }
+        None => estimate_tokens(chars) as f64 * rates.input.max(rates.output) / 1_000_000.0,
+    }
+}
+
+// The month `now` falls in for the given IANA timezone, UTC if it's unset or unknown
+pub fn current_month(timezone: Option<&str>, now: DateTime<Utc>) -> BudgetMonth {
+    match timezone.and_then(|name| name.parse::<Tz>().ok()) {
+        Some(tz) => {
+            let local = now.with_timezone(&tz);
+            (local.year(), local.month())
+        }
+        None => (now.year(), now.month()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetEvent {
+    // Spend just crossed the warning threshold
+    WarningReached,
+    // Spend just crossed the cap; summaries fall back to extracts
+    CapReached,
+}
+
+// The part of the tracker that has to survive restarts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BudgetState {
+    month: Option<BudgetMonth>,
+    spent_usd: f64,
+    // The owner lifted the cap for the rest of the month
+    lifted: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct BudgetTracker {
+    config: Option<BudgetConfig>,
+    state: BudgetState,
+}
+
+impl BudgetTracker {
+    pub fn new(config: Option<BudgetConfig>) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn restore(&mut self, state: BudgetState) {
+        self.state = state;
+    }
+
+    pub fn state(&self) -> BudgetState {
+        self.state
+    }
+
+    // Spend and overrides only count for the month they happened in
+    fn roll_over(&mut self, month: BudgetMonth) {
+        if self.state.month != Some(month) {
+            if self.state.month.is_some() {
+                info!(target: "budget", "New budget month {}-{:02}, resetting spend", month.0, month.1);
+            }
+            self.state = BudgetState {
+                month: Some(month),
+                ..BudgetState::default()
+            };
+        }
+    }
+
+    pub fn allows_llm(&mut self, month: BudgetMonth) -> bool {
+        self.roll_over(month);
+        match &self.config {
+            Some(config) => self.state.lifted || self.state.spent_usd < config.cap_usd,
+            None => true,
+        }
+    }
+
+    // Add the cost of a provider call, reporting a threshold it crossed
+    pub fn record(&mut self, month: BudgetMonth, cost_usd: f64) -> Option<BudgetEvent> {
+        self.roll_over(month);
+        let config = self.config?;
+
+        let before = self.state.spent_usd;
+        self.state.spent_usd += cost_usd;
+
+        if before < config.cap_usd && self.state.spent_usd >= config.cap_usd {
+            Some(BudgetEvent::CapReached)
+        } else if before < config.warn_usd() && self.state.spent_usd >= config.warn_usd() {
+            Some(BudgetEvent::WarningReached)
+        } else {
+            None
+        }
+    }
+
+    // Returns false when no budget is configured
+    pub fn lift(&mut self, month: BudgetMonth) -> bool {
+        self.roll_over(month);
+        if self.config.is_none() {
+            return false;
+        }
+        self.state.lifted = true;
+        true
+    }
+
+    pub fn status_line(&mut self, month: BudgetMonth) -> String {
+        self.roll_over(month);
+        let Some(config) = &self.config else {
+            return "Budget: no monthly cap".to_string();
+        };
+        let state = if self.state.lifted {
+            " (cap lifted for this month)"
+        } else if self.state.spent_usd >= config.cap_usd {
+            " (cap reached, extracts only)"
+        } else {
+            ""
+        };
+        format!(
+            "Budget: ${:.2} of ${:.2} spent in {}-{:02}{}",
+            self.state.spent_usd, config.cap_usd, month.0, month.1, state
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BudgetDebug {
+    pub cap_usd: Option<f64>,
+    pub warn_percent: Option<f64>,
+    pub month: Option<String>,
+    pub spent_usd: f64,
+    pub lifted: bool,
+}
+
+impl BudgetTracker {
+    pub fn debug_snapshot(&self) -> BudgetDebug {
+        BudgetDebug {
+            cap_usd: self.config.map(|config| config.cap_usd),
+            warn_percent: self.config.map(|config| config.warn_percent),
+            month: self
+                .state
+                .month
+                .map(|(year, month)| format!("{}-{:02}", year, month)),
+            spent_usd: self.state.spent_usd,
+            lifted: self.state.lifted,
+        }
+    }
+}
+
+pub type BudgetType = Arc<Mutex<BudgetTracker>>;
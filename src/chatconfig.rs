@@ -0,0 +1,213 @@
+use crate::{
+    blockterms::{self, TermError},
+    digest, glossary, lang,
+    settings::{self, ChatSettings},
+};
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Bumped when the file changes in a way older versions can't read
+pub const FORMAT_VERSION: u32 = 1;
+// Settings files are a few KB at most; anything bigger isn't one
+pub const MAX_FILE_BYTES: u32 = 64 * 1024;
+
+// A chat's configuration as /settings export writes it: its settings and its
+// daily digest time, without messages or anything secret
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatConfig {
+    pub version: u32,
+    pub settings: ChatSettings,
+    // "HH:MM" of the daily digest where the export was made
+    pub digest_time: Option<String>,
+}
+
+// Why a settings file was rejected, worded for the admin who sent it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportError {
+    Format(String),
+    Version(u32),
+    Field(&'static str, String),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Format(reason) => {
+                write!(f, "the file isn't a settings export ({})", reason)
+            }
+            ImportError::Version(version) => write!(
+                f,
+                "the file has format version {}, but I only read version {}",
+                version, FORMAT_VERSION
+            ),
+            ImportError::Field(field, reason) => write!(f, "{}: {}", field, reason),
+        }
+    }
+}
+
+// A settings file that passed every check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Imported {
+    pub settings: ChatSettings,
+    pub digest_time: Option<NaiveTime>,
+}
+
+impl Imported {
+    // Replace `current` with the imported settings. Ignored users name people
+    // of the chat the file came from, so they're only taken over on request.
+    pub fn apply_to(&self, current: &mut ChatSettings, include_users: bool) {
+        let ignored_users = if include_users {
+            self.settings.ignored_users.clone()
+        } else {
+            std::mem::take(&mut current.ignored_users)
+        };
+        *current = ChatSettings {
+            ignored_users,
+            ..self.settings.clone()
+        };
+    }
+}
+
+pub fn export(
+    settings: &ChatSettings,
+    digest_time: Option<NaiveTime>,
+) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec_pretty(&ChatConfig {
+        version: FORMAT_VERSION,
+        settings: settings.clone(),
+        digest_time: digest_time.map(|time| time.format("%H:%M").to_string()),
+    })
+}
+
+// Read a settings file, checking every field the way the command that sets
+// it does. Values come back normalized, e.g. usernames lowercased.
+pub fn parse(bytes: &[u8], max_messages: usize) -> Result<Imported, ImportError> {
+    // The version is checked first, so a newer file isn't reported as broken
+    let value: serde_json::Value =
+        serde_json::from_slice(bytes).map_err(|e| ImportError::Format(e.to_string()))?;
+    match value.get("version").and_then(serde_json::Value::as_u64) {
+        Some(version) if version == u64::from(FORMAT_VERSION) => {}
+        Some(version) => {
+            return Err(ImportError::Version(
+                u32::try_from(version).unwrap_or(u32::MAX),
+            ));
+        }
+        None => return Err(ImportError::Format("no version".to_string())),
+    }
+    let config: ChatConfig =
+        serde_json::from_value(value).map_err(|e| ImportError::Format(e.to_string()))?;
+    let mut settings = config.settings;
+
+    if let Some(max) = settings.max_summarize
+        && !(1..=max_messages).contains(&max)
+    {
+        return Err(ImportError::Field(
+            "max_summarize",
+            format!("{} isn't between 1 and {}", max, max_messages),
+        ));
+    }
+    if let Some(timezone) = &settings.timezone {
+        let tz = timezone.parse::<chrono_tz::Tz>().map_err(|_| {
+            ImportError::Field("timezone", format!("unknown timezone '{}'", timezone))
+        })?;
+        settings.timezone = Some(tz.name().to_string());
+    }
+    if let Some(language) = &settings.language {
+        let code = lang::parse_language(language).ok_or_else(|| {
+            ImportError::Field("language", format!("unknown language '{}'", language))
+        })?;
+        settings.language = Some(code.to_string());
+    }
+    settings.ignored_users = settings
+        .ignored_users
+        .iter()
+        .map(|name| {
+            settings::normalize_username(name).ok_or_else(|| {
+                ImportError::Field("ignored_users", format!("'{}' isn't a username", name))
+            })
+        })
+        .collect::<Result<BTreeSet<_>, _>>()?;
+
+    let mut entries = BTreeMap::new();
+    for (term, definition) in &settings.glossary {
+        let field = |e: glossary::EntryError| {
+            ImportError::Field("glossary", format!("entry '{}': {}", term, e))
+        };
+        let (term, definition) = glossary::check_entry(term, definition).map_err(field)?;
+        glossary::insert(&mut entries, term, definition).map_err(field)?;
+    }
+    settings.glossary = entries;
+
+    let mut terms = BTreeSet::new();
+    for term in &settings.blocked_terms {
+        blockterms::insert(&mut terms, term).map_err(|e| {
+            ImportError::Field(
+                "blocked_terms",
+                match e {
+                    TermError::Empty => "a term is empty".to_string(),
+                    e => e.to_string(),
+                },
+            )
+        })?;
+    }
+    settings.blocked_terms = terms;
+
+    let digest_time = config
+        .digest_time
+        .map(|time| {
+            digest::parse_time(&time).ok_or_else(|| {
+                ImportError::Field("digest_time", format!("'{}' isn't a time like 18:00", time))
+            })
+        })
+        .transpose()?;
+
+    Ok(Imported {
+        settings,
+        digest_time,
+    })
+}
+
+// "Setting: old → new" for each setting that differs, in the order and
+// wording of /settings. Glossaries and blocked terms are only counted, so a
+// changed list of the same length is called out separately.
+pub fn describe_changes(
+    old: &ChatSettings,
+    new: &ChatSettings,
+    old_digest: Option<NaiveTime>,
+    new_digest: Option<NaiveTime>,
+) -> Vec<String> {
+    let old_description = old.describe();
+    let new_description = new.describe();
+    let mut changes: Vec<String> = old_description
+        .lines()
+        .zip(new_description.lines())
+        .filter(|(before, after)| before != after)
+        .map(
+            |(before, after)| match (before.split_once(": "), after.split_once(": ")) {
+                (Some((name, before)), Some((_, after))) => {
+                    format!("{}: {} → {}", name, before, after)
+                }
+                _ => after.to_string(),
+            },
+        )
+        .collect();
+    if old.glossary != new.glossary && old.glossary.len() == new.glossary.len() {
+        changes.push("Glossary: entries replaced".to_string());
+    }
+    if old.blocked_terms != new.blocked_terms && old.blocked_terms.len() == new.blocked_terms.len()
+    {
+        changes.push("Blocked terms: list replaced".to_string());
+    }
+    if old_digest != new_digest {
+        let describe = |time: Option<NaiveTime>| {
+            time.map_or("off".to_string(), |time| time.format("%H:%M").to_string())
+        };
+        changes.push(format!(
+            "Daily digest: {} → {}",
+            describe(old_digest),
+            describe(new_digest)
+        ));
+    }
+    changes
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Arc};
+use teloxide::{
+    ApiError, RequestError,
+    prelude::*,
+    types::{ChatId, ChatMemberKind},
+};
+use tokio::sync::Mutex;
+
+// How long chat details fetched with get_chat are trusted
+const CHAT_INFO_TTL_MINUTES: i64 = 60;
+
+#[derive(Debug, Clone, Copy)]
+struct ChatInfo {
+    has_protected_content: bool,
+    fetched_at: DateTime<Utc>,
+}
+
+// Chat details that are only available through get_chat, cached per chat
+#[derive(Debug, Default)]
+pub struct ChatInfoCache {
+    chats: HashMap<ChatId, ChatInfo>,
+    // Chats where the bot last failed to post, or was restricted, and when.
+    // Entries expire with the TTL, since a chat's default permissions can
+    // change without the bot being told.
+    send_blocked: HashMap<ChatId, DateTime<Utc>>,
+}
+
+impl ChatInfoCache {
+    fn fresh(&self, chat_id: ChatId, now: DateTime<Utc>) -> Option<ChatInfo> {
+        self.chats
+            .get(&chat_id)
+            .filter(|info| now - info.fetched_at < Duration::minutes(CHAT_INFO_TTL_MINUTES))
+            .copied()
+    }
+
+    fn stale(&self, chat_id: ChatId) -> Option<ChatInfo> {
+        self.chats.get(&chat_id).copied()
+    }
+
+    // Whether posting in the chat is worth trying
+    pub fn can_send(&self, chat_id: ChatId, now: DateTime<Utc>) -> bool {
+        self.send_blocked
+            .get(&chat_id)
+            .is_none_or(|since| now - *since >= Duration::minutes(CHAT_INFO_TTL_MINUTES))
+    }
+
+    pub fn set_can_send(&mut self, chat_id: ChatId, can_send: bool, now: DateTime<Utc>) {
+        if can_send {
+            self.send_blocked.remove(&chat_id);
+        } else {
+            self.send_blocked.insert(chat_id, now);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatInfoDebug {
+    pub cached_chats: usize,
+    // Chats the bot recently failed to post in
+    pub send_blocked: Vec<(i64, DateTime<Utc>)>,
+}
+
+impl ChatInfoCache {
+    pub fn debug_snapshot(&self) -> ChatInfoDebug {
+        let mut send_blocked: Vec<(i64, DateTime<Utc>)> = self
+            .send_blocked
+            .iter()
+            .map(|(chat_id, since)| (chat_id.0, *since))
+            .collect();
+        send_blocked.sort_unstable();
+        ChatInfoDebug {
+            cached_chats: self.chats.len(),
+            send_blocked,
+        }
+    }
+}
+
+// Errors that mean the bot can't post in the chat at all, as opposed to a
+// problem with one particular message
+pub fn is_send_forbidden(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(
+            ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::NotEnoughRightsToPostMessages,
+        ) => true,
+        RequestError::Api(ApiError::Unknown(text)) => {
+            text.starts_with("Forbidden:")
+                || text.contains("not enough rights to send")
+                || text.contains("CHAT_WRITE_FORBIDDEN")
+        }
+        _ => false,
+    }
+}
+
+// Whether the bot may post with the given membership
+pub fn member_can_send(kind: &ChatMemberKind) -> bool {
+    match kind {
+        ChatMemberKind::Owner(_) | ChatMemberKind::Administrator(_) | ChatMemberKind::Member => {
+            true
+        }
+        ChatMemberKind::Restricted(restricted) => {
+            restricted.is_member && restricted.can_send_messages
+        }
+        ChatMemberKind::Left | ChatMemberKind::Banned(_) => false,
+    }
+}
+
+pub type ChatInfoCacheType = Arc<Mutex<ChatInfoCache>>;
+
+// Whether the chat has content protection (no forwarding or saving) enabled.
+// Falls back to the last known value, or unprotected, if get_chat fails.
+pub async fn has_protected_content(bot: &Bot, cache: &ChatInfoCacheType, chat_id: ChatId) -> bool {
+    let now = Utc::now();
+    if let Some(info) = cache.lock().await.fresh(chat_id, now) {
+        return info.has_protected_content;
+    }
+
+    match bot.get_chat(chat_id).await {
+        Ok(chat) => {
+            let info = ChatInfo {
+                has_protected_content: chat.has_protected_content().is_some(),
+                fetched_at: now,
+            };
+            cache.lock().await.chats.insert(chat_id, info);
+            info.has_protected_content
+        }
+        Err(e) => {
+            warn!(target: "chat_info", "Failed to fetch chat info for {}: {}", chat_id, e);
+            cache
+                .lock()
+                .await
+                .stale(chat_id)
+                .is_some_and(|info| info.has_protected_content)
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::format;
+use teloxide::{
+    types::{ChatId, MessageId},
+    utils::markdown,
+};
+
+// Longest text between "[#" and "]" still read as a tag, e.g. "[#12, #13, #14]"
+const MAX_TAG_LEN: usize = 40;
+
+// What the [#n] tags in a summary point at: message n of the prompt, in a
+// chat whose messages can be linked to
+#[derive(Debug, Clone, Default)]
+pub struct Citations {
+    // "https://t.me/c/<id>" for supergroups and channels. Links of that form
+    // don't open in basic groups or private chats, so tags are dropped there.
+    base: Option<String>,
+    // The message behind each prompt index, starting at 1
+    message_ids: Vec<MessageId>,
+}
+
+impl Citations {
+    pub fn new(chat_id: ChatId, message_ids: Vec<MessageId>) -> Self {
+        Self {
+            base: link_base(chat_id),
+            message_ids,
+        }
+    }
+
+    // Whether tags can become links; the model is only asked for them then
+    pub fn linkable(&self) -> bool {
+        self.base.is_some()
+    }
+
+    fn url(&self, index: usize) -> Option<String> {
+        let base = self.base.as_ref()?;
+        let message_id = self.message_ids.get(index.checked_sub(1)?)?;
+        Some(format!("{}/{}", base, message_id.0))
+    }
+
+    // Plain summary text as italic MarkdownV2, with its tags turned into links
+    pub fn render(&self, text: &str) -> String {
+        substitute(text, |index| self.url(index))
+    }
+}
+
+// Supergroup and channel ids are -100 followed by the id t.me/c expects
+fn link_base(chat_id: ChatId) -> Option<String> {
+    chat_id
+        .is_channel_or_supergroup()
+        .then(|| format!("https://t.me/c/{}", -chat_id.0 - 1_000_000_000_000))
+}
+
+// Render plain text as italic MarkdownV2, replacing each "[#n]" or
+// "[#n, #m]" tag with links to the urls `url` gives for those indices. This
+// runs before escaping, so the links aren't escaped away. Indices without a
+// url are left out of their tag; a tag with none left, or one that isn't a
+// list of numbers, is dropped along with the spaces before it.
+pub fn substitute(text: &str, url: impl Fn(usize) -> Option<String>) -> String {
+    rewrite(text, url, italic_run)
+}
+
+// The text with every tag dropped, for summaries kept as plain text
+pub fn strip_tags(text: &str) -> String {
+    rewrite(text, |_| None, str::to_string)
+}
+
+// Split `text` at its tags, passing the text between them through `plain_run`
+fn rewrite(
+    text: &str,
+    url: impl Fn(usize) -> Option<String>,
+    plain_run: impl Fn(&str) -> String,
+) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut plain = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("[#") {
+        let tag = &rest[start + 2..];
+        let Some(end) = tag
+            .find(']')
+            .filter(|end| *end <= MAX_TAG_LEN && !tag[..*end].contains('\n'))
+        else {
+            plain.push_str(&rest[..start + 2]);
+            rest = tag;
+            continue;
+        };
+        plain.push_str(&rest[..start]);
+        rest = &tag[end + 1..];
+
+        let indices: Option<Vec<usize>> = tag[..end]
+            .split(',')
+            .map(|part| part.trim().trim_start_matches('#').parse().ok())
+            .collect();
+        let links: Vec<(usize, String)> = indices
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|index| Some((index, url(index)?)))
+            .collect();
+        if links.is_empty() {
+            plain.truncate(plain.trim_end_matches(' ').len());
+            continue;
+        }
+
+        rendered.push_str(&plain_run(&plain));
+        plain.clear();
+        let links: Vec<String> = links
+            .iter()
+            .map(|(index, url)| format::link(&format!("#{}", index), url))
+            .collect();
+        rendered.push_str(&links.join(" "));
+    }
+
+    plain.push_str(rest);
+    rendered.push_str(&plain_run(&plain));
+    rendered
+}
+
+// Whitespace alone has nothing to italicize but still separates links
+fn italic_run(text: &str) -> String {
+    if text.trim().is_empty() {
+        markdown::escape(text)
+    } else {
+        format::italic(text)
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{
+    dayslice::DaySlice,
+    limits::{self, ChatKind},
+};
+use std::str::FromStr;
+use teloxide::types::{ChatId, MessageId, ThreadId};
+
+// "<chat_id> [thread_id]" as given to /admin seed and the seed CLI
+pub fn parse_seed_target(args: &[&str]) -> Option<(ChatId, Option<ThreadId>)> {
+    let chat_id = ChatId(args.first()?.parse().ok()?);
+    let thread_id = match args.get(1) {
+        Some(thread) => Some(ThreadId(MessageId(thread.parse().ok()?))),
+        None => None,
+    };
+    Some((chat_id, thread_id))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryRange {
+    Count(usize),
+    Window(chrono::Duration),
+    Slice(DaySlice),
+}
+
+// A plain number is a message count; a number with an m, h or d suffix is a
+// window of time ending now; today, yesterday, morning, afternoon and evening
+// are parts of the day in the chat's timezone
+pub fn parse_range(arg: &str, limit: usize, kind: ChatKind) -> Option<SummaryRange> {
+    let trimmed = arg.trim();
+    if let Some(slice) = DaySlice::parse(trimmed) {
+        return Some(SummaryRange::Slice(slice));
+    }
+    let Some(unit) = trimmed.chars().last().filter(|c| c.is_ascii_alphabetic()) else {
+        return parse_count(trimmed, limit, kind).map(SummaryRange::Count);
+    };
+
+    let amount = i64::from_str(&trimmed[..trimmed.len() - 1])
+        .ok()
+        .filter(|amount| *amount > 0)?;
+    let window = match unit.to_ascii_lowercase() {
+        'm' => chrono::Duration::try_minutes(amount),
+        'h' => chrono::Duration::try_hours(amount),
+        'd' => chrono::Duration::try_days(amount),
+        _ => None,
+    }?;
+    Some(SummaryRange::Window(window))
+}
+
+// Split /summarize arguments into the range and an optional focus topic, e.g.
+// "300 about the hackathon" into "300" and "the hackathon". The range is the
+// first word when it starts with a digit or names a part of the day; anything
+// else is all topic, over the default range. A leading "about" is dropped.
+pub fn split_focus(arg: &str) -> (&str, Option<String>) {
+    let arg = arg.trim();
+    let (first, rest) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let (range, topic) =
+        if first.starts_with(|c: char| c.is_ascii_digit()) || DaySlice::parse(first).is_some() {
+            (first, rest.trim_start())
+        } else {
+            ("", arg)
+        };
+    let topic = match topic.split_once(char::is_whitespace) {
+        Some(("about", rest)) => rest.trim(),
+        _ if topic == "about" => "",
+        _ => topic.trim(),
+    };
+    (range, (!topic.is_empty()).then(|| topic.to_string()))
+}
+
+// Parse the optional count argument of the summarize commands; without one,
+// the default depends on the kind of chat
+pub fn parse_count(arg: &str, limit: usize, kind: ChatKind) -> Option<usize> {
+    let trimmed = arg.trim();
+    if trimmed.is_empty() {
+        return Some(limits::default_count(kind, limit));
+    }
+    match usize::from_str(trimmed) {
+        Ok(n) if n > 0 && n <= limit => Some(n),
+        _ => None,
+    }
+}
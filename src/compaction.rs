@@ -0,0 +1,53 @@
+use log::warn;
+use std::env;
+
+pub const COMPACTION_PROMPT: &str = "You are condensing the older part of a Telegram conversation into notes that later summaries will use as context. Keep names, decisions, dates, open questions and ongoing topics. Be compact and factual. Don't use markdown.";
+// Marks the synthetic entry in prompts so the model treats it as background
+// rather than something a participant said
+pub const SUMMARY_MARKER: &str = "[earlier conversation summary]";
+// Ends a compacted summary whose batch had messages kept out by the chat's
+// ignore list or blocked terms. Those are deleted with the rest of the batch,
+// so summaries built on the entry keep saying something was left out.
+pub const EXCLUSIONS_MARK: &str = "(Some messages were left out of these notes by chat settings.)";
+
+const DEFAULT_BATCH_SIZE: usize = 200;
+const DEFAULT_MAX_PER_DAY: u32 = 2;
+
+// Collapsing the oldest messages of a full queue into a single summary entry,
+// instead of evicting them. Off unless COMPACTION_ENABLED is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionConfig {
+    // How many of the oldest messages are collapsed at once
+    pub batch_size: usize,
+    // Provider calls allowed per chat/thread per UTC day
+    pub max_per_day: u32,
+}
+
+impl CompactionConfig {
+    pub fn from_env() -> Option<Self> {
+        let enabled = env::var("COMPACTION_ENABLED")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+
+        let batch_size = parse_or("COMPACTION_BATCH_SIZE", DEFAULT_BATCH_SIZE);
+        let max_per_day = parse_or("COMPACTION_MAX_PER_DAY", DEFAULT_MAX_PER_DAY);
+        Some(Self {
+            // A batch needs at least two entries to save anything
+            batch_size: batch_size.max(2),
+            max_per_day,
+        })
+    }
+}
+
+fn parse_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            warn!(target: "config", "Ignoring invalid {} '{}'", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,382 @@
+use crate::access;
+use crate::admins;
+use crate::compaction::CompactionConfig;
+use crate::context::ContextLimits;
+use crate::prompt;
+use crate::usage::CostRates;
+use log::{info, warn};
+use serde::Serialize;
+use std::{collections::HashSet, env, time::Duration};
+use teloxide::types::{ChatId, UserId};
+
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are a Telegram conversation summarizer. Your task is to create a concise, accurate, and well-structured summary of the conversation provided. Make it as short as possible while retaining all important information. Don't include any personal opinions or additional comments. Don't use markdown.";
+const DEFAULT_SUMMARIZE_COOLDOWN_SECS: i64 = 60;
+const DEFAULT_SNAPSHOT_MAX_AGE_HOURS: i64 = 24;
+const DEFAULT_PROMPT_TOKEN_BUDGET: u64 = 6000;
+const DEFAULT_MAX_TRACKED_CHATS: usize = 5000;
+const DEFAULT_STARTUP_RETRY_SECS: u64 = 120;
+
+#[derive(Debug, Serialize)]
+pub struct ConfigDebug {
+    pub owner_configured: bool,
+    pub prompt_variants: bool,
+    pub compaction_batch_size: Option<usize>,
+    pub compaction_max_per_day: Option<u32>,
+    pub show_usage: bool,
+    pub cost_per_mtok_input: Option<f64>,
+    pub cost_per_mtok_output: Option<f64>,
+    pub prompt_soft_cap_ms: u64,
+    pub prompt_token_budget: u64,
+    pub summarize_cooldown_secs: i64,
+    pub cooldown_admins_exempt: bool,
+    pub admin_cache_ttl_secs: i64,
+    pub snapshot_path: Option<String>,
+    pub snapshot_max_age_hours: i64,
+    pub ignore_bots: bool,
+    pub digest_timezone: Option<String>,
+    pub max_tracked_chats: Option<usize>,
+    pub max_store_bytes: Option<usize>,
+    pub metrics_addr: Option<String>,
+    pub startup_retry_secs: u64,
+    // How many chats ALLOWED_CHAT_IDS lists
+    pub allowed_chats: Option<usize>,
+    pub leave_unallowed_chats: bool,
+    pub exit_on_conflict: bool,
+}
+
+// A byte count, optionally with a K, M or G suffix (powers of 1024)
+pub fn parse_byte_size(value: &str) -> Option<usize> {
+    let value = value.trim().to_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (number, multiplier) = match value.char_indices().last()? {
+        (at, 'K') => (&value[..at], 1 << 10),
+        (at, 'M') => (&value[..at], 1 << 20),
+        (at, 'G') => (&value[..at], 1 << 30),
+        _ => (value, 1),
+    };
+    number.trim().parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+// Settings read once from the environment at startup
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub owner_user_id: Option<UserId>,
+    pub prompt_variants: Option<PromptVariants>,
+    pub compaction: Option<CompactionConfig>,
+    // Add the token count under each summary
+    pub show_usage: bool,
+    // Prices /usage estimates costs with; None leaves costs out there and
+    // charges the budget at budget::DEFAULT_RATES
+    pub cost_rates: Option<CostRates>,
+    // Prompt preparation beyond this skips optional passes
+    pub prompt_soft_cap: Duration,
+    // Conversations estimated above this many tokens are summarized in parts
+    // and merged; zero sends everything in one request
+    pub prompt_token_budget: u64,
+    // Context windows of the models, for warning about requests that fill them
+    pub context_limits: ContextLimits,
+    // Minimum time between summaries in one chat/thread; zero disables it
+    pub summarize_cooldown: chrono::Duration,
+    pub cooldown_admins_exempt: bool,
+    // How long a chat's administrator list is cached
+    pub admin_cache_ttl: chrono::Duration,
+    // Where the store is written on shutdown and read back on startup
+    pub snapshot_path: Option<String>,
+    // Snapshots older than this are ignored
+    pub snapshot_max_age: chrono::Duration,
+    // Skip messages sent by other bots, e.g. feed bots
+    pub ignore_bots: bool,
+    // IANA timezone scheduled digests follow in chats without their own
+    pub digest_timezone: Option<String>,
+    // Messages of chats beyond this many aren't stored; None for no limit
+    pub max_tracked_chats: Option<usize>,
+    // Estimated size the message store may grow to; None for no limit
+    pub max_store_bytes: Option<usize>,
+    // Where /healthz and /metrics are served; None leaves the server off
+    pub metrics_addr: Option<std::net::SocketAddr>,
+    // How long startup keeps retrying Telegram before exiting
+    pub startup_retry_window: Duration,
+    // The only chats the bot works in; None for every chat
+    pub allowed_chat_ids: Option<HashSet<ChatId>>,
+    // Leave chats that aren't allowed instead of just ignoring them
+    pub leave_unallowed_chats: bool,
+    // Stop when another instance keeps taking over polling
+    pub exit_on_conflict: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let owner_user_id = match env::var("OWNER_USER_ID") {
+            Ok(value) => match value.trim().parse::<u64>() {
+                Ok(id) => Some(UserId(id)),
+                Err(_) => {
+                    warn!(target: "config", "Ignoring invalid OWNER_USER_ID '{}'", value);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        // A/B testing is only enabled once a second variant is configured
+        let prompt_variants = env::var("PROMPT_VARIANT_B")
+            .ok()
+            .filter(|b| !b.trim().is_empty())
+            .map(|b| PromptVariants {
+                a: env::var("PROMPT_VARIANT_A")
+                    .ok()
+                    .filter(|a| !a.trim().is_empty())
+                    .unwrap_or_else(|| DEFAULT_SYSTEM_PROMPT.to_string()),
+                b,
+            });
+
+        if prompt_variants.is_some() {
+            info!(target: "config", "System prompt A/B testing enabled");
+        }
+
+        let compaction = CompactionConfig::from_env();
+        let show_usage = env::var("SHOW_USAGE")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        let cost_rates = CostRates::from_env();
+        if let Some(compaction) = &compaction {
+            info!(target: "config", "History compaction enabled ({} messages per batch, {} per chat per day)",
+                compaction.batch_size, compaction.max_per_day);
+        }
+
+        let prompt_soft_cap = Duration::from_millis(
+            env::var("PROMPT_PREP_SOFT_CAP_MS")
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(prompt::DEFAULT_SOFT_CAP_MS),
+        );
+
+        let prompt_token_budget = env::var("PROMPT_TOKEN_BUDGET")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(DEFAULT_PROMPT_TOKEN_BUDGET);
+
+        let summarize_cooldown = chrono::Duration::seconds(
+            env::var("SUMMARIZE_COOLDOWN_SECS")
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(DEFAULT_SUMMARIZE_COOLDOWN_SECS),
+        );
+        let admin_cache_ttl = chrono::Duration::seconds(
+            env::var("ADMIN_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .filter(|secs| *secs >= 0)
+                .unwrap_or(admins::DEFAULT_ADMIN_TTL_SECS),
+        );
+        let cooldown_admins_exempt = env::var("SUMMARIZE_COOLDOWN_ADMINS_EXEMPT")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+
+        let snapshot_path = env::var("SNAPSHOT_PATH")
+            .ok()
+            .filter(|path| !path.is_empty());
+        let snapshot_max_age = chrono::Duration::hours(
+            env::var("SNAPSHOT_MAX_AGE_HOURS")
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(DEFAULT_SNAPSHOT_MAX_AGE_HOURS),
+        );
+
+        let ignore_bots = env::var("IGNORE_BOTS")
+            .map(|value| value != "false" && value != "0")
+            .unwrap_or(true);
+
+        let digest_timezone = env::var("DIGEST_TZ")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .filter(|name| {
+                let valid = name.parse::<chrono_tz::Tz>().is_ok();
+                if !valid {
+                    warn!(target: "config", "Ignoring unknown DIGEST_TZ '{}', digests use UTC", name);
+                }
+                valid
+            });
+
+        let max_tracked_chats = match env::var("MAX_TRACKED_CHATS") {
+            Ok(value) => match value.trim().parse() {
+                Ok(0) => None,
+                Ok(max) => Some(max),
+                Err(_) => {
+                    warn!(target: "config", "Ignoring invalid MAX_TRACKED_CHATS '{}'", value);
+                    Some(DEFAULT_MAX_TRACKED_CHATS)
+                }
+            },
+            Err(_) => Some(DEFAULT_MAX_TRACKED_CHATS),
+        };
+
+        let max_store_bytes =
+            env::var("MAX_STORE_BYTES")
+                .ok()
+                .and_then(|value| match parse_byte_size(&value) {
+                    Some(0) => None,
+                    Some(max) => Some(max),
+                    None => {
+                        warn!(target: "config", "Ignoring invalid MAX_STORE_BYTES '{}'", value);
+                        None
+                    }
+                });
+
+        let metrics_addr = env::var("METRICS_ADDR")
+            .ok()
+            .filter(|value| !value.is_empty())
+            .and_then(|value| match value.trim().parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    warn!(target: "config", "Ignoring invalid METRICS_ADDR '{}', expected e.g. 0.0.0.0:9100", value);
+                    None
+                }
+            });
+
+        let startup_retry_window = Duration::from_secs(
+            env::var("STARTUP_RETRY_SECS")
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(DEFAULT_STARTUP_RETRY_SECS),
+        );
+
+        let allowed_chat_ids = env::var("ALLOWED_CHAT_IDS")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+            .map(|value| access::parse_chat_ids(&value));
+        if let Some(allowed) = &allowed_chat_ids {
+            info!(target: "config", "Restricted to {} allowed chats", allowed.len());
+        }
+        let leave_unallowed_chats = env::var("LEAVE_UNALLOWED_CHATS")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        let exit_on_conflict = env::var("EXIT_ON_CONFLICT")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+
+        Self {
+            owner_user_id,
+            prompt_variants,
+            compaction,
+            show_usage,
+            cost_rates,
+            prompt_soft_cap,
+            prompt_token_budget,
+            context_limits: ContextLimits::from_env(),
+            summarize_cooldown,
+            admin_cache_ttl,
+            cooldown_admins_exempt,
+            snapshot_path,
+            snapshot_max_age,
+            ignore_bots,
+            digest_timezone,
+            max_tracked_chats,
+            max_store_bytes,
+            metrics_addr,
+            startup_retry_window,
+            allowed_chat_ids,
+            leave_unallowed_chats,
+            exit_on_conflict,
+        }
+    }
+
+    // Whether the bot works in this chat, given the runtime overrides
+    pub fn is_chat_allowed(&self, chat_id: ChatId, overrides: &access::ChatOverrides) -> bool {
+        access::is_chat_allowed(
+            chat_id,
+            self.allowed_chat_ids.as_ref(),
+            overrides,
+            self.owner_user_id,
+        )
+    }
+
+    // The settings for /admin dump. Prompts are reduced to whether they're
+    // custom; nothing here is secret.
+    pub fn debug_snapshot(&self) -> ConfigDebug {
+        ConfigDebug {
+            owner_configured: self.owner_user_id.is_some(),
+            prompt_variants: self.prompt_variants.is_some(),
+            compaction_batch_size: self.compaction.as_ref().map(|c| c.batch_size),
+            compaction_max_per_day: self.compaction.as_ref().map(|c| c.max_per_day),
+            show_usage: self.show_usage,
+            cost_per_mtok_input: self.cost_rates.map(|rates| rates.input),
+            cost_per_mtok_output: self.cost_rates.map(|rates| rates.output),
+            prompt_soft_cap_ms: self.prompt_soft_cap.as_millis() as u64,
+            prompt_token_budget: self.prompt_token_budget,
+            summarize_cooldown_secs: self.summarize_cooldown.num_seconds(),
+            admin_cache_ttl_secs: self.admin_cache_ttl.num_seconds(),
+            cooldown_admins_exempt: self.cooldown_admins_exempt,
+            snapshot_path: self.snapshot_path.clone(),
+            snapshot_max_age_hours: self.snapshot_max_age.num_hours(),
+            ignore_bots: self.ignore_bots,
+            digest_timezone: self.digest_timezone.clone(),
+            max_tracked_chats: self.max_tracked_chats,
+            max_store_bytes: self.max_store_bytes,
+            metrics_addr: self.metrics_addr.map(|addr| addr.to_string()),
+            startup_retry_secs: self.startup_retry_window.as_secs(),
+            allowed_chats: self.allowed_chat_ids.as_ref().map(HashSet::len),
+            leave_unallowed_chats: self.leave_unallowed_chats,
+            exit_on_conflict: self.exit_on_conflict,
+        }
+    }
+
+    pub fn is_owner(&self, user_id: UserId) -> bool {
+        self.owner_user_id == Some(user_id)
+    }
+
+    // Pick the system prompt for a chat, along with the variant it came from
+    pub fn system_prompt(&self, chat_id: ChatId) -> (&str, Option<PromptVariant>) {
+        match &self.prompt_variants {
+            Some(variants) => {
+                let variant = assign_variant(chat_id);
+                (variants.prompt(variant), Some(variant))
+            }
+            None => (DEFAULT_SYSTEM_PROMPT, None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PromptVariant {
+    A,
+    B,
+}
+
+impl std::fmt::Display for PromptVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromptVariant::A => write!(f, "A"),
+            PromptVariant::B => write!(f, "B"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PromptVariants {
+    a: String,
+    b: String,
+}
+
+impl PromptVariants {
+    pub fn prompt(&self, variant: PromptVariant) -> &str {
+        match variant {
+            PromptVariant::A => &self.a,
+            PromptVariant::B => &self.b,
+        }
+    }
+}
+
+// Assign a variant from a stable hash of the chat id, so every thread of a chat
+// gets the same variant and the assignment survives restarts. FNV-1a is used
+// instead of the std hasher, whose output isn't guaranteed across releases.
+pub fn assign_variant(chat_id: ChatId) -> PromptVariant {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in chat_id.0.to_le_bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    if hash.is_multiple_of(2) {
+        PromptVariant::A
+    } else {
+        PromptVariant::B
+    }
+}
@@ -0,0 +1,131 @@
+use futures::future::BoxFuture;
+use log::error;
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use teloxide::{
+    ApiError, RequestError, dispatching::ShutdownToken, error_handlers::ErrorHandler, prelude::*,
+    types::UserId,
+};
+
+// This many conflicts within the window means another instance is polling
+const CONFLICT_THRESHOLD: usize = 3;
+const CONFLICT_WINDOW: Duration = Duration::from_secs(120);
+
+// Recent "terminated by other getUpdates request" errors. One can happen when
+// a restart overlaps the old process by a moment; a steady stream means two
+// instances share the token and each only sees part of the updates.
+#[derive(Debug, Default)]
+pub struct ConflictWindow {
+    hits: VecDeque<Instant>,
+    // Set once the current run of conflicts was reported
+    reported: bool,
+}
+
+impl ConflictWindow {
+    // Count a conflict; true the first time the threshold is reached. Once
+    // the window empties out the next run is reported again.
+    pub fn record(&mut self, now: Instant) -> bool {
+        while self
+            .hits
+            .front()
+            .is_some_and(|hit| now.duration_since(*hit) > CONFLICT_WINDOW)
+        {
+            self.hits.pop_front();
+        }
+        if self.hits.is_empty() {
+            self.reported = false;
+        }
+        self.hits.push_back(now);
+        if self.hits.len() >= CONFLICT_THRESHOLD && !self.reported {
+            self.reported = true;
+            return true;
+        }
+        false
+    }
+}
+
+// Errors from polling. Logged like teloxide's default handler, except that
+// repeated conflicts are reported to the owner and, with EXIT_ON_CONFLICT,
+// shut the bot down.
+pub struct ListenerErrorHandler {
+    bot: Bot,
+    owner: Option<UserId>,
+    window: Mutex<ConflictWindow>,
+    // Set with EXIT_ON_CONFLICT; the dispatcher is stopped and `exiting` set
+    shutdown: Option<ShutdownToken>,
+    exiting: Arc<AtomicBool>,
+}
+
+impl ListenerErrorHandler {
+    pub fn new(
+        bot: Bot,
+        owner: Option<UserId>,
+        shutdown: Option<ShutdownToken>,
+        exiting: Arc<AtomicBool>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            bot,
+            owner,
+            window: Mutex::default(),
+            shutdown,
+            exiting,
+        })
+    }
+}
+
+pub fn is_conflict(error: &RequestError) -> bool {
+    matches!(
+        error,
+        RequestError::Api(ApiError::TerminatedByOtherGetUpdates)
+    )
+}
+
+impl ErrorHandler<RequestError> for ListenerErrorHandler {
+    fn handle_error(self: Arc<Self>, error: RequestError) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            if !is_conflict(&error) {
+                error!(target: "dispatcher", "An error from the update listener: {:?}", error);
+                return;
+            }
+            if !self
+                .window
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(Instant::now())
+            {
+                error!(target: "dispatcher", "Another getUpdates request took over polling: {}", error);
+                return;
+            }
+
+            error!(target: "dispatcher",
+                "ANOTHER INSTANCE OF THIS BOT IS RUNNING with the same TELEGRAM_BOT_TOKEN. Telegram \
+                hands each update to only one of them, so both miss messages and summaries have gaps. \
+                Stop all but one instance.");
+            if let Some(owner) = self.owner
+                && let Err(e) = self
+                    .bot
+                    .send_message(
+                        owner,
+                        "Another instance of this bot is polling Telegram with the same token. \
+                        Each instance only gets part of the messages, so summaries will have \
+                        gaps until all but one are stopped.",
+                    )
+                    .await
+            {
+                error!(target: "dispatcher", "Couldn't tell the owner about the conflict: {}", e);
+            }
+            if let Some(shutdown) = &self.shutdown {
+                error!(target: "dispatcher", "Shutting down because EXIT_ON_CONFLICT is set");
+                self.exiting.store(true, Ordering::Relaxed);
+                // Waiting here would wait on this very handler
+                let _ = shutdown.shutdown();
+            }
+        })
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{budget, store::SavedMessage};
+use log::{info, warn};
+use std::{collections::HashMap, env};
+
+// Share of the model's context past which a request counts as large
+const LARGE_REQUEST_PERCENT: u64 = 80;
+
+pub const LARGE_REQUEST_NOTE: &str =
+    "(large request — the summary may take longer and cover a condensed view)";
+
+// Context windows of common models, in tokens. A model matches the longest
+// entry its name starts with, so dated or suffixed variants are covered too.
+const KNOWN_LIMITS: &[(&str, u64)] = &[
+    ("llama-3.3-70b", 131_072),
+    ("llama-3.1-8b", 131_072),
+    ("llama3-70b", 8_192),
+    ("llama3-8b", 8_192),
+    ("gemma2-9b", 8_192),
+    ("mixtral-8x7b", 32_768),
+    ("qwen-qwq", 131_072),
+    ("deepseek-r1-distill", 131_072),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-3.5-turbo", 16_385),
+];
+
+// Model context windows, with MODEL_CONTEXT_LIMITS overriding or extending the
+// built-in table, e.g. "my-model=32768,llama-3.3-70b=65536"
+#[derive(Debug, Clone, Default)]
+pub struct ContextLimits {
+    overrides: HashMap<String, u64>,
+}
+
+impl ContextLimits {
+    pub fn from_env() -> Self {
+        let Ok(value) = env::var("MODEL_CONTEXT_LIMITS") else {
+            return Self::default();
+        };
+        let mut overrides = HashMap::new();
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry
+                .split_once('=')
+                .and_then(|(model, tokens)| Some((model.trim(), tokens.trim().parse().ok()?)))
+            {
+                Some((model, tokens)) if !model.is_empty() && tokens > 0 => {
+                    overrides.insert(model.to_lowercase(), tokens);
+                }
+                _ => {
+                    warn!(target: "config", "Ignoring MODEL_CONTEXT_LIMITS entry '{}', expected model=tokens", entry)
+                }
+            }
+        }
+        info!(target: "config", "Context limits overridden for {} models", overrides.len());
+        Self { overrides }
+    }
+
+    // The context window of a model, None if it isn't known
+    pub fn limit(&self, model: &str) -> Option<u64> {
+        let model = model.to_lowercase();
+        // Providers like OpenRouter prefix the vendor, e.g. "meta-llama/..."
+        let name = model.rsplit('/').next().unwrap_or(&model);
+        if let Some(&tokens) = self
+            .overrides
+            .get(&model)
+            .or_else(|| self.overrides.get(name))
+        {
+            return Some(tokens);
+        }
+        KNOWN_LIMITS
+            .iter()
+            .filter(|(prefix, _)| name.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, tokens)| tokens)
+    }
+}
+
+// How much of the model's context a request would take
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Headroom {
+    pub estimated_tokens: u64,
+    pub limit: u64,
+}
+
+impl Headroom {
+    pub fn percent(&self) -> u64 {
+        self.estimated_tokens * 100 / self.limit
+    }
+
+    pub fn is_large(&self) -> bool {
+        self.estimated_tokens * 100 > self.limit * LARGE_REQUEST_PERCENT
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.estimated_tokens)
+    }
+
+    // What will happen to the conversation on its way to the provider, given
+    // PROMPT_TOKEN_BUDGET (0 for no splitting)
+    pub fn predicted_trimming(&self, token_budget: u64) -> String {
+        if token_budget > 0 && self.estimated_tokens > token_budget {
+            format!(
+                "split into about {} parts of up to {} tokens, summarized separately and merged",
+                self.estimated_tokens.div_ceil(token_budget),
+                token_budget
+            )
+        } else if self.estimated_tokens > self.limit {
+            "too long for the model; the provider will likely reject it".to_string()
+        } else {
+            "sent in full in one request".to_string()
+        }
+    }
+}
+
+// Characters the conversation takes once rendered as prompt lines: the text,
+// the author and the "[#n] " and ": " around them
+pub fn conversation_chars(messages: &[SavedMessage]) -> usize {
+    messages
+        .iter()
+        .map(|m| m.text.len() + m.from_user.as_ref().map_or(7, String::len) + 10)
+        .sum()
+}
+
+// The share of `model`'s context taken by a prompt of `prompt_chars`, None if
+// the model's context window isn't known
+pub fn headroom(prompt_chars: usize, model: &str, limits: &ContextLimits) -> Option<Headroom> {
+    Some(Headroom {
+        estimated_tokens: budget::estimate_tokens(prompt_chars),
+        limit: limits.limit(model)?,
+    })
+}
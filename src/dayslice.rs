@@ -0,0 +1,124 @@
+use crate::locale::Locale;
+use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+// A named part of the day, as in "/summarize yesterday"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaySlice {
+    Today,
+    Yesterday,
+    Morning,
+    Afternoon,
+    Evening,
+}
+
+impl DaySlice {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "today" => Some(DaySlice::Today),
+            "yesterday" => Some(DaySlice::Yesterday),
+            "morning" => Some(DaySlice::Morning),
+            "afternoon" => Some(DaySlice::Afternoon),
+            "evening" => Some(DaySlice::Evening),
+            _ => None,
+        }
+    }
+
+    // Local hours the slice spans within its day
+    fn hours(self) -> (u32, u32) {
+        match self {
+            DaySlice::Today | DaySlice::Yesterday => (0, 24),
+            DaySlice::Morning => (6, 12),
+            DaySlice::Afternoon => (12, 18),
+            DaySlice::Evening => (18, 24),
+        }
+    }
+}
+
+impl std::fmt::Display for DaySlice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DaySlice::Today => write!(f, "today"),
+            DaySlice::Yesterday => write!(f, "yesterday"),
+            DaySlice::Morning => write!(f, "morning"),
+            DaySlice::Afternoon => write!(f, "afternoon"),
+            DaySlice::Evening => write!(f, "evening"),
+        }
+    }
+}
+
+// The instant a local wall-clock time occurs. A time skipped by a DST change
+// occurs when the clocks jump; a time that happens twice occurs the first time.
+fn local_instant(tz: Tz, mut naive: NaiveDateTime) -> DateTime<Utc> {
+    // Gaps are at most a few hours; step forward until the wall clock exists
+    for _ in 0..4 {
+        if let Some(instant) = tz.from_local_datetime(&naive).earliest() {
+            return instant.with_timezone(&Utc);
+        }
+        naive += chrono::Duration::minutes(30);
+    }
+    naive.and_utc()
+}
+
+// The instant a local wall-clock hour starts
+fn local_hour(tz: Tz, date: NaiveDate, hour: u32) -> DateTime<Utc> {
+    let (date, hour) = if hour == 24 {
+        (date + Days::new(1), 0)
+    } else {
+        (date, hour)
+    };
+    local_instant(
+        tz,
+        date.and_time(NaiveTime::MIN) + chrono::Duration::hours(hour.into()),
+    )
+}
+
+// The first instant after `now` at which the local clock shows `time`
+pub fn next_occurrence(tz: Tz, time: NaiveTime, now: DateTime<Utc>) -> DateTime<Utc> {
+    let today = now.with_timezone(&tz).date_naive();
+    let instant = local_instant(tz, today.and_time(time));
+    if instant > now {
+        instant
+    } else {
+        local_instant(tz, (today + Days::new(1)).and_time(time))
+    }
+}
+
+// The [start, end) range a slice covers at `now` in the given timezone. Parts
+// of the day that haven't started yet refer to the previous day, so "evening"
+// at 9am means last night. Ranges never extend past `now`.
+pub fn resolve(slice: DaySlice, tz: Tz, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let today = now.with_timezone(&tz).date_naive();
+    let (start_hour, end_hour) = slice.hours();
+
+    let mut day = match slice {
+        DaySlice::Yesterday => today - Days::new(1),
+        _ => today,
+    };
+    if local_hour(tz, day, start_hour) > now {
+        day = day - Days::new(1);
+    }
+
+    let start = local_hour(tz, day, start_hour);
+    let end = local_hour(tz, day, end_hour).min(now);
+    (start, end)
+}
+
+// The chat's timezone, then the bot's default, then UTC
+pub fn timezone(chat: Option<&str>, default: Option<&str>) -> Tz {
+    chat.or(default)
+        .and_then(|name| name.parse::<Tz>().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+// "Mon 14 Oct 06:00 – 12:00 (Europe/Warsaw)", naming the end's date only
+// when it differs from the start's
+pub fn format_range(start: DateTime<Utc>, end: DateTime<Utc>, tz: Tz, locale: Locale) -> String {
+    let (start, end) = (start.with_timezone(&tz), end.with_timezone(&tz));
+    let end_text = if start.date_naive() == end.date_naive() {
+        locale.time(&end)
+    } else {
+        locale.datetime(&end)
+    };
+    format!("{} – {} ({})", locale.datetime(&start), end_text, tz.name())
+}
@@ -0,0 +1,259 @@
+use log::{error, warn};
+use std::time::Duration;
+use teloxide::{
+    ApiError, RequestError,
+    payloads::{EditMessageText, SendMessage},
+    prelude::*,
+    requests::JsonRequest,
+    types::{ChatId, Message, MessageId, ParseMode, ReplyParameters, ThreadId},
+};
+
+// Attempts per request when Telegram asks to slow down
+const MAX_FLOOD_ATTEMPTS: u32 = 4;
+// Longer flood waits are cut short; Telegram rejects the retry if it's early
+const MAX_FLOOD_WAIT: Duration = Duration::from_secs(60);
+const NETWORK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    Retry(Duration),
+    GiveUp,
+}
+
+// Whether a request that failed for the `failures`-th time should be sent
+// again. Flood waits are honored a few times; network errors get one retry.
+pub fn retry_decision(error: &RequestError, failures: u32) -> RetryDecision {
+    match error {
+        RequestError::RetryAfter(wait) if failures < MAX_FLOOD_ATTEMPTS => {
+            RetryDecision::Retry(wait.duration().min(MAX_FLOOD_WAIT))
+        }
+        RequestError::Network(_) | RequestError::Io(_) if failures == 1 => {
+            RetryDecision::Retry(NETWORK_RETRY_DELAY)
+        }
+        _ => RetryDecision::GiveUp,
+    }
+}
+
+// Run a Telegram request, sending it again while retry_decision allows
+pub async fn with_retry<T, Fut>(chat_id: ChatId, mut call: impl FnMut() -> Fut) -> ResponseResult<T>
+where
+    Fut: Future<Output = ResponseResult<T>>,
+{
+    let mut failures = 0;
+    loop {
+        let error = match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        failures += 1;
+        match retry_decision(&error, failures) {
+            RetryDecision::Retry(wait) => {
+                warn!(target: "send", "Request to chat {} failed ({}), retrying in {}s", chat_id, error, wait.as_secs());
+                tokio::time::sleep(wait).await;
+            }
+            RetryDecision::GiveUp => {
+                if failures > 1 {
+                    error!(target: "send", "Giving up on a request to chat {} after {} attempts: {}", chat_id, failures, error);
+                }
+                return Err(error);
+            }
+        }
+    }
+}
+
+// Where a message goes: a chat, and a forum topic if it has one. Every
+// outbound message is built from one of these so follow-ups (error edits,
+// extra chunks, footers) can't drift to a different topic than the first send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChatDestination {
+    pub chat_id: ChatId,
+    pub thread_id: Option<ThreadId>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendOptions {
+    pub reply_to: Option<MessageId>,
+    pub parse_mode: Option<ParseMode>,
+    // Deliver without a notification
+    pub silent: bool,
+}
+
+impl ChatDestination {
+    pub fn new(chat_id: ChatId, thread_id: Option<ThreadId>) -> Self {
+        Self { chat_id, thread_id }
+    }
+
+    // The chat and topic a message was posted in
+    pub fn of(msg: &Message) -> Self {
+        Self::new(msg.chat.id, msg.thread_id)
+    }
+
+    // A send request with the thread already applied, for callers that need
+    // extra parameters such as a keyboard
+    pub fn message(&self, bot: &Bot, text: impl Into<String>) -> JsonRequest<SendMessage> {
+        let mut request = bot.send_message(self.chat_id, text);
+        if let Some(thread) = self.thread_id {
+            request = request.message_thread_id(thread);
+        }
+        request
+    }
+
+    // Messages are edited by id, which already pins the topic; the chat still
+    // has to match the destination
+    pub fn edit_message(
+        &self,
+        bot: &Bot,
+        message_id: MessageId,
+        text: impl Into<String>,
+    ) -> JsonRequest<EditMessageText> {
+        bot.edit_message_text(self.chat_id, message_id, text)
+    }
+
+    pub async fn send(
+        &self,
+        bot: &Bot,
+        text: String,
+        options: SendOptions,
+    ) -> ResponseResult<Message> {
+        let build = |text: String, parse_mode: Option<ParseMode>| {
+            let mut request = self.message(bot, text).disable_notification(options.silent);
+            if let Some(reply_to) = options.reply_to {
+                request = request.reply_parameters(ReplyParameters::new(reply_to));
+            }
+            if let Some(parse_mode) = parse_mode {
+                request = request.parse_mode(parse_mode);
+            }
+            request
+        };
+
+        let first = with_retry(self.chat_id, || {
+            build(text.clone(), options.parse_mode).into_future()
+        })
+        .await;
+        match first {
+            Err(e) if options.parse_mode.is_some() && is_parse_error(&e) => {
+                warn!(target: "send", "Formatting rejected in chat {}, sending as plain text: {}", self.chat_id, e);
+                let plain = strip_markdown(&text);
+                with_retry(self.chat_id, || build(plain.clone(), None).into_future()).await
+            }
+            result => result,
+        }
+    }
+
+    pub async fn edit(
+        &self,
+        bot: &Bot,
+        message_id: MessageId,
+        text: String,
+        parse_mode: Option<ParseMode>,
+    ) -> ResponseResult<Message> {
+        let build = |text: String, parse_mode: Option<ParseMode>| {
+            let mut request = self.edit_message(bot, message_id, text);
+            if let Some(parse_mode) = parse_mode {
+                request = request.parse_mode(parse_mode);
+            }
+            request
+        };
+
+        let first = with_retry(self.chat_id, || {
+            build(text.clone(), parse_mode).into_future()
+        })
+        .await;
+        match first {
+            Err(e) if parse_mode.is_some() && is_parse_error(&e) => {
+                warn!(target: "send", "Formatting rejected in chat {}, editing as plain text: {}", self.chat_id, e);
+                let plain = strip_markdown(&text);
+                with_retry(self.chat_id, || build(plain.clone(), None).into_future()).await
+            }
+            result => result,
+        }
+    }
+}
+
+// Longest text Telegram accepts in one message, in UTF-16 code units, counted
+// after formatting is parsed away
+pub const MESSAGE_LIMIT: usize = 4096;
+
+pub fn telegram_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+// Split plain text into pieces of at most `limit` UTF-16 units, preferring
+// paragraph breaks, then line breaks, then sentence ends, then spaces. Splitting
+// happens before any MarkdownV2 escaping, so escapes can't be cut in half.
+pub fn split_text(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+
+    while telegram_len(rest) > limit {
+        // Byte offset of the longest prefix that fits
+        let mut units = 0;
+        let mut fits = 0;
+        for (offset, c) in rest.char_indices() {
+            units += c.len_utf16();
+            if units > limit {
+                break;
+            }
+            fits = offset + c.len_utf8();
+        }
+
+        let window = &rest[..fits];
+        let cut = ["\n\n", "\n", ". ", " "]
+            .iter()
+            .filter_map(|separator| {
+                window
+                    .rfind(separator)
+                    .map(|position| position + separator.len())
+            })
+            // A break in the first half wastes too much of the message
+            .find(|position| *position > fits / 2)
+            .unwrap_or(fits)
+            .max(rest.chars().next().map_or(1, char::len_utf8));
+
+        chunks.push(rest[..cut].trim_end().to_string());
+        rest = rest[cut..].trim_start();
+    }
+
+    if !rest.is_empty() {
+        chunks.push(rest.to_string());
+    }
+    chunks
+}
+
+fn is_parse_error(error: &RequestError) -> bool {
+    matches!(error, RequestError::Api(ApiError::CantParseEntities(_)))
+}
+
+// Best-effort plain version of MarkdownV2 text: escaped characters are kept,
+// formatting markers are dropped
+fn strip_markdown(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => plain.extend(chars.next()),
+            '*' | '_' | '~' | '`' => {}
+            _ => plain.push(c),
+        }
+    }
+    plain
+}
+
+// Reply to a command message, staying in its thread if it has one
+pub fn reply_to(bot: &Bot, msg: &Message, text: String) -> JsonRequest<SendMessage> {
+    ChatDestination::of(msg)
+        .message(bot, text)
+        .reply_parameters(ReplyParameters::new(msg.id))
+}
+
+// Send a message, again after flood waits and network hiccups
+pub async fn send_retrying(
+    chat_id: ChatId,
+    request: JsonRequest<SendMessage>,
+) -> ResponseResult<Message> {
+    with_retry(chat_id, || request.clone().into_future()).await
+}
+
+pub async fn reply_retrying(bot: &Bot, msg: &Message, text: String) -> ResponseResult<Message> {
+    send_retrying(msg.chat.id, reply_to(bot, msg, text)).await
+}
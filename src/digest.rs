@@ -0,0 +1,57 @@
+use crate::locale::Locale;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Digests summarize everything a chat said since its previous digest. The
+// watermark is the newest sequence number the last digest covered; nothing at
+// or below it is included again.
+pub const WATERMARKS_META_KEY: &str = "digest_watermarks";
+pub const SCHEDULES_META_KEY: &str = "digest_schedules";
+// The text of each chat's or thread's last posted digest, which the next one
+// builds on. Kept apart from anything /summarize records, so manual summaries
+// never change what a digest considers already covered.
+pub const TEXTS_META_KEY: &str = "digest_texts";
+
+// Leads the previous digest in the next digest's prompt
+pub const PREVIOUS_DIGEST_LABEL: &str = "Previous digest:";
+
+// A daily digest set up with /digest on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigestSchedule {
+    // Local wall-clock time in the chat's digest timezone
+    pub time: NaiveTime,
+    pub next_due: DateTime<Utc>,
+    // When a digest was last posted; runs with nothing new don't count
+    #[serde(default)]
+    pub last_digest_at: Option<DateTime<Utc>>,
+}
+
+impl DigestSchedule {
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.next_due <= now
+    }
+}
+
+// "HH:MM" on a 24-hour clock
+pub fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestOutcome {
+    // Posted a digest of this many messages
+    Sent(usize),
+    // Nothing new since the last digest
+    NothingNew,
+    // The monthly budget is used up; the watermark stays so nothing is skipped
+    OverBudget,
+}
+
+// Closing line of a digest
+pub fn trailer(messages: usize, locale: Locale) -> String {
+    format!(
+        "Digest of {} message{} since the last one.",
+        locale.integer(messages as u64),
+        if messages == 1 { "" } else { "s" }
+    )
+}
@@ -0,0 +1,106 @@
+use crate::{
+    admins::AdminCacheDebug, budget::BudgetDebug, chatinfo::ChatInfoDebug, config::ConfigDebug,
+    llm::ProvidersDebug, metrics::MetricsDebug, ratelimit::RateLimitDebug, stats::StatsDebug,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+// What /admin dump sends, for attaching to bug reports. It's assembled only
+// from each subsystem's debug_snapshot(), and none of those structs has a
+// field for message text, prompts or credentials, so a dump can't leak them
+// however it's filled in.
+#[derive(Debug, Serialize)]
+pub struct DebugDump {
+    pub taken_at: DateTime<Utc>,
+    pub build: BuildInfo,
+    pub features: Features,
+    pub config: ConfigDebug,
+    pub providers: ProvidersDebug,
+    pub store: StoreDebug,
+    pub budget: BudgetDebug,
+    pub rate_limits: RateLimitDebug,
+    pub admin_cache: AdminCacheDebug,
+    pub chat_info: ChatInfoDebug,
+    pub stats: StatsDebug,
+    pub metrics: MetricsDebug,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub profile: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+        }
+    }
+}
+
+// Optional parts of the bot and whether this deployment turned them on
+#[derive(Debug, Serialize)]
+pub struct Features {
+    pub database: bool,
+    pub snapshot: bool,
+    pub compaction: bool,
+    pub budget: bool,
+    pub events: bool,
+    pub metrics: bool,
+    pub prompt_variants: bool,
+    pub failover: bool,
+    pub streaming: bool,
+    pub allowed_chats: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoreDebug {
+    pub total_messages: usize,
+    pub total_bytes: usize,
+    pub tracked_chats: usize,
+    pub chats_with_settings: usize,
+    pub startup_time: DateTime<Utc>,
+    pub launched_at: DateTime<Utc>,
+    pub restored_from: Option<DateTime<Utc>>,
+    pub compactions_running: usize,
+    pub allowed_chats: Vec<i64>,
+    pub blocked_chats: Vec<i64>,
+    pub chats: Vec<ChatDebug>,
+    pub digests: Vec<DigestDebug>,
+}
+
+// One chat/thread's queue: sizes and times only
+#[derive(Debug, Serialize)]
+pub struct ChatDebug {
+    pub chat_id: i64,
+    pub thread_id: Option<i32>,
+    pub messages: usize,
+    pub bytes: usize,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    pub evictions: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DigestDebug {
+    pub chat_id: i64,
+    pub thread_id: Option<i32>,
+    pub time: String,
+    pub next_due: DateTime<Utc>,
+    pub last_digest_at: Option<DateTime<Utc>>,
+    pub watermark: Option<u64>,
+}
+
+pub fn filename(now: DateTime<Utc>) -> String {
+    format!("duck_summarizer-dump-{}.json", now.format("%Y-%m-%d-%H%M"))
+}
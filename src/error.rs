@@ -0,0 +1,200 @@
+use crate::task::LlmTask;
+use reqwest::StatusCode;
+use std::time::Duration;
+use tokio::task::JoinError;
+
+// Error bodies are kept for the logs, cut to this many characters
+const MAX_BODY_CHARS: usize = 500;
+
+// Why a summary, or any other provider task, couldn't be produced. Carries
+// what callers need to decide on retrying, failing over and what to tell users.
+#[derive(Debug)]
+pub enum SummarizeError {
+    // The provider needs a key and none is configured
+    MissingApiKey {
+        provider: String,
+    },
+    // The request couldn't be sent or its response couldn't be read
+    Http(reqwest::Error),
+    // The provider answered with an error status
+    ApiStatus {
+        status: StatusCode,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    // The prompt doesn't fit the model's context
+    ContextTooLarge {
+        status: StatusCode,
+        body: String,
+    },
+    // A successful response without any choices
+    EmptyChoices,
+    // A response or stream chunk that isn't what the API should send
+    ParseError(String),
+    // A stream that broke off or reported an error before it finished
+    Stream(String),
+    // Something on our side, e.g. prompt preparation panicked
+    Internal(String),
+}
+
+impl SummarizeError {
+    // Classify an error status. Providers report an oversized prompt as a 413
+    // or as a 400 mentioning the context length.
+    pub fn from_status(status: StatusCode, body: &str, retry_after: Option<Duration>) -> Self {
+        let body: String = body.chars().take(MAX_BODY_CHARS).collect();
+        let lower = body.to_lowercase();
+        if status == StatusCode::PAYLOAD_TOO_LARGE
+            || (status == StatusCode::BAD_REQUEST
+                && (lower.contains("context_length_exceeded")
+                    || lower.contains("context length")
+                    || lower.contains("context window")))
+        {
+            return SummarizeError::ContextTooLarge { status, body };
+        }
+        SummarizeError::ApiStatus {
+            status,
+            body,
+            retry_after,
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            SummarizeError::ApiStatus { status, .. }
+            | SummarizeError::ContextTooLarge { status, .. } => Some(*status),
+            SummarizeError::Http(e) => e.status(),
+            _ => None,
+        }
+    }
+
+    // The pause the server asked for before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SummarizeError::ApiStatus { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.status() == Some(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        match self {
+            SummarizeError::Http(_) => true,
+            _ => self.status().is_some_and(|status| status.is_server_error()),
+        }
+    }
+
+    // Worth sending the same request again after a pause
+    pub fn is_transient(&self) -> bool {
+        self.is_rate_limited() || self.is_server_error()
+    }
+
+    // Setup problems only the operator can fix: a missing or rejected key, or
+    // a model the provider doesn't know
+    pub fn is_configuration(&self) -> bool {
+        matches!(self, SummarizeError::MissingApiKey { .. })
+            || matches!(
+                self.status(),
+                Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND)
+            )
+    }
+
+    // Another provider may still serve the request. Requests the provider
+    // rejected as such would most likely be rejected there too.
+    pub fn fails_over(&self) -> bool {
+        self.is_transient() || self.is_configuration()
+    }
+
+    // Recorded in stats and usage events
+    pub fn class(&self) -> &'static str {
+        match self {
+            _ if self.is_configuration() => "provider_unavailable",
+            _ if self.is_rate_limited() => "provider_rate_limited",
+            _ if self.is_server_error() => "provider_server_error",
+            SummarizeError::ContextTooLarge { .. } => "provider_context_too_large",
+            SummarizeError::Internal(_) => "internal",
+            _ => "provider_failed",
+        }
+    }
+
+    // What the user who asked is told
+    pub fn user_message(&self, task: LlmTask) -> &'static str {
+        match self {
+            _ if self.is_configuration() => {
+                "The summarization service isn't set up correctly. An admin should check \
+                the bot's logs."
+            }
+            _ if self.is_rate_limited() => {
+                "The summarization service is rate limiting me right now. Please try again \
+                in a few minutes."
+            }
+            _ if self.is_server_error() => {
+                "The summarization service is having server problems. Please try again later."
+            }
+            SummarizeError::ContextTooLarge { .. } => {
+                "That's too much conversation for the model at once. Please try a smaller \
+                count or a shorter time window."
+            }
+            SummarizeError::EmptyChoices => {
+                "The summarization service returned an empty answer. Please try again."
+            }
+            _ => task.failure(),
+        }
+    }
+}
+
+impl std::fmt::Display for SummarizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SummarizeError::MissingApiKey { provider } => {
+                write!(f, "no API key configured for {}", provider)
+            }
+            SummarizeError::Http(e) => write!(f, "request failed: {}", e),
+            SummarizeError::ApiStatus {
+                status,
+                body,
+                retry_after,
+            } => {
+                write!(f, "API error: status {}: {}", status, body)?;
+                if let Some(wait) = retry_after {
+                    write!(f, " (retry after {}s)", wait.as_secs())?;
+                }
+                Ok(())
+            }
+            SummarizeError::ContextTooLarge { status, body } => {
+                write!(
+                    f,
+                    "prompt too large for the model (status {}): {}",
+                    status, body
+                )
+            }
+            SummarizeError::EmptyChoices => write!(f, "API returned no choices"),
+            SummarizeError::ParseError(reason) => write!(f, "unparseable response: {}", reason),
+            SummarizeError::Stream(reason) => write!(f, "stream failed: {}", reason),
+            SummarizeError::Internal(reason) => write!(f, "internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SummarizeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SummarizeError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for SummarizeError {
+    fn from(e: reqwest::Error) -> Self {
+        SummarizeError::Http(e)
+    }
+}
+
+impl From<JoinError> for SummarizeError {
+    fn from(e: JoinError) -> Self {
+        SummarizeError::Internal(format!("prompt preparation failed: {}", e))
+    }
+}
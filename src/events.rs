@@ -0,0 +1,176 @@
+use crate::tasks::{self, TaskRegistryType};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{env, sync::Arc, time::Duration};
+use teloxide::types::{ChatId, ThreadId};
+use tokio::sync::{Mutex, mpsc};
+
+// Bump when fields change meaning or disappear; adding fields is fine
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+pub const SIGNATURE_HEADER: &str = "X-Duck-Signature";
+// Events beyond this are dropped rather than delaying anything
+const QUEUE_CAPACITY: usize = 100;
+const RETRY_DELAY_SECS: u64 = 2;
+
+// One summarization, as reported to the webhook. Never carries message content.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SummaryEvent {
+    pub version: u32,
+    pub timestamp: DateTime<Utc>,
+    // "summarize" or "summarizeall"
+    pub command: &'static str,
+    // Keyed hash so the dashboard can group by chat without learning its id
+    pub chat: String,
+    pub thread: Option<i32>,
+    pub requested: usize,
+    pub actual: usize,
+    // "provider", "failover" or "extracts"
+    pub source: &'static str,
+    pub provider: Option<String>,
+    pub latency_ms: u64,
+    pub estimated_tokens: Option<u64>,
+    // As counted by the provider; None if it didn't report them
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    pub error: Option<&'static str>,
+}
+
+impl SummaryEvent {
+    pub fn new(
+        command: &'static str,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        secret: &str,
+    ) -> Self {
+        Self {
+            version: EVENT_SCHEMA_VERSION,
+            timestamp: Utc::now(),
+            command,
+            chat: chat_hash(chat_id, secret),
+            thread: thread_id.map(|thread| thread.0.0),
+            requested: 0,
+            actual: 0,
+            source: "provider",
+            provider: None,
+            latency_ms: 0,
+            estimated_tokens: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+            error: None,
+        }
+    }
+}
+
+pub fn chat_hash(chat_id: ChatId, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(secret.as_bytes());
+    hasher.update(chat_id.0.to_string().as_bytes());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+// Hex HMAC-SHA256 of the request body, sent as "sha256=<hex>"
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Fire-and-forget delivery to EVENT_WEBHOOK_URL through a bounded queue
+#[derive(Debug, Clone)]
+pub struct EventSink {
+    sender: mpsc::Sender<SummaryEvent>,
+    secret: String,
+}
+
+impl EventSink {
+    pub fn from_env(registry: &TaskRegistryType) -> Option<Self> {
+        let url = env::var("EVENT_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let secret = env::var("EVENT_WEBHOOK_SECRET").unwrap_or_default();
+        if secret.is_empty() {
+            warn!(target: "config", "EVENT_WEBHOOK_SECRET is not set, webhook events will be signed with an empty key");
+        }
+        info!(target: "config", "Sending summarization events to {}", url);
+
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        // Shared, so a delivery loop restarted after a panic picks up the queue
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (beats, delivery_secret) = (registry.clone(), secret.clone());
+        tokio::spawn(tasks::supervise(
+            registry.clone(),
+            "event delivery",
+            None,
+            tasks::RESTART_BACKOFF,
+            move || {
+                deliver(
+                    url.clone(),
+                    delivery_secret.clone(),
+                    receiver.clone(),
+                    beats.clone(),
+                )
+            },
+        ));
+        Some(Self { sender, secret })
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    pub fn emit(&self, event: SummaryEvent) {
+        if let Err(e) = self.sender.try_send(event) {
+            debug!(target: "events", "Dropping summarization event: {}", e);
+        }
+    }
+}
+
+async fn deliver(
+    url: String,
+    secret: String,
+    receiver: Arc<Mutex<mpsc::Receiver<SummaryEvent>>>,
+    tasks: TaskRegistryType,
+) {
+    let client = reqwest::Client::new();
+    let mut receiver = receiver.lock().await;
+    while let Some(event) = receiver.recv().await {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(target: "events", "Couldn't serialize event: {}", e);
+                continue;
+            }
+        };
+        let signature = format!("sha256={}", sign(&secret, &body));
+
+        for attempt in 0..2 {
+            let result = client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => {
+                    tasks.beat("event delivery", "delivered", Utc::now());
+                    break;
+                }
+                Err(e) if attempt == 0 => {
+                    debug!(target: "events", "Webhook delivery failed, retrying: {}", e);
+                    tokio::time::sleep(Duration::from_secs(RETRY_DELAY_SECS)).await;
+                }
+                Err(e) => {
+                    warn!(target: "events", "Dropping event after failed retry: {}", e);
+                    tasks.beat("event delivery", format!("dropped: {}", e), Utc::now());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use crate::store::{ChatThreadId, SavedMessage};
+use std::collections::VecDeque;
+
+// Rough fixed cost of a stored message beyond its strings: the struct itself,
+// the String headers, the queue slot and allocator slack
+const MESSAGE_OVERHEAD: usize = 192;
+
+// Estimated heap footprint of one stored message
+pub fn message_bytes(message: &SavedMessage) -> usize {
+    MESSAGE_OVERHEAD
+        + message.text.len()
+        + message.from_user.as_ref().map_or(0, String::len)
+        + message.username.as_ref().map_or(0, String::len)
+        + message.reply_to_user.as_ref().map_or(0, String::len)
+}
+
+pub fn queue_bytes<'a>(messages: impl IntoIterator<Item = &'a SavedMessage>) -> usize {
+    messages.into_iter().map(message_bytes).sum()
+}
+
+// Where a message can be evicted from: the front, or right behind a compacted
+// summary, which outlives the messages after it. None if nothing but the
+// summary is left.
+pub fn evictable_position(queue: &VecDeque<SavedMessage>) -> Option<usize> {
+    let position = usize::from(queue.front().is_some_and(|m| m.synthetic));
+    (position < queue.len()).then_some(position)
+}
+
+// The queue to evict from when the store is over MAX_STORE_BYTES: the one
+// holding the most messages, so busy chats give up history before quiet ones
+// lose what little they have. Ties go to the queue whose evictable message is
+// oldest. Queues with nothing evictable are skipped.
+pub fn pick_victim<'a>(
+    queues: impl IntoIterator<Item = (&'a ChatThreadId, &'a VecDeque<SavedMessage>)>,
+) -> Option<ChatThreadId> {
+    queues
+        .into_iter()
+        .filter_map(|(key, queue)| {
+            let position = evictable_position(queue)?;
+            Some((queue.len(), std::cmp::Reverse(queue[position].seq), key))
+        })
+        .max_by_key(|(len, oldest, _)| (*len, *oldest))
+        .map(|(_, _, key)| key.clone())
+}
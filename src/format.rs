@@ -0,0 +1,52 @@
+use crate::{blockterms, prompt, store::SavedMessage};
+use teloxide::utils::markdown;
+
+// Wrap plain text in a MarkdownV2 entity. Whitespace at the edges is moved
+// outside the entity (Telegram rejects or mis-renders entities that start or
+// end with it), and empty text yields no entity at all, since "__" would open
+// an underline instead of an empty italic. The escaped body can't contain an
+// unescaped marker, so the entity is always balanced.
+fn styled(text: &str, marker: &str) -> String {
+    let inner = text.trim();
+    if inner.is_empty() {
+        return String::new();
+    }
+    let start = text.len() - text.trim_start().len();
+    let end = start + inner.len();
+    format!(
+        "{}{}{}{}{}",
+        markdown::escape(&text[..start]),
+        marker,
+        markdown::escape(inner),
+        marker,
+        markdown::escape(&text[end..])
+    )
+}
+
+pub fn italic(text: &str) -> String {
+    styled(text, "_")
+}
+
+pub fn bold(text: &str) -> String {
+    styled(text, "*")
+}
+
+pub fn link(text: &str, url: &str) -> String {
+    format!(
+        "[{}]({})",
+        markdown::escape(text),
+        markdown::escape_link_url(url)
+    )
+}
+
+// The messages rendered exactly as a summary prompt would show them, without
+// condensing pastes or cutting optional passes short
+pub fn format_conversation(messages: &[SavedMessage]) -> String {
+    prompt::build(
+        messages,
+        std::time::Duration::MAX,
+        false,
+        &blockterms::Matcher::default(),
+    )
+    .text
+}
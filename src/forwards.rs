@@ -0,0 +1,101 @@
+use crate::store::SavedMessage;
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+use teloxide::types::{ChatId, MessageOrigin};
+use tokio::sync::Mutex;
+
+// Forwards kept per private chat; the oldest are dropped past this
+const MAX_FORWARDS: usize = 200;
+// Forwards not summarized within this long are dropped
+const FORWARD_TTL_MINUTES: i64 = 10;
+
+// Who wrote a forwarded message, as far as the forward tells
+pub fn origin_name(origin: &MessageOrigin) -> String {
+    match origin {
+        MessageOrigin::User { sender_user, .. } => sender_user.full_name(),
+        MessageOrigin::HiddenUser {
+            sender_user_name, ..
+        } if !sender_user_name.trim().is_empty() => sender_user_name.clone(),
+        MessageOrigin::HiddenUser { .. } => "Hidden user".to_string(),
+        MessageOrigin::Chat {
+            sender_chat,
+            author_signature,
+            ..
+        }
+        | MessageOrigin::Channel {
+            chat: sender_chat,
+            author_signature,
+            ..
+        } => match (author_signature, sender_chat.title()) {
+            (Some(signature), Some(title)) => format!("{} ({})", signature, title),
+            (None, Some(title)) => title.to_string(),
+            (Some(signature), None) => signature.clone(),
+            (None, None) => "Hidden user".to_string(),
+        },
+    }
+}
+
+// The @username of a forward's author, when Telegram shares it
+pub fn origin_username(origin: &MessageOrigin) -> Option<String> {
+    match origin {
+        MessageOrigin::User { sender_user, .. } => sender_user.username.clone(),
+        MessageOrigin::Chat { sender_chat, .. } => sender_chat.username().map(str::to_string),
+        MessageOrigin::Channel { chat, .. } => chat.username().map(str::to_string),
+        MessageOrigin::HiddenUser { .. } => None,
+    }
+}
+
+// Messages forwarded to the bot in private chats, waiting for /summarize.
+// They're kept apart from the conversation with the bot, so a batch from
+// another chat is summarized on its own, under its authors' names.
+#[derive(Debug, Default)]
+pub struct PendingForwards {
+    by_chat: HashMap<ChatId, VecDeque<(DateTime<Utc>, SavedMessage)>>,
+    next_seq: u64,
+}
+
+impl PendingForwards {
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - Duration::minutes(FORWARD_TTL_MINUTES);
+        self.by_chat.retain(|_, forwards| {
+            forwards.retain(|(received, _)| *received > cutoff);
+            !forwards.is_empty()
+        });
+    }
+
+    pub fn push(&mut self, chat_id: ChatId, mut message: SavedMessage, now: DateTime<Utc>) {
+        self.expire(now);
+        message.seq = self.next_seq;
+        self.next_seq += 1;
+        let forwards = self.by_chat.entry(chat_id).or_default();
+        forwards.push_back((now, message));
+        if forwards.len() > MAX_FORWARDS {
+            forwards.pop_front();
+        }
+    }
+
+    // The chat's unexpired forwards, oldest first
+    pub fn pending(&mut self, chat_id: ChatId, now: DateTime<Utc>) -> Vec<SavedMessage> {
+        self.expire(now);
+        self.by_chat
+            .get(&chat_id)
+            .map(|forwards| forwards.iter().map(|(_, m)| m.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    // Drop the forwards a summary covered, up to and including `seq`. Ones
+    // that arrived while it was written stay for the next /summarize.
+    pub fn clear_through(&mut self, chat_id: ChatId, seq: u64) {
+        if let Some(forwards) = self.by_chat.get_mut(&chat_id) {
+            forwards.retain(|(_, m)| m.seq > seq);
+            if forwards.is_empty() {
+                self.by_chat.remove(&chat_id);
+            }
+        }
+    }
+}
+
+pub type PendingForwardsType = Arc<Mutex<PendingForwards>>;
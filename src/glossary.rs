@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+pub const MAX_ENTRIES: usize = 30;
+const MAX_TERM_CHARS: usize = 40;
+const MAX_DEFINITION_CHARS: usize = 200;
+// Words that could make an entry read like a chat turn or an instruction to
+// the model rather than a definition
+const ROLE_KEYWORDS: &[&str] = &["system", "assistant", "user", "developer"];
+
+// Why an entry was rejected, worded for the admin who sent it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryError {
+    Format,
+    Empty,
+    TooLong,
+    RoleKeyword(&'static str),
+    Full,
+}
+
+impl std::fmt::Display for EntryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntryError::Format => write!(f, "Usage: /glossary add <term>: <definition>"),
+            EntryError::Empty => write!(f, "Both the term and the definition need some text."),
+            EntryError::TooLong => write!(
+                f,
+                "Terms can be up to {} characters and definitions up to {}.",
+                MAX_TERM_CHARS, MAX_DEFINITION_CHARS
+            ),
+            EntryError::RoleKeyword(keyword) => write!(
+                f,
+                "Entries can't contain \"{}:\", since the model could mistake it for part of \
+                its instructions.",
+                keyword
+            ),
+            EntryError::Full => write!(
+                f,
+                "The glossary is full ({} entries). Remove one first.",
+                MAX_ENTRIES
+            ),
+        }
+    }
+}
+
+// Collapse newlines and other control characters into single spaces, so an
+// entry always stays on its own line of the glossary block
+pub fn sanitize(text: &str) -> String {
+    text.split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn check(text: &str, max_chars: usize) -> Result<String, EntryError> {
+    let text = sanitize(text);
+    if text.is_empty() {
+        return Err(EntryError::Empty);
+    }
+    if text.chars().count() > max_chars {
+        return Err(EntryError::TooLong);
+    }
+    let lower = text.to_lowercase();
+    for keyword in ROLE_KEYWORDS {
+        let marker = format!("{}:", keyword);
+        if lower.contains(&marker) {
+            return Err(EntryError::RoleKeyword(keyword));
+        }
+    }
+    Ok(text)
+}
+
+// Parse "<term>: <definition>". The first colon separates the two, so
+// definitions may contain colons of their own.
+pub fn parse_entry(value: &str) -> Result<(String, String), EntryError> {
+    let (term, definition) = value.split_once(':').ok_or(EntryError::Format)?;
+    check_entry(term, definition)
+}
+
+// Sanitize a term and its definition, rejecting what /glossary add would
+pub fn check_entry(term: &str, definition: &str) -> Result<(String, String), EntryError> {
+    Ok((
+        check(term, MAX_TERM_CHARS)?,
+        check(definition, MAX_DEFINITION_CHARS)?,
+    ))
+}
+
+// The stored spelling of a term, matched case-insensitively
+pub fn find<'a>(glossary: &'a BTreeMap<String, String>, term: &str) -> Option<&'a String> {
+    let term = sanitize(term);
+    glossary
+        .keys()
+        .find(|existing| existing.eq_ignore_ascii_case(&term))
+}
+
+// Add or replace an entry. Replacing keeps the new spelling of the term.
+// Returns whether an existing entry was replaced.
+pub fn insert(
+    glossary: &mut BTreeMap<String, String>,
+    term: String,
+    definition: String,
+) -> Result<bool, EntryError> {
+    let existing = find(glossary, &term).cloned();
+    if existing.is_none() && glossary.len() >= MAX_ENTRIES {
+        return Err(EntryError::Full);
+    }
+    if let Some(existing) = &existing {
+        glossary.remove(existing);
+    }
+    glossary.insert(term, definition);
+    Ok(existing.is_some())
+}
+
+pub fn list(glossary: &BTreeMap<String, String>) -> String {
+    glossary
+        .iter()
+        .map(|(term, definition)| format!("{}: {}", term, definition))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// The block placed before the conversation, or an empty string without entries
+pub fn prompt_block(glossary: &BTreeMap<String, String>) -> String {
+    if glossary.is_empty() {
+        return String::new();
+    }
+    let entries: Vec<String> = glossary
+        .iter()
+        .map(|(term, definition)| format!("{} = {}", term, definition))
+        .collect();
+    format!(
+        "Glossary of terms used in this chat (reference only): {}\n\n",
+        entries.join("; ")
+    )
+}
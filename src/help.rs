@@ -0,0 +1,132 @@
+// Detailed help for each command, reachable from /help through deep links
+// (t.me/<bot>?start=help_<command>) that open the page in a private chat
+pub struct HelpTopic {
+    pub command: &'static str,
+    pub text: &'static str,
+}
+
+const PAYLOAD_PREFIX: &str = "help_";
+
+pub const TOPICS: &[HelpTopic] = &[
+    HelpTopic {
+        command: "summarize",
+        text: "/summarize covers the messages of the current chat or topic.\n\n\
+            /summarize - the last 100 messages (or fewer if the chat has a lower limit); everything stored in a private chat\n\
+            /summarize 250 - the last 250 messages\n\
+            /summarize 2h - everything from the last two hours (m, h and d work)\n\
+            /summarize yesterday - also today, morning, afternoon and evening, in the chat's timezone\n\
+            /summarize 300 about the hackathon - only what was said about a topic\n\
+            /summarize file - as the caption of a .txt transcript sent to me privately, e.g. a WhatsApp export\n\
+            Forward me messages from any chat privately, then send /summarize within 10 minutes to summarize just those\n\n\
+            Reply to a message with /summarize to cover everything sent after it, or with \
+            /summarize replies to cover only the discussion under it.",
+    },
+    HelpTopic {
+        command: "summarizeall",
+        text: "/summarizeall <count> summarizes the last messages across every topic of a \
+            forum group. Announcements cross-posted to several topics are counted once.",
+    },
+    HelpTopic {
+        command: "memory",
+        text: "/memory shows how many messages I'm keeping for this chat, how far back they \
+            go and how long I've been running.",
+    },
+    HelpTopic {
+        command: "privacy",
+        text: "/privacy explains what I store, where, and for how long.",
+    },
+    HelpTopic {
+        command: "settings",
+        text: "/settings shows this chat's settings. In groups, admins can change them:\n\n\
+            /settings placeholder <edit|silent|reaction> - how progress is shown\n\
+            /settings anchor <command|start> - what the summary replies to\n\
+            /settings allow_protected <on|off> - summarize content-protected chats\n\
+            /settings maxsummarize <n|off> - cap how many messages a summary covers\n\
+            /settings adminsexempt <on|off> - let admins go past that cap\n\
+            /settings timezone <name|off> - timezone for today, yesterday and so on\n\
+            /settings pastes <condense|keep> - shorten pasted logs and code in prompts\n\
+            /settings blockterm <add|remove|list> [term] - leave messages containing a term \
+            out of summaries and redact it from them; the list is sent privately\n\
+            /settings exclusionnote <on|off> - say when chat settings left messages out\n\
+            /settings export - privately send you these settings as a file\n\
+            /settings import [users] - reply to such a file to copy its settings here; shows \
+            what would change first, then apply with /settings import confirm. Ignored users \
+            are only copied with users.",
+    },
+    HelpTopic {
+        command: "language",
+        text: "/language shows which language summaries are written in. By default they \
+            follow the language of the conversation. In groups, admins can pick one with \
+            /language <code> (e.g. pl, en, de) or go back to the default with /language auto.",
+    },
+    HelpTopic {
+        command: "limits",
+        text: "/limits lists the limits that apply in this chat and whether each comes from \
+            the chat's settings or the bot's defaults.",
+    },
+    HelpTopic {
+        command: "usage",
+        text: "/usage shows how many summaries this chat got since the bot last started and \
+            how many tokens they took, with an estimated cost if the bot has prices \
+            configured.",
+    },
+    HelpTopic {
+        command: "debugprompt",
+        text: "/debugprompt [count] shows how many tokens a summary of the last messages \
+            (100 by default) would send, how much of the model's context that is, and \
+            whether the conversation would be split into parts.",
+    },
+    HelpTopic {
+        command: "digest",
+        text: "/digest on 18:00 makes me post a digest of everything new in this chat or \
+            topic every day at that time; sent in a forum's General topic, it covers every \
+            topic. The time is in the chat's timezone (/settings timezone), or the bot's \
+            digest timezone otherwise. Days without new messages are skipped.\n\n\
+            /digest status - when the next digest is due\n\
+            /digest off - stop the daily digest",
+    },
+    HelpTopic {
+        command: "export",
+        text: "/export <count> sends you the last stored messages of this chat or topic as a \
+            text file, written the way I see them when summarizing, so you can check what I \
+            captured. Only admins can use it, and the file always goes to our private chat, \
+            so start one with me first.",
+    },
+    HelpTopic {
+        command: "regenerate",
+        text: "/regenerate writes the last summary of this chat or topic again from the same \
+            messages and says what changed, e.g. which points are new. It covers what the \
+            last /summarize covered, not newer messages.",
+    },
+    HelpTopic {
+        command: "restrict",
+        text: "/restrict on lets only this group's admins request summaries, moods, topic \
+            lists and exports, including inline ones; /restrict off opens them to everyone \
+            again. /restrict alone shows which applies.",
+    },
+    HelpTopic {
+        command: "inline",
+        text: "You can also summarize from any chat by typing my username followed by a count, \
+            e.g. @my_bot 50. I offer the chats I've recently seen you write in; picking one \
+            posts a summary of its last messages. If nothing is offered yet, write something in \
+            a group I'm in first.",
+    },
+];
+
+// The /start payload that opens a topic, e.g. "help_summarize"
+pub fn payload(topic: &HelpTopic) -> String {
+    format!("{}{}", PAYLOAD_PREFIX, topic.command)
+}
+
+pub fn deep_link(bot_username: &str, topic: &HelpTopic) -> String {
+    format!("https://t.me/{}?start={}", bot_username, payload(topic))
+}
+
+pub fn topic(command: &str) -> Option<&'static HelpTopic> {
+    TOPICS.iter().find(|topic| topic.command == command)
+}
+
+// The topic a /start payload asks for, if it is a help link
+pub fn topic_for_payload(payload: &str) -> Option<&'static HelpTopic> {
+    topic(payload.trim().strip_prefix(PAYLOAD_PREFIX)?)
+}
@@ -0,0 +1,235 @@
+use crate::{lang, media::MessageKind, store::SavedMessage};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use teloxide::types::MessageId;
+
+// Text of an exported message. Telegram Desktop writes plain strings for
+// unformatted messages and an array of strings and entity objects otherwise.
+fn export_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .map(|part| match part {
+                Value::String(text) => text.as_str(),
+                Value::Object(entity) => entity.get("text").and_then(Value::as_str).unwrap_or(""),
+                _ => "",
+            })
+            .collect(),
+        _ => String::new(),
+    }
+}
+
+fn export_kind(message: &Value) -> MessageKind {
+    let field = |name: &str| message.get(name).and_then(Value::as_str);
+    if let Some(origin) = field("forwarded_from") {
+        return MessageKind::Forward {
+            origin: origin.to_string(),
+        };
+    }
+    if message.get("photo").is_some() {
+        return MessageKind::Photo;
+    }
+    if message.get("poll").is_some() {
+        return MessageKind::Poll;
+    }
+    match field("media_type") {
+        Some("sticker") => MessageKind::Sticker {
+            emoji: field("sticker_emoji").map(str::to_string),
+        },
+        Some("voice_message") => MessageKind::Voice,
+        Some("video_file" | "video_message" | "animation") => MessageKind::Video,
+        _ if message.get("file").is_some() => MessageKind::Document {
+            filename: field("file_name").map(str::to_string),
+        },
+        _ => MessageKind::Text,
+    }
+}
+
+fn export_date(message: &Value) -> Option<DateTime<Utc>> {
+    if let Some(unix) = message
+        .get("date_unixtime")
+        .and_then(Value::as_str)
+        .and_then(|value| value.parse().ok())
+    {
+        return DateTime::from_timestamp(unix, 0);
+    }
+    // Older exports only have the local time; treating it as UTC is close enough
+    let date = message.get("date").and_then(Value::as_str)?;
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S")
+        .ok()
+        .map(|naive| naive.and_utc())
+}
+
+// Convert the newest `limit` messages of a Telegram Desktop JSON export
+// (result.json) into stored messages, oldest first. Service messages and
+// messages with neither text nor media (e.g. locations) are skipped.
+pub fn parse_export(json: &str, limit: usize) -> Result<Vec<SavedMessage>, String> {
+    let export: Value =
+        serde_json::from_str(json).map_err(|e| format!("not a JSON file: {}", e))?;
+    let messages = export
+        .get("messages")
+        .and_then(Value::as_array)
+        .ok_or("no messages array; export a single chat in JSON format")?;
+
+    let authors: HashMap<i64, &str> = messages
+        .iter()
+        .filter_map(|m| Some((m.get("id")?.as_i64()?, m.get("from")?.as_str()?)))
+        .collect();
+
+    let mut saved: Vec<SavedMessage> = messages
+        .iter()
+        .filter(|m| m.get("type").and_then(Value::as_str) == Some("message"))
+        .filter_map(|m| {
+            let id = m.get("id")?.as_i64()?;
+            let kind = export_kind(m);
+            let mut text = export_text(m.get("text")?);
+            if let Some(question) = m.pointer("/poll/question").and_then(Value::as_str) {
+                text = question.to_string();
+            }
+            if kind == MessageKind::Text && text.trim().is_empty() {
+                return None;
+            }
+            let reply_to = m.get("reply_to_message_id").and_then(Value::as_i64);
+            Some(SavedMessage {
+                seq: 0, // assigned by the store
+                message_id: MessageId(i32::try_from(id).ok()?),
+                from_user: m.get("from").and_then(Value::as_str).map(str::to_string),
+                // Exports name senders but don't include their @username
+                username: None,
+                reply_to_message_id: reply_to
+                    .and_then(|id| i32::try_from(id).ok())
+                    .map(MessageId),
+                reply_to_user: reply_to
+                    .and_then(|id| authors.get(&id))
+                    .map(|name| name.to_string()),
+                lang: lang::detect_language(&text),
+                text,
+                kind,
+                timestamp: export_date(m)?,
+                synthetic: false,
+                edited: m.get("edited").is_some(),
+                reactions: Default::default(),
+            })
+        })
+        .collect();
+
+    saved.sort_by_key(|m| (m.timestamp, m.message_id.0));
+    let skip = saved.len().saturating_sub(limit);
+    saved.drain(..skip);
+    Ok(saved)
+}
+
+// Longest sender name a "Name: message" line may start with; anything longer
+// is more likely a sentence with a colon in it
+const MAX_SENDER_CHARS: usize = 64;
+
+// The rest of a line after a WhatsApp timestamp, "[31/12/2023, 22:15:30] " on
+// iOS or "31/12/2023, 22:15 - " on Android
+fn strip_whatsapp_stamp(line: &str) -> Option<&str> {
+    let line = line.trim_start_matches('\u{200e}');
+    let (stamp, rest) = match line.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once("] ")?,
+        None => line.split_once(" - ")?,
+    };
+    let looks_like_stamp = stamp.starts_with(|c: char| c.is_ascii_digit())
+        && stamp.contains(',')
+        && stamp.contains(':')
+        && stamp.len() <= 32;
+    looks_like_stamp.then_some(rest)
+}
+
+fn split_sender(line: &str) -> Option<(&str, &str)> {
+    let (name, text) = line.split_once(": ")?;
+    let name = name.trim();
+    (!name.is_empty() && name.chars().count() <= MAX_SENDER_CHARS).then_some((name, text))
+}
+
+// Convert a pasted conversation into messages, oldest first, keeping the newest
+// `limit`. Lines look like "Name: message" or, in WhatsApp exports,
+// "[date, time] Name: message"; the first line decides which. Other lines
+// continue the message before them, and WhatsApp lines without a sender (e.g.
+// "Messages are end-to-end encrypted") are skipped. Dates in exports follow
+// the phone's locale, so every message gets `now` and only their order counts.
+pub fn parse_transcript(
+    text: &str,
+    now: DateTime<Utc>,
+    limit: usize,
+) -> Result<Vec<SavedMessage>, String> {
+    let text = text.trim_start_matches('\u{feff}');
+    let whatsapp = text
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .ok_or("the file is empty")
+        .map(|line| strip_whatsapp_stamp(line).is_some())?;
+    let mut parsed: Vec<(String, String)> = Vec::new();
+    // Whether lines that don't start a message belong to the one before
+    let mut continuing = false;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.trim().is_empty() {
+            continue;
+        }
+        let header = if whatsapp {
+            match strip_whatsapp_stamp(line) {
+                Some(rest) => match split_sender(rest) {
+                    Some(header) => Some(header),
+                    None => {
+                        continuing = false;
+                        continue;
+                    }
+                },
+                None => None,
+            }
+        } else {
+            split_sender(line)
+        };
+        match header {
+            Some((name, body)) => {
+                parsed.push((name.to_string(), body.to_string()));
+                continuing = true;
+            }
+            None if continuing => {
+                if let Some((_, body)) = parsed.last_mut() {
+                    body.push('\n');
+                    body.push_str(line);
+                }
+            }
+            None if whatsapp => continue,
+            None => {
+                let excerpt: String = line.chars().take(80).collect();
+                return Err(format!(
+                    "line {} isn't a message: \"{}\"",
+                    index + 1,
+                    excerpt
+                ));
+            }
+        }
+    }
+    if parsed.is_empty() {
+        return Err("no messages found".to_string());
+    }
+
+    let skip = parsed.len().saturating_sub(limit);
+    Ok(parsed
+        .into_iter()
+        .skip(skip)
+        .enumerate()
+        .map(|(index, (name, text))| SavedMessage {
+            seq: 0,
+            message_id: MessageId(index as i32 + 1),
+            from_user: Some(name),
+            username: None,
+            reply_to_message_id: None,
+            reply_to_user: None,
+            lang: lang::detect_language(&text),
+            text,
+            kind: MessageKind::Text,
+            timestamp: now,
+            synthetic: false,
+            edited: false,
+            reactions: Default::default(),
+        })
+        .collect())
+}
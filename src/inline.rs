@@ -0,0 +1,83 @@
+use crate::store::ChatThreadId;
+use chrono::{DateTime, Utc};
+use std::{collections::HashMap, sync::Arc};
+use teloxide::types::{ChatId, MessageId, ThreadId, UserId};
+use tokio::sync::Mutex;
+
+// Chats offered per inline query
+const MAX_CHATS_PER_USER: usize = 5;
+// Users are pruned once the map grows past this many
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct RecentChat {
+    pub key: ChatThreadId,
+    pub title: String,
+    pub last_seen: DateTime<Utc>,
+}
+
+// Inline queries don't say which chat they were typed in, so each user is
+// offered the chats the bot has recently seen them write in. That also keeps
+// anyone from summarizing a chat they aren't part of.
+#[derive(Debug, Default)]
+pub struct RecentChats {
+    by_user: HashMap<UserId, Vec<RecentChat>>,
+}
+
+impl RecentChats {
+    pub fn record(&mut self, user: UserId, key: ChatThreadId, title: String, now: DateTime<Utc>) {
+        if self.by_user.len() >= PRUNE_THRESHOLD && !self.by_user.contains_key(&user) {
+            let cutoff = now - chrono::Duration::days(7);
+            self.by_user
+                .retain(|_, chats| chats.first().is_some_and(|chat| chat.last_seen > cutoff));
+        }
+        let chats = self.by_user.entry(user).or_default();
+        chats.retain(|chat| chat.key != key);
+        chats.insert(
+            0,
+            RecentChat {
+                key,
+                title,
+                last_seen: now,
+            },
+        );
+        chats.truncate(MAX_CHATS_PER_USER);
+    }
+
+    // Most recent first
+    pub fn chats(&self, user: UserId) -> &[RecentChat] {
+        self.by_user.get(&user).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn contains(&self, user: UserId, key: &ChatThreadId) -> bool {
+        self.chats(user).iter().any(|chat| chat.key == *key)
+    }
+}
+
+pub type RecentChatsType = Arc<Mutex<RecentChats>>;
+
+// Inline result ids carry what to summarize, since the chosen-result update
+// only hands the id back: "s:<chat_id>:<thread_id or 0>:<count>"
+pub fn result_id(key: &ChatThreadId, count: usize) -> String {
+    format!(
+        "s:{}:{}:{}",
+        key.chat_id.0,
+        key.thread_id.map(|thread| thread.0.0).unwrap_or(0),
+        count
+    )
+}
+
+pub fn parse_result_id(id: &str) -> Option<(ChatThreadId, usize)> {
+    let mut parts = id.strip_prefix("s:")?.split(':');
+    let chat_id = ChatId(parts.next()?.parse().ok()?);
+    let thread: i32 = parts.next()?.parse().ok()?;
+    let count = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let key = ChatThreadId {
+        chat_id,
+        thread_id: (thread != 0).then_some(ThreadId(MessageId(thread))),
+    };
+    Some((key, count))
+}
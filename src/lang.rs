@@ -0,0 +1,249 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+
+// Texts with fewer letters than this are too short to classify reliably
+const MIN_LETTERS: usize = 20;
+// Minimum number of profile trigrams a text must hit to get a label
+const MIN_SCORE: usize = 3;
+// Share of labelled messages a language needs to count as dominant
+const DOMINANT_SHARE_PERCENT: usize = 60;
+
+// Most frequent character trigrams per language, with spaces marking word edges
+const PROFILES: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            " th", "the", "he ", "and", " an", "nd ", " to", "ing", "ng ", " of", "of ", " in",
+            "is ", "at ", "ion", "ent", " is", "hat", "for", " yo", "you", "ou ", "er ", "it ",
+            "re ", "tha", "wit", " wh", "thi", "ll ",
+        ],
+    ),
+    (
+        "pl",
+        &[
+            "nie", " ni", "ie ", " pr", "ego", " po", " na", "ch ", "że ", " że", " si", "się",
+            "ię ", " je", "est", " w ", "dzi", "ani", "rze", "prz", "owa", "jak", "to ", "go ",
+            " za", " do", "ak ", "jes", "cie", "ać ",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "en ", "er ", "der", " de", "ie ", "ich", "die", " di", "und", " un", "nd ", "ein",
+            " ei", "sch", "che", "ch ", "cht", " da", "den", "ten", "ist", " is", "nic", "das",
+            " ge", "gen", "ht ", " ni", "auf", "nn ",
+        ],
+    ),
+    (
+        "es",
+        &[
+            " de", "de ", "os ", " la", "la ", "que", " qu", "ue ", "es ", "el ", " el", "en ",
+            " en", "as ", "ent", " co", "ado", "nte", "con", " lo", "ión", "par", " pa", "est",
+            "ara", "ien", "por", " po", "una", " un",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            " de", "es ", "de ", "ent", " le", "le ", "nt ", "la ", " la", "les", " et", "et ",
+            "que", " qu", "ue ", "ion", " pa", "ous", "our", " po", " un", "une", "est", " es",
+            "re ", "ait", "eux", "oi ", " je", "pas",
+        ],
+    ),
+    (
+        "it",
+        &[
+            " di", "di ", "che", " ch", "to ", "la ", " la", "re ", "he ", "ell", "del", " de",
+            " il", "il ", "ere", "one", "per", " pe", "zio", "ion", "no ", "non", " no", "con",
+            " co", "ono", "gli", " gl", "ato", "lla",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            " de", "de ", "os ", "que", " qu", "ue ", " co", "ão ", "ção", "do ", " do", "da ",
+            " da", "ent", "com", "as ", "es ", "nte", " pa", "par", "ra ", "não", " nã", "em ",
+            " em", "um ", " um", "uma", "ar ", "men",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "en ", "de ", " de", "an ", "het", " he", "et ", "van", " va", "een", " ee", "ij ",
+            "er ", "ing", " ge", "dat", " da", "at ", "nie", "ie ", "oor", "ver", " ve", "aar",
+            "cht", " ik", "ik ", "jk ", "lij", "zij",
+        ],
+    ),
+];
+
+// Guess the language of a message. Cheap enough to run on every stored message:
+// one pass over the text plus a lookup per trigram.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let lowered = text.to_lowercase();
+    let letters = lowered.chars().filter(|c| c.is_alphabetic()).count();
+    if letters < MIN_LETTERS {
+        return None;
+    }
+
+    // Cyrillic text is told apart by letters that only exist in Ukrainian
+    let cyrillic = lowered
+        .chars()
+        .filter(|c| ('\u{0400}'..='\u{04FF}').contains(c))
+        .count();
+    if cyrillic * 2 > letters {
+        return if lowered.chars().any(|c| matches!(c, 'і' | 'ї' | 'є' | 'ґ')) {
+            Some("uk")
+        } else {
+            Some("ru")
+        };
+    }
+
+    // Normalize everything that isn't a letter to single spaces so trigrams
+    // capture word boundaries the same way the profiles do
+    let mut normalized: Vec<char> = vec![' '];
+    for c in lowered.chars() {
+        if c.is_alphabetic() {
+            normalized.push(c);
+        } else if normalized.last() != Some(&' ') {
+            normalized.push(' ');
+        }
+    }
+    if normalized.last() != Some(&' ') {
+        normalized.push(' ');
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for window in normalized.windows(3) {
+        *counts.entry(window.iter().collect()).or_default() += 1;
+    }
+
+    PROFILES
+        .iter()
+        .map(|(lang, trigrams)| {
+            let score: usize = trigrams
+                .iter()
+                .map(|trigram| counts.get(*trigram).copied().unwrap_or(0))
+                .sum();
+            (*lang, score)
+        })
+        .filter(|(_, score)| *score >= MIN_SCORE)
+        .max_by_key(|(_, score)| *score)
+        .map(|(lang, _)| lang)
+}
+
+// Share of each language among the labelled messages, largest first.
+// Every labelled message counts once, however long it is.
+pub fn language_mix<'a>(
+    labels: impl IntoIterator<Item = Option<&'a str>>,
+) -> Vec<(&'a str, usize)> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut total = 0;
+    for lang in labels.into_iter().flatten() {
+        *counts.entry(lang).or_default() += 1;
+        total += 1;
+    }
+
+    let mut mix: Vec<(&str, usize)> = counts
+        .into_iter()
+        .map(|(lang, count)| (lang, count * 100 / total))
+        .collect();
+    mix.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    mix
+}
+
+pub fn format_language_mix(mix: &[(&str, usize)]) -> Option<String> {
+    if mix.is_empty() {
+        return None;
+    }
+    Some(
+        mix.iter()
+            .take(3)
+            .map(|(lang, percent)| format!("{}% {}", percent, lang))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+pub fn dominant_language(mix: &[(&'static str, usize)]) -> Option<&'static str> {
+    mix.first()
+        .filter(|(_, percent)| *percent >= DOMINANT_SHARE_PERCENT)
+        .map(|(lang, _)| *lang)
+}
+
+// A detected language label. The alias keeps serde's derive from treating
+// fields of this type as borrowed from the input.
+pub type LangCode = &'static str;
+
+// Deserialize a stored code into its static label; unknown codes become None
+pub fn deserialize_code<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<&'static str>, D::Error> {
+    let code: Option<String> = Option::deserialize(deserializer)?;
+    Ok(code.as_deref().and_then(static_code))
+}
+
+// The static label for a code read back from storage, if it's one we detect
+pub fn static_code(code: &str) -> Option<&'static str> {
+    PROFILES
+        .iter()
+        .map(|(lang, _)| *lang)
+        .chain(["ru", "uk"])
+        .find(|lang| *lang == code)
+}
+
+// Languages a chat can ask summaries in with /language
+const NAMES: &[(&str, &str)] = &[
+    ("en", "English"),
+    ("pl", "Polish"),
+    ("de", "German"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("it", "Italian"),
+    ("pt", "Portuguese"),
+    ("nl", "Dutch"),
+    ("ru", "Russian"),
+    ("uk", "Ukrainian"),
+    ("cs", "Czech"),
+    ("sk", "Slovak"),
+    ("sv", "Swedish"),
+    ("tr", "Turkish"),
+    ("ja", "Japanese"),
+    ("zh", "Chinese"),
+];
+
+pub fn language_name(code: &str) -> &str {
+    NAMES
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, name)| *name)
+        .unwrap_or(code)
+}
+
+// The canonical code for user input like "PL" or "polish"
+pub fn parse_language(value: &str) -> Option<&'static str> {
+    let value = value.trim().to_lowercase();
+    NAMES
+        .iter()
+        .find(|(code, name)| *code == value || name.to_lowercase() == value)
+        .map(|(code, _)| *code)
+}
+
+pub fn supported_codes() -> String {
+    NAMES
+        .iter()
+        .map(|(code, _)| *code)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// The sentence appended to the system prompt. A chat's preferred language
+// wins; otherwise the conversation's own language, so summaries don't default
+// to English just because the prompt is written in it.
+pub fn summary_instruction(preferred: Option<&str>, mix: &[(&'static str, usize)]) -> String {
+    match preferred.or(dominant_language(mix)) {
+        Some(code) => format!("Respond in {}.", language_name(code)),
+        None => "Write the summary in the dominant language of the conversation, \
+            not necessarily English."
+            .to_string(),
+    }
+}
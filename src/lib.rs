@@ -0,0 +1,57 @@
+// The bot's building blocks: message storage, prompt building, provider
+// access and the rest of what the Telegram handlers in main.rs wire together
+pub mod access;
+pub mod admins;
+pub mod aggregate;
+pub mod backoff;
+pub mod blockterms;
+pub mod budget;
+pub mod chatconfig;
+pub mod chatinfo;
+pub mod citations;
+pub mod commands;
+pub mod compaction;
+pub mod config;
+pub mod conflict;
+pub mod context;
+pub mod dayslice;
+pub mod destination;
+pub mod digest;
+pub mod dump;
+pub mod error;
+pub mod events;
+pub mod footprint;
+pub mod format;
+pub mod forwards;
+pub mod glossary;
+pub mod help;
+pub mod import;
+pub mod inline;
+pub mod lang;
+pub mod limits;
+pub mod llm;
+pub mod locale;
+pub mod logging;
+pub mod media;
+pub mod metrics;
+pub mod paste;
+pub mod persist;
+pub mod pipeline;
+pub mod progress;
+pub mod prompt;
+pub mod ratelimit;
+pub mod reactions;
+pub mod replies;
+pub mod revision;
+pub mod scheduler;
+pub mod settings;
+pub mod sse;
+pub mod state;
+pub mod stats;
+pub mod store;
+pub mod summary;
+pub mod task;
+pub mod tasks;
+pub mod timing;
+pub mod usage;
+pub mod wizard;
@@ -0,0 +1,223 @@
+use crate::{
+    compaction::CompactionConfig,
+    config::Config,
+    settings::{ChatSettings, PlaceholderMode},
+    store::MAX_MESSAGES,
+};
+use teloxide::types::{Chat, ChatId};
+
+pub const DEFAULT_SUMMARIZE_COUNT: usize = 100;
+
+// Cooldown between summaries in private chats, when the configured one is longer
+const PRIVATE_MAX_COOLDOWN_SECS: i64 = 5;
+
+// The kinds of chat that get their own defaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatKind {
+    Private,
+    Group,
+    Supergroup,
+    Channel,
+}
+
+impl ChatKind {
+    pub fn of(chat: &Chat) -> Self {
+        if chat.is_private() {
+            ChatKind::Private
+        } else if chat.is_channel() {
+            ChatKind::Channel
+        } else if chat.is_supergroup() {
+            ChatKind::Supergroup
+        } else {
+            ChatKind::Group
+        }
+    }
+
+    // For chats known only by id, e.g. the targets of inline summaries.
+    // Channels and supergroups share an id range, so both read as supergroups.
+    pub fn of_id(chat_id: ChatId) -> Self {
+        if chat_id.is_user() {
+            ChatKind::Private
+        } else if chat_id.is_group() {
+            ChatKind::Group
+        } else {
+            ChatKind::Supergroup
+        }
+    }
+}
+
+impl std::fmt::Display for ChatKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatKind::Private => write!(f, "private chat"),
+            ChatKind::Group => write!(f, "group"),
+            ChatKind::Supergroup => write!(f, "supergroup"),
+            ChatKind::Channel => write!(f, "channel"),
+        }
+    }
+}
+
+// How a kind of chat behaves where its settings don't say otherwise. A private
+// chat is one person's notes and forwards: summaries cover everything, arrive
+// as one message, and hardly need a cooldown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChatTypeDefaults {
+    // What /summarize without a count covers; None for everything allowed
+    pub default_count: Option<usize>,
+    // Post a placeholder while working, unless the chat chose another mode
+    pub placeholder: bool,
+    // Longer configured cooldowns are shortened to this
+    pub max_cooldown: Option<chrono::Duration>,
+}
+
+pub fn chat_type_defaults(kind: ChatKind) -> ChatTypeDefaults {
+    match kind {
+        ChatKind::Private => ChatTypeDefaults {
+            default_count: None,
+            placeholder: false,
+            max_cooldown: Some(chrono::Duration::seconds(PRIVATE_MAX_COOLDOWN_SECS)),
+        },
+        ChatKind::Group | ChatKind::Supergroup | ChatKind::Channel => ChatTypeDefaults {
+            default_count: Some(DEFAULT_SUMMARIZE_COUNT),
+            placeholder: true,
+            max_cooldown: None,
+        },
+    }
+}
+
+// Where an effective value comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Global,
+    Chat,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Global => write!(f, "global default"),
+            Source::Chat => write!(f, "chat setting"),
+        }
+    }
+}
+
+// The limits that apply to one chat, resolved the same way the features
+// resolve them, so /limits can't disagree with actual behavior
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectiveLimits {
+    pub chat_kind: ChatKind,
+    // Messages kept per chat/topic before the oldest are dropped
+    pub store_cap: usize,
+    pub max_summarize: (usize, Source),
+    pub admins_exempt: bool,
+    pub compaction: Option<CompactionConfig>,
+    // Minimum time between summaries in one chat/thread
+    pub cooldown: chrono::Duration,
+    pub cooldown_admins_exempt: bool,
+    pub placeholder_mode: PlaceholderMode,
+}
+
+impl EffectiveLimits {
+    // The most messages a summary may cover for a sender
+    pub fn summarize_limit(&self, is_admin: bool) -> usize {
+        if is_admin && self.admins_exempt {
+            self.store_cap
+        } else {
+            self.max_summarize.0
+        }
+    }
+
+    // The cooldown the sender is subject to, if any
+    pub fn cooldown_for(&self, is_admin: bool) -> Option<chrono::Duration> {
+        if self.cooldown.is_zero() || (is_admin && self.cooldown_admins_exempt) {
+            None
+        } else {
+            Some(self.cooldown)
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let (max, source) = self.max_summarize;
+        let mut text = format!(
+            "Stored messages per chat/topic: {} (global default)\n\
+            Max messages per summary: {} ({}{})\n\
+            Default /summarize count: {} ({} default)",
+            self.store_cap,
+            max,
+            source,
+            if self.admins_exempt && source == Source::Chat {
+                ", admins exempt"
+            } else {
+                ""
+            },
+            default_count(self.chat_kind, max),
+            self.chat_kind
+        );
+        text.push_str(&match &self.compaction {
+            Some(compaction) => format!(
+                "\nOld history: compacted {} messages at a time, at most {} times a day (global default)",
+                compaction.batch_size, compaction.max_per_day
+            ),
+            None => "\nOld history: dropped once the store is full (global default)".to_string(),
+        });
+        text.push_str(&if self.cooldown.is_zero() {
+            "\nCooldown between summaries: none (global default)".to_string()
+        } else {
+            format!(
+                "\nCooldown between summaries: {}s ({} default{})",
+                self.cooldown.num_seconds(),
+                if chat_type_defaults(self.chat_kind).max_cooldown.is_some() {
+                    self.chat_kind.to_string()
+                } else {
+                    "global".to_string()
+                },
+                if self.cooldown_admins_exempt {
+                    ", admins exempt"
+                } else {
+                    ""
+                }
+            )
+        });
+        text
+    }
+}
+
+// What /summarize without a count covers, given the sender's limit
+pub fn default_count(kind: ChatKind, limit: usize) -> usize {
+    chat_type_defaults(kind)
+        .default_count
+        .map_or(limit, |count| count.min(limit))
+}
+
+pub fn resolve_effective_limits(
+    chat_settings: &ChatSettings,
+    config: &Config,
+    chat_kind: ChatKind,
+) -> EffectiveLimits {
+    let defaults = chat_type_defaults(chat_kind);
+    let max_summarize = match chat_settings.max_summarize {
+        Some(max) => (max.min(MAX_MESSAGES), Source::Chat),
+        None => (MAX_MESSAGES, Source::Global),
+    };
+    // Edit is the default mode, so only it gives way; silent and reaction stay
+    let placeholder_mode =
+        if !defaults.placeholder && chat_settings.placeholder_mode == PlaceholderMode::Edit {
+            PlaceholderMode::Silent
+        } else {
+            chat_settings.placeholder_mode
+        };
+    EffectiveLimits {
+        chat_kind,
+        store_cap: MAX_MESSAGES,
+        max_summarize,
+        admins_exempt: chat_settings.max_summarize.is_some() && chat_settings.admins_exempt,
+        compaction: config.compaction,
+        cooldown: defaults
+            .max_cooldown
+            .map_or(config.summarize_cooldown, |max| {
+                config.summarize_cooldown.min(max)
+            }),
+        cooldown_admins_exempt: config.cooldown_admins_exempt,
+        placeholder_mode,
+    }
+}
@@ -0,0 +1,107 @@
+use chrono::{DateTime, Datelike, TimeZone, Timelike};
+
+const PL_WEEKDAYS: [&str; 7] = ["pon", "wt", "śr", "czw", "pt", "sob", "nd"];
+const PL_MONTHS: [&str; 12] = [
+    "sty", "lut", "mar", "kwi", "maj", "cze", "lip", "sie", "wrz", "paź", "lis", "gru",
+];
+
+// How numbers, durations and dates are written in replies. Only the languages
+// replies are formatted for get a variant; everything else reads as English.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    English,
+    Polish,
+}
+
+impl Locale {
+    // From a chat's /language code; unset or unknown codes format as English
+    pub fn from_code(code: Option<&str>) -> Self {
+        match code {
+            Some("pl") => Locale::Polish,
+            _ => Locale::English,
+        }
+    }
+
+    // "15,234" in English; "15 234" in Polish, which leaves four-digit
+    // numbers ungrouped and separates with a no-break space
+    pub fn integer(&self, value: u64) -> String {
+        let digits = value.to_string();
+        let (separator, min_grouped) = match self {
+            Locale::English => (',', 4),
+            Locale::Polish => ('\u{a0}', 5),
+        };
+        if digits.len() < min_grouped {
+            return digits;
+        }
+        let mut grouped = String::with_capacity(digits.len() + digits.len() / 3 * 2);
+        for (index, digit) in digits.chars().enumerate() {
+            if index > 0 && (digits.len() - index).is_multiple_of(3) {
+                grouped.push(separator);
+            }
+            grouped.push(digit);
+        }
+        grouped
+    }
+
+    // "1d 2h 3m 4s" or "1 d 2 godz. 3 min 4 s", dropping leading zero units
+    pub fn duration(&self, duration: chrono::Duration) -> String {
+        let days = duration.num_days();
+        let hours = duration.num_hours() % 24;
+        let minutes = duration.num_minutes() % 60;
+        let seconds = duration.num_seconds() % 60;
+        let units = match self {
+            Locale::English => ["d", "h", "m", "s"],
+            Locale::Polish => [" d", " godz.", " min", " s"],
+        };
+        let parts = [days, hours, minutes, seconds];
+        let first = parts.iter().position(|part| *part > 0).unwrap_or(3);
+        parts[first..]
+            .iter()
+            .zip(&units[first..])
+            .map(|(value, unit)| format!("{}{}", value, unit))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // "Thu 05 Jun 14:30" or "czw, 5 cze, 14:30"
+    pub fn datetime<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> String
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        match self {
+            Locale::English => time.format("%a %d %b %H:%M").to_string(),
+            Locale::Polish => format!(
+                "{}, {} {}, {}",
+                PL_WEEKDAYS[time.weekday().num_days_from_monday() as usize],
+                time.day(),
+                PL_MONTHS[time.month0() as usize],
+                self.time(time)
+            ),
+        }
+    }
+
+    // "12.3 MB" or "12,3 MB", in powers of 1024
+    pub fn bytes(&self, bytes: u64) -> String {
+        const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+        if bytes < 1024 {
+            return format!("{} B", bytes);
+        }
+        let mut value = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        let number = format!("{:.1}", value);
+        let number = match self {
+            Locale::English => number,
+            Locale::Polish => number.replace('.', ","),
+        };
+        format!("{} {}", number, UNITS[unit])
+    }
+
+    // "14:30" in both locales
+    pub fn time<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> String {
+        format!("{:02}:{:02}", time.hour(), time.minute())
+    }
+}
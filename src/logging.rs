@@ -0,0 +1,194 @@
+use crate::config::parse_byte_size;
+use fern::colors::{Color, ColoredLevelConfig};
+use log::LevelFilter;
+use std::{
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+const DEFAULT_LOG_FILE: &str = "duck_summarizer.log";
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Debug;
+const DEFAULT_MAX_BYTES: u64 = 10 << 20;
+const DEFAULT_KEEP_FILES: usize = 5;
+
+// Set from LOG_MESSAGE_CONTENT when the logger starts
+static MESSAGE_CONTENT: AtomicBool = AtomicBool::new(false);
+
+// Where and how much is logged, read before anything else so startup problems
+// are logged too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    // None logs to stdout only
+    pub file: Option<PathBuf>,
+    // The file is rotated once it grows past this
+    pub max_bytes: u64,
+    // Rotated files kept as <file>.1 (newest) to <file>.<keep>
+    pub keep: usize,
+    // Log what users wrote, not just its length
+    pub message_content: bool,
+}
+
+impl LogConfig {
+    pub fn from_env() -> Self {
+        // LOG_LEVEL wins; from RUST_LOG only a bare level such as "info" is used
+        let level = env::var("LOG_LEVEL")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .or_else(|| {
+                env::var("RUST_LOG").ok().and_then(|value| {
+                    value
+                        .split(',')
+                        .find_map(|directive| directive.trim().parse().ok())
+                })
+            })
+            .unwrap_or(DEFAULT_LEVEL);
+        let file = match env::var("LOG_FILE") {
+            Ok(value) if matches!(value.trim(), "" | "off" | "none") => None,
+            Ok(value) => Some(PathBuf::from(value.trim())),
+            Err(_) => Some(PathBuf::from(DEFAULT_LOG_FILE)),
+        };
+        let max_bytes = env::var("LOG_MAX_BYTES")
+            .ok()
+            .and_then(|value| parse_byte_size(&value))
+            .filter(|max| *max > 0)
+            .map_or(DEFAULT_MAX_BYTES, |max| max as u64);
+        let keep = env::var("LOG_KEEP_FILES")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(DEFAULT_KEEP_FILES);
+        let message_content = env::var("LOG_MESSAGE_CONTENT")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+        Self {
+            level,
+            file,
+            max_bytes,
+            keep,
+            message_content,
+        }
+    }
+}
+
+// Setup logger with fern
+pub fn setup(config: &LogConfig) -> Result<(), fern::InitError> {
+    let colors = ColoredLevelConfig::new()
+        .trace(Color::Cyan)
+        .debug(Color::Cyan)
+        .error(Color::Red)
+        .info(Color::Green)
+        .warn(Color::Yellow);
+    MESSAGE_CONTENT.store(config.message_content, Ordering::Relaxed);
+
+    let mut dispatch = fern::Dispatch::new()
+        .format(move |out, message, record| {
+            out.finish(format_args!(
+                "{timestamp} | {colored_level} | {target}: {message}",
+                timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                colored_level = colors.color(record.level()),
+                target = record.target(),
+                message = message,
+            ))
+        })
+        .level(config.level)
+        .chain(io::stdout());
+    if let Some(path) = &config.file {
+        let file = RotatingFile::open(path, config.max_bytes, config.keep)?;
+        dispatch = dispatch.chain(Box::new(file) as Box<dyn Write + Send>);
+    }
+    dispatch.apply()?;
+    Ok(())
+}
+
+// What users wrote, for log lines: the text itself with LOG_MESSAGE_CONTENT,
+// otherwise only its length, so the log doesn't become a copy of every chat
+pub fn message_content(text: &str) -> MessageContent<'_> {
+    MessageContent(text)
+}
+
+pub struct MessageContent<'a>(&'a str);
+
+impl fmt::Display for MessageContent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if MESSAGE_CONTENT.load(Ordering::Relaxed) {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "<{} characters>", self.0.chars().count())
+        }
+    }
+}
+
+// A log file that's moved aside once it reaches `max_bytes`: the current file
+// becomes <path>.1, the previous <path>.1 becomes <path>.2 and so on, and
+// files past `keep` are deleted
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            file,
+            written,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest may not exist yet
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for index in (1..self.keep).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    // fern flushes after every record, so rotating here never splits a line
+    // across files. A file can overshoot the limit by at most one record.
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.written >= self.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
@@ -1,140 +1,104 @@
 use chrono::{DateTime, Utc};
 use dotenvy::dotenv;
-use fern::colors::{Color, ColoredLevelConfig};
-use log::{LevelFilter, debug, error, info, trace, warn};
-use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderValue};
-use serde::{Deserialize, Serialize};
+use log::{debug, error, info, trace, warn};
 use std::str::FromStr;
 use std::{
-    collections::{HashMap, VecDeque},
-    env, io,
-    sync::Arc,
+    env,
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
 };
 use teloxide::{
+    ApiError, RequestError,
     dispatching::UpdateFilterExt,
+    net::Download,
     prelude::*,
-    types::{ChatId, Message, MessageId, ParseMode, ReplyParameters, ThreadId, Update},
+    types::{
+        CallbackQuery, ChatId, ChatMemberUpdated, ChosenInlineResult, InlineKeyboardButton,
+        InlineKeyboardMarkup, InlineQuery, InlineQueryResult, InlineQueryResultArticle,
+        InlineQueryResultsButton, InlineQueryResultsButtonKind, InputFile, InputMessageContent,
+        InputMessageContentText, MenuButton, Message, MessageReactionCountUpdated,
+        MessageReactionUpdated, ParseMode, Update, UpdateId,
+    },
+    update_listeners,
     utils::{command::BotCommands, markdown},
 };
-use tokio::sync::Mutex;
-
-const MAX_MESSAGES: usize = 1000;
-
-// Setup logger with fern
-fn setup_logger() -> Result<(), fern::InitError> {
-    let colors = ColoredLevelConfig::new()
-        .trace(Color::Cyan)
-        .debug(Color::Cyan)
-        .error(Color::Red)
-        .info(Color::Green)
-        .warn(Color::Yellow);
-
-    let log_level = LevelFilter::Debug;
-
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "{timestamp} | {colored_level} | {target}: {message}",
-                timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                colored_level = colors.color(record.level()),
-                target = record.target(),
-                message = message,
-            ))
-        })
-        .level(log_level)
-        // Set specific module log levels if needed
-        // .level_for(env!("CARGO_PKG_NAME"), log_level)
-        // Output to stdout and log file
-        .chain(io::stdout())
-        .chain(fern::log_file("duck_summarizer.log")?)
-        .apply()?;
-
-    Ok(())
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct ChatThreadId {
-    chat_id: ChatId,
-    thread_id: Option<ThreadId>,
-}
-
-#[derive(Debug, Clone)]
-struct SavedMessage {
-    message_id: MessageId,
-    from_user: Option<String>, // Username or first_name
-    reply_to_message_id: Option<MessageId>,
-    text: String,
-}
+use tokio::sync::{Mutex, watch};
+
+use duck_summarizer::{
+    access, admins, backoff, blockterms, budget, chatconfig, chatinfo, commands, config, conflict,
+    context, dayslice, destination, digest, dump, events, format, forwards, glossary, help, import,
+    inline, lang, limits, llm, locale, logging, media, metrics, persist, pipeline, prompt,
+    ratelimit, reactions, scheduler, settings, state, stats, store, summary, task, tasks, timing,
+    usage, wizard,
+};
 
-#[derive(Debug, Clone)]
-struct MessageStore {
-    // Map of chat_id+thread_id to message queue for that chat/thread
-    chats: HashMap<ChatThreadId, VecDeque<SavedMessage>>,
-    startup_time: DateTime<Utc>,
+use admins::is_chat_admin;
+use backoff::Backoff;
+use commands::{SummaryRange, parse_count, parse_range, parse_seed_target, split_focus};
+use config::Config;
+use destination::{ChatDestination, SendOptions, reply_retrying, reply_to, send_retrying};
+use digest::{DigestOutcome, DigestSchedule};
+use events::EventSink;
+use futures::FutureExt;
+use limits::{ChatKind, DEFAULT_SUMMARIZE_COUNT};
+use llm::LlmProviders;
+use locale::Locale;
+use media::user_display_name;
+use metrics::Metrics;
+use persist::Database;
+use pipeline::{compact_history, request_headroom, run_llm_task, summary_chunks};
+use scheduler::{DIGEST_TICK, digest_timezone, run_digest_scheduler, run_scheduled_digest};
+use settings::{PlaceholderMode, ReplyAnchor};
+use state::{
+    AppState, BUDGET_META_KEY, budget_month, charge_budget, chat_timezone, notify_model_change,
+    summarize_limit,
+};
+use store::{
+    Admission, CAPACITY_IDLE_HOURS, ChatSnapshot, ChatThreadId, MAX_MESSAGES, MessageSelector,
+    MessageStore, SavedMessage, SnapshotSource,
+};
+use summary::summarize_snapshot;
+use task::LlmTask;
+use tasks::TaskRegistryType;
+use timing::{Stage, StageTimings};
+use wizard::{Transition, WizardAction, WizardStep};
+
+// Largest transcript /summarize file downloads
+const UPLOAD_MAX_BYTES: u32 = 1024 * 1024;
+// Evictions in a chat after which /memory suggests raising MAX_MESSAGES
+const EVICTION_HINT_THRESHOLD: u64 = 100;
+// "Oldest stored message: Sun 01 Jun 09:14 (UTC) (6h 32m 5s ago), newest: 2m 10s ago"
+fn format_time_range(
+    oldest: DateTime<Utc>,
+    newest: DateTime<Utc>,
+    now: DateTime<Utc>,
+    tz: chrono_tz::Tz,
+    locale: Locale,
+) -> String {
+    format!(
+        "{} ({}) ({} ago), newest: {} ago",
+        locale.datetime(&oldest.with_timezone(&tz)),
+        tz.name(),
+        locale.duration(now.signed_duration_since(oldest)),
+        locale.duration(now.signed_duration_since(newest))
+    )
 }
 
-impl MessageStore {
-    fn new() -> Self {
-        Self {
-            chats: HashMap::new(),
-            startup_time: Utc::now(),
-        }
-    }
-
-    fn add_message(&mut self, chat_id: ChatId, thread_id: Option<ThreadId>, message: SavedMessage) {
-        let chat_thread_id = ChatThreadId { chat_id, thread_id };
-
-        let chat_messages = self
-            .chats
-            .entry(chat_thread_id)
-            .or_insert_with(|| VecDeque::with_capacity(MAX_MESSAGES));
-
-        if chat_messages.len() >= MAX_MESSAGES {
-            chat_messages.pop_front();
-        }
-        chat_messages.push_back(message);
-    }
-
-    fn get_last_n_messages(
-        &self,
-        chat_id: ChatId,
-        thread_id: Option<ThreadId>,
-        n: usize,
-    ) -> Vec<SavedMessage> {
-        let chat_thread_id = ChatThreadId { chat_id, thread_id };
-
-        match self.chats.get(&chat_thread_id) {
-            Some(messages) => {
-                let count = n.min(messages.len());
-                messages.iter().rev().take(count).rev().cloned().collect()
-            }
-            None => Vec::new(),
-        }
-    }
-
-    fn get_uptime(&self) -> String {
-        let now = Utc::now();
-        let duration = now.signed_duration_since(self.startup_time);
-
-        let days = duration.num_days();
-        let hours = duration.num_hours() % 24;
-        let minutes = duration.num_minutes() % 60;
-        let seconds = duration.num_seconds() % 60;
-
-        if days > 0 {
-            format!("{}d {}h {}m {}s", days, hours, minutes, seconds)
-        } else if hours > 0 {
-            format!("{}h {}m {}s", hours, minutes, seconds)
-        } else if minutes > 0 {
-            format!("{}m {}s", minutes, seconds)
-        } else {
-            format!("{}s", seconds)
-        }
-    }
+// Suggest a larger limit once a chat has been losing messages to eviction
+fn eviction_hint(evictions: u64, locale: Locale) -> Option<String> {
+    (evictions >= EVICTION_HINT_THRESHOLD).then(|| {
+        format!(
+            "{} older messages were already dropped here; raising MAX_MESSAGES (currently {}) would extend how far back summaries can go.",
+            locale.integer(evictions),
+            locale.integer(MAX_MESSAGES as u64)
+        )
+    })
 }
 
-type MessageStoreType = Arc<Mutex<MessageStore>>;
-
 #[derive(BotCommands, Clone, Debug)]
 #[command(
     rename_rule = "lowercase",
@@ -142,11 +106,19 @@ type MessageStoreType = Arc<Mutex<MessageStore>>;
 )]
 enum Command {
     #[command(description = "info about the bot")]
-    Start,
+    Start(String),
     #[command(description = "display this help message")]
     Help,
-    #[command(description = "summarize the last n messages, defaults to 100")]
+    #[command(
+        description = "summarize the last n messages (defaults to 100) or a time window like 2h"
+    )]
     Summarize(String),
+    #[command(description = "summarize the last n messages across all topics of this chat")]
+    SummarizeAll(String),
+    #[command(description = "describe the tone of the last n messages and who argues with whom")]
+    Mood(String),
+    #[command(description = "list the topics of the last n messages and who discussed them")]
+    Topics(String),
     #[command(
         description = "show total messages and chat count in-memory",
         alias = "stats"
@@ -154,73 +126,244 @@ enum Command {
     Memory,
     #[command(description = "display privacy disclaimer")]
     Privacy,
+    #[command(description = "show or change chat settings (admins only in groups)")]
+    Settings(String),
+    #[command(description = "show the limits that apply in this chat")]
+    Limits,
+    #[command(description = "show tokens used by summaries since startup")]
+    Usage,
+    #[command(
+        description = "show how much of the model's context a summary of the last n messages takes"
+    )]
+    DebugPrompt(String),
+    #[command(description = "show or set the summary language, e.g. /language pl")]
+    Language(String),
+    #[command(description = "list, add or remove chat-specific terms the summaries should know")]
+    Glossary(String),
+    #[command(description = "leave a user out of summaries, or list ignored users (admins only)")]
+    Ignore(String),
+    #[command(description = "include an ignored user in summaries again (admins only)")]
+    Unignore(String),
+    #[command(description = "show, start or stop this chat's daily digest, e.g. /digest on 18:00")]
+    Digest(String),
+    #[command(
+        description = "privately send you the last n stored messages as a text file (admins only)"
+    )]
+    Export(String),
+    #[command(
+        description = "let only admins request summaries here, e.g. /restrict on (admins only)"
+    )]
+    Restrict(String),
+    #[command(description = "write the last summary here again from the same messages")]
+    Regenerate,
+    #[command(description = "owner-only administration commands", hide)]
+    Admin(String),
+    #[command(description = "let the bot work in a chat (owner only)", hide)]
+    AllowChat(String),
+    #[command(description = "stop the bot working in a chat (owner only)", hide)]
+    BlockChat(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatMessage {
-    role: String,
-    content: String,
-}
-
-#[derive(Serialize, Debug)]
-struct ChatCompletionRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    temperature: f32,
-    max_tokens: u32,
-}
-
-#[derive(Deserialize, Debug)]
-struct ChatCompletionResponse {
-    choices: Vec<Choice>,
+// Whether the bot works in a chat, under ALLOWED_CHAT_IDS and the runtime
+// overrides
+async fn chat_allowed(state: &AppState, chat_id: ChatId) -> bool {
+    let store = state.store.lock().await;
+    state.config.is_chat_allowed(chat_id, &store.chat_access)
 }
 
-#[derive(Deserialize, Debug)]
-struct Choice {
-    message: ChatMessage,
+// Leave a chat that isn't allowed if LEAVE_UNALLOWED_CHATS asks for it.
+// Private chats can't be left, so they're only ignored.
+async fn leave_unallowed_chat(bot: &Bot, chat: &teloxide::types::Chat, config: &Config) {
+    if !config.leave_unallowed_chats || chat.is_private() {
+        return;
+    }
+    match bot.leave_chat(chat.id).await {
+        Ok(_) => info!(target: "access", "Left chat {}, which isn't allowed", chat.id),
+        Err(e) => warn!(target: "access", "Failed to leave chat {}: {}", chat.id, e),
+    }
 }
 
-async fn handle_message(msg: Message, message_store: MessageStoreType) -> ResponseResult<()> {
+async fn handle_message(bot: Bot, msg: Message, state: AppState) -> ResponseResult<()> {
     let chat_id = msg.chat.id;
     let thread_id = msg.thread_id;
 
-    if let Some(text) = msg.text() {
-        let display_name = msg.from.as_ref().map(|user| {
-            if let Some(last_name) = &user.last_name {
-                format!("{} {}", user.first_name, last_name)
-            } else {
-                user.first_name.clone()
-            }
-        });
-
-        trace!(target: "message_handler", "DisplayName: {}, FirstName: {}", 
-            display_name.clone().unwrap_or_else(|| "None".to_string()), 
-            msg.from.as_ref().map(|u| u.first_name.clone()).unwrap_or_else(|| "None".to_string()));
+    // Anonymous admins arrive from a bot account but speak for the group
+    if state.config.ignore_bots
+        && msg.sender_chat.is_none()
+        && let Some(user) = &msg.from
+        && user.is_bot
+    {
+        trace!(target: "message_handler", "Skipping message from bot {} in chat {}", user.id, chat_id);
+        return Ok(());
+    }
 
-        let user_id = match msg.from.as_ref() {
-            Some(user) => user.id,
-            None => {
-                debug!(target: "message_handler", "Received a message without a sender in chat {}, skipping", chat_id);
-                return Ok(());
-            }
-        };
+    if !chat_allowed(&state, chat_id).await {
+        trace!(target: "message_handler", "Skipping message in chat {}, which isn't allowed", chat_id);
+        leave_unallowed_chat(&bot, &msg.chat, &state.config).await;
+        return Ok(());
+    }
 
-        trace!(target: "message_handler", "Received message from {} (ID: {}) in chat {} thread {:?}: {}", 
-            display_name.clone().unwrap_or_else(|| "Unknown".to_string()), 
+    if let Some((kind, text)) = media::classify(&msg) {
+        let display_name = sender_display_name(&msg);
+        // Channel posts and anonymous admins carry no user of their own
+        let user_id = msg
+            .from
+            .as_ref()
+            .filter(|_| msg.sender_chat.is_none())
+            .map(|user| user.id);
+
+        trace!(target: "message_handler", "Received message from {} (ID: {:?}) in chat {} thread {:?}: {}", 
+            display_name.as_deref().unwrap_or("Unknown"), 
             user_id, 
             chat_id,
             thread_id,
-            text);
+            logging::message_content(&text));
+
+        // Forwards in a private chat wait for /summarize on their own
+        if msg.chat.is_private()
+            && let Some(origin) = msg.forward_origin()
+        {
+            let forwarded = SavedMessage {
+                seq: 0, // assigned by the buffer
+                message_id: msg.id,
+                from_user: Some(forwards::origin_name(origin)),
+                username: forwards::origin_username(origin),
+                reply_to_message_id: None,
+                reply_to_user: None,
+                lang: lang::detect_language(&text),
+                text,
+                kind,
+                timestamp: origin.date(),
+                synthetic: false,
+                edited: false,
+                reactions: Default::default(),
+            };
+            state
+                .forwards
+                .lock()
+                .await
+                .push(chat_id, forwarded, Utc::now());
+            return Ok(());
+        }
+
+        // Offered to this user when they summarize inline
+        if let Some(user_id) = user_id {
+            state.recent_chats.lock().await.record(
+                user_id,
+                ChatThreadId { chat_id, thread_id },
+                msg.chat
+                    .title()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| "our private chat".to_string()),
+                Utc::now(),
+            );
+        }
 
         let saved_message = SavedMessage {
+            seq: 0, // assigned by the store
             message_id: msg.id,
             from_user: display_name,
+            username: match &msg.sender_chat {
+                Some(sender) => sender.username().map(str::to_string),
+                None => msg.from.as_ref().and_then(|user| user.username.clone()),
+            },
             reply_to_message_id: msg.reply_to_message().map(|reply| reply.id),
-            text: text.to_string(),
+            reply_to_user: msg.reply_to_message().and_then(sender_display_name),
+            lang: lang::detect_language(&text),
+            text,
+            kind,
+            timestamp: msg.date,
+            synthetic: false,
+            edited: false,
+            reactions: Default::default(),
         };
 
-        let mut store = message_store.lock().await;
-        store.add_message(chat_id, thread_id, saved_message.clone());
+        let mut store = state.store.lock().await;
+        if let Admission::Denied { first } =
+            store.add_message(chat_id, thread_id, saved_message.clone())
+        {
+            drop(store);
+            debug!(target: "message_handler", "Not storing messages of chat {}: tracked chat limit reached", chat_id);
+            if first {
+                report_capacity_reached(&bot, &state).await;
+            }
+            return Ok(());
+        }
+        Metrics::increment(&state.metrics.messages_stored);
+
+        if let Some(compaction) = &state.config.compaction
+            && let Some(batch) =
+                store.begin_compaction(chat_id, thread_id, compaction, Utc::now().date_naive())
+        {
+            drop(store);
+            let state = state.clone();
+            tokio::spawn(async move {
+                let job = compact_history(bot, state.clone(), chat_id, thread_id, batch);
+                if !tasks::run_job(&state.tasks, "compaction", job).await {
+                    // Release the claim, so the chat can be compacted again
+                    state
+                        .store
+                        .lock()
+                        .await
+                        .finish_compaction(chat_id, thread_id, 0, None);
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+// Keep stored copies in line with edits, so summaries don't repeat text the
+// author has since corrected
+async fn handle_edited_message(msg: Message, state: AppState) -> ResponseResult<()> {
+    let Some((_, text)) = media::classify(&msg) else {
+        return Ok(());
+    };
+    let updated = state
+        .store
+        .lock()
+        .await
+        .update_message(msg.chat.id, msg.thread_id, msg.id, text);
+    if updated {
+        trace!(target: "message_handler", "Updated edited message {} in chat {} thread {:?}", msg.id, msg.chat.id, msg.thread_id);
+    }
+    Ok(())
+}
+
+// Keep reaction counts on stored messages, so summaries can tell what the chat
+// agreed with. Only arrives where the bot is an administrator.
+async fn handle_message_reaction(
+    reaction: MessageReactionUpdated,
+    state: AppState,
+) -> ResponseResult<()> {
+    let found =
+        state
+            .store
+            .lock()
+            .await
+            .apply_reaction(reaction.chat.id, reaction.message_id, |counts| {
+                reactions::apply_change(counts, &reaction.old_reaction, &reaction.new_reaction)
+            });
+    if !found {
+        trace!(target: "message_handler", "Ignoring reaction to message {} in chat {}, which isn't stored", reaction.message_id, reaction.chat.id);
+    }
+    Ok(())
+}
+
+// Totals of anonymous reactions replace what was counted before
+async fn handle_message_reaction_count(
+    update: MessageReactionCountUpdated,
+    state: AppState,
+) -> ResponseResult<()> {
+    let totals = reactions::from_totals(&update.reactions);
+    let found =
+        state
+            .store
+            .lock()
+            .await
+            .apply_reaction(update.chat.id, update.message_id, |counts| *counts = totals);
+    if !found {
+        trace!(target: "message_handler", "Ignoring reaction counts of message {} in chat {}, which isn't stored", update.message_id, update.chat.id);
     }
     Ok(())
 }
@@ -229,13 +372,25 @@ async fn handle_command(
     bot: Bot,
     msg: Message,
     cmd: Command,
-    message_store: MessageStoreType,
+    state: AppState,
 ) -> ResponseResult<()> {
+    let AppState {
+        store: message_store,
+        config,
+        stats,
+        blocklist,
+        llm,
+        settings,
+        wizards,
+        ..
+    } = &state;
     let chat_id = msg.chat.id;
     let thread_id = msg.thread_id;
     let chat_type = format!("{:?}", msg.chat.kind);
+    let user_id = msg.from.as_ref().map(|user| user.id);
     let display_name = msg
         .from
+        .as_ref()
         .map(|user| {
             if let Some(last_name) = &user.last_name {
                 format!("{} {}", user.first_name, last_name)
@@ -247,257 +402,2719 @@ async fn handle_command(
         })
         .unwrap_or_else(|| "Unknown".to_string());
 
+    if let Some(id) = user_id
+        && blocklist.lock().await.is_blocked(id)
+    {
+        debug!(target: "command", "Ignoring {:?} from blocked user {} ({}) in chat {}", cmd, display_name, id, chat_id);
+        return Ok(());
+    }
+    Metrics::increment(&state.metrics.commands_handled);
+
     // Helper function to add thread_id to message requests if present
-    let send_message = |text: String| {
-        let mut request = bot
-            .send_message(msg.chat.id, text)
-            .reply_parameters(ReplyParameters::new(msg.id));
+    let send_message = |text: String| reply_retrying(&bot, &msg, text);
+
+    // The owner can still manage access from a chat that isn't allowed
+    let owner_command = user_id.is_some_and(|id| config.is_owner(id))
+        && matches!(
+            cmd,
+            Command::Admin(_) | Command::AllowChat(_) | Command::BlockChat(_)
+        );
+    if !owner_command && !chat_allowed(&state, chat_id).await {
+        info!(target: "command", "Refusing {:?} in chat {}, which isn't allowed", cmd, chat_id);
+        send_message("Sorry, I'm not available in this chat.".to_string()).await?;
+        leave_unallowed_chat(&bot, &msg.chat, config).await;
+        return Ok(());
+    }
 
-        if let Some(thread) = thread_id {
-            request = request.message_thread_id(thread);
-        }
+    // Chats turned away at MAX_TRACKED_CHATS have no history to work with
+    if !matches!(
+        cmd,
+        Command::Start(_) | Command::Help | Command::Privacy | Command::Admin(_)
+    ) && !message_store.lock().await.has_room_for(chat_id)
+    {
+        info!(target: "command", "Refusing {:?} in chat {}: tracked chat limit reached", cmd, chat_id);
+        send_message(
+            "I'm at capacity and can't keep messages for new chats right now. Please try \
+            again later."
+                .to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
 
-        request
-    };
+    // With /restrict on, only admins can have the conversation read back
+    if !msg.chat.is_private()
+        && matches!(
+            cmd,
+            Command::Summarize(_)
+                | Command::SummarizeAll(_)
+                | Command::Mood(_)
+                | Command::Topics(_)
+                | Command::Export(_)
+                | Command::Regenerate
+        )
+        && message_store.lock().await.chat_settings(chat_id).restricted
+        && !is_chat_admin(&bot, &state.admins, &msg).await?
+    {
+        info!(target: "command", "Refusing {:?} from non-admin {} in restricted chat {}", cmd, display_name, chat_id);
+        send_message("Only admins can request summaries in this chat.".to_string()).await?;
+        return Ok(());
+    }
 
     match cmd {
-        Command::Start => {
-            info!(target: "command", "User {} requested /start in chat {} ({})", display_name, chat_id, chat_type);
-            send_message("Hello!\n\n\
+        Command::Start(payload) => {
+            info!(target: "command", "User {} requested /start {} in chat {} ({})", display_name, payload, chat_id, chat_type);
+            // Deep links from /help land here with the page in the payload
+            if let Some(topic) = help::topic_for_payload(&payload) {
+                send_message(topic.text.to_string()).await?;
+                return Ok(());
+            }
+            send_message(if msg.chat.is_private() {
+                "Hello!\n\n\
+                Add me to a group and I can summarize its conversations\\. Here, I summarize \
+                what you send me, like notes to yourself, or a batch of messages you forward \
+                me from another chat\\.\n\
+                Use /summarize to cover everything I've kept from our chat\\.\n\
+                For more commands, use /help\\."
+                    .to_string()
+            } else {
+                "Hello!\n\n\
                 I can summarize the last n messages in this chat or thread\\.\n\
                 Use /summarize <n> to get started\\.\n\
-                For more commands, use /help\\.".to_string())
+                For more commands, use /help\\."
+                    .to_string()
+            })
             .await?;
+
+            // Offer the setup wizard to the owner while defaults are missing
+            if msg.chat.is_private()
+                && let Some(id) = user_id
+                && config.is_owner(id)
+                && settings.lock().await.is_incomplete()
+            {
+                wizards.lock().await.start(id, Utc::now());
+                send_message(
+                    "Some optional settings aren't configured yet. Let's go through them \
+                    (you can skip any step or cancel, and rerun this with /admin setup)."
+                        .to_string(),
+                )
+                .await?;
+                send_wizard_step(&bot, ChatDestination::of(&msg), WizardStep::Model).await?;
+            }
         }
         Command::Help => {
             info!(target: "command", "User {} requested /help in chat {} ({})", display_name, chat_id, chat_type);
-            send_message(Command::descriptions().to_string()).await?;
+            let mut text = markdown::escape(&Command::descriptions().to_string());
+            if let Some(username) = &state.bot_username {
+                let links: Vec<String> = help::TOPICS
+                    .iter()
+                    .map(|topic| format::link(topic.command, &help::deep_link(username, topic)))
+                    .collect();
+                text.push_str(&format!("\n\nDetailed help: {}", links.join(", ")));
+            }
+            send_retrying(
+                chat_id,
+                reply_to(&bot, &msg, text).parse_mode(ParseMode::MarkdownV2),
+            )
+            .await?;
         }
-        Command::Summarize(count_str) => {
-            info!(target: "command", "User {} requested /summarize {} in chat {} thread {:?} ({})", 
-                  display_name, count_str, chat_id, thread_id, chat_type);
-            let trimmed = count_str.trim();
-            let count = if trimmed.is_empty() {
-                100
+        Command::Summarize(ref arg) if arg.trim() == "file" => {
+            summarize_upload(&bot, &msg, &state, &display_name).await?;
+        }
+        Command::Summarize(ref arg) if msg.chat.is_private() && arg.trim().is_empty() => {
+            let forwarded = state.forwards.lock().await.pending(chat_id, Utc::now());
+            if forwarded.is_empty() {
+                run_task_command(&bot, &msg, &state, LlmTask::Summarize, "", &display_name).await?;
             } else {
-                match usize::from_str(trimmed) {
-                    Ok(n) if n > 0 && n <= MAX_MESSAGES => n,
-                    _ => {
-                        warn!(target: "command", "Invalid count '{}' provided for /summarize by {} in chat {}", count_str, display_name, chat_id);
-                        send_message(format!(
-                            "Please provide a valid number between 1 and {}",
-                            MAX_MESSAGES
-                        ))
-                        .await?;
-                        return Ok(());
-                    }
-                }
-            };
-
-            let store = message_store.lock().await;
-            let messages = store.get_last_n_messages(msg.chat.id, thread_id, count);
-
-            if messages.is_empty() {
-                info!(target: "command", "No messages found to summarize in chat {} thread {:?} for user {}", chat_id, thread_id, display_name);
-                send_message("No messages to summarize.".to_string()).await?;
-                return Ok(());
+                summarize_forwards(&bot, &msg, &state, forwarded, &display_name).await?;
             }
+        }
+        Command::Summarize(count_str) => {
+            run_task_command(
+                &bot,
+                &msg,
+                &state,
+                LlmTask::Summarize,
+                &count_str,
+                &display_name,
+            )
+            .await?;
+        }
+        Command::Mood(count_str) => {
+            run_task_command(&bot, &msg, &state, LlmTask::Mood, &count_str, &display_name).await?;
+        }
+        Command::Topics(count_str) => {
+            run_task_command(
+                &bot,
+                &msg,
+                &state,
+                LlmTask::Topics,
+                &count_str,
+                &display_name,
+            )
+            .await?;
+        }
+        Command::SummarizeAll(count_str) => {
+            info!(target: "command", "User {} requested /summarizeall {} in chat {} ({})",
+                  display_name, count_str, chat_id, chat_type);
+            let mut timings = StageTimings::start();
+            let resolving = Instant::now();
+            let chat_settings = message_store.lock().await.chat_settings(chat_id);
+            let limit = summarize_limit(&bot, &msg, &chat_settings, &state).await?;
+            let Some(count) = parse_count(&count_str, limit, ChatKind::of(&msg.chat)) else {
+                warn!(target: "command", "Invalid count '{}' provided for /summarizeall by {} in chat {}", count_str, display_name, chat_id);
+                send_message(format!(
+                    "Please provide a valid number between 1 and {}",
+                    limit
+                ))
+                .await?;
+                return Ok(());
+            };
 
-            debug!(target: "command", "Summarizing {} messages in chat {} thread {:?} for user {}", messages.len(), chat_id, thread_id, display_name);
-            // Use actual number of messages retrieved in the summary message
-            let bot_msg =
-                send_message(format!("Summarizing {} messages...", messages.len())).await?;
-
-            match summarize_conversation(&messages).await {
-                Ok(summary) => {
-                    info!(target: "summarization", "Successfully generated summary in chat {} thread {:?} for user {}", chat_id, thread_id, display_name);
-                    let summary = format!("_{}_", markdown::escape(&summary));
-                    bot.edit_message_text(bot_msg.chat.id, bot_msg.id, summary)
-                        .parse_mode(ParseMode::MarkdownV2)
-                        .await?;
-                }
-                Err(e) => {
-                    error!(target: "summarization", "Failed to summarize conversation in chat {} thread {:?} for user {}: {}", chat_id, thread_id, display_name, e);
-                    bot.edit_message_text(
-                        bot_msg.chat.id,
-                        bot_msg.id,
-                        "Failed to summarize the conversation.",
-                    )
-                    .await?;
+            let snapshotting = timings.lap(Stage::Arguments, resolving);
+            let snapshot = message_store.lock().await.snapshot(
+                chat_id,
+                thread_id,
+                MessageSelector::AllThreads(count),
+            );
+            timings.lap(Stage::Snapshot, snapshotting);
+
+            summarize_snapshot(
+                &bot,
+                &msg,
+                &snapshot,
+                count,
+                &state,
+                LlmTask::Summarize,
+                None,
+                &display_name,
+                timings,
+            )
+            .await?;
+        }
+        Command::Usage => {
+            info!(target: "command", "User {} requested /usage in chat {}", display_name, chat_id);
+            let locale = message_store.lock().await.chat_settings(chat_id).locale();
+            let mut report = {
+                let stats = stats.lock().await;
+                let mut report = usage::report(
+                    "This chat",
+                    &stats.usage().chat(chat_id),
+                    config.cost_rates,
+                    locale,
+                );
+                // Other chats' usage is the owner's business only
+                if user_id.is_some_and(|id| config.is_owner(id)) {
+                    report.push_str("\n\n");
+                    report.push_str(&usage::report(
+                        "All chats",
+                        &stats.usage().global(),
+                        config.cost_rates,
+                        locale,
+                    ));
                 }
+                report
+            };
+            report.push_str("\n\nCounted since the bot last started.");
+            send_message(report).await?;
+        }
+        Command::DebugPrompt(ref arg) => {
+            info!(target: "command", "User {} requested /debugprompt {} in chat {}", display_name, arg, chat_id);
+            let chat_settings = message_store.lock().await.chat_settings(chat_id);
+            let limit = summarize_limit(&bot, &msg, &chat_settings, &state).await?;
+            let count = match arg.trim() {
+                "" => DEFAULT_SUMMARIZE_COUNT,
+                count => match count.parse::<usize>() {
+                    Ok(count) if count > 0 => count,
+                    _ => {
+                        send_message("Usage: /debugprompt [count]".to_string()).await?;
+                        return Ok(());
+                    }
+                },
             }
+            .min(limit);
+            let snapshot = message_store.lock().await.snapshot(
+                chat_id,
+                thread_id,
+                MessageSelector::Last(count),
+            );
+            let model = state
+                .settings
+                .lock()
+                .await
+                .model
+                .clone()
+                .unwrap_or_else(|| state.llm.primary_model().to_string());
+            let headroom = request_headroom(
+                &state,
+                &snapshot.messages,
+                LlmTask::Summarize,
+                chat_id,
+                &chat_settings,
+                &model,
+            );
+            let text = match headroom {
+                Some(headroom) => format!(
+                    "A summary of the last {} messages:\n\
+                    Estimated prompt: {} of {} tokens for {} ({}%, {} left)\n\
+                    Conversation: {}{}",
+                    snapshot.messages.len(),
+                    headroom.estimated_tokens,
+                    headroom.limit,
+                    model,
+                    headroom.percent(),
+                    headroom.remaining(),
+                    headroom.predicted_trimming(config.prompt_token_budget),
+                    if headroom.is_large() {
+                        format!(
+                            "\n\nSummaries this size show {}",
+                            context::LARGE_REQUEST_NOTE
+                        )
+                    } else {
+                        String::new()
+                    }
+                ),
+                None => format!(
+                    "The context window of {} isn't known. Add it to MODEL_CONTEXT_LIMITS, \
+                    e.g. {}=131072.",
+                    model, model
+                ),
+            };
+            send_message(text).await?;
         }
         Command::Memory => {
+            let chat_settings = message_store.lock().await.chat_settings(chat_id);
+            let locale = chat_settings.locale();
+            let tz = chat_timezone(&state, &chat_settings).await;
             let store = message_store.lock().await;
             let total_chats = store.chats.len();
-            let total_messages: usize = store.chats.values().map(|v| v.len()).sum();
+            let total_messages = store.total_messages;
 
             // Count messages for this chat/thread combination
             let current_chat_thread = ChatThreadId { chat_id, thread_id };
-            let current_chat_messages = store
-                .chats
-                .get(&current_chat_thread)
-                .map(|v| v.len())
-                .unwrap_or(0);
+            let current_queue = store.chats.get(&current_chat_thread);
+            let current_chat_messages = current_queue.map(|v| v.len()).unwrap_or(0);
+            let language_mix = current_queue
+                .map(|queue| lang::language_mix(queue.iter().map(|m| m.lang)))
+                .and_then(|mix| lang::format_language_mix(&mix))
+                .map(|mix| format!("Language mix: {}\n", format::bold(&mix)))
+                .unwrap_or_default();
+
+            let time_range = store
+                .time_range(chat_id, thread_id)
+                .map(|(oldest, newest)| {
+                    format!(
+                        "Oldest stored message: {}\n",
+                        format::bold(&format_time_range(oldest, newest, Utc::now(), tz, locale))
+                    )
+                })
+                .unwrap_or_default();
+            let eviction_note = eviction_hint(store.eviction_count(chat_id, thread_id), locale)
+                .map(|hint| format!("{}\n", markdown::escape(&hint)))
+                .unwrap_or_default();
 
             // Calculate uptime and format startup time
-            let uptime = store.get_uptime();
+            let uptime = store.get_uptime(locale);
+            let restored_note = store
+                .restored_from
+                .map(|taken_at| {
+                    format!(
+                        "{}\n",
+                        markdown::escape(&format!(
+                            "History restored from a snapshot taken {} ago ({}).",
+                            locale.duration(Utc::now().signed_duration_since(taken_at)),
+                            locale.datetime(&taken_at.with_timezone(&tz))
+                        ))
+                    )
+                })
+                .unwrap_or_default();
 
             let thread_info = match thread_id {
                 Some(_) => "thread",
                 None => "chat",
             };
+            let store_size = match store.max_store_bytes {
+                Some(max) => format!(
+                    "{} \\(limit {}\\)",
+                    format::bold(&locale.bytes(store.total_bytes as u64)),
+                    markdown::escape(&locale.bytes(max as u64))
+                ),
+                None => format::bold(&locale.bytes(store.total_bytes as u64)),
+            };
 
-            send_message(format!(
-                "There are *{}* messages in memory from *{}* different chats/threads\\.\n\
-                 Messages in this {}: *{}*\n\
-                 Uptime: *{}*\n\
-                 _Messages are *only* saved in memory since bot startup\\._",
-                total_messages,
-                total_chats,
+            let text = format!(
+                "There are {} messages in memory from {} different chats/threads\\.\n\
+                 Messages in this {}: {}\n\
+                 Estimated size: {}\n\
+                 {}\
+                 {}\
+                 {}\
+                 Uptime: {}\n\
+                 {}\
+                 {}",
+                format::bold(&locale.integer(total_messages as u64)),
+                format::bold(&locale.integer(total_chats as u64)),
                 thread_info,
-                current_chat_messages,
-                markdown::escape(&uptime)
+                format::bold(&locale.integer(current_chat_messages as u64)),
+                store_size,
+                time_range,
+                eviction_note,
+                language_mix,
+                format::bold(&uptime),
+                restored_note,
+                format::italic(if state.database.is_some() {
+                    "Messages are kept in a database across restarts."
+                } else if config.snapshot_path.is_some() {
+                    "Messages are kept in memory and saved to a snapshot when the bot restarts."
+                } else {
+                    "Messages are only saved in memory since bot startup."
+                })
+            );
+            send_retrying(
+                chat_id,
+                reply_to(&bot, &msg, text).parse_mode(ParseMode::MarkdownV2),
+            )
+            .await?;
+        }
+        Command::Limits => {
+            info!(target: "command", "User {} requested /limits in chat {} thread {:?} ({})", display_name, chat_id, thread_id, chat_type);
+            let chat_settings = message_store.lock().await.chat_settings(chat_id);
+            let limits =
+                limits::resolve_effective_limits(&chat_settings, config, ChatKind::of(&msg.chat));
+            let month = budget_month(&state).await;
+            send_message(format!(
+                "{}\nTime windows: only messages stored since {}\n{}",
+                limits.describe(),
+                if state.database.is_some() {
+                    "the chat was added"
+                } else {
+                    "the bot started"
+                },
+                state.budget.lock().await.status_line(month)
             ))
-            .parse_mode(ParseMode::MarkdownV2)
             .await?;
         }
         Command::Privacy => {
             info!(target: "command", "User {} requested /privacy in chat {} thread {:?} ({})", display_name, chat_id, thread_id, chat_type);
-            send_message(
-                "This bot stores all messages *only* in memory and *never* writes any data to disk\\.\n\n[Source Code](https://github.com/DuckyBlender/duck_summarizer)".to_string()
+            send_retrying(
+                chat_id,
+                reply_to(&bot, &msg, privacy_text(&state, msg.chat.is_private()))
+                    .parse_mode(ParseMode::MarkdownV2),
             )
-            .parse_mode(ParseMode::MarkdownV2)
             .await?;
         }
-    }
-
-    Ok(())
-}
+        Command::Settings(args) => {
+            let (key, value) = args
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((args.trim(), ""));
+            // Blocked terms are what the chat wants kept out of logs too
+            info!(target: "command", "User {} requested /settings {} in chat {} ({})", display_name, if key == "blockterm" { key } else { &args }, chat_id, chat_type);
+
+            if key.is_empty() {
+                let current = message_store.lock().await.chat_settings(chat_id);
+                send_message(format!(
+                    "{}\n\nChange with /settings placeholder <edit|silent|reaction>, \
+                    /settings anchor <command|start>, /settings allow_protected <on|off>, \
+                    /settings maxsummarize <n|off>, /settings adminsexempt <on|off>, \
+                    /settings timezone <name|off>, /settings pastes <condense|keep>, \
+                    /settings blockterm <add|remove|list> [term] or \
+                    /settings exclusionnote <on|off>. Copy them to another chat with \
+                    /settings export and /settings import.",
+                    current.describe()
+                ))
+                .await?;
+                return Ok(());
+            }
 
-async fn summarize_conversation(
-    messages: &[SavedMessage],
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    debug!(target: "summarization", "Starting conversation summarization for {} messages", messages.len());
+            if !is_chat_admin(&bot, &state.admins, &msg).await? {
+                send_message("Only chat administrators can change settings.".to_string()).await?;
+                return Ok(());
+            }
 
-    let api_key = match env::var("GROQ_API_KEY") {
-        Ok(key) => key,
-        Err(e) => {
-            error!(target: "summarization", "GROQ_API_KEY not set: {}", e);
-            return Err("GROQ_API_KEY environment variable not set".into());
-        }
-    };
+            match key {
+                "placeholder" => {
+                    let Some(mode) = PlaceholderMode::parse(value) else {
+                        send_message(
+                            "Usage: /settings placeholder <edit|silent|reaction>".to_string(),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.placeholder_mode = mode);
+                    info!(target: "command", "Placeholder mode in chat {} set to {} by {}", chat_id, mode, display_name);
+                    send_message(format!("Placeholder mode set to {}.", mode)).await?;
+                }
+                "anchor" => {
+                    let Some(anchor) = ReplyAnchor::parse(value) else {
+                        send_message("Usage: /settings anchor <command|start>".to_string()).await?;
+                        return Ok(());
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.reply_anchor = anchor);
+                    info!(target: "command", "Reply anchor in chat {} set to {} by {}", chat_id, anchor, display_name);
+                    send_message(format!(
+                        "Summaries will now reply to the {}.",
+                        match anchor {
+                            ReplyAnchor::Command => "summarize command",
+                            ReplyAnchor::RangeStart => "first summarized message",
+                        }
+                    ))
+                    .await?;
+                }
+                "allow_protected" => {
+                    let Some(allow) = settings::parse_toggle(value) else {
+                        send_message("Usage: /settings allow_protected <on|off>".to_string())
+                            .await?;
+                        return Ok(());
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.allow_protected = allow);
+                    info!(target: "command", "Summaries of protected content in chat {} set to {} by {}", chat_id, allow, display_name);
+                    send_message(if allow {
+                        "Summaries are now allowed even if this chat has content protection enabled.".to_string()
+                    } else {
+                        "Summaries are now disabled while this chat has content protection enabled.".to_string()
+                    })
+                    .await?;
+                }
+                "maxsummarize" => {
+                    let max = match value.trim() {
+                        "off" => None,
+                        value => match usize::from_str(value) {
+                            Ok(n) if n > 0 && n <= MAX_MESSAGES => Some(n),
+                            _ => {
+                                send_message(format!(
+                                    "Usage: /settings maxsummarize <1-{}|off>",
+                                    MAX_MESSAGES
+                                ))
+                                .await?;
+                                return Ok(());
+                            }
+                        },
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.max_summarize = max);
+                    info!(target: "command", "Summary limit in chat {} set to {:?} by {}", chat_id, max, display_name);
+                    send_message(match max {
+                        Some(max) => {
+                            format!("Summaries in this chat now cover at most {} messages.", max)
+                        }
+                        None => format!(
+                            "Summaries in this chat can cover up to {} messages again.",
+                            MAX_MESSAGES
+                        ),
+                    })
+                    .await?;
+                }
+                "adminsexempt" => {
+                    let Some(exempt) = settings::parse_toggle(value) else {
+                        send_message("Usage: /settings adminsexempt <on|off>".to_string()).await?;
+                        return Ok(());
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.admins_exempt = exempt);
+                    info!(target: "command", "Admin exemption from the summary limit in chat {} set to {} by {}", chat_id, exempt, display_name);
+                    send_message(if exempt {
+                        "Admins can now summarize past this chat's limit.".to_string()
+                    } else {
+                        "The chat's summary limit now applies to admins too.".to_string()
+                    })
+                    .await?;
+                }
+                "timezone" => {
+                    let timezone = match value.trim() {
+                        "off" => None,
+                        value => match value.parse::<chrono_tz::Tz>() {
+                            Ok(tz) => Some(tz.name().to_string()),
+                            Err(_) => {
+                                send_message(
+                                    "Usage: /settings timezone <IANA name like Europe/Warsaw|off>"
+                                        .to_string(),
+                                )
+                                .await?;
+                                return Ok(());
+                            }
+                        },
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.timezone = timezone.clone());
+                    info!(target: "command", "Timezone in chat {} set to {:?} by {}", chat_id, timezone, display_name);
+                    send_message(match timezone {
+                        Some(timezone) => {
+                            format!("Day slices like /summarize yesterday now use {}.", timezone)
+                        }
+                        None => "Day slices now use the bot's default timezone.".to_string(),
+                    })
+                    .await?;
+                }
+                "pastes" => {
+                    let keep = match value.trim() {
+                        "keep" => true,
+                        "condense" => false,
+                        _ => {
+                            send_message("Usage: /settings pastes <condense|keep>".to_string())
+                                .await?;
+                            return Ok(());
+                        }
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.keep_pastes = keep);
+                    info!(target: "command", "Paste handling in chat {} set to {} by {}", chat_id, if keep { "keep" } else { "condense" }, display_name);
+                    send_message(if keep {
+                        "Pasted logs and code are now summarized in full.".to_string()
+                    } else {
+                        "Pasted logs and code are now condensed before summarizing.".to_string()
+                    })
+                    .await?;
+                }
+                "exclusionnote" => {
+                    let Some(show) = settings::parse_toggle(value) else {
+                        send_message("Usage: /settings exclusionnote <on|off>".to_string()).await?;
+                        return Ok(());
+                    };
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| s.hide_exclusion_note = !show);
+                    info!(target: "command", "Exclusion note in chat {} set to {} by {}", chat_id, show, display_name);
+                    send_message(if show {
+                        "Summaries will mention when chat settings left messages out.".to_string()
+                    } else {
+                        "Summaries will no longer mention when chat settings left messages out."
+                            .to_string()
+                    })
+                    .await?;
+                }
+                "blockterm" => {
+                    let (action, term) = value
+                        .trim()
+                        .split_once(char::is_whitespace)
+                        .unwrap_or((value.trim(), ""));
+                    match action {
+                        "add" => {
+                            let mut result = Ok(false);
+                            let mut count = 0;
+                            message_store
+                                .lock()
+                                .await
+                                .update_chat_settings(chat_id, |s| {
+                                    result = blockterms::insert(&mut s.blocked_terms, term);
+                                    count = s.blocked_terms.len();
+                                });
+                            send_message(match result {
+                                Ok(true) => {
+                                    info!(target: "command", "Blocked term added in chat {} by {} ({} in total)", chat_id, display_name, count);
+                                    format!(
+                                        "Added. Messages containing it are left out of summaries \
+                                        and it's redacted from what I write. {} blocked in total; \
+                                        you may want to delete your command message.",
+                                        count
+                                    )
+                                }
+                                Ok(false) => "That term is already blocked.".to_string(),
+                                Err(e) => e.to_string(),
+                            })
+                            .await?;
+                        }
+                        "remove" => {
+                            let mut removed = false;
+                            message_store
+                                .lock()
+                                .await
+                                .update_chat_settings(chat_id, |s| {
+                                    if let Some(existing) =
+                                        blockterms::find(&s.blocked_terms, term).cloned()
+                                    {
+                                        removed = s.blocked_terms.remove(&existing);
+                                    }
+                                });
+                            if removed {
+                                info!(target: "command", "Blocked term removed in chat {} by {}", chat_id, display_name);
+                            }
+                            send_message(if removed {
+                                "Removed the blocked term.".to_string()
+                            } else {
+                                "That term isn't blocked.".to_string()
+                            })
+                            .await?;
+                        }
+                        "list" => {
+                            let terms = message_store
+                                .lock()
+                                .await
+                                .chat_settings(chat_id)
+                                .blocked_terms;
+                            if terms.is_empty() {
+                                send_message("No terms are blocked in this chat.".to_string())
+                                    .await?;
+                                return Ok(());
+                            }
+                            let list = format!(
+                                "Blocked terms in {}:\n{}",
+                                msg.chat.title().unwrap_or("our chat"),
+                                terms.iter().cloned().collect::<Vec<_>>().join("\n")
+                            );
+                            // Listing them in the group would repeat them to everyone
+                            if msg.chat.is_private() {
+                                send_message(list).await?;
+                                return Ok(());
+                            }
+                            let Some(user) =
+                                msg.from.as_ref().filter(|_| msg.sender_chat.is_none())
+                            else {
+                                send_message(
+                                    "The list is sent privately, so it can't be requested \
+                                    anonymously."
+                                        .to_string(),
+                                )
+                                .await?;
+                                return Ok(());
+                            };
+                            match ChatDestination::new(user.id.into(), None)
+                                .send(&bot, list, SendOptions::default())
+                                .await
+                            {
+                                Ok(_) => {
+                                    send_message("I've sent you the list privately.".to_string())
+                                        .await?;
+                                }
+                                Err(e) if cannot_message_privately(&e) => {
+                                    send_message(
+                                        "I can't message you privately yet. Open a private chat \
+                                        with me, send /start, and then ask again."
+                                            .to_string(),
+                                    )
+                                    .await?;
+                                }
+                                Err(e) => return Err(e),
+                            }
+                        }
+                        _ => {
+                            send_message(
+                                "Usage: /settings blockterm <add|remove|list> [term]".to_string(),
+                            )
+                            .await?;
+                        }
+                    }
+                }
+                "export" => {
+                    // Blocked terms are in the file, so it only goes to the admin
+                    let Some(user) = msg.from.as_ref().filter(|_| msg.sender_chat.is_none()) else {
+                        send_message(
+                            "Settings are sent privately, so they can't be exported anonymously."
+                                .to_string(),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+                    let key = ChatThreadId {
+                        chat_id,
+                        thread_id: msg.thread_id,
+                    };
+                    let (chat_settings, digest_time) = {
+                        let store = message_store.lock().await;
+                        (
+                            store.chat_settings(chat_id),
+                            store
+                                .digest_schedules
+                                .get(&key)
+                                .map(|schedule| schedule.time),
+                        )
+                    };
+                    let json = match chatconfig::export(&chat_settings, digest_time) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            error!(target: "command", "Failed to serialize the settings of chat {}: {}", chat_id, e);
+                            send_message("Couldn't export the settings.".to_string()).await?;
+                            return Ok(());
+                        }
+                    };
+                    let file = InputFile::memory(json).file_name(export_filename(
+                        msg.chat.title(),
+                        Utc::now(),
+                        "json",
+                    ));
+                    let caption = format!(
+                        "Settings{}. Reply to this file with /settings import in another chat \
+                        to copy them there.",
+                        msg.chat
+                            .title()
+                            .map(|title| format!(" of {}", title))
+                            .unwrap_or_default()
+                    );
+                    let sent = destination::with_retry(user.id.into(), || {
+                        bot.send_document(user.id, file.clone())
+                            .caption(caption.clone())
+                            .into_future()
+                    })
+                    .await;
+                    match sent {
+                        Ok(_) => {
+                            info!(target: "command", "Exported the settings of chat {} to {}", chat_id, display_name);
+                            if !msg.chat.is_private() {
+                                send_message("I've sent you the settings privately.".to_string())
+                                    .await?;
+                            }
+                        }
+                        Err(e) if cannot_message_privately(&e) => {
+                            send_message(
+                                "I can't message you privately yet. Open a private chat with \
+                                me, send /start, and then try /settings export again."
+                                    .to_string(),
+                            )
+                            .await?;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                "import" => {
+                    let mut include_users = false;
+                    let mut confirm = false;
+                    for flag in value.split_whitespace() {
+                        match flag {
+                            "users" => include_users = true,
+                            "confirm" => confirm = true,
+                            _ => {
+                                send_message(
+                                    "Usage: reply to a settings file with \
+                                    /settings import [users] [confirm]"
+                                        .to_string(),
+                                )
+                                .await?;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    let Some(document) = msg.reply_to_message().and_then(|reply| reply.document())
+                    else {
+                        send_message(
+                            "Reply to a file from /settings export with /settings import."
+                                .to_string(),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+                    if document.file.size > chatconfig::MAX_FILE_BYTES {
+                        send_message("That file is too large to be a settings export.".to_string())
+                            .await?;
+                        return Ok(());
+                    }
+                    let file = bot.get_file(document.file.id.clone()).await?;
+                    let mut buffer = Vec::new();
+                    if let Err(e) = bot.download_file(&file.path, &mut buffer).await {
+                        warn!(target: "command", "Failed to download settings file: {}", e);
+                        send_message("Couldn't download the file.".to_string()).await?;
+                        return Ok(());
+                    }
+                    let imported = match chatconfig::parse(&buffer, MAX_MESSAGES) {
+                        Ok(imported) => imported,
+                        Err(e) => {
+                            info!(target: "command", "Rejected settings import in chat {} by {}: {}", chat_id, display_name, e);
+                            send_message(format!("Couldn't import the settings: {}.", e)).await?;
+                            return Ok(());
+                        }
+                    };
+
+                    let key = ChatThreadId {
+                        chat_id,
+                        thread_id: msg.thread_id,
+                    };
+                    let (current, current_digest) = {
+                        let store = message_store.lock().await;
+                        (
+                            store.chat_settings(chat_id),
+                            store
+                                .digest_schedules
+                                .get(&key)
+                                .map(|schedule| schedule.time),
+                        )
+                    };
+                    let mut updated = current.clone();
+                    imported.apply_to(&mut updated, include_users);
+                    let changes = chatconfig::describe_changes(
+                        &current,
+                        &updated,
+                        current_digest,
+                        imported.digest_time,
+                    );
+                    if changes.is_empty() {
+                        send_message("This chat already has these settings.".to_string()).await?;
+                        return Ok(());
+                    }
+                    if !confirm {
+                        let skipped_users = if !include_users
+                            && imported.settings.ignored_users != current.ignored_users
+                        {
+                            format!(
+                                "\n\nThe file also ignores {} user(s) of the chat it came \
+                                from. They're left as they are here unless you add users to \
+                                the command.",
+                                imported.settings.ignored_users.len()
+                            )
+                        } else {
+                            String::new()
+                        };
+                        send_message(format!(
+                            "Importing this file would change:\n{}{}\n\nReply to it again \
+                            with /settings import {}confirm to apply.",
+                            changes.join("\n"),
+                            skipped_users,
+                            if include_users { "users " } else { "" }
+                        ))
+                        .await?;
+                        return Ok(());
+                    }
 
-    let model = "llama-3.3-70b-versatile";
-    let client = reqwest::Client::new();
-
-    // Convert messages to conversation format
-    let mut conversation_text = String::new();
-    for message in messages {
-        let username = message.from_user.as_deref().unwrap_or("Unknown");
-
-        // Replace newlines with literals
-        let text = message.text.replace('\n', "\\n");
-
-        // Add reply information if available
-        if let Some(reply_id) = message.reply_to_message_id {
-            let replied_to = messages
-                .iter()
-                .find(|m| m.message_id == reply_id)
-                .and_then(|m| m.from_user.as_ref())
-                .map(|u| u.as_str())
-                .unwrap_or("someone");
-
-            conversation_text.push_str(&format!(
-                "{} (replying to {}): {}\n",
-                username, replied_to, text
-            ));
-        } else {
-            conversation_text.push_str(&format!("{}: {}\n", username, text));
+                    let updated = message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| imported.apply_to(s, include_users));
+                    let schedule = match imported.digest_time {
+                        Some(time) => {
+                            let tz = digest_timezone(&state, &updated);
+                            Some((time, dayslice::next_occurrence(tz, time, Utc::now())))
+                        }
+                        None => None,
+                    };
+                    {
+                        let mut store = message_store.lock().await;
+                        let last_digest_at = store
+                            .digest_schedules
+                            .get(&key)
+                            .and_then(|schedule| schedule.last_digest_at);
+                        store.set_digest_schedule(
+                            key,
+                            schedule.map(|(time, next_due)| DigestSchedule {
+                                time,
+                                next_due,
+                                last_digest_at,
+                            }),
+                        );
+                    }
+                    info!(target: "command", "Imported settings in chat {} by {} ({} changes, users {})", chat_id, display_name, changes.len(), if include_users { "included" } else { "skipped" });
+                    send_message(format!("Imported the settings:\n{}", changes.join("\n"))).await?;
+                }
+                _ => {
+                    send_message(format!("Unknown setting '{}'.", key)).await?;
+                }
+            }
         }
-    }
-
-    trace!(target: "summarization", "Prepared conversation text for summarization: {} characters", conversation_text.len());
-
-    let system_prompt = "You are a Telegram conversation summarizer. Your task is to create a concise, accurate, and well-structured summary of the conversation provided. Make it as short as possible while retaining all important information. Don't include any personal opinions or additional comments. Don't use markdown.";
-
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
-    let request = ChatCompletionRequest {
-        model: model.to_string(),
-        messages: vec![
-            ChatMessage {
-                role: "system".to_string(),
-                content: system_prompt.to_string(),
-            },
-            ChatMessage {
-                role: "user".to_string(),
-                content: conversation_text.to_string(),
-            },
-        ],
-        temperature: 0.4,
-        max_tokens: 2000,
-    };
+        Command::Language(code) => {
+            info!(target: "command", "User {} requested /language {} in chat {} ({})", display_name, code, chat_id, chat_type);
+            let code = code.trim();
+            if code.is_empty() {
+                let current = message_store.lock().await.chat_settings(chat_id).language;
+                send_message(match current {
+                    Some(code) => format!(
+                        "Summaries in this chat are written in {} ({}). Use /language auto to \
+                        follow the conversation's language instead.",
+                        lang::language_name(&code),
+                        code
+                    ),
+                    None => "Summaries follow the language the conversation is in. Set one \
+                        with /language <code>, e.g. /language pl."
+                        .to_string(),
+                })
+                .await?;
+                return Ok(());
+            }
 
-    debug!(target: "api", "Sending request to Groq API for summarization, model: {}", model);
+            if !is_chat_admin(&bot, &state.admins, &msg).await? {
+                send_message("Only chat administrators can change the language.".to_string())
+                    .await?;
+                return Ok(());
+            }
 
-    let response = match client
-        .post("https://api.groq.com/openai/v1/chat/completions")
-        .headers(headers)
-        .bearer_auth(&api_key)
-        .json(&request)
-        .send()
-        .await
-    {
-        Ok(resp) => {
-            if !resp.status().is_success() {
-                let status = resp.status();
-                let error_text = resp
-                    .text()
+            let language = if code.eq_ignore_ascii_case("auto") {
+                None
+            } else {
+                let Some(language) = lang::parse_language(code) else {
+                    send_message(format!(
+                        "I don't know the language '{}'. Use a code like pl, en or de \
+                        (supported: {}), or auto.",
+                        code,
+                        lang::supported_codes()
+                    ))
+                    .await?;
+                    return Ok(());
+                };
+                Some(language.to_string())
+            };
+            message_store
+                .lock()
+                .await
+                .update_chat_settings(chat_id, |s| s.language = language.clone());
+            info!(target: "command", "Summary language in chat {} set to {:?} by {}", chat_id, language, display_name);
+            send_message(match language {
+                Some(code) => format!(
+                    "Summaries in this chat will be written in {}.",
+                    lang::language_name(&code)
+                ),
+                None => "Summaries will follow the conversation's language again.".to_string(),
+            })
+            .await?;
+        }
+        Command::Digest(args) => {
+            info!(target: "command", "User {} requested /digest {} in chat {} ({})", display_name, args, chat_id, chat_type);
+            let (action, rest) = args
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((args.trim(), ""));
+            let key = ChatThreadId { chat_id, thread_id };
+            let chat_settings = message_store.lock().await.chat_settings(chat_id);
+            let tz = digest_timezone(&state, &chat_settings);
+            let locale = chat_settings.locale();
+
+            if action.is_empty() || action == "status" {
+                let schedule = message_store
+                    .lock()
                     .await
-                    .unwrap_or_else(|_| "Unable to read error response".to_string());
-                error!(target: "api", "Groq API returned error status {}: {}", status, error_text);
-                return Err(format!("API error: Status {}", status).into());
+                    .digest_schedules
+                    .get(&key)
+                    .cloned();
+                send_message(match schedule {
+                    Some(schedule) => format!(
+                        "Daily digest at {} ({}). Next: {}. Last posted: {}.",
+                        schedule.time.format("%H:%M"),
+                        tz.name(),
+                        locale.datetime(&schedule.next_due.with_timezone(&tz)),
+                        schedule
+                            .last_digest_at
+                            .map(|at| locale.datetime(&at.with_timezone(&tz)))
+                            .unwrap_or_else(|| "never".to_string())
+                    ),
+                    None => "There's no daily digest here. Admins can start one with \
+                        /digest on <HH:MM>."
+                        .to_string(),
+                })
+                .await?;
+                return Ok(());
             }
-            resp
-        }
-        Err(e) => {
-            error!(target: "api", "Failed to send request to Groq API: {}", e);
-            return Err(Box::new(e));
-        }
-    };
 
-    match response.json::<ChatCompletionResponse>().await {
-        Ok(parsed) => {
-            if parsed.choices.is_empty() {
-                error!(target: "api", "Groq API returned empty choices array");
-                return Err("API returned no choices".into());
+            if !is_chat_admin(&bot, &state.admins, &msg).await? {
+                send_message("Only chat administrators can change the daily digest.".to_string())
+                    .await?;
+                return Ok(());
             }
 
-            let summary = parsed.choices[0].message.content.clone();
-            debug!(target: "summarization", "Successfully received summary from API: {} characters", summary.len());
-            Ok(summary)
-        }
-        Err(e) => {
-            error!(target: "api", "Failed to parse Groq API response: {}", e);
-            Err(Box::new(e))
+            match action {
+                "on" => {
+                    let default_time = state.settings.lock().await.default_digest_time.clone();
+                    let time = match rest.trim() {
+                        "" => default_time.as_deref().and_then(digest::parse_time),
+                        value => digest::parse_time(value),
+                    };
+                    let Some(time) = time else {
+                        send_message(
+                            "Please give a time like 18:00, e.g. /digest on 18:00.".to_string(),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+                    let next_due = dayslice::next_occurrence(tz, time, Utc::now());
+                    {
+                        let mut store = message_store.lock().await;
+                        let last_digest_at = store
+                            .digest_schedules
+                            .get(&key)
+                            .and_then(|schedule| schedule.last_digest_at);
+                        store.set_digest_schedule(
+                            key,
+                            Some(DigestSchedule {
+                                time,
+                                next_due,
+                                last_digest_at,
+                            }),
+                        );
+                    }
+                    info!(target: "digest", "Daily digest at {} ({}) set up in chat {} thread {:?} by {}", time.format("%H:%M"), tz.name(), chat_id, thread_id, display_name);
+                    send_message(format!(
+                        "I'll post a digest of everything new here every day at {} ({}), \
+                        starting {}. Days without new messages are skipped.",
+                        time.format("%H:%M"),
+                        tz.name(),
+                        locale.datetime(&next_due.with_timezone(&tz))
+                    ))
+                    .await?;
+                }
+                "off" => {
+                    let removed = {
+                        let mut store = message_store.lock().await;
+                        let existed = store.digest_schedules.contains_key(&key);
+                        store.set_digest_schedule(key, None);
+                        existed
+                    };
+                    if removed {
+                        info!(target: "digest", "Daily digest turned off in chat {} thread {:?} by {}", chat_id, thread_id, display_name);
+                    }
+                    send_message(if removed {
+                        "The daily digest is off.".to_string()
+                    } else {
+                        "There was no daily digest here.".to_string()
+                    })
+                    .await?;
+                }
+                _ => {
+                    send_message("Usage: /digest status | on <HH:MM> | off".to_string()).await?;
+                }
+            }
         }
-    }
-}
+        Command::Glossary(args) => {
+            info!(target: "command", "User {} requested /glossary {} in chat {} ({})", display_name, args, chat_id, chat_type);
+            let (action, rest) = args
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((args.trim(), ""));
+
+            if action.is_empty() || action == "list" {
+                let entries = message_store.lock().await.chat_settings(chat_id).glossary;
+                send_message(if entries.is_empty() {
+                    "The glossary is empty. Admins can add terms with \
+                    /glossary add <term>: <definition>."
+                        .to_string()
+                } else {
+                    format!("Glossary:\n{}", glossary::list(&entries))
+                })
+                .await?;
+                return Ok(());
+            }
 
-#[tokio::main]
-async fn main() {
+            if !is_chat_admin(&bot, &state.admins, &msg).await? {
+                send_message("Only chat administrators can change the glossary.".to_string())
+                    .await?;
+                return Ok(());
+            }
+
+            match action {
+                "add" => {
+                    let (term, definition) = match glossary::parse_entry(rest) {
+                        Ok(entry) => entry,
+                        Err(e) => {
+                            send_message(e.to_string()).await?;
+                            return Ok(());
+                        }
+                    };
+                    let mut result = Ok(false);
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| {
+                            result =
+                                glossary::insert(&mut s.glossary, term.clone(), definition.clone());
+                        });
+                    send_message(match result {
+                        Ok(replaced) => {
+                            info!(target: "command", "Glossary entry '{}' {} in chat {} by {}", term, if replaced { "updated" } else { "added" }, chat_id, display_name);
+                            format!(
+                                "{} {}: {}",
+                                if replaced { "Updated" } else { "Added" },
+                                term,
+                                definition
+                            )
+                        }
+                        Err(e) => e.to_string(),
+                    })
+                    .await?;
+                }
+                "remove" => {
+                    let mut removed = None;
+                    message_store
+                        .lock()
+                        .await
+                        .update_chat_settings(chat_id, |s| {
+                            if let Some(term) = glossary::find(&s.glossary, rest).cloned() {
+                                s.glossary.remove(&term);
+                                removed = Some(term);
+                            }
+                        });
+                    send_message(match removed {
+                        Some(term) => {
+                            info!(target: "command", "Glossary entry '{}' removed in chat {} by {}", term, chat_id, display_name);
+                            format!("Removed {} from the glossary.", term)
+                        }
+                        None => format!("'{}' isn't in the glossary.", rest.trim()),
+                    })
+                    .await?;
+                }
+                _ => {
+                    send_message(
+                        "Usage: /glossary list | add <term>: <definition> | remove <term>"
+                            .to_string(),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::Export(count_str) => {
+            info!(target: "command", "User {} requested /export {} in chat {} ({})", display_name, count_str, chat_id, chat_type);
+            if !is_chat_admin(&bot, &state.admins, &msg).await? {
+                send_message(
+                    "Only chat administrators can export the stored conversation.".to_string(),
+                )
+                .await?;
+                return Ok(());
+            }
+            // Anonymous admins post as the group, which has no private chat to send to
+            let Some(user) = msg.from.as_ref().filter(|_| msg.sender_chat.is_none()) else {
+                send_message(
+                    "Exports are sent privately, so they can't be requested anonymously."
+                        .to_string(),
+                )
+                .await?;
+                return Ok(());
+            };
+            let chat_settings = message_store.lock().await.chat_settings(chat_id);
+            let limit = summarize_limit(&bot, &msg, &chat_settings, &state).await?;
+            let Some(count) = parse_count(&count_str, limit, ChatKind::of(&msg.chat)) else {
+                send_message(format!(
+                    "Please provide a valid number between 1 and {}",
+                    limit
+                ))
+                .await?;
+                return Ok(());
+            };
+
+            let snapshot = message_store.lock().await.snapshot(
+                chat_id,
+                thread_id,
+                MessageSelector::Last(count),
+            );
+            if snapshot.messages.is_empty() {
+                send_message("There are no stored messages here to export yet.".to_string())
+                    .await?;
+                return Ok(());
+            }
+
+            let text = format::format_conversation(&snapshot.messages);
+            let file = InputFile::memory(text.into_bytes()).file_name(export_filename(
+                msg.chat.title(),
+                Utc::now(),
+                "txt",
+            ));
+            let caption = format!(
+                "The last {} stored messages{}",
+                snapshot.messages.len(),
+                msg.chat
+                    .title()
+                    .map(|title| format!(" of {}", title))
+                    .unwrap_or_default()
+            );
+            let sent = destination::with_retry(user.id.into(), || {
+                bot.send_document(user.id, file.clone())
+                    .caption(caption.clone())
+                    .into_future()
+            })
+            .await;
+            match sent {
+                Ok(_) => {
+                    info!(target: "command", "Exported {} messages of chat {} to {}", snapshot.messages.len(), chat_id, display_name);
+                    if !msg.chat.is_private() {
+                        send_message("I've sent you the export privately.".to_string()).await?;
+                    }
+                }
+                Err(e) if cannot_message_privately(&e) => {
+                    debug!(target: "command", "Couldn't send the export to {} privately: {}", display_name, e);
+                    send_message(
+                        "I can't message you privately yet. Open a private chat with me, send \
+                        /start, and then try /export again."
+                            .to_string(),
+                    )
+                    .await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Command::Regenerate => {
+            info!(target: "command", "User {} requested /regenerate in chat {} thread {:?} ({})", display_name, chat_id, msg.thread_id, chat_type);
+            let key = ChatThreadId {
+                chat_id,
+                thread_id: msg.thread_id,
+            };
+            let Some(last) = message_store.lock().await.last_summary(&key) else {
+                send_message(
+                    "There's no summary here to regenerate yet. Use /summarize first.".to_string(),
+                )
+                .await?;
+                return Ok(());
+            };
+            let snapshot = message_store.lock().await.regenerate_snapshot(&key, &last);
+            if snapshot.messages.is_empty() {
+                send_message(
+                    "The messages of the last summary are no longer stored, so it can't be \
+                    regenerated."
+                        .to_string(),
+                )
+                .await?;
+                return Ok(());
+            }
+            let snapshot = ChatSnapshot {
+                previous_summary: Some(last.text),
+                ..snapshot
+            };
+            summarize_snapshot(
+                &bot,
+                &msg,
+                &snapshot,
+                last.requested,
+                &state,
+                LlmTask::Summarize,
+                last.focus.as_deref(),
+                &display_name,
+                StageTimings::start(),
+            )
+            .await?;
+        }
+        Command::Restrict(ref arg) => {
+            info!(target: "command", "User {} requested /restrict {} in chat {} ({})", display_name, arg, chat_id, chat_type);
+            if msg.chat.is_private() {
+                send_message("/restrict only applies to groups.".to_string()).await?;
+                return Ok(());
+            }
+            if arg.trim().is_empty() {
+                let restricted = message_store.lock().await.chat_settings(chat_id).restricted;
+                send_message(if restricted {
+                    "Only admins can request summaries in this chat. Allow everyone again \
+                    with /restrict off."
+                        .to_string()
+                } else {
+                    "Everyone here can request summaries. Limit them to admins with \
+                    /restrict on."
+                        .to_string()
+                })
+                .await?;
+                return Ok(());
+            }
+            if !is_chat_admin(&bot, &state.admins, &msg).await? {
+                send_message("Only chat administrators can change this.".to_string()).await?;
+                return Ok(());
+            }
+            let Some(restricted) = settings::parse_toggle(arg) else {
+                send_message("Usage: /restrict <on|off>".to_string()).await?;
+                return Ok(());
+            };
+            message_store
+                .lock()
+                .await
+                .update_chat_settings(chat_id, |s| s.restricted = restricted);
+            info!(target: "command", "Summaries in chat {} restricted to admins: {} (by {})", chat_id, restricted, display_name);
+            send_message(if restricted {
+                "Only admins can request summaries in this chat now.".to_string()
+            } else {
+                "Everyone here can request summaries now.".to_string()
+            })
+            .await?;
+        }
+        Command::Ignore(username) | Command::Unignore(username) if username.trim().is_empty() => {
+            info!(target: "command", "User {} listed ignored users in chat {} ({})", display_name, chat_id, chat_type);
+            let ignored = message_store
+                .lock()
+                .await
+                .chat_settings(chat_id)
+                .ignored_users;
+            send_message(if ignored.is_empty() {
+                "Nobody is ignored in this chat. Leave someone out of summaries with \
+                /ignore @username."
+                    .to_string()
+            } else {
+                format!(
+                    "Left out of summaries: {}",
+                    settings::format_usernames(&ignored)
+                )
+            })
+            .await?;
+        }
+        Command::Ignore(ref username) | Command::Unignore(ref username) => {
+            let ignore = matches!(cmd, Command::Ignore(_));
+            let command = if ignore { "ignore" } else { "unignore" };
+            info!(target: "command", "User {} requested /{} {} in chat {} ({})", display_name, command, username, chat_id, chat_type);
+            if !is_chat_admin(&bot, &state.admins, &msg).await? {
+                send_message("Only chat administrators can change who is ignored.".to_string())
+                    .await?;
+                return Ok(());
+            }
+            let Some(username) = settings::normalize_username(username) else {
+                send_message(format!("Usage: /{} @username", command)).await?;
+                return Ok(());
+            };
+
+            let mut changed = false;
+            message_store
+                .lock()
+                .await
+                .update_chat_settings(chat_id, |s| {
+                    changed = if ignore {
+                        s.ignored_users.insert(username.clone())
+                    } else {
+                        s.ignored_users.remove(&username)
+                    };
+                });
+            if changed {
+                info!(target: "command", "{} @{} in chat {} ({})", if ignore { "Ignoring" } else { "No longer ignoring" }, username, chat_id, display_name);
+            }
+            send_message(match (ignore, changed) {
+                (true, true) => format!(
+                    "Messages from @{} will be left out of summaries, including ones already stored.",
+                    username
+                ),
+                (true, false) => format!("@{} is already ignored.", username),
+                (false, true) => format!("Messages from @{} will be summarized again.", username),
+                (false, false) => format!("@{} isn't ignored.", username),
+            })
+            .await?;
+        }
+        Command::AllowChat(ref arg) | Command::BlockChat(ref arg) => {
+            let allow = matches!(cmd, Command::AllowChat(_));
+            let name = if allow { "allowchat" } else { "blockchat" };
+            if !user_id.is_some_and(|id| config.is_owner(id)) {
+                debug!(target: "command", "Ignoring /{} from non-owner {} in chat {}", name, display_name, chat_id);
+                return Ok(());
+            }
+            // Without an id the command applies to the chat it's sent in
+            let target = match arg.trim() {
+                "" => Some(chat_id),
+                id => id.parse::<i64>().ok().map(ChatId),
+            };
+            let Some(target) = target else {
+                send_message(format!("Usage: /{} [chat_id]", name)).await?;
+                return Ok(());
+            };
+            if !allow && user_id.is_some_and(|id| ChatId::from(id) == target) {
+                send_message("Our private chat always stays available.".to_string()).await?;
+                return Ok(());
+            }
+
+            let changed = message_store.lock().await.set_chat_access(target, allow);
+            if changed {
+                info!(target: "command", "Owner {} chat {}", if allow { "allowed" } else { "blocked" }, target);
+            }
+            send_message(match (allow, changed) {
+                (true, true) => format!("Chat {} is allowed.", target),
+                (true, false) => format!("Chat {} is already allowed.", target),
+                (false, true) => format!("Chat {} is blocked. I'll ignore it from now on.", target),
+                (false, false) => format!("Chat {} is already blocked.", target),
+            })
+            .await?;
+        }
+        Command::Admin(args) => {
+            if !user_id.is_some_and(|id| config.is_owner(id)) {
+                debug!(target: "command", "Ignoring /admin from non-owner {} in chat {}", display_name, chat_id);
+                return Ok(());
+            }
+            info!(target: "command", "Owner requested /admin {} in chat {}", args, chat_id);
+
+            let (subcommand, rest) = args
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((args.trim(), ""));
+
+            match subcommand {
+                "stats" => {
+                    let report = match &config.prompt_variants {
+                        Some(_) => stats::format_variant_report(stats.lock().await.variant_stats()),
+                        None => "Prompt A/B testing is disabled.".to_string(),
+                    };
+                    let blocked = blocklist.lock().await.len();
+                    let store_range = message_store
+                        .lock()
+                        .await
+                        .global_time_range()
+                        .map(|(oldest, newest)| {
+                            format_time_range(
+                                oldest,
+                                newest,
+                                Utc::now(),
+                                chrono_tz::Tz::UTC,
+                                Locale::English,
+                            )
+                        })
+                        .unwrap_or_else(|| "none".to_string());
+                    let tracked_chats = {
+                        let store = message_store.lock().await;
+                        match store.max_tracked_chats {
+                            Some(max) => format!("{}/{}", store.tracked_chats.len(), max),
+                            None => store.tracked_chats.len().to_string(),
+                        }
+                    };
+                    let month = budget_month(&state).await;
+                    let (preparation, stages, panics) = {
+                        let stats = stats.lock().await;
+                        (
+                            stats.preparation_report(),
+                            stats.stages().report(),
+                            stats.panics(),
+                        )
+                    };
+                    send_message(format!(
+                        "{}\nServed model mismatches: {}\n{}\nOldest stored message: {}\nTracked chats: {}\n{}\n{}\n{}\nHandler panics: {}\nBlocked users: {}\n{}",
+                        llm.status_line(),
+                        llm.model_mismatches(),
+                        state.budget.lock().await.status_line(month),
+                        store_range,
+                        tracked_chats,
+                        report,
+                        preparation,
+                        stages,
+                        panics,
+                        blocked,
+                        settings.lock().await.describe()
+                    ))
+                    .await?;
+                }
+                "block" | "unblock" => {
+                    let reply_sender = msg
+                        .reply_to_message()
+                        .and_then(|reply| reply.from.as_ref())
+                        .map(|user| user.id);
+                    let Some(target) = access::resolve_target_user(rest, reply_sender) else {
+                        send_message(format!(
+                            "Usage: /admin {} <user_id>, or reply to one of their messages",
+                            subcommand
+                        ))
+                        .await?;
+                        return Ok(());
+                    };
+
+                    let reply = if subcommand == "block" {
+                        if config.is_owner(target) {
+                            "You can't block yourself.".to_string()
+                        } else if blocklist.lock().await.block(target) {
+                            info!(target: "command", "Owner blocked user {}", target);
+                            format!("Blocked user {}. Their commands will be ignored.", target)
+                        } else {
+                            format!("User {} is already blocked.", target)
+                        }
+                    } else if blocklist.lock().await.unblock(target) {
+                        info!(target: "command", "Owner unblocked user {}", target);
+                        format!("Unblocked user {}.", target)
+                    } else {
+                        format!("User {} isn't blocked.", target)
+                    };
+                    send_message(reply).await?;
+                }
+                "dump" => {
+                    // Chat ids and settings aren't for a group's eyes
+                    if !msg.chat.is_private() {
+                        send_message("Use /admin dump in our private chat.".to_string()).await?;
+                        return Ok(());
+                    }
+                    let dump = debug_dump(&state).await;
+                    let json = match serde_json::to_vec_pretty(&dump) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            error!(target: "command", "Failed to serialize the debug dump: {}", e);
+                            send_message("Failed to build the dump.".to_string()).await?;
+                            return Ok(());
+                        }
+                    };
+                    let file = InputFile::memory(json).file_name(dump::filename(dump.taken_at));
+                    destination::with_retry(chat_id, || {
+                        bot.send_document(chat_id, file.clone())
+                            .caption(
+                                "Debug dump: settings, counts and timings, no messages or keys",
+                            )
+                            .into_future()
+                    })
+                    .await?;
+                }
+                "budget" => {
+                    let month = budget_month(&state).await;
+                    let mut budget = state.budget.lock().await;
+                    let reply = match rest.trim() {
+                        "" => budget.status_line(month),
+                        "lift" if budget.lift(month) => {
+                            info!(target: "command", "Owner lifted the monthly budget cap");
+                            if let Some(database) = &state.database {
+                                database.save_meta(BUDGET_META_KEY, &budget.state());
+                            }
+                            "Budget cap lifted until the end of the month.".to_string()
+                        }
+                        "lift" => "No monthly budget is configured.".to_string(),
+                        _ => "Usage: /admin budget [lift]".to_string(),
+                    };
+                    drop(budget);
+                    send_message(reply).await?;
+                }
+                "seed" => {
+                    let args: Vec<&str> = rest.split_whitespace().collect();
+                    let document = msg.reply_to_message().and_then(|reply| reply.document());
+                    let (Some((target_chat, target_thread)), Some(document)) =
+                        (parse_seed_target(&args), document)
+                    else {
+                        send_message(
+                            "Usage: reply to an uploaded result.json from a Telegram Desktop \
+                            export with /admin seed <chat_id> [thread_id]"
+                                .to_string(),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+
+                    let file = bot.get_file(document.file.id.clone()).await?;
+                    let mut buffer = Vec::new();
+                    if let Err(e) = bot.download_file(&file.path, &mut buffer).await {
+                        warn!(target: "command", "Failed to download export for seeding: {}", e);
+                        send_message("Couldn't download the file.".to_string()).await?;
+                        return Ok(());
+                    }
+
+                    let reply = match import::parse_export(
+                        &String::from_utf8_lossy(&buffer),
+                        MAX_MESSAGES,
+                    ) {
+                        Ok(imported) => {
+                            let found = imported.len();
+                            let added = message_store.lock().await.seed(
+                                target_chat,
+                                target_thread,
+                                imported,
+                            );
+                            info!(target: "command", "Owner seeded chat {} thread {:?} with {} messages", target_chat, target_thread, added);
+                            format!(
+                                "Imported {} of {} messages into chat {}.",
+                                added, found, target_chat
+                            )
+                        }
+                        Err(e) => format!("Couldn't read the export: {}", e),
+                    };
+                    send_message(reply).await?;
+                }
+                "setup" => {
+                    if !msg.chat.is_private() {
+                        send_message("Run /admin setup in a private chat with me.".to_string())
+                            .await?;
+                        return Ok(());
+                    }
+                    let Some(id) = user_id else {
+                        return Ok(());
+                    };
+                    wizards.lock().await.start(id, Utc::now());
+                    send_wizard_step(&bot, ChatDestination::of(&msg), WizardStep::Model).await?;
+                }
+                "rundigest" => {
+                    let args: Vec<&str> = rest.split_whitespace().collect();
+                    let dry = args.contains(&"--dry");
+                    let target_args: Vec<&str> =
+                        args.into_iter().filter(|arg| *arg != "--dry").collect();
+                    // "here" is this chat and topic, like /digest sets them
+                    let target = match target_args.as_slice() {
+                        ["here"] => Some((chat_id, thread_id)),
+                        target_args => parse_seed_target(target_args),
+                    };
+                    let (Some((target, target_thread)), Some(owner)) = (target, user_id) else {
+                        send_message(
+                            "Usage: /admin rundigest <chat_id [thread_id]|here> [--dry]"
+                                .to_string(),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+
+                    // A dry run goes to the owner's private chat and leaves the
+                    // watermark and schedule
+                    let dry_run_to = dry.then_some(ChatId::from(owner));
+                    let key = ChatThreadId {
+                        chat_id: target,
+                        thread_id: target_thread,
+                    };
+                    let reply = match run_scheduled_digest(&bot, &state, key, dry_run_to).await {
+                        Ok(DigestOutcome::Sent(count)) => format!(
+                            "Posted a digest of {} messages{}.",
+                            count,
+                            if dry {
+                                " to you (dry run, watermark unchanged)"
+                            } else {
+                                ""
+                            }
+                        ),
+                        Ok(DigestOutcome::NothingNew) => {
+                            "Nothing new since the last digest.".to_string()
+                        }
+                        Ok(DigestOutcome::OverBudget) => {
+                            "The monthly budget is used up, so no digest was made.".to_string()
+                        }
+                        Err(e) => {
+                            warn!(target: "digest", "Forced digest of chat {} failed: {}", target, e);
+                            format!("The digest failed: {}", e)
+                        }
+                    };
+                    send_message(reply).await?;
+                }
+                "tasks" => {
+                    send_message(state.tasks.describe(Utc::now())).await?;
+                }
+                "recount" => {
+                    let (tracked, counted) = message_store.lock().await.recount();
+                    send_message(if tracked == counted {
+                        format!("Message counter is consistent: {} messages.", counted)
+                    } else {
+                        format!(
+                            "Message counter drifted: tracked {}, counted {}. It has been corrected.",
+                            tracked, counted
+                        )
+                    })
+                    .await?;
+                }
+                _ => {
+                    send_message(
+                        "Usage: /admin stats | block <user_id> | unblock <user_id> | budget [lift] | seed <chat_id> | setup | rundigest <chat_id [thread_id]|here> [--dry] | recount | tasks | dump"
+                            .to_string(),
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Describes what actually happens to messages under the current configuration
+// In private chats the text speaks about the user's own messages rather than
+// those of every chat
+fn privacy_text(state: &AppState, private: bool) -> String {
+    let subject = |group: &'static str| {
+        if private {
+            "the messages you send me here"
+        } else {
+            group
+        }
+    };
+    let mut text = if state.database.is_some() {
+        format!(
+            "This bot stores {} in a database on its server, so summaries keep working after \
+            restarts\\.\n\n\
+            Deleting a message in Telegram doesn't remove the copy I already stored\\. It stays \
+            until newer messages push it out\\.",
+            subject("the latest messages of each chat")
+        )
+    } else if state.config.snapshot_path.is_some() {
+        format!(
+            "This bot keeps {} in memory while running and writes them to a file on its \
+            server when it restarts, so recent history survives restarts\\.\n\n\
+            Deleting a message in Telegram doesn't remove the copy I already stored\\. It stays \
+            until newer messages push it out\\.",
+            subject("messages")
+        )
+    } else {
+        format!(
+            "This bot stores {} {} in memory and {} writes any data to disk\\.\n\n\
+            Deleting a message in Telegram doesn't remove the copy I already stored\\. It stays \
+            until newer messages push it out or the bot restarts\\.",
+            subject("all messages"),
+            format::bold("only"),
+            format::bold("never")
+        )
+    };
+    if private {
+        text.push_str("\n\nOnly you can summarize or export what's stored from this chat\\.");
+    }
+    text.push_str(
+        "\n\nFor photos, videos, voice messages, stickers and files I only keep the caption \
+        and what kind of media it was, never the media itself\\.",
+    );
+    if state.config.compaction.is_some() {
+        text.push_str(
+            "\n\nOlder messages may be condensed into a summary that is kept in memory instead\\.",
+        );
+    }
+    if state.events.is_some() {
+        text.push_str(
+            "\n\nThe operator receives usage events \\(counts and timings, never message \
+            content\\)\\.",
+        );
+    }
+    text.push_str("\n\n[Source Code](https://github.com/DuckyBlender/duck_summarizer)");
+    text
+}
+
+// Import a Telegram export from the command line. Only useful with a database,
+// since the in-memory store ends with the process. Returns the exit code.
+fn seed_from_cli(args: &[&str], mut store: MessageStore) -> i32 {
+    let (Some(path), Some((chat_id, thread_id))) =
+        (args.first(), args.get(1..).and_then(parse_seed_target))
+    else {
+        eprintln!("Usage: duck_summarizer seed <result.json> <chat_id> [thread_id]");
+        return 2;
+    };
+    if store.database.is_none() {
+        eprintln!("Set DATABASE_PATH so the imported messages outlive this command");
+        return 1;
+    }
+
+    let imported = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| import::parse_export(&json, MAX_MESSAGES))
+    {
+        Ok(imported) => imported,
+        Err(e) => {
+            error!(target: "startup", "Couldn't read export {}: {}", path, e);
+            return 1;
+        }
+    };
+    let found = imported.len();
+    let added = store.seed(chat_id, thread_id, imported);
+    info!(target: "startup", "Imported {} of {} messages into chat {} thread {:?}", added, found, chat_id, thread_id);
+    0
+}
+
+// Posts made on behalf of a chat are marked, so summaries don't mistake a
+// channel or an anonymous admin for a regular member
+fn sender_display_name(msg: &Message) -> Option<String> {
+    match &msg.sender_chat {
+        Some(sender) if sender.is_channel() => Some(format!(
+            "[Channel] {}",
+            sender.title().unwrap_or("Unnamed channel")
+        )),
+        Some(_) => Some(match msg.author_signature() {
+            Some(signature) => format!("[Anonymous admin] {signature}"),
+            None => "Anonymous admin".to_string(),
+        }),
+        None => msg.from.as_ref().map(user_display_name),
+    }
+}
+
+// /summarize, /mood and /topics: pick the messages the arguments ask for and
+// run the task over them
+async fn run_task_command(
+    bot: &Bot,
+    msg: &Message,
+    state: &AppState,
+    task: LlmTask,
+    count_str: &str,
+    display_name: &str,
+) -> ResponseResult<()> {
+    let chat_id = msg.chat.id;
+    let thread_id = msg.thread_id;
+    let send_message = |text: String| reply_retrying(bot, msg, text);
+
+    info!(target: "command", "User {} requested /{} {} in chat {} thread {:?} ({:?})",
+        display_name, task.command(), count_str, chat_id, thread_id, msg.chat.kind);
+    let mut timings = StageTimings::start();
+    let resolving = Instant::now();
+    let chat_settings = state.store.lock().await.chat_settings(chat_id);
+    let limit = summarize_limit(bot, msg, &chat_settings, state).await?;
+
+    // In forum topics every message replies to the topic's first message,
+    // so only a reply to anything else counts
+    let replied_to = msg
+        .reply_to_message()
+        .map(|reply| reply.id)
+        .filter(|id| thread_id.is_none_or(|thread| thread.0 != *id));
+    // `replies` narrows a reply down to the discussion under the message
+    let (replies_only, count_str) = match count_str.trim().strip_prefix("replies") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+            (true, rest.trim())
+        }
+        _ => (false, count_str.trim()),
+    };
+    // Only summaries can be narrowed to a topic
+    let (count_str, focus) = if task == LlmTask::Summarize {
+        split_focus(count_str)
+    } else {
+        (count_str, None)
+    };
+    if replies_only && replied_to.is_none() {
+        send_message(format!(
+            "Reply to a message with /{} replies to cover only the replies to it.",
+            task.command()
+        ))
+        .await?;
+        return Ok(());
+    }
+    let selector = if let Some(replied_to) = replied_to {
+        // When replying, the count only caps how many messages are covered
+        let cap = if count_str.is_empty() {
+            Some(limit)
+        } else {
+            parse_count(count_str, limit, ChatKind::of(&msg.chat))
+        };
+        let Some(cap) = cap else {
+            warn!(target: "command", "Invalid count '{}' provided for /{} by {} in chat {}", count_str, task.command(), display_name, chat_id);
+            send_message(format!(
+                "Please provide a valid number between 1 and {}",
+                limit
+            ))
+            .await?;
+            return Ok(());
+        };
+        if replies_only {
+            MessageSelector::Replies(replied_to, cap)
+        } else {
+            MessageSelector::After(replied_to, cap)
+        }
+    } else {
+        let Some(range) = parse_range(count_str, limit, ChatKind::of(&msg.chat)) else {
+            warn!(target: "command", "Invalid count '{}' provided for /{} by {} in chat {}", count_str, task.command(), display_name, chat_id);
+            send_message(format!(
+                "Please provide a valid number between 1 and {}, a duration like 30m, 2h or 1d, \
+                or today, yesterday, morning, afternoon or evening",
+                limit
+            ))
+            .await?;
+            return Ok(());
+        };
+        match range {
+            SummaryRange::Count(count) => MessageSelector::Last(count),
+            SummaryRange::Window(window) => MessageSelector::Since(Utc::now() - window, limit),
+            SummaryRange::Slice(slice) => {
+                let tz = chat_timezone(state, &chat_settings).await;
+                let (start, end) = dayslice::resolve(slice, tz, Utc::now());
+                MessageSelector::Between(start, end, limit)
+            }
+        }
+    };
+
+    // Every later stage works on this snapshot, so messages arriving while
+    // the summary is generated can't change the covered range
+    let snapshotting = timings.lap(Stage::Arguments, resolving);
+    let snapshot = {
+        let store = state.store.lock().await;
+        if let MessageSelector::After(replied_to, _) = selector
+            && store
+                .get_messages_after(chat_id, thread_id, replied_to)
+                .is_none()
+        {
+            drop(store);
+            info!(target: "command", "Replied-to message {} isn't stored in chat {} thread {:?}", replied_to, chat_id, thread_id);
+            send_message(format!(
+                "I don't have the message you replied to anymore. It was sent before I \
+                started or has been pushed out by newer messages, so I can't tell where \
+                to start. Use /{0} <count> or /{0} 2h instead.",
+                task.command()
+            ))
+            .await?;
+            return Ok(());
+        }
+        store.snapshot(chat_id, thread_id, selector)
+    };
+    timings.lap(Stage::Snapshot, snapshotting);
+    if let MessageSelector::Replies(root, _) = selector {
+        let root_stored = snapshot
+            .messages
+            .first()
+            .is_some_and(|m| m.message_id == root);
+        if snapshot.messages.len() == usize::from(root_stored) {
+            info!(target: "command", "No stored replies to message {} in chat {} thread {:?}", root, chat_id, thread_id);
+            send_message(if root_stored {
+                "Nobody has replied to that message yet, at least not in the messages I have."
+                    .to_string()
+            } else {
+                "I don't have that message or any replies to it anymore.".to_string()
+            })
+            .await?;
+            return Ok(());
+        }
+    }
+    let requested = match selector {
+        MessageSelector::Last(count) => count,
+        _ => snapshot.messages.len(),
+    };
+
+    summarize_snapshot(
+        bot,
+        msg,
+        &snapshot,
+        requested,
+        state,
+        task,
+        focus.as_deref(),
+        display_name,
+        timings,
+    )
+    .await
+}
+
+// /summarize in a private chat with forwards waiting: summarize just those,
+// then let them go
+async fn summarize_forwards(
+    bot: &Bot,
+    msg: &Message,
+    state: &AppState,
+    forwarded: Vec<SavedMessage>,
+    display_name: &str,
+) -> ResponseResult<()> {
+    info!(target: "command", "User {} requested /summarize of {} forwarded messages in chat {}", display_name, forwarded.len(), msg.chat.id);
+    let last_seq = forwarded.last().map(|m| m.seq);
+    let count = forwarded.len();
+    let snapshot = ChatSnapshot {
+        selector: MessageSelector::Last(count),
+        messages: forwarded.into(),
+        watermark: None,
+        first_seen: None,
+        taken_at: Utc::now(),
+        source: SnapshotSource::Forwards,
+        previous_summary: None,
+    };
+    summarize_snapshot(
+        bot,
+        msg,
+        &snapshot,
+        count,
+        state,
+        LlmTask::Summarize,
+        None,
+        display_name,
+        StageTimings::start(),
+    )
+    .await?;
+    if let Some(seq) = last_seq {
+        state.forwards.lock().await.clear_through(msg.chat.id, seq);
+    }
+    Ok(())
+}
+
+// /summarize file: summarize a conversation the bot never saw, uploaded as a
+// text file in a private chat with the command as its caption or in reply to
+// it. The transcript is only held until the summary is sent.
+async fn summarize_upload(
+    bot: &Bot,
+    msg: &Message,
+    state: &AppState,
+    display_name: &str,
+) -> ResponseResult<()> {
+    let send_message = |text: String| reply_retrying(bot, msg, text);
+    info!(target: "command", "User {} requested /summarize file in chat {}", display_name, msg.chat.id);
+    let mut timings = StageTimings::start();
+    let resolving = Instant::now();
+    if !msg.chat.is_private() {
+        send_message(
+            "Send the file to me in a private chat, with /summarize file as its caption."
+                .to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let Some(document) = msg
+        .document()
+        .or_else(|| msg.reply_to_message().and_then(Message::document))
+    else {
+        send_message(
+            "Send a .txt file with /summarize file as its caption, or reply to one with \
+            /summarize file."
+                .to_string(),
+        )
+        .await?;
+        return Ok(());
+    };
+    if document.file.size > UPLOAD_MAX_BYTES {
+        send_message(format!(
+            "That file is too large. I can read transcripts of up to {} KB.",
+            UPLOAD_MAX_BYTES / 1024
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    let file = bot.get_file(document.file.id.clone()).await?;
+    let mut buffer = Vec::new();
+    if let Err(e) = bot.download_file(&file.path, &mut buffer).await {
+        warn!(target: "command", "Failed to download transcript for /summarize file: {}", e);
+        send_message("Couldn't download the file.".to_string()).await?;
+        return Ok(());
+    }
+    let Ok(text) = String::from_utf8(buffer) else {
+        send_message("That doesn't look like a text file.".to_string()).await?;
+        return Ok(());
+    };
+
+    let chat_settings = state.store.lock().await.chat_settings(msg.chat.id);
+    let limit = summarize_limit(bot, msg, &chat_settings, state).await?;
+    let messages = match import::parse_transcript(&text, Utc::now(), limit) {
+        Ok(messages) => messages,
+        Err(e) => {
+            info!(target: "command", "Couldn't parse transcript from {}: {}", display_name, e);
+            send_message(format!(
+                "Couldn't read the file: {}. Each message should start a line as \
+                \"Name: message\", or \"[date, time] Name: message\" in a WhatsApp export.",
+                e
+            ))
+            .await?;
+            return Ok(());
+        }
+    };
+    drop(text);
+    debug!(target: "command", "Parsed {} messages from an uploaded transcript", messages.len());
+
+    let count = messages.len();
+    let snapshot = ChatSnapshot {
+        selector: MessageSelector::Last(count),
+        messages: messages.into(),
+        watermark: None,
+        first_seen: None,
+        taken_at: Utc::now(),
+        source: SnapshotSource::Upload,
+        previous_summary: None,
+    };
+    timings.lap(Stage::Arguments, resolving);
+    summarize_snapshot(
+        bot,
+        msg,
+        &snapshot,
+        count,
+        state,
+        LlmTask::Summarize,
+        None,
+        display_name,
+        timings,
+    )
+    .await
+}
+
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!(target: "startup", "Can't listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(target: "startup", "Can't listen for Ctrl+C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+// Exit status when Telegram stayed unreachable for the whole startup window,
+// so supervisors can tell it apart from a configuration error
+const EXIT_TELEGRAM_UNREACHABLE: i32 = 3;
+// Exit status after stopping for another instance polling with the same token
+const EXIT_CONFLICT: i32 = 4;
+
+fn exit_unreachable(what: &str, (error, attempts): (RequestError, u32)) -> ! {
+    if backoff::is_transient(&error) {
+        error!(target: "startup", "Giving up trying to {} after {} attempts: {}", what, attempts, error);
+        std::process::exit(EXIT_TELEGRAM_UNREACHABLE);
+    }
+    error!(target: "startup", "Failed to {}: {}", what, error);
+    std::process::exit(1);
+}
+
+// Optional startup calls to Telegram are retried a few times and then
+// skipped, so a hiccup leaves a feature missing instead of crashing the bot
+async fn retry_startup<T, Fut>(what: &str, mut call: impl FnMut() -> Fut) -> Option<T>
+where
+    Fut: Future<Output = ResponseResult<T>>,
+{
+    const ATTEMPTS: u32 = 3;
+    for attempt in 1..=ATTEMPTS {
+        match call().await {
+            Ok(value) => return Some(value),
+            Err(e) if attempt < ATTEMPTS => {
+                warn!(target: "startup", "Failed to {} (attempt {}/{}): {}", what, attempt, ATTEMPTS, e);
+                tokio::time::sleep(std::time::Duration::from_secs(2 * attempt as u64)).await;
+            }
+            Err(e) => error!(target: "startup", "Giving up trying to {}: {}", what, e),
+        }
+    }
+    None
+}
+
+// Run a handler, turning a panic into a log entry, a counter bump and a
+// (rate-limited) note to the owner, then carry on with the next update
+async fn guarded(
+    bot: Bot,
+    state: AppState,
+    update_id: UpdateId,
+    chat_id: Option<ChatId>,
+    handler: impl Future<Output = ResponseResult<()>>,
+) -> ResponseResult<()> {
+    let payload = match AssertUnwindSafe(handler).catch_unwind().await {
+        Ok(result) => {
+            // Remember chats the bot can't post in, so the next summary there
+            // doesn't spend a provider call on a reply that can't be delivered
+            if let (Err(e), Some(chat_id)) = (&result, chat_id)
+                && chatinfo::is_send_forbidden(e)
+            {
+                warn!(target: "dispatch", "Can't post in chat {}: {}", chat_id, e);
+                state
+                    .chat_info
+                    .lock()
+                    .await
+                    .set_can_send(chat_id, false, Utc::now());
+            }
+            return result;
+        }
+        Err(payload) => payload,
+    };
+    let reason = tasks::panic_reason(&*payload);
+    error!(target: "dispatch", "Handler panicked on update {} in chat {:?}: {}", update_id.0, chat_id, reason);
+
+    let notify = state.stats.lock().await.record_panic(Utc::now());
+    if notify && let Some(owner) = state.config.owner_user_id {
+        let text = format!(
+            "A handler panicked on update {} in chat {:?}: {}\nSee /admin stats for the total.",
+            update_id.0, chat_id, reason
+        );
+        if let Err(e) = ChatDestination::new(owner.into(), None)
+            .message(&bot, text)
+            .await
+        {
+            warn!(target: "dispatch", "Couldn't notify the owner about a panic: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// The user never started the bot, or blocked it
+fn cannot_message_privately(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(
+            ApiError::CantInitiateConversation | ApiError::BotBlocked | ApiError::CantTalkWithBots,
+        ) => true,
+        RequestError::Api(ApiError::Unknown(text)) => text.contains("can't initiate conversation"),
+        _ => false,
+    }
+}
+
+// Everything /admin dump reports, gathered from each subsystem in turn
+async fn debug_dump(state: &AppState) -> dump::DebugDump {
+    let config = state.config.debug_snapshot();
+    let providers = state.llm.debug_snapshot();
+    let budget = state.budget.lock().await.debug_snapshot();
+    let features = dump::Features {
+        database: state.database.is_some(),
+        snapshot: config.snapshot_path.is_some() && state.database.is_none(),
+        compaction: state.config.compaction.is_some(),
+        budget: budget.cap_usd.is_some(),
+        events: state.events.is_some(),
+        metrics: config.metrics_addr.is_some(),
+        prompt_variants: config.prompt_variants,
+        failover: providers.secondary.is_some(),
+        streaming: providers.streaming,
+        allowed_chats: config.allowed_chats.is_some(),
+    };
+    dump::DebugDump {
+        taken_at: Utc::now(),
+        build: dump::BuildInfo::current(),
+        features,
+        config,
+        providers,
+        store: state.store.lock().await.debug_snapshot(),
+        budget,
+        rate_limits: state
+            .rate_limiter
+            .lock()
+            .await
+            .debug_snapshot(&state.in_flight),
+        admin_cache: state.admins.debug_snapshot(),
+        chat_info: state.chat_info.lock().await.debug_snapshot(),
+        stats: state.stats.lock().await.debug_snapshot(),
+        metrics: state.metrics.debug_snapshot(),
+    }
+}
+
+// e.g. "Book_club-2026-06-05-1430.txt", with anything unusual in the title
+// replaced so the name is safe on every platform
+fn export_filename(title: Option<&str>, now: DateTime<Utc>, extension: &str) -> String {
+    let title: String = title
+        .unwrap_or("chat")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .take(64)
+        .collect();
+    format!("{}-{}.{}", title, now.format("%Y-%m-%d-%H%M"), extension)
+}
+
+// Keep the administrator lists of the chats that check most often fresh, so
+// their admin-only commands rarely wait on Telegram
+async fn run_admin_prefetch(bot: Bot, state: AppState) {
+    let mut ticker = tokio::time::interval(admins::PREFETCH_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let chats = state.admins.take_busiest(admins::PREFETCH_CHATS);
+        let mut failed = 0;
+        for &chat_id in &chats {
+            if let Err(e) = state.admins.refresh(&bot, chat_id).await {
+                failed += 1;
+                debug!(target: "admins", "Couldn't refresh the administrators of chat {}: {}", chat_id, e);
+            }
+        }
+        state.tasks.beat(
+            "admin prefetch",
+            format!(
+                "refreshed {} chats, {} failed",
+                chats.len() - failed,
+                failed
+            ),
+            Utc::now(),
+        );
+    }
+}
+
+// Run a background loop under the task registry, restarting it if it panics
+fn spawn_supervised<Fut>(
+    state: &AppState,
+    name: &'static str,
+    interval: std::time::Duration,
+    run: impl FnMut() -> Fut + Send + 'static,
+) where
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(tasks::supervise(
+        state.tasks.clone(),
+        name,
+        Some(interval),
+        tasks::RESTART_BACKOFF,
+        run,
+    ));
+}
+
+// Tell the owner when a background task panicked or stopped reporting
+async fn run_task_monitor(bot: Bot, state: AppState) {
+    let mut ticker = tokio::time::interval(tasks::MONITOR_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let alerts = state.tasks.check(Utc::now());
+        for alert in &alerts {
+            warn!(target: "tasks", "{}", alert);
+            let Some(owner) = state.config.owner_user_id else {
+                continue;
+            };
+            if let Err(e) = ChatDestination::new(owner.into(), None)
+                .message(&bot, alert.to_string())
+                .await
+            {
+                warn!(target: "tasks", "Couldn't notify the owner about a background task: {}", e);
+            }
+        }
+        state.tasks.beat(
+            "task monitor",
+            format!("{} new alerts", alerts.len()),
+            Utc::now(),
+        );
+    }
+}
+
+// Offer a summary of each chat the user recently wrote in. The query is the
+// count, as with /summarize; chats without stored messages say so instead.
+async fn handle_inline_query(bot: Bot, q: InlineQuery, state: AppState) -> ResponseResult<()> {
+    if state.blocklist.lock().await.is_blocked(q.from.id) {
+        bot.answer_inline_query(q.id, Vec::<InlineQueryResult>::new())
+            .await?;
+        return Ok(());
+    }
+    debug!(target: "command", "Inline query '{}' from {}", q.query, q.from.id);
+
+    let recent = state.recent_chats.lock().await.chats(q.from.id).to_vec();
+    let mut results = Vec::with_capacity(recent.len());
+    {
+        let store = state.store.lock().await;
+        for chat in &recent {
+            // Chats blocked with /blockchat since the user wrote there
+            if !state
+                .config
+                .is_chat_allowed(chat.key.chat_id, &store.chat_access)
+            {
+                continue;
+            }
+            let chat_settings = store.chat_settings(chat.key.chat_id);
+            let limit = limits::resolve_effective_limits(
+                &chat_settings,
+                &state.config,
+                ChatKind::of_id(chat.key.chat_id),
+            )
+            .summarize_limit(false);
+            let stored = store
+                .chats
+                .get(&chat.key)
+                .map_or(0, |queue| queue.iter().filter(|m| !m.synthetic).count());
+            let text = |text: String| InputMessageContent::Text(InputMessageContentText::new(text));
+
+            let article = if stored == 0 {
+                InlineQueryResultArticle::new(
+                    format!("e:{}", inline::result_id(&chat.key, 0)),
+                    format!("No stored messages in {}", chat.title),
+                    text(format!(
+                        "I don't have any stored messages from {} yet.",
+                        chat.title
+                    )),
+                )
+            } else if let Some(count) =
+                parse_count(&q.query, limit, ChatKind::of_id(chat.key.chat_id))
+            {
+                let count = count.min(stored);
+                // The keyboard is what makes Telegram report an inline_message_id
+                // to edit once the summary is ready
+                InlineQueryResultArticle::new(
+                    inline::result_id(&chat.key, count),
+                    format!("Summarize {}", chat.title),
+                    text(format!(
+                        "Summarizing the last {} messages of {}...",
+                        count, chat.title
+                    )),
+                )
+                .description(format!("The last {} messages", count))
+                .reply_markup(InlineKeyboardMarkup::new([[
+                    InlineKeyboardButton::switch_inline_query_current_chat(
+                        "Summarize again",
+                        q.query.clone(),
+                    ),
+                ]]))
+            } else {
+                InlineQueryResultArticle::new(
+                    format!("i:{}", inline::result_id(&chat.key, 0)),
+                    format!("Type a number between 1 and {}", limit),
+                    text(format!(
+                        "Summaries of {} can cover 1 to {} messages.",
+                        chat.title, limit
+                    )),
+                )
+            };
+            results.push(InlineQueryResult::Article(article));
+        }
+    }
+
+    let mut answer = bot
+        .answer_inline_query(q.id, results)
+        .is_personal(true)
+        .cache_time(10);
+    // Nothing to offer until the bot has seen the user in a chat
+    if recent.is_empty()
+        && let Some(topic) = help::topic("inline")
+    {
+        answer = answer.button(InlineQueryResultsButton {
+            text: "Write in a group with me first".to_string(),
+            kind: InlineQueryResultsButtonKind::StartParameter(help::payload(topic)),
+        });
+    }
+    answer.await?;
+    Ok(())
+}
+
+// Replace the posted "Summarizing..." article with the summary. Only one
+// message can be edited, so a summary longer than that is cut at the limit.
+async fn handle_chosen_inline_result(
+    bot: Bot,
+    chosen: ChosenInlineResult,
+    state: AppState,
+) -> ResponseResult<()> {
+    let (Some((key, count)), Some(inline_message_id)) = (
+        inline::parse_result_id(&chosen.result_id),
+        chosen.inline_message_id.clone(),
+    ) else {
+        return Ok(());
+    };
+    // Result ids come back from the user's client, so check them again
+    if !state
+        .recent_chats
+        .lock()
+        .await
+        .contains(chosen.from.id, &key)
+    {
+        warn!(target: "command", "Ignoring inline summary of chat {} picked by {}, who wasn't seen there", key.chat_id, chosen.from.id);
+        return Ok(());
+    }
+    // The same gates as commands: blocked users and chats that aren't allowed
+    if state.blocklist.lock().await.is_blocked(chosen.from.id) {
+        debug!(target: "command", "Ignoring inline summary picked by blocked user {}", chosen.from.id);
+        return Ok(());
+    }
+    info!(target: "command", "User {} picked an inline summary of {} messages from chat {} thread {:?}", chosen.from.id, count, key.chat_id, key.thread_id);
+    let edit = |text: &str| bot.edit_message_text_inline(inline_message_id.clone(), text);
+    if !chat_allowed(&state, key.chat_id).await {
+        info!(target: "command", "Refusing inline summary of chat {}, which isn't allowed", key.chat_id);
+        edit("Sorry, I'm not available in that chat.").await?;
+        return Ok(());
+    }
+
+    let chat_settings = state.store.lock().await.chat_settings(key.chat_id);
+    if chat_settings.restricted
+        && !state
+            .admins
+            .admins(&bot, key.chat_id)
+            .await?
+            .contains(&chosen.from.id)
+    {
+        info!(target: "command", "Refusing inline summary of restricted chat {} to non-admin {}", key.chat_id, chosen.from.id);
+        edit("Only admins can request summaries of that chat.").await?;
+        return Ok(());
+    }
+    if !chat_settings.allow_protected
+        && chatinfo::has_protected_content(&bot, &state.chat_info, key.chat_id).await
+    {
+        edit("That chat has content protection enabled, so I won't summarize it.").await?;
+        return Ok(());
+    }
+    let limits = limits::resolve_effective_limits(
+        &chat_settings,
+        &state.config,
+        ChatKind::of_id(key.chat_id),
+    );
+    if let Some(cooldown) = limits.cooldown_for(false)
+        && state
+            .rate_limiter
+            .lock()
+            .await
+            .try_acquire(key.clone(), cooldown, Utc::now())
+            .is_err()
+    {
+        edit("A summary of that chat was just made. Please try again in a moment.").await?;
+        return Ok(());
+    }
+
+    let snapshot = state.store.lock().await.snapshot(
+        key.chat_id,
+        key.thread_id,
+        MessageSelector::Last(count.min(limits.summarize_limit(false))),
+    );
+    if snapshot.messages.is_empty() {
+        edit("No messages to summarize.").await?;
+        return Ok(());
+    }
+    let month = budget_month(&state).await;
+    if !state.budget.lock().await.allows_llm(month) {
+        edit("The monthly summarization budget has been reached.").await?;
+        return Ok(());
+    }
+
+    let model = state.settings.lock().await.model.clone();
+    match run_llm_task(
+        &state.task_context(),
+        snapshot.messages.clone(),
+        LlmTask::Summarize,
+        None,
+        key.chat_id,
+        &chat_settings,
+        model.as_deref(),
+        None,
+    )
+    .await
+    {
+        Ok((completion, _, citations, exclusions)) => {
+            charge_budget(&bot, &state, &completion).await;
+            notify_model_change(&bot, &state, &completion).await;
+            let trailer: Vec<String> = (exclusions.by_policy()
+                && !chat_settings.hide_exclusion_note)
+                .then(|| prompt::EXCLUSION_NOTE.to_string())
+                .into_iter()
+                .collect();
+            let summary = summary_chunks(&completion.text, &trailer, &citations)
+                .into_iter()
+                .next()
+                .unwrap_or_default();
+            // Paid for already, so wait out flood control rather than lose it
+            let request = bot
+                .edit_message_text_inline(inline_message_id.clone(), summary)
+                .parse_mode(ParseMode::MarkdownV2);
+            destination::with_retry(key.chat_id, || request.clone().into_future()).await?;
+        }
+        Err(e) => {
+            error!(target: "summarization", "Failed to summarize chat {} inline for {}: {}", key.chat_id, chosen.from.id, e);
+            edit(e.user_message(LlmTask::Summarize)).await?;
+        }
+    }
+    Ok(())
+}
+
+// Track whether the bot may post in a chat as admins change its membership
+async fn handle_my_chat_member(update: ChatMemberUpdated, state: AppState) -> ResponseResult<()> {
+    let can_send = chatinfo::member_can_send(&update.new_chat_member.kind);
+    info!(target: "chat_info", "My membership in chat {} changed, can post: {}", update.chat.id, can_send);
+    state
+        .chat_info
+        .lock()
+        .await
+        .set_can_send(update.chat.id, can_send, Utc::now());
+    Ok(())
+}
+
+fn wizard_keyboard(step: WizardStep) -> InlineKeyboardMarkup {
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = step
+        .options()
+        .iter()
+        .map(|option| {
+            vec![InlineKeyboardButton::callback(
+                *option,
+                WizardAction::Choose(option.to_string()).to_callback_data(),
+            )]
+        })
+        .collect();
+    rows.push(vec![
+        InlineKeyboardButton::callback("Skip", WizardAction::Skip.to_callback_data()),
+        InlineKeyboardButton::callback("Cancel", WizardAction::Cancel.to_callback_data()),
+    ]);
+    InlineKeyboardMarkup::new(rows)
+}
+
+async fn send_wizard_step(
+    bot: &Bot,
+    destination: ChatDestination,
+    step: WizardStep,
+) -> ResponseResult<()> {
+    destination
+        .message(bot, step.question())
+        .reply_markup(wizard_keyboard(step))
+        .await?;
+    Ok(())
+}
+
+async fn handle_callback(bot: Bot, q: CallbackQuery, state: AppState) -> ResponseResult<()> {
+    let Some(action) = q.data.as_deref().and_then(WizardAction::from_callback_data) else {
+        debug!(target: "callback", "Ignoring unknown callback data {:?} from {}", q.data, q.from.id);
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    };
+
+    if !state.config.is_owner(q.from.id) {
+        debug!(target: "callback", "Ignoring wizard callback from non-owner {}", q.from.id);
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    }
+
+    let transition = state
+        .wizards
+        .lock()
+        .await
+        .apply(q.from.id, action, Utc::now());
+    bot.answer_callback_query(q.id.clone()).await?;
+
+    let Some(message) = q.regular_message() else {
+        return Ok(());
+    };
+    let (destination, message_id) = (ChatDestination::of(message), message.id);
+
+    match transition {
+        None => {
+            destination
+                .edit_message(
+                    &bot,
+                    message_id,
+                    "This setup session has expired. Run /admin setup to start again.",
+                )
+                .await?;
+        }
+        Some(Transition::Continue(step)) => {
+            destination
+                .edit_message(&bot, message_id, step.question())
+                .reply_markup(wizard_keyboard(step))
+                .await?;
+        }
+        Some(Transition::Finished(changes)) => {
+            info!(target: "callback", "Owner finished the setup wizard");
+            // Shown as saved, including anything changed while the wizard ran
+            let summary = settings::update_bot_settings(&state.settings, |s| changes.apply(s))
+                .await
+                .describe();
+            destination
+                .edit_message(&bot, message_id, format!("Setup complete.\n\n{}", summary))
+                .await?;
+        }
+        Some(Transition::Cancelled) => {
+            destination
+                .edit_message(
+                    &bot,
+                    message_id,
+                    "Setup cancelled. Run /admin setup any time to continue.",
+                )
+                .await?;
+        }
+        Some(Transition::Invalid) => {
+            debug!(target: "callback", "Ignoring stale wizard choice from {}", q.from.id);
+        }
+    }
+
+    Ok(())
+}
+
+// Tell the owner once that new chats are being turned away
+async fn report_capacity_reached(bot: &Bot, state: &AppState) {
+    let max = state.config.max_tracked_chats.unwrap_or_default();
+    warn!(target: "store", "Tracked chat limit of {} reached, new chats aren't stored", max);
+    let Some(owner) = state.config.owner_user_id else {
+        return;
+    };
+    if let Err(e) = ChatDestination::new(owner.into(), None)
+        .message(
+            bot,
+            format!(
+                "I'm storing messages for {} chats, the MAX_TRACKED_CHATS limit, and none has \
+                been idle for {} hours, so new chats aren't stored. Raise the limit or check \
+                whether I was added to groups in bulk.",
+                max, CAPACITY_IDLE_HOURS
+            ),
+        )
+        .await
+    {
+        warn!(target: "store", "Couldn't notify the owner about the chat limit: {}", e);
+    }
+}
+
+#[tokio::main]
+async fn main() {
     dotenv().ok();
 
-    // Initialize the logger with fern
-    if let Err(e) = setup_logger() {
+    if let Err(e) = logging::setup(&logging::LogConfig::from_env()) {
         eprintln!("Error setting up logger: {}", e);
         std::process::exit(1);
     }
 
     info!(target: "startup", "Ducky Summarizer starting up");
 
+    let database = match env::var("DATABASE_PATH") {
+        Ok(path) if !path.is_empty() => match Database::open(&path) {
+            Ok(database) => Some(Arc::new(database)),
+            Err(e) => {
+                error!(target: "startup", "Failed to open database {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let config = Arc::new(Config::from_env());
+    let mut store = match &database {
+        Some(database) => match MessageStore::with_database(database.clone()) {
+            Ok(store) => store,
+            Err(e) => {
+                error!(target: "startup", "Failed to load stored messages: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => MessageStore::new(),
+    };
+    // The database already survives restarts; snapshots are for running without one
+    if let Some(path) = &config.snapshot_path {
+        if database.is_some() {
+            warn!(target: "startup", "SNAPSHOT_PATH is ignored while DATABASE_PATH is set");
+        } else if let Some(snapshot) =
+            persist::read_snapshot(path, config.snapshot_max_age, Utc::now())
+        {
+            store.restore(snapshot);
+        }
+    }
+
+    // `duck_summarizer seed <result.json> <chat_id> [thread_id]` imports an
+    // export into the database without starting the bot
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("seed") {
+        let args: Vec<&str> = args[2..].iter().map(String::as_str).collect();
+        std::process::exit(seed_from_cli(&args, store));
+    }
+
     let bot_token = match env::var("TELEGRAM_BOT_TOKEN") {
         Ok(token) => token,
         Err(e) => {
@@ -509,33 +3126,319 @@ async fn main() {
     info!(target: "startup", "Initializing bot");
     let bot = Bot::new(bot_token);
 
+    // The network may come up after the bot does, so these are retried for a
+    // while; without them the bot can't work at all
+    let startup = Backoff::new(config.startup_retry_window);
+    let me = startup
+        .retry("reach Telegram", backoff::is_transient, || {
+            bot.get_me().into_future()
+        })
+        .await
+        .unwrap_or_else(|failure| exit_unreachable("reach Telegram", failure));
     info!(target: "startup", "Setting bot commands");
-    bot.set_my_commands(Command::bot_commands()).await.unwrap();
-
-    let message_store = Arc::new(Mutex::new(MessageStore::new()));
+    startup
+        .retry("set bot commands", backoff::is_transient, || {
+            bot.set_my_commands(Command::bot_commands()).into_future()
+        })
+        .await
+        .unwrap_or_else(|failure| exit_unreachable("set bot commands", failure));
+    // Show the command list behind the menu button in private chats
+    retry_startup("set the menu button", || {
+        bot.set_chat_menu_button()
+            .menu_button(MenuButton::Commands)
+            .into_future()
+    })
+    .await;
+    if !me.supports_inline_queries {
+        info!(target: "startup", "Inline mode is off; enable it with /setinline and /setinlinefeedback in BotFather to summarize from any chat");
+    }
+    let bot_username = Some(Arc::from(me.username()));
+
+    store.max_tracked_chats = config.max_tracked_chats;
+    // The limit may have been lowered since the history was stored
+    store.max_store_bytes = config.max_store_bytes;
+    store.enforce_byte_limit();
+    store.compaction = config.compaction;
+    let message_store = Arc::new(Mutex::new(store));
     info!(target: "startup", "Message store initialized");
 
+    let llm = match LlmProviders::from_env() {
+        Ok(llm) => Arc::new(llm),
+        Err(e) => {
+            error!(target: "startup", "Invalid LLM provider configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    llm.check_reachable().await;
+
+    let mut budget_tracker = budget::BudgetTracker::new(budget::BudgetConfig::from_env());
+    if let Some(saved) = database
+        .as_ref()
+        .and_then(|db| db.load_meta(BUDGET_META_KEY))
+    {
+        budget_tracker.restore(saved);
+    }
+
+    let task_registry: TaskRegistryType = Arc::new(tasks::TaskRegistry::default());
+    let state = AppState {
+        store: message_store.clone(),
+        config: config.clone(),
+        stats: Arc::new(Mutex::new(stats::BotStats::new())),
+        blocklist: Arc::new(Mutex::new(access::Blocklist::from_env())),
+        llm,
+        settings: Arc::new(Mutex::new(settings::BotSettings::default())),
+        wizards: Arc::new(Mutex::new(wizard::WizardSessions::default())),
+        chat_info: Arc::new(Mutex::new(chatinfo::ChatInfoCache::default())),
+        admins: Arc::new(admins::AdminCache::new(config.admin_cache_ttl)),
+        budget: Arc::new(Mutex::new(budget_tracker)),
+        rate_limiter: Arc::new(Mutex::new(ratelimit::RateLimiter::default())),
+        in_flight: Arc::new(ratelimit::InFlight::default()),
+        recent_chats: Arc::new(Mutex::new(inline::RecentChats::default())),
+        forwards: Arc::new(Mutex::new(forwards::PendingForwards::default())),
+        bot_username,
+        events: EventSink::from_env(&task_registry),
+        database: database.clone(),
+        metrics: Arc::new(Metrics::default()),
+        tasks: task_registry,
+    };
+
+    spawn_supervised(&state, "digest scheduler", DIGEST_TICK, {
+        let (bot, state) = (bot.clone(), state.clone());
+        move || run_digest_scheduler(bot.clone(), state.clone())
+    });
+    spawn_supervised(&state, "admin prefetch", admins::PREFETCH_INTERVAL, {
+        let (bot, state) = (bot.clone(), state.clone());
+        move || run_admin_prefetch(bot.clone(), state.clone())
+    });
+    spawn_supervised(&state, "task monitor", tasks::MONITOR_INTERVAL, {
+        let (bot, state) = (bot.clone(), state.clone());
+        move || run_task_monitor(bot.clone(), state.clone())
+    });
+
+    let (stop_metrics, metrics_stopped) = watch::channel(false);
+    let metrics_server = state.config.metrics_addr.map(|addr| {
+        let render_state = state.clone();
+        let render = move || {
+            let state = render_state.clone();
+            async move {
+                let gauges = {
+                    let store = state.store.lock().await;
+                    metrics::StoreGauges {
+                        messages: store.total_messages,
+                        chats: store.chats.len(),
+                        bytes: store.total_bytes,
+                    }
+                };
+                let stats = state.stats.lock().await;
+                state.metrics.render(gauges, stats.stages())
+            }
+        };
+        let metrics = state.metrics.clone();
+        tokio::spawn(tasks::supervise(
+            state.tasks.clone(),
+            "metrics server",
+            None,
+            tasks::RESTART_BACKOFF,
+            move || {
+                metrics::serve(
+                    addr,
+                    metrics.clone(),
+                    render.clone(),
+                    metrics_stopped.clone(),
+                )
+            },
+        ))
+    });
+
+    // Every endpoint runs under catch_unwind, so a panic is reported instead of
+    // silently dropping the update
     let command_handler = teloxide::filter_command::<Command, _>().branch(dptree::endpoint(
-        move |bot: Bot, msg: Message, cmd: Command, store: MessageStoreType| {
-            handle_command(bot, msg, cmd, store)
+        move |bot: Bot, update: Update, msg: Message, cmd: Command, state: AppState| {
+            let chat_id = msg.chat.id;
+            guarded(
+                bot.clone(),
+                state.clone(),
+                update.id,
+                Some(chat_id),
+                async move { handle_command(bot, msg, cmd, state).await },
+            )
         },
     ));
 
-    let message_handler =
-        Update::filter_message()
-            .branch(command_handler)
-            .branch(dptree::endpoint(
-                move |_: Bot, msg: Message, store: MessageStoreType| handle_message(msg, store),
-            ));
+    // Commands in a file's caption, e.g. /summarize file with a transcript
+    let caption_command_handler = dptree::filter_map(|msg: Message, me: teloxide::types::Me| {
+        msg.document()?;
+        let bot_name = me.user.username.as_deref()?;
+        Command::parse(msg.caption()?, bot_name).ok()
+    })
+    .endpoint(
+        move |bot: Bot, update: Update, msg: Message, cmd: Command, state: AppState| {
+            let chat_id = msg.chat.id;
+            guarded(
+                bot.clone(),
+                state.clone(),
+                update.id,
+                Some(chat_id),
+                async move { handle_command(bot, msg, cmd, state).await },
+            )
+        },
+    );
+
+    let message_handler = Update::filter_message()
+        .branch(command_handler)
+        .branch(caption_command_handler)
+        .branch(dptree::endpoint(
+            move |bot: Bot, update: Update, msg: Message, state: AppState| {
+                let chat_id = msg.chat.id;
+                guarded(
+                    bot.clone(),
+                    state.clone(),
+                    update.id,
+                    Some(chat_id),
+                    async move { handle_message(bot, msg, state).await },
+                )
+            },
+        ));
+
+    // Channels the bot administers are stored under the channel's own id
+    let channel_post_handler = Update::filter_channel_post().endpoint(
+        move |bot: Bot, update: Update, msg: Message, state: AppState| {
+            let chat_id = msg.chat.id;
+            guarded(
+                bot.clone(),
+                state.clone(),
+                update.id,
+                Some(chat_id),
+                async move { handle_message(bot, msg, state).await },
+            )
+        },
+    );
+
+    let edited_channel_post_handler = Update::filter_edited_channel_post().endpoint(
+        move |bot: Bot, update: Update, msg: Message, state: AppState| {
+            let chat_id = msg.chat.id;
+            guarded(bot, state.clone(), update.id, Some(chat_id), async move {
+                handle_edited_message(msg, state).await
+            })
+        },
+    );
+
+    let edited_message_handler = Update::filter_edited_message().endpoint(
+        move |bot: Bot, update: Update, msg: Message, state: AppState| {
+            let chat_id = msg.chat.id;
+            guarded(bot, state.clone(), update.id, Some(chat_id), async move {
+                handle_edited_message(msg, state).await
+            })
+        },
+    );
+
+    let handler = dptree::entry()
+        .branch(message_handler)
+        .branch(edited_message_handler)
+        .branch(channel_post_handler)
+        .branch(edited_channel_post_handler)
+        .branch(Update::filter_message_reaction_updated().endpoint(
+            move |bot: Bot, update: Update, reaction: MessageReactionUpdated, state: AppState| {
+                let chat_id = reaction.chat.id;
+                guarded(bot, state.clone(), update.id, Some(chat_id), async move {
+                    handle_message_reaction(reaction, state).await
+                })
+            },
+        ))
+        .branch(Update::filter_message_reaction_count_updated().endpoint(
+            move |bot: Bot,
+                  update: Update,
+                  counts: MessageReactionCountUpdated,
+                  state: AppState| {
+                let chat_id = counts.chat.id;
+                guarded(bot, state.clone(), update.id, Some(chat_id), async move {
+                    handle_message_reaction_count(counts, state).await
+                })
+            },
+        ))
+        .branch(Update::filter_my_chat_member().endpoint(
+            move |bot: Bot, update: Update, member: ChatMemberUpdated, state: AppState| {
+                let chat_id = member.chat.id;
+                guarded(bot, state.clone(), update.id, Some(chat_id), async move {
+                    handle_my_chat_member(member, state).await
+                })
+            },
+        ))
+        .branch(Update::filter_inline_query().endpoint(
+            move |bot: Bot, update: Update, q: InlineQuery, state: AppState| {
+                guarded(bot.clone(), state.clone(), update.id, None, async move {
+                    handle_inline_query(bot, q, state).await
+                })
+            },
+        ))
+        .branch(Update::filter_chosen_inline_result().endpoint(
+            move |bot: Bot, update: Update, chosen: ChosenInlineResult, state: AppState| {
+                guarded(bot.clone(), state.clone(), update.id, None, async move {
+                    handle_chosen_inline_result(bot, chosen, state).await
+                })
+            },
+        ))
+        .branch(Update::filter_callback_query().endpoint(
+            move |bot: Bot, update: Update, q: CallbackQuery, state: AppState| {
+                let chat_id = q.regular_message().map(|message| message.chat.id);
+                guarded(bot.clone(), state.clone(), update.id, chat_id, async move {
+                    handle_callback(bot, q, state).await
+                })
+            },
+        ));
 
     info!(target: "startup", "Setting up dispatcher and starting bot");
 
-    Dispatcher::builder(bot, message_handler)
-        .dependencies(dptree::deps![message_store])
-        .enable_ctrlc_handler()
-        .build()
-        .dispatch()
+    let metrics = state.metrics.clone();
+    let owner = state.config.owner_user_id;
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![state])
+        .build();
+    // systemd stops services with SIGTERM, so both it and Ctrl+C shut down cleanly
+    let shutdown = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!(target: "shutdown", "Shutdown requested, finishing in-flight updates");
+        if let Ok(done) = shutdown.shutdown() {
+            done.await;
+        }
+    });
+    let exiting = Arc::new(AtomicBool::new(false));
+    let listener_errors = conflict::ListenerErrorHandler::new(
+        bot.clone(),
+        owner,
+        config.exit_on_conflict.then(|| dispatcher.shutdown_token()),
+        exiting.clone(),
+    );
+    metrics.set_ready();
+    dispatcher
+        .dispatch_with_listener(
+            update_listeners::polling_default(bot).await,
+            listener_errors,
+        )
         .await;
 
+    let _ = stop_metrics.send(true);
+    if let Some(server) = metrics_server
+        && let Err(e) = server.await
+    {
+        error!(target: "shutdown", "Metrics server task failed: {}", e);
+    }
+
+    if database.is_none()
+        && let Some(path) = &config.snapshot_path
+    {
+        let snapshot = message_store.lock().await.to_snapshot();
+        match persist::write_snapshot(path, &snapshot) {
+            Ok(()) => {
+                info!(target: "shutdown", "Wrote a snapshot of the message store to {}", path)
+            }
+            Err(e) => error!(target: "shutdown", "Failed to write snapshot {}: {}", path, e),
+        }
+    }
+
     info!(target: "shutdown", "Bot has been shut down");
+    if exiting.load(Ordering::Relaxed) {
+        std::process::exit(EXIT_CONFLICT);
+    }
 }
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use teloxide::types::{Message, MessageOrigin, User};
+
+// What a stored message was. The caption, forwarded text or poll question
+// lives in `SavedMessage::text` like a plain message's text does, so language
+// detection, deduplication and extracts work the same for every kind.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    Text,
+    Photo,
+    Video,
+    Voice,
+    Sticker {
+        emoji: Option<String>,
+    },
+    Document {
+        filename: Option<String>,
+    },
+    // Forwarded text or media; the original sender's name or chat title
+    Forward {
+        origin: String,
+    },
+    Poll,
+}
+
+pub fn user_display_name(user: &User) -> String {
+    match &user.last_name {
+        Some(last_name) => format!("{} {}", user.first_name, last_name),
+        None => user.first_name.clone(),
+    }
+}
+
+fn origin_name(origin: &MessageOrigin) -> String {
+    match origin {
+        MessageOrigin::User { sender_user, .. } => user_display_name(sender_user),
+        MessageOrigin::HiddenUser {
+            sender_user_name, ..
+        } => sender_user_name.clone(),
+        MessageOrigin::Chat { sender_chat, .. } => {
+            sender_chat.title().unwrap_or("a group").to_string()
+        }
+        MessageOrigin::Channel { chat, .. } => chat.title().unwrap_or("a channel").to_string(),
+    }
+}
+
+// The kind of an incoming message and the text to store with it, or None for
+// messages worth nothing to a summary (service messages, locations, ...)
+pub fn classify(msg: &Message) -> Option<(MessageKind, String)> {
+    let caption = msg.caption().unwrap_or_default().to_string();
+
+    if let Some(origin) = msg.forward_origin() {
+        let text = msg.text().map(str::to_string).unwrap_or(caption);
+        return Some((
+            MessageKind::Forward {
+                origin: origin_name(origin),
+            },
+            text,
+        ));
+    }
+
+    if let Some(text) = msg.text() {
+        Some((MessageKind::Text, text.to_string()))
+    } else if msg.photo().is_some() {
+        Some((MessageKind::Photo, caption))
+    } else if msg.video().is_some() || msg.animation().is_some() || msg.video_note().is_some() {
+        Some((MessageKind::Video, caption))
+    } else if msg.voice().is_some() {
+        Some((MessageKind::Voice, caption))
+    } else if let Some(sticker) = msg.sticker() {
+        Some((
+            MessageKind::Sticker {
+                emoji: sticker.emoji.clone(),
+            },
+            String::new(),
+        ))
+    } else if let Some(document) = msg.document() {
+        Some((
+            MessageKind::Document {
+                filename: document.file_name.clone(),
+            },
+            caption,
+        ))
+    } else {
+        msg.poll()
+            .map(|poll| (MessageKind::Poll, poll.question.clone()))
+    }
+}
+
+impl MessageKind {
+    // How the message reads in a prompt or an extract, e.g. "[sent a photo: caption]"
+    pub fn render(&self, text: &str) -> String {
+        let with_text = |placeholder: &str| {
+            if text.is_empty() {
+                format!("[{}]", placeholder)
+            } else {
+                format!("[{}: {}]", placeholder, text)
+            }
+        };
+        match self {
+            MessageKind::Text => text.to_string(),
+            MessageKind::Photo => with_text("sent a photo"),
+            MessageKind::Video => with_text("sent a video"),
+            MessageKind::Voice => with_text("sent a voice message"),
+            MessageKind::Sticker { emoji } => match emoji {
+                Some(emoji) => format!("[sent a {} sticker]", emoji),
+                None => "[sent a sticker]".to_string(),
+            },
+            MessageKind::Document { filename } => match filename {
+                Some(filename) => with_text(&format!("sent a file \"{}\"", filename)),
+                None => with_text("sent a file"),
+            },
+            MessageKind::Forward { origin } => {
+                with_text(&format!("forwarded a message from {}", origin))
+            }
+            MessageKind::Poll => with_text("started a poll"),
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::timing::{BUCKET_BOUNDS_MS, StageStats};
+use log::{debug, error, info};
+use serde::Serialize;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+// A request that hasn't been read and answered by then is dropped
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+// Histogram with the same buckets as the per-stage timings, updated without
+// locking so it can be observed from any handler
+#[derive(Debug, Default)]
+pub struct AtomicHistogram {
+    counts: [AtomicU64; BUCKET_BOUNDS_MS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+impl AtomicHistogram {
+    pub fn observe(&self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ([u64; BUCKET_BOUNDS_MS.len() + 1], u64) {
+        (
+            std::array::from_fn(|bucket| self.counts[bucket].load(Ordering::Relaxed)),
+            self.sum_ms.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// Counters exported on /metrics when METRICS_ADDR is set. They're kept either
+// way, since incrementing an atomic costs next to nothing.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub messages_stored: AtomicU64,
+    pub commands_handled: AtomicU64,
+    pub summaries_requested: AtomicU64,
+    pub summaries_succeeded: AtomicU64,
+    pub summaries_failed: AtomicU64,
+    pub provider_latency: AtomicHistogram,
+    // Set once the dispatcher is running; /healthz fails until then
+    ready: AtomicBool,
+}
+
+pub type MetricsType = Arc<Metrics>;
+
+#[derive(Debug, Serialize)]
+pub struct MetricsDebug {
+    pub messages_stored: u64,
+    pub commands_handled: u64,
+    pub summaries_requested: u64,
+    pub summaries_succeeded: u64,
+    pub summaries_failed: u64,
+    pub ready: bool,
+}
+
+// Values read from the store when /metrics is scraped
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StoreGauges {
+    pub messages: usize,
+    pub chats: usize,
+    pub bytes: usize,
+}
+
+impl Metrics {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn debug_snapshot(&self) -> MetricsDebug {
+        let read = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsDebug {
+            messages_stored: read(&self.messages_stored),
+            commands_handled: read(&self.commands_handled),
+            summaries_requested: read(&self.summaries_requested),
+            summaries_succeeded: read(&self.summaries_succeeded),
+            summaries_failed: read(&self.summaries_failed),
+            ready: self.is_ready(),
+        }
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self, store: StoreGauges, stages: &StageStats) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "duck_messages_stored_total",
+                "Messages added to the store",
+                &self.messages_stored,
+            ),
+            (
+                "duck_commands_total",
+                "Commands handled",
+                &self.commands_handled,
+            ),
+            (
+                "duck_summaries_requested_total",
+                "Summaries, moods and topic lists requested",
+                &self.summaries_requested,
+            ),
+            (
+                "duck_summaries_succeeded_total",
+                "Requested summaries that were posted",
+                &self.summaries_succeeded,
+            ),
+            (
+                "duck_summaries_failed_total",
+                "Requested summaries the provider couldn't produce",
+                &self.summaries_failed,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let gauges = [
+            (
+                "duck_store_messages",
+                "Messages currently stored",
+                store.messages,
+            ),
+            (
+                "duck_store_chats",
+                "Chats and topics with stored messages",
+                store.chats,
+            ),
+            (
+                "duck_store_bytes",
+                "Estimated size of the stored messages",
+                store.bytes,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let (counts, sum_ms) = self.provider_latency.snapshot();
+        let _ = writeln!(
+            out,
+            "# HELP duck_provider_request_seconds Time per summarization provider call, retries included"
+        );
+        let _ = writeln!(out, "# TYPE duck_provider_request_seconds histogram");
+        write_histogram(
+            &mut out,
+            "duck_provider_request_seconds",
+            "",
+            &counts,
+            sum_ms,
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP duck_request_stage_seconds Time spent in each stage of a summary request"
+        );
+        let _ = writeln!(out, "# TYPE duck_request_stage_seconds histogram");
+        for (stage, histogram) in &stages.stages {
+            write_histogram(
+                &mut out,
+                "duck_request_stage_seconds",
+                &format!("stage=\"{}\"", stage.name()),
+                &histogram.counts,
+                histogram.sum.as_millis() as u64,
+            );
+        }
+        out
+    }
+}
+
+// Cumulative buckets, sum and count of one histogram series
+fn write_histogram(out: &mut String, name: &str, labels: &str, counts: &[u64], sum_ms: u64) {
+    let separator = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+    for (bucket, count) in counts.iter().enumerate() {
+        cumulative += count;
+        let bound = BUCKET_BOUNDS_MS
+            .get(bucket)
+            .map(|ms| format!("{}", *ms as f64 / 1000.0))
+            .unwrap_or_else(|| "+Inf".to_string());
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"{}\"}} {}",
+            name, labels, separator, bound, cumulative
+        );
+    }
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    let _ = writeln!(out, "{}_sum{} {}", name, labels, sum_ms as f64 / 1000.0);
+    let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+}
+
+// Serve /healthz and /metrics until `shutdown` turns true. `render` produces
+// the /metrics body; it's async since the store sits behind a lock.
+pub async fn serve<F, Fut>(
+    addr: SocketAddr,
+    metrics: MetricsType,
+    render: F,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = String> + Send,
+{
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(target: "metrics", "Couldn't listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!(target: "metrics", "Serving /healthz and /metrics on {}", addr);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let metrics = metrics.clone();
+                    let render = render.clone();
+                    tokio::spawn(async move {
+                        let answered = tokio::time::timeout(
+                            CONNECTION_TIMEOUT,
+                            respond(stream, &metrics, render),
+                        )
+                        .await;
+                        if !matches!(answered, Ok(Ok(()))) {
+                            debug!(target: "metrics", "Dropped a metrics request from {}", peer);
+                        }
+                    });
+                }
+                Err(e) => debug!(target: "metrics", "Failed to accept a connection: {}", e),
+            },
+            _ = shutdown.changed() => break,
+        }
+    }
+    info!(target: "metrics", "Metrics server stopped");
+}
+
+async fn respond<F, Fut>(mut stream: TcpStream, metrics: &Metrics, render: F) -> std::io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = String>,
+{
+    // Only the request line matters; headers are read past and ignored
+    let mut request = Vec::with_capacity(1024);
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_BYTES {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/healthz") if metrics.is_ready() => ("200 OK", "text/plain", "ok\n".to_string()),
+        ("GET", "/healthz") => (
+            "503 Service Unavailable",
+            "text/plain",
+            "starting\n".to_string(),
+        ),
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", render().await),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+// Fewer lines than this is a normal message, however technical it looks
+const MIN_LINES: usize = 8;
+// Share of lines that must look like code or log output
+const LINE_SHARE_PERCENT: usize = 50;
+// Share of non-space characters that are symbols in typical code and JSON
+const SYMBOL_SHARE_PERCENT: usize = 30;
+const TOP_IDENTIFIERS: usize = 2;
+// Words too common in code to say anything about a paste
+const STOPWORDS: &[&str] = &[
+    "self", "true", "false", "null", "None", "return", "from", "with", "this", "that", "function",
+    "const", "struct", "impl", "async", "await", "print", "line", "file", "error", "info", "debug",
+    "warn", "main", "string", "String",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PasteKind {
+    StackTrace(&'static str),
+    Json,
+    Log,
+    Code,
+}
+
+impl std::fmt::Display for PasteKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasteKind::StackTrace(language) => write!(f, "{} stack trace", language),
+            PasteKind::Json => write!(f, "JSON"),
+            PasteKind::Log => write!(f, "log output"),
+            PasteKind::Code => write!(f, "code"),
+        }
+    }
+}
+
+// "2024-05-01 ...", "[2024-05-01T...", "12:34:56 ..."
+fn starts_with_timestamp(line: &str) -> bool {
+    let line = line.trim_start_matches(['[', '(']);
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    matches!(
+        (digits, line.chars().nth(digits)),
+        (4, Some('-' | '/' | '.')) | (2, Some(':' | '-' | '/' | '.'))
+    )
+}
+
+fn stack_trace_language(text: &str) -> Option<&'static str> {
+    if text.contains("panicked at") || text.contains("stack backtrace:") {
+        Some("Rust")
+    } else if text.contains("Traceback (most recent call last)") {
+        Some("Python")
+    } else if (text.contains(".java:") && text.contains("\tat "))
+        || text.contains("Exception in thread")
+    {
+        Some("Java")
+    } else if text.contains("    at ") && (text.contains(".js:") || text.contains(".ts:")) {
+        Some("JavaScript")
+    } else if text.contains("goroutine ") && text.contains(".go:") {
+        Some("Go")
+    } else {
+        None
+    }
+}
+
+fn classify(text: &str, lines: &[&str]) -> Option<PasteKind> {
+    let fenced = text.contains("```");
+    if lines.len() < MIN_LINES && !(fenced && lines.len() >= MIN_LINES / 2) {
+        return None;
+    }
+    if let Some(language) = stack_trace_language(text) {
+        return Some(PasteKind::StackTrace(language));
+    }
+
+    let trimmed = text.trim().trim_matches('`').trim();
+    if (trimmed.starts_with('{') && trimmed.ends_with('}'))
+        || (trimmed.starts_with('[') && trimmed.ends_with(']') && trimmed.contains('{'))
+    {
+        return Some(PasteKind::Json);
+    }
+
+    let timestamped = lines.iter().filter(|l| starts_with_timestamp(l)).count();
+    if timestamped * 100 >= lines.len() * LINE_SHARE_PERCENT {
+        return Some(PasteKind::Log);
+    }
+
+    let indented = lines
+        .iter()
+        .filter(|l| l.starts_with([' ', '\t']) && !l.trim().is_empty())
+        .count();
+    let visible: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let symbols = visible.iter().filter(|c| !c.is_alphanumeric()).count();
+    if fenced
+        || indented * 100 >= lines.len() * LINE_SHARE_PERCENT
+        || symbols * 100 >= visible.len().max(1) * SYMBOL_SHARE_PERCENT
+    {
+        return Some(PasteKind::Code);
+    }
+    None
+}
+
+// The most frequent identifiers (paths like tokio::sync::Mutex count as one),
+// ties broken by first appearance
+fn top_identifiers(text: &str) -> Vec<String> {
+    let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+    let tokens = text
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':' || c == '.'))
+        .map(|token| token.trim_matches([':', '.']))
+        .filter(|token| {
+            token.len() >= 4
+                && token.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                && !STOPWORDS.contains(token)
+        });
+    for (position, token) in tokens.enumerate() {
+        counts.entry(token).or_insert((0, position)).0 += 1;
+    }
+
+    let mut ranked: Vec<(&str, (usize, usize))> = counts.into_iter().collect();
+    ranked.sort_by(|(_, (a_count, a_first)), (_, (b_count, b_first))| {
+        b_count.cmp(a_count).then(a_first.cmp(b_first))
+    });
+    ranked
+        .into_iter()
+        .take(TOP_IDENTIFIERS)
+        .map(|(token, _)| token.to_string())
+        .collect()
+}
+
+// A one-line stand-in for a pasted stack trace, log, JSON blob or code
+// listing, keeping its first and last lines verbatim. None for anything that
+// reads like a normal message.
+pub fn condense(text: &str) -> Option<String> {
+    let lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty() && line.trim() != "```")
+        .collect();
+    let kind = classify(text, &lines)?;
+
+    let mut label = format!("[pasted {}, {} lines", kind, lines.len());
+    let identifiers = top_identifiers(text);
+    if !identifiers.is_empty() {
+        let quoted: Vec<String> = identifiers.iter().map(|id| format!("'{}'", id)).collect();
+        label.push_str(&format!(", mentions {}", quoted.join(", ")));
+    }
+    label.push(']');
+
+    let first = lines.first().map(|line| line.trim()).unwrap_or_default();
+    let last = lines.last().map(|line| line.trim()).unwrap_or_default();
+    Some(format!(
+        "{} first line: {} / last line: {}",
+        label, first, last
+    ))
+}
@@ -0,0 +1,359 @@
+use crate::{
+    access::ChatOverrides,
+    digest::DigestSchedule,
+    lang,
+    media::MessageKind,
+    settings::ChatSettings,
+    store::{ChatThreadId, SavedMessage},
+};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+use teloxide::types::{ChatId, MessageId, ThreadId};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS messages (
+    chat_id INTEGER NOT NULL,
+    -- 0 for messages outside forum topics
+    thread_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    seq INTEGER NOT NULL,
+    from_user TEXT,
+    reply_to_message_id INTEGER,
+    reply_to_user TEXT,
+    text TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    lang TEXT,
+    synthetic INTEGER NOT NULL DEFAULT 0,
+    -- JSON MessageKind; NULL for plain text
+    kind TEXT,
+    edited INTEGER NOT NULL DEFAULT 0,
+    username TEXT,
+    -- JSON map of emoji to count; NULL without reactions
+    reactions TEXT,
+    PRIMARY KEY (chat_id, thread_id, message_id)
+);
+CREATE INDEX IF NOT EXISTS messages_by_seq ON messages (chat_id, thread_id, seq);
+CREATE TABLE IF NOT EXISTS chat_settings (
+    chat_id INTEGER PRIMARY KEY,
+    settings TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+// Optional SQLite write-through copy of the in-memory store, enabled by
+// DATABASE_PATH. Memory stays the source of truth while running; the database
+// only has to survive restarts, so write errors are logged and otherwise ignored.
+#[derive(Debug)]
+pub struct Database {
+    conn: Mutex<Connection>,
+}
+
+// What a restart recovers
+#[derive(Debug, Default)]
+pub struct Loaded {
+    pub chats: HashMap<ChatThreadId, Vec<SavedMessage>>,
+    pub settings: HashMap<ChatId, ChatSettings>,
+}
+
+// Bring databases created by older versions up to the current schema
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let columns = [
+        ("kind", "kind TEXT"),
+        ("edited", "edited INTEGER NOT NULL DEFAULT 0"),
+        ("username", "username TEXT"),
+        ("reactions", "reactions TEXT"),
+    ];
+    for (name, definition) in columns {
+        let exists = conn
+            .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = ?1")?
+            .exists([name])?;
+        if !exists {
+            conn.execute(
+                &format!("ALTER TABLE messages ADD COLUMN {}", definition),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn thread_key(thread_id: Option<ThreadId>) -> i64 {
+    thread_id.map(|thread| thread.0.0 as i64).unwrap_or(0)
+}
+
+impl Database {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        info!(target: "persist", "Persisting messages to {}", path);
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn log_error<T>(result: rusqlite::Result<T>, action: &str) {
+        if let Err(e) = result {
+            warn!(target: "persist", "Failed to {}: {}", action, e);
+        }
+    }
+
+    pub fn insert_message(&self, key: &ChatThreadId, message: &SavedMessage) {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = conn.execute(
+            "INSERT OR REPLACE INTO messages (chat_id, thread_id, message_id, seq, from_user, \
+             reply_to_message_id, reply_to_user, text, timestamp, lang, synthetic, kind, edited, \
+             username, reactions) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, \
+             ?14, ?15)",
+            params![
+                key.chat_id.0,
+                thread_key(key.thread_id),
+                message.message_id.0,
+                message.seq as i64,
+                message.from_user,
+                message.reply_to_message_id.map(|id| id.0),
+                message.reply_to_user,
+                message.text,
+                message.timestamp.timestamp(),
+                message.lang,
+                message.synthetic,
+                (message.kind != MessageKind::Text)
+                    .then(|| serde_json::to_string(&message.kind).ok())
+                    .flatten(),
+                message.edited,
+                message.username,
+                (!message.reactions.is_empty())
+                    .then(|| serde_json::to_string(&message.reactions).ok())
+                    .flatten(),
+            ],
+        );
+        Self::log_error(result, "store message");
+    }
+
+    pub fn delete_message(&self, key: &ChatThreadId, message_id: MessageId) {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = conn.execute(
+            "DELETE FROM messages WHERE chat_id = ?1 AND thread_id = ?2 AND message_id = ?3",
+            params![key.chat_id.0, thread_key(key.thread_id), message_id.0],
+        );
+        Self::log_error(result, "delete message");
+    }
+
+    pub fn delete_chat_thread(&self, key: &ChatThreadId) {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = conn.execute(
+            "DELETE FROM messages WHERE chat_id = ?1 AND thread_id = ?2",
+            params![key.chat_id.0, thread_key(key.thread_id)],
+        );
+        Self::log_error(result, "clear messages");
+    }
+
+    // Remove every message of the chat/thread up to and including `seq`
+    pub fn delete_through(&self, key: &ChatThreadId, seq: u64) {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = conn.execute(
+            "DELETE FROM messages WHERE chat_id = ?1 AND thread_id = ?2 AND seq <= ?3",
+            params![key.chat_id.0, thread_key(key.thread_id), seq as i64],
+        );
+        Self::log_error(result, "prune messages");
+    }
+
+    // Move the sequence number of every stored message up by `offset`
+    pub fn shift_seqs(&self, offset: u64) {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = conn.execute("UPDATE messages SET seq = seq + ?1", params![offset as i64]);
+        Self::log_error(result, "renumber messages");
+    }
+
+    pub fn save_chat_settings(&self, chat_id: ChatId, settings: &ChatSettings) {
+        let json = match serde_json::to_string(settings) {
+            Ok(json) => json,
+            Err(e) => return warn!(target: "persist", "Failed to serialize chat settings: {}", e),
+        };
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = conn.execute(
+            "INSERT OR REPLACE INTO chat_settings (chat_id, settings) VALUES (?1, ?2)",
+            params![chat_id.0, json],
+        );
+        Self::log_error(result, "store chat settings");
+    }
+
+    // Small bot-wide values such as the budget, stored as JSON
+    pub fn save_meta<T: Serialize>(&self, key: &str, value: &T) {
+        let json = match serde_json::to_string(value) {
+            Ok(json) => json,
+            Err(e) => return warn!(target: "persist", "Failed to serialize {}: {}", key, e),
+        };
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![key, json],
+        );
+        Self::log_error(result, "store metadata");
+    }
+
+    pub fn load_meta<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let json: Option<String> = conn
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()
+            .unwrap_or_else(|e| {
+                warn!(target: "persist", "Failed to load {}: {}", key, e);
+                None
+            });
+        json.and_then(|json| serde_json::from_str(&json).ok())
+    }
+
+    // Load the newest `per_chat` messages of every chat/thread, deleting older
+    // rows so the file doesn't outgrow what the store can hold
+    pub fn load(&self, per_chat: usize) -> rusqlite::Result<Loaded> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut loaded = Loaded::default();
+
+        let keys: Vec<(i64, i64)> = conn
+            .prepare("SELECT DISTINCT chat_id, thread_id FROM messages")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut select = conn.prepare(
+            "SELECT message_id, seq, from_user, reply_to_message_id, reply_to_user, text, \
+             timestamp, lang, synthetic, kind, edited, username, reactions FROM messages \
+             WHERE chat_id = ?1 AND thread_id = ?2 ORDER BY seq DESC LIMIT ?3",
+        )?;
+        for (chat_id, thread) in keys {
+            let mut messages: Vec<SavedMessage> = select
+                .query_map(params![chat_id, thread, per_chat as i64], |row| {
+                    let lang: Option<String> = row.get(7)?;
+                    let kind: Option<String> = row.get(9)?;
+                    let reactions: Option<String> = row.get(12)?;
+                    Ok(SavedMessage {
+                        message_id: MessageId(row.get(0)?),
+                        seq: row.get::<_, i64>(1)? as u64,
+                        from_user: row.get(2)?,
+                        reply_to_message_id: row.get::<_, Option<i32>>(3)?.map(MessageId),
+                        reply_to_user: row.get(4)?,
+                        text: row.get(5)?,
+                        timestamp: DateTime::from_timestamp(row.get(6)?, 0).unwrap_or_default(),
+                        lang: lang.as_deref().and_then(lang::static_code),
+                        synthetic: row.get(8)?,
+                        kind: kind
+                            .and_then(|kind| serde_json::from_str(&kind).ok())
+                            .unwrap_or_default(),
+                        edited: row.get(10)?,
+                        username: row.get(11)?,
+                        reactions: reactions
+                            .and_then(|reactions| serde_json::from_str(&reactions).ok())
+                            .unwrap_or_default(),
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            messages.reverse();
+
+            let key = ChatThreadId {
+                chat_id: ChatId(chat_id),
+                thread_id: (thread != 0).then_some(ThreadId(MessageId(thread as i32))),
+            };
+            if let Some(oldest) = messages.first() {
+                conn.execute(
+                    "DELETE FROM messages WHERE chat_id = ?1 AND thread_id = ?2 AND seq < ?3",
+                    params![chat_id, thread, oldest.seq as i64],
+                )?;
+            }
+            loaded.chats.insert(key, messages);
+        }
+
+        let mut select = conn.prepare("SELECT chat_id, settings FROM chat_settings")?;
+        let rows = select.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (chat_id, json) = row?;
+            match serde_json::from_str(&json) {
+                Ok(settings) => {
+                    loaded.settings.insert(ChatId(chat_id), settings);
+                }
+                Err(e) => {
+                    warn!(target: "persist", "Ignoring unreadable settings of chat {}: {}", chat_id, e)
+                }
+            }
+        }
+
+        Ok(loaded)
+    }
+}
+
+// Bumped whenever the snapshot layout changes, so older files are ignored
+// rather than misread
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// The in-memory store written to SNAPSHOT_PATH on shutdown, for deployments
+// without a database
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoreSnapshot {
+    pub version: u32,
+    pub taken_at: DateTime<Utc>,
+    pub startup_time: DateTime<Utc>,
+    pub chats: Vec<(ChatThreadId, Vec<SavedMessage>)>,
+    pub first_seen: Vec<(ChatThreadId, DateTime<Utc>)>,
+    pub settings: Vec<(ChatId, ChatSettings)>,
+    #[serde(default)]
+    pub digest_watermarks: Vec<(ChatThreadId, u64)>,
+    #[serde(default)]
+    pub digest_schedules: Vec<(ChatThreadId, DigestSchedule)>,
+    #[serde(default)]
+    pub chat_access: ChatOverrides,
+    #[serde(default)]
+    pub digest_texts: Vec<(ChatThreadId, String)>,
+}
+
+// Written to a temporary file first, so a crash mid-write can't leave a
+// truncated snapshot behind
+pub fn write_snapshot(path: &str, snapshot: &StoreSnapshot) -> std::io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    fs::write(&temporary, serde_json::to_vec(snapshot)?)?;
+    fs::rename(&temporary, path)
+}
+
+// The snapshot at `path` if there is a usable one. Missing, unreadable,
+// incompatible and stale snapshots are logged and skipped.
+pub fn read_snapshot(
+    path: &str,
+    max_age: chrono::Duration,
+    now: DateTime<Utc>,
+) -> Option<StoreSnapshot> {
+    if !Path::new(path).exists() {
+        return None;
+    }
+    let snapshot: StoreSnapshot = match fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()))
+    {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!(target: "persist", "Ignoring unreadable snapshot {}: {}", path, e);
+            return None;
+        }
+    };
+    if snapshot.version != SNAPSHOT_VERSION {
+        warn!(target: "persist", "Ignoring snapshot {} with version {} (expected {})", path, snapshot.version, SNAPSHOT_VERSION);
+        return None;
+    }
+    if now - snapshot.taken_at > max_age {
+        info!(target: "persist", "Ignoring snapshot {} taken {}, older than {}h", path, snapshot.taken_at, max_age.num_hours());
+        return None;
+    }
+    Some(snapshot)
+}
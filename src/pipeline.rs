@@ -0,0 +1,389 @@
+use crate::{
+    blockterms, budget,
+    citations::Citations,
+    compaction,
+    config::Config,
+    context, destination,
+    error::SummarizeError,
+    glossary, lang,
+    llm::{self, Completer, Completion},
+    media::MessageKind,
+    metrics::Metrics,
+    prompt, reactions,
+    settings::ChatSettings,
+    state::{AppState, budget_month, charge_budget, notify_model_change},
+    stats::StatsType,
+    store::SavedMessage,
+    task::LlmTask,
+    timing::{Stage, StageTimings},
+};
+use log::{debug, info, trace, warn};
+use std::{sync::Arc, time::Instant};
+use teloxide::{
+    prelude::*,
+    types::{ChatId, MessageId, ThreadId},
+    utils::markdown,
+};
+use tokio::sync::watch;
+
+// Lets summaries point at the messages behind a decision or date; the tags are
+// turned into links when the summary is posted
+pub const CITATION_INSTRUCTION: &str = " Each message starts with its number, like [#42]. When you mention \
+    something a specific message decided, announced or asked, put that message's number in the same \
+    form right after it, e.g. \"They agreed to meet on Friday [#42].\" Use only numbers from the \
+    conversation, and at most one or two per point.";
+
+// What a provider round needs from the bot: the settings, where usage and
+// latency are recorded, and whatever answers the prompts
+pub struct TaskContext<'a, C> {
+    pub config: &'a Config,
+    pub stats: &'a StatsType,
+    pub metrics: &'a Metrics,
+    pub llm: &'a C,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run_llm_task<C: Completer>(
+    ctx: &TaskContext<'_, C>,
+    messages: Arc<[SavedMessage]>,
+    task: LlmTask,
+    focus: Option<&str>,
+    chat_id: ChatId,
+    chat_settings: &ChatSettings,
+    model: Option<&str>,
+    progress: Option<&watch::Sender<String>>,
+) -> Result<(Completion, StageTimings, Citations, prompt::Exclusions), SummarizeError> {
+    debug!(target: "summarization", "Starting /{} for {} messages", task.command(), messages.len());
+    let mut timings = StageTimings::start();
+
+    let (system_prompt, _) = task.system_prompt(ctx.config, chat_id);
+
+    // Applied here rather than at ingest, so ignoring someone also covers what
+    // they said before
+    let mut exclusions = prompt::Exclusions::default();
+    let messages: Arc<[SavedMessage]> = if chat_settings.ignored_users.is_empty() {
+        messages
+    } else {
+        let kept: Arc<[SavedMessage]> = messages
+            .iter()
+            .filter(|m| !chat_settings.ignores(m.username.as_deref()))
+            .cloned()
+            .collect();
+        exclusions.ignored_users = messages.len() - kept.len();
+        kept
+    };
+
+    let mix = lang::language_mix(messages.iter().map(|m| m.lang));
+    let mut system_prompt = format!(
+        "{} {}",
+        system_prompt,
+        lang::summary_instruction(chat_settings.language.as_deref(), &mix)
+    );
+
+    let reacted = messages.iter().any(|m| reactions::is_notable(&m.reactions));
+    let blocked = blockterms::Matcher::new(&chat_settings.blocked_terms);
+    // Streamed text is shown before it could be redacted
+    let progress = progress.filter(|_| blocked.is_empty());
+
+    let prepared = prompt::build_blocking(
+        messages,
+        ctx.config.prompt_soft_cap,
+        !chat_settings.keep_pastes,
+        blocked.clone(),
+    )
+    .await?;
+    ctx.stats.lock().await.record_preparation(
+        prepared.elapsed,
+        prepared.degraded,
+        prepared.blocked,
+    );
+    timings.add(Stage::Prompt, prepared.elapsed);
+    exclusions.blocked_terms = prepared.blocked;
+    exclusions.compacted = prepared.compacted_exclusions;
+    if prepared.blocked > 0 {
+        debug!(target: "summarization", "Left out {} messages containing blocked terms in chat {}", prepared.blocked, chat_id);
+    }
+    if prepared.degraded {
+        warn!(target: "summarization", "Prompt preparation exceeded {:?}, skipped optional passes ({:?} total)", ctx.config.prompt_soft_cap, prepared.elapsed);
+    }
+    trace!(target: "summarization", "Prepared conversation text for summarization: {} characters in {:?}", prepared.text.len(), prepared.elapsed);
+
+    // Tags are only asked for where they can become links
+    let citations = Citations::new(chat_id, prepared.message_ids.clone());
+    if let Some(instruction) = task.extra_instruction() {
+        system_prompt.push(' ');
+        system_prompt.push_str(instruction);
+    }
+    if let Some(topic) = focus {
+        system_prompt.push(' ');
+        system_prompt.push_str(&LlmTask::focus_instruction(topic));
+    }
+    if matches!(task, LlmTask::Summarize | LlmTask::DigestUpdate) && citations.linkable() {
+        system_prompt.push_str(CITATION_INSTRUCTION);
+    }
+    if reacted {
+        system_prompt.push_str(reactions::INSTRUCTION);
+    }
+
+    // Sent ahead of the conversation in every request, so it counts against
+    // the budget of each part
+    let glossary = glossary::prompt_block(&chat_settings.glossary);
+
+    // Partial summaries aren't worth streaming; only the final text is, while
+    // the provider has streaming on
+    let budget = ctx.config.prompt_token_budget;
+    if budget == 0 || budget::estimate_tokens(glossary.len() + prepared.text.len()) <= budget {
+        let content = format!("{}{}", glossary, prepared.text);
+        let call = Instant::now();
+        let mut completion = ctx
+            .llm
+            .complete(&system_prompt, &content, model, progress)
+            .await?;
+        timings.provider_call(call.elapsed(), completion.retry_time);
+        ctx.metrics.provider_latency.observe(call.elapsed());
+        debug!(target: "summarization", "Successfully received summary from {}: {} characters", completion.provider, completion.text.len());
+        redact_completion(&mut completion, &blocked, chat_id);
+        ctx.stats
+            .lock()
+            .await
+            .record_usage(chat_id, completion.usage);
+        return Ok((completion, timings, citations, exclusions));
+    }
+
+    // Too long for one request: summarize consecutive parts, then merge them
+    let parts = prompt::chunk(
+        &prepared,
+        budget
+            .saturating_sub(budget::estimate_tokens(glossary.len()))
+            .max(1),
+    );
+    info!(target: "summarization", "Conversation of about {} tokens exceeds the budget of {}, summarizing in {} parts",
+        budget::estimate_tokens(prepared.text.len()), budget, parts.len());
+    let mut partials = Vec::with_capacity(parts.len());
+    let mut chars = 0;
+    let mut usage = Some(llm::Usage::default());
+    let mut failed_over = false;
+    let mut served_model_changed = None;
+    for (index, part) in parts.iter().enumerate() {
+        if let Some(progress) = progress {
+            progress.send_replace(format!("Summarizing part {}/{}", index + 1, parts.len()));
+        }
+        let part_prompt = format!(
+            "{} This is part {} of {} of a longer conversation; cover only this part.",
+            system_prompt,
+            index + 1,
+            parts.len()
+        );
+        let content = format!("{}{}", glossary, part);
+        let call = Instant::now();
+        let completion = ctx
+            .llm
+            .complete(&part_prompt, &content, model, None)
+            .await?;
+        timings.provider_call(call.elapsed(), completion.retry_time);
+        ctx.metrics.provider_latency.observe(call.elapsed());
+        chars += completion.chars;
+        usage = llm::Usage::combine(usage, completion.usage);
+        failed_over |= completion.failed_over;
+        served_model_changed = served_model_changed.or(completion.served_model_changed);
+        partials.push(completion.text);
+    }
+
+    if let Some(progress) = progress {
+        progress.send_replace(format!("Combining {} partial summaries", partials.len()));
+    }
+    let merge_prompt = format!(
+        "{} The input consists of your results for consecutive parts of one conversation, \
+        in order. Combine them into a single result without repeating anything.",
+        system_prompt
+    );
+    let merged = partials
+        .iter()
+        .enumerate()
+        .map(|(index, partial)| format!("Part {}:\n{}", index + 1, partial))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let content = format!("{}{}", glossary, merged);
+    let call = Instant::now();
+    let mut completion = ctx
+        .llm
+        .complete(&merge_prompt, &content, model, progress)
+        .await?;
+    timings.provider_call(call.elapsed(), completion.retry_time);
+    ctx.metrics.provider_latency.observe(call.elapsed());
+    completion.chars += chars;
+    completion.usage = llm::Usage::combine(usage, completion.usage);
+    completion.failed_over |= failed_over;
+    completion.served_model_changed = served_model_changed.or(completion.served_model_changed);
+    debug!(target: "summarization", "Successfully merged {} partial summaries from {}: {} characters", partials.len(), completion.provider, completion.text.len());
+    redact_completion(&mut completion, &blocked, chat_id);
+    ctx.stats
+        .lock()
+        .await
+        .record_usage(chat_id, completion.usage);
+    Ok((completion, timings, citations, exclusions))
+}
+
+// How much of the model's context a request would take: the system prompt,
+// the glossary and the conversation. None if the model's window isn't known.
+pub fn request_headroom(
+    state: &AppState,
+    messages: &[SavedMessage],
+    task: LlmTask,
+    chat_id: ChatId,
+    chat_settings: &ChatSettings,
+    model: &str,
+) -> Option<context::Headroom> {
+    let (system_prompt, _) = task.system_prompt(&state.config, chat_id);
+    let chars = system_prompt.len()
+        + glossary::prompt_block(&chat_settings.glossary).len()
+        + context::conversation_chars(messages);
+    context::headroom(chars, model, &state.config.context_limits)
+}
+
+// Blocked terms can still surface in the output, e.g. from messages that
+// mention them indirectly, so they're redacted there as well
+pub fn redact_completion(
+    completion: &mut Completion,
+    blocked: &blockterms::Matcher,
+    chat_id: ChatId,
+) {
+    let (text, redactions) = blocked.redact(&completion.text);
+    if redactions > 0 {
+        info!(target: "summarization", "Redacted {} blocked term occurrences from a summary in chat {}", redactions, chat_id);
+        completion.text = text;
+    }
+}
+
+// Format a summary as MarkdownV2 messages that each fit Telegram's limit: the
+// summary in italics with its message tags linked, split where needed, then
+// the trailer lines in plain text on the last message if there's room
+pub fn summary_chunks(summary: &str, trailer: &[String], citations: &Citations) -> Vec<String> {
+    let trailer = trailer.join("\n\n");
+    let plain = destination::split_text(summary, destination::MESSAGE_LIMIT);
+    let mut chunks: Vec<String> = plain.iter().map(|chunk| citations.render(chunk)).collect();
+    if trailer.is_empty() {
+        return chunks;
+    }
+
+    let fits = plain.last().is_some_and(|last| {
+        destination::telegram_len(last) + 2 + destination::telegram_len(&trailer)
+            <= destination::MESSAGE_LIMIT
+    });
+    match chunks.last_mut() {
+        Some(last) if fits => last.push_str(&format!("\n\n{}", markdown::escape(&trailer))),
+        _ => chunks.extend(
+            destination::split_text(&trailer, destination::MESSAGE_LIMIT)
+                .iter()
+                .map(|chunk| markdown::escape(chunk)),
+        ),
+    }
+    chunks
+}
+
+// Provider-free stand-in for a summary: the longest messages, in chat order
+pub fn extractive_summary(messages: &[SavedMessage], blocked: &blockterms::Matcher) -> String {
+    const EXTRACT_COUNT: usize = 8;
+    const EXTRACT_CHARS: usize = 200;
+
+    let mut picked: Vec<&SavedMessage> = messages
+        .iter()
+        .filter(|m| !m.synthetic && !blocked.matches(&m.text))
+        .collect();
+    picked.sort_by_key(|m| std::cmp::Reverse(m.text.chars().count()));
+    picked.truncate(EXTRACT_COUNT);
+    picked.sort_by_key(|m| m.seq);
+
+    picked
+        .iter()
+        .map(|m| {
+            let rendered = m.kind.render(&m.text);
+            let mut text: String = rendered.chars().take(EXTRACT_CHARS).collect();
+            if text.len() < rendered.len() {
+                text.push('…');
+            }
+            format!(
+                "- {}: {}",
+                m.from_user.as_deref().unwrap_or("Unknown"),
+                text.replace('\n', " ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Summarize a batch of old messages and fold it into a single synthetic entry
+pub async fn compact_history(
+    bot: Bot,
+    state: AppState,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    batch: Vec<SavedMessage>,
+) {
+    let (Some(first), Some(last)) = (batch.first(), batch.last()) else {
+        return;
+    };
+    let (first_timestamp, last_seq) = (first.timestamp, last.seq);
+    let month = budget_month(&state).await;
+    if !state.budget.lock().await.allows_llm(month) {
+        debug!(target: "compaction", "Monthly budget reached, not compacting chat {} thread {:?}", chat_id, thread_id);
+        state
+            .store
+            .lock()
+            .await
+            .finish_compaction(chat_id, thread_id, last_seq, None);
+        return;
+    }
+    info!(target: "compaction", "Compacting {} old messages in chat {} thread {:?}", batch.len(), chat_id, thread_id);
+
+    let model = state.settings.lock().await.model.clone();
+    let chat_settings = state.store.lock().await.chat_settings(chat_id);
+    let summary = match run_llm_task(
+        &state.task_context(),
+        batch.into(),
+        LlmTask::Compact,
+        None,
+        chat_id,
+        &chat_settings,
+        model.as_deref(),
+        None,
+    )
+    .await
+    {
+        Ok((completion, _, _, exclusions)) => {
+            charge_budget(&bot, &state, &completion).await;
+            notify_model_change(&bot, &state, &completion).await;
+            let mut text = completion.text;
+            if exclusions.by_policy() {
+                text.push('\n');
+                text.push_str(compaction::EXCLUSIONS_MARK);
+            }
+            Some(SavedMessage {
+                seq: last_seq,
+                // Never matches a real message, so replies can't resolve to it
+                message_id: MessageId(0),
+                from_user: None,
+                username: None,
+                reply_to_message_id: None,
+                reply_to_user: None,
+                text,
+                kind: MessageKind::Text,
+                timestamp: first_timestamp,
+                lang: None,
+                synthetic: true,
+                edited: false,
+                reactions: Default::default(),
+            })
+        }
+        Err(e) => {
+            warn!(target: "compaction", "Failed to compact history in chat {} thread {:?}: {}", chat_id, thread_id, e);
+            None
+        }
+    };
+
+    state
+        .store
+        .lock()
+        .await
+        .finish_compaction(chat_id, thread_id, last_seq, summary);
+}
@@ -0,0 +1,225 @@
+use crate::{
+    destination::{self, ChatDestination, SendOptions},
+    settings::PlaceholderMode,
+};
+use log::{debug, warn};
+use std::time::Duration;
+use teloxide::{
+    ApiError, RequestError,
+    prelude::*,
+    types::{Message, MessageId, ParseMode, ReactionType},
+};
+use tokio::sync::watch;
+
+const WORKING_REACTION: &str = "👀";
+// Telegram allows roughly one edit per second per chat; stay under it
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1500);
+
+// Everything the bot posts while generating a summary goes through here, so
+// the chat's placeholder mode and reply anchoring are honored in one place
+pub struct SummaryReply<'a> {
+    bot: &'a Bot,
+    command: &'a Message,
+    // Every message of the flow goes to the command's chat and topic
+    destination: ChatDestination,
+    mode: PlaceholderMode,
+    // Message to reply to instead of the command, e.g. the first summarized one
+    anchor: Option<MessageId>,
+    placeholder: Option<Message>,
+}
+
+// Telegram reports a deleted reply target as "message to be replied not found"
+// or "reply message not found" depending on the endpoint
+fn is_reply_target_missing(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(ApiError::MessageToReplyNotFound) => true,
+        RequestError::Api(ApiError::Unknown(description)) => {
+            description.contains("replied not found")
+                || description.contains("reply message not found")
+        }
+        _ => false,
+    }
+}
+
+impl<'a> SummaryReply<'a> {
+    // Signal that work has started, according to the mode. A reaction that
+    // can't be set (missing rights, reactions disabled) falls back to a placeholder.
+    pub async fn start(
+        bot: &'a Bot,
+        command: &'a Message,
+        mode: PlaceholderMode,
+        anchor: Option<MessageId>,
+        placeholder_text: String,
+    ) -> ResponseResult<SummaryReply<'a>> {
+        let mut reply = SummaryReply {
+            bot,
+            command,
+            destination: ChatDestination::of(command),
+            mode,
+            anchor,
+            placeholder: None,
+        };
+
+        match mode {
+            PlaceholderMode::Edit => {
+                reply.placeholder = Some(reply.send(placeholder_text, None, false).await?);
+            }
+            PlaceholderMode::Silent => {}
+            PlaceholderMode::Reaction => {
+                let reacted = bot
+                    .set_message_reaction(command.chat.id, command.id)
+                    .reaction(vec![ReactionType::Emoji {
+                        emoji: WORKING_REACTION.to_string(),
+                    }])
+                    .await;
+                if let Err(e) = reacted {
+                    warn!(target: "command", "Couldn't react in chat {}, falling back to a placeholder: {}", command.chat.id, e);
+                    reply.mode = PlaceholderMode::Edit;
+                    reply.placeholder = Some(reply.send(placeholder_text, None, false).await?);
+                }
+            }
+        }
+
+        Ok(reply)
+    }
+
+    // Send a new message replying to the anchor, or to the command if there is
+    // no anchor or it has been deleted in the meantime
+    async fn send(
+        &self,
+        text: String,
+        parse_mode: Option<ParseMode>,
+        silent: bool,
+    ) -> ResponseResult<Message> {
+        let options = SendOptions {
+            reply_to: Some(self.command.id),
+            parse_mode,
+            silent,
+        };
+
+        if let Some(anchor) = self.anchor {
+            let anchored = SendOptions {
+                reply_to: Some(anchor),
+                ..options
+            };
+            match self
+                .destination
+                .send(self.bot, text.clone(), anchored)
+                .await
+            {
+                Err(e) if is_reply_target_missing(&e) => {
+                    warn!(target: "command", "Range start {} in chat {} is gone, replying to the command instead", anchor, self.command.chat.id);
+                }
+                result => return result,
+            }
+        }
+
+        self.destination.send(self.bot, text, options).await
+    }
+
+    // Whether there's a placeholder that partial output could be shown in
+    pub fn has_placeholder(&self) -> bool {
+        self.placeholder.is_some()
+    }
+
+    // Mirror a streamed summary into the placeholder as plain text, at most
+    // once per interval, until the sender is dropped. Failed edits are skipped;
+    // the final edit in finish_chunks replaces whatever was shown last.
+    pub async fn stream_progress(&self, mut partial: watch::Receiver<String>) {
+        let Some(placeholder) = &self.placeholder else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(STREAM_EDIT_INTERVAL);
+        let mut shown = 0;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                changed = partial.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+
+            let text = partial.borrow().clone();
+            if text.len() == shown {
+                continue;
+            }
+            shown = text.len();
+            // Only the beginning fits once the summary outgrows one message
+            let preview = destination::split_text(&text, destination::MESSAGE_LIMIT - 1)
+                .into_iter()
+                .next()
+                .unwrap_or_default();
+            if let Err(e) = self
+                .destination
+                .edit_message(self.bot, placeholder.id, format!("{}…", preview))
+                .await
+            {
+                debug!(target: "command", "Skipping a progress edit in chat {}: {}", self.command.chat.id, e);
+            }
+        }
+    }
+
+    pub async fn finish(self, text: String, parse_mode: Option<ParseMode>) -> ResponseResult<()> {
+        self.finish_chunks(vec![text], parse_mode).await
+    }
+
+    // Deliver the final text: the first chunk edits the placeholder if there is
+    // one, otherwise it's sent as a new reply. Further chunks follow as replies
+    // to the previous one. A placeholder deleted in the meantime is replaced by
+    // a new reply rather than losing the summary.
+    pub async fn finish_chunks(
+        self,
+        chunks: Vec<String>,
+        parse_mode: Option<ParseMode>,
+    ) -> ResponseResult<()> {
+        let mut chunks = chunks.into_iter();
+        let Some(first) = chunks.next() else {
+            return Ok(());
+        };
+
+        let mut previous = match &self.placeholder {
+            Some(placeholder) => match self
+                .destination
+                .edit(self.bot, placeholder.id, first.clone(), parse_mode)
+                .await
+            {
+                Err(RequestError::Api(ApiError::MessageToEditNotFound)) => {
+                    warn!(target: "command", "Placeholder {} in chat {} is gone, sending a new reply", placeholder.id, self.command.chat.id);
+                    self.send(first, parse_mode, false).await?
+                }
+                result => result?,
+            },
+            None => {
+                self.send(first, parse_mode, self.mode == PlaceholderMode::Silent)
+                    .await?
+            }
+        };
+
+        for chunk in chunks {
+            let options = SendOptions {
+                reply_to: Some(previous.id),
+                parse_mode,
+                // Only the first part notifies
+                silent: true,
+            };
+            previous = self.destination.send(self.bot, chunk, options).await?;
+        }
+
+        if self.mode == PlaceholderMode::Reaction {
+            // Clearing the reaction is cosmetic, so a failure is only logged
+            if let Err(e) = self
+                .bot
+                .set_message_reaction(self.command.chat.id, self.command.id)
+                .await
+            {
+                debug!(target: "command", "Couldn't clear reaction in chat {}: {}", self.command.chat.id, e);
+            }
+        }
+
+        Ok(())
+    }
+}
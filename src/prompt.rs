@@ -0,0 +1,197 @@
+use crate::{blockterms, budget, compaction, paste, reactions, store::SavedMessage};
+use log::warn;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use teloxide::types::MessageId;
+use tokio::task::JoinError;
+
+pub const DEFAULT_SOFT_CAP_MS: u64 = 200;
+
+// The conversation as sent to the provider, plus how building it went
+#[derive(Debug, Clone)]
+pub struct PreparedPrompt {
+    pub text: String,
+    // For each line of `text`, whether it replies to the message on the line
+    // before, so chunking can keep the pair together
+    pub replies_to_previous: Vec<bool>,
+    pub elapsed: Duration,
+    // The soft cap was hit and optional passes were skipped
+    pub degraded: bool,
+    // Messages left out for containing a blocked term
+    pub blocked: usize,
+    // Compacted summaries whose own batch had messages left out
+    pub compacted_exclusions: usize,
+    // The message each "[#n]" line prefix stands for, at index n - 1
+    pub message_ids: Vec<MessageId>,
+}
+
+// Messages chat settings kept out of a prompt. Trimming to fit size or time
+// limits isn't counted: readers are only told about what admins chose to leave
+// out, never which filter did it or whose messages it hit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Exclusions {
+    pub blocked_terms: usize,
+    pub ignored_users: usize,
+    // Compacted summaries that were made without some of their messages
+    pub compacted: usize,
+}
+
+impl Exclusions {
+    pub fn by_policy(&self) -> bool {
+        self.blocked_terms + self.ignored_users + self.compacted > 0
+    }
+}
+
+pub const EXCLUSION_NOTE: &str = "Some messages were excluded from this summary by chat settings.";
+
+// Render the messages as "[#n] name (replying to other): text" lines, numbered
+// so summaries can point back at them. Resolving reply
+// authors from the slice is optional: once `soft_cap` is exceeded, only the
+// author recorded at ingest is used. With `condense_pastes`, pasted logs and
+// code are replaced by a one-line description. Messages containing a blocked
+// term are left out. Pure CPU over owned data, so it can run on a blocking
+// thread.
+pub fn build(
+    messages: &[SavedMessage],
+    soft_cap: Duration,
+    condense_pastes: bool,
+    blocked: &blockterms::Matcher,
+) -> PreparedPrompt {
+    let started = Instant::now();
+    let mut degraded = false;
+    let mut blocked_count = 0;
+    let mut compacted_exclusions = 0;
+
+    let authors: HashMap<MessageId, &str> = messages
+        .iter()
+        .filter_map(|m| Some((m.message_id, m.from_user.as_deref()?)))
+        .collect();
+
+    let mut text = String::new();
+    let mut replies_to_previous = Vec::with_capacity(messages.len());
+    let mut previous: Option<MessageId> = None;
+    let mut message_ids = Vec::new();
+    for message in messages {
+        if blocked.matches(&message.text) {
+            blocked_count += 1;
+            continue;
+        }
+        replies_to_previous
+            .push(previous.is_some_and(|previous| message.reply_to_message_id == Some(previous)));
+        previous = (!message.synthetic).then_some(message.message_id);
+        if message.synthetic {
+            if message.text.ends_with(compaction::EXCLUSIONS_MARK) {
+                compacted_exclusions += 1;
+            }
+            text.push_str(&format!(
+                "{} (context only, not part of the conversation): {}\n",
+                compaction::SUMMARY_MARKER,
+                message.text.replace('\n', "\\n")
+            ));
+            continue;
+        }
+
+        if !degraded && started.elapsed() > soft_cap {
+            degraded = true;
+        }
+
+        let username = message.from_user.as_deref().unwrap_or("Unknown");
+
+        // Replace newlines with literals
+        let condensed = condense_pastes
+            .then(|| paste::condense(&message.text))
+            .flatten();
+        let content = condensed.as_deref().unwrap_or(&message.text);
+        let mut body = message.kind.render(content).replace('\n', "\\n");
+        if message.edited {
+            body.push_str(" (edited)");
+        }
+        if let Some(annotation) = reactions::annotation(&message.reactions) {
+            body.push(' ');
+            body.push_str(&annotation);
+        }
+
+        // Add reply information if available. A reply whose target is gone and
+        // whose author is unknown is rendered as a plain message.
+        let replied_to = message.reply_to_message_id.and_then(|reply_id| {
+            let in_slice = if degraded {
+                None
+            } else {
+                authors.get(&reply_id).copied()
+            };
+            in_slice.or(message.reply_to_user.as_deref())
+        });
+        message_ids.push(message.message_id);
+        text.push_str(&format!("[#{}] ", message_ids.len()));
+        match replied_to {
+            Some(replied_to) => text.push_str(&format!(
+                "{} (replying to {}): {}\n",
+                username, replied_to, body
+            )),
+            None => text.push_str(&format!("{}: {}\n", username, body)),
+        }
+    }
+
+    PreparedPrompt {
+        text,
+        replies_to_previous,
+        elapsed: started.elapsed(),
+        degraded,
+        blocked: blocked_count,
+        compacted_exclusions,
+        message_ids,
+    }
+}
+
+// Build the prompt on the blocking pool so large chats don't stall other handlers
+pub async fn build_blocking(
+    messages: Arc<[SavedMessage]>,
+    soft_cap: Duration,
+    condense_pastes: bool,
+    blocked: blockterms::Matcher,
+) -> Result<PreparedPrompt, JoinError> {
+    tokio::task::spawn_blocking(move || build(&messages, soft_cap, condense_pastes, &blocked)).await
+}
+
+// Split a prepared prompt into pieces of at most `budget_tokens` (estimated),
+// cutting only between lines. When a cut would separate a reply from the line
+// it answers, the answered line moves to the next piece as well if both fit.
+// A single line longer than the whole budget is truncated.
+pub fn chunk(prepared: &PreparedPrompt, budget_tokens: u64) -> Vec<String> {
+    let max_chars = (budget_tokens as usize * budget::CHARS_PER_TOKEN).max(2);
+    let mut chunks = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut current_chars = 0;
+
+    for (line, &replies) in prepared.text.lines().zip(&prepared.replies_to_previous) {
+        let line = if line.len() >= max_chars {
+            warn!(target: "summarization", "Truncating a {} character message to fit the prompt budget", line.len());
+            format!("{}…", &line[..line.floor_char_boundary(max_chars - 4)])
+        } else {
+            line.to_string()
+        };
+
+        if current_chars + line.len() + 1 > max_chars && !current.is_empty() {
+            let carried = match current.last() {
+                Some(last)
+                    if replies && current.len() > 1 && last.len() + line.len() + 2 <= max_chars =>
+                {
+                    current.pop()
+                }
+                _ => None,
+            };
+            chunks.push(current.join("\n") + "\n");
+            current_chars = carried.as_ref().map_or(0, |carried| carried.len() + 1);
+            current = carried.into_iter().collect();
+        }
+        current_chars += line.len() + 1;
+        current.push(line);
+    }
+    if !current.is_empty() {
+        chunks.push(current.join("\n") + "\n");
+    }
+    chunks
+}
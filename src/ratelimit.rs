@@ -0,0 +1,109 @@
+use crate::store::ChatThreadId;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, PoisonError},
+};
+use tokio::sync::Mutex;
+
+// Entries are pruned once the map grows past this many chats/threads
+const PRUNE_THRESHOLD: usize = 1000;
+
+// One summarization per chat/thread per cooldown
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    last_summarize: HashMap<ChatThreadId, DateTime<Utc>>,
+}
+
+impl RateLimiter {
+    // Claim the chat/thread's slot, or return how long until it frees up
+    pub fn try_acquire(
+        &mut self,
+        key: ChatThreadId,
+        cooldown: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<(), Duration> {
+        if let Some(last) = self.last_summarize.get(&key) {
+            let ready_at = *last + cooldown;
+            if now < ready_at {
+                return Err(ready_at - now);
+            }
+        }
+
+        if self.last_summarize.len() >= PRUNE_THRESHOLD {
+            self.last_summarize.retain(|_, last| now - *last < cooldown);
+        }
+        self.last_summarize.insert(key, now);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RateLimitDebug {
+    // Chats/threads with a recorded summary time
+    pub cooldowns_tracked: usize,
+    // Chats/threads with a summary being generated
+    pub in_flight: Vec<(i64, Option<i32>)>,
+}
+
+impl RateLimiter {
+    pub fn debug_snapshot(&self, in_flight: &InFlight) -> RateLimitDebug {
+        let mut active: Vec<(i64, Option<i32>)> = in_flight
+            .active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|key| (key.chat_id.0, key.thread_id.map(|thread| thread.0.0)))
+            .collect();
+        active.sort_unstable();
+        RateLimitDebug {
+            cooldowns_tracked: self.last_summarize.len(),
+            in_flight: active,
+        }
+    }
+}
+
+pub type RateLimiterType = Arc<Mutex<RateLimiter>>;
+
+// Chats/threads with a summary being generated right now, so a second request
+// arriving meanwhile doesn't start an identical one. A plain mutex, since the
+// guard releases its entry from Drop.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    active: std::sync::Mutex<HashSet<ChatThreadId>>,
+}
+
+impl InFlight {
+    // Claim the chat/thread until the guard is dropped, or None if it's taken
+    pub fn try_start(self: &Arc<Self>, key: ChatThreadId) -> Option<InFlightGuard> {
+        self.active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key.clone())
+            .then(|| InFlightGuard {
+                in_flight: self.clone(),
+                key,
+            })
+    }
+}
+
+// Releases the claim however the summary ends: success, error, early return
+// or panic
+#[derive(Debug)]
+pub struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+    key: ChatThreadId,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight
+            .active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
+
+pub type InFlightType = Arc<InFlight>;
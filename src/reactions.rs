@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use teloxide::types::{ReactionCount, ReactionType};
+
+// Messages with fewer reactions than this aren't annotated in prompts
+const MIN_REACTIONS_SHOWN: u32 = 3;
+
+// Added to the system prompt when the conversation has annotated messages
+pub const INSTRUCTION: &str = " Messages many people reacted to end with their reactions, like \
+    [reactions: 👍x5]. Give what the chat visibly agreed with or cared about more weight.";
+
+// How often each reaction was given to a message, by emoji
+pub type ReactionCounts = BTreeMap<String, u32>;
+
+fn key(reaction: &ReactionType) -> String {
+    match reaction {
+        ReactionType::Emoji { emoji } => emoji.clone(),
+        // Custom emoji ids mean nothing to the model
+        ReactionType::CustomEmoji { .. } => "custom emoji".to_string(),
+    }
+}
+
+// One user changed their reactions from `old` to `new`. Removed reactions are
+// counted down and dropped at zero, added ones counted up.
+pub fn apply_change(counts: &mut ReactionCounts, old: &[ReactionType], new: &[ReactionType]) {
+    for reaction in old.iter().filter(|reaction| !new.contains(reaction)) {
+        let key = key(reaction);
+        if let Some(count) = counts.get_mut(&key) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                counts.remove(&key);
+            }
+        }
+    }
+    for reaction in new.iter().filter(|reaction| !old.contains(reaction)) {
+        *counts.entry(key(reaction)).or_default() += 1;
+    }
+}
+
+// Anonymous reactions, e.g. in channels, only arrive as totals
+pub fn from_totals(totals: &[ReactionCount]) -> ReactionCounts {
+    let mut counts = ReactionCounts::new();
+    for total in totals.iter().filter(|total| total.total_count > 0) {
+        *counts.entry(key(&total.r#type)).or_default() +=
+            u32::try_from(total.total_count).unwrap_or(u32::MAX);
+    }
+    counts
+}
+
+pub fn is_notable(counts: &ReactionCounts) -> bool {
+    counts.values().sum::<u32>() >= MIN_REACTIONS_SHOWN
+}
+
+// "[reactions: 👍x5 ❤x2]", most given first, for messages that got enough
+// reactions to matter
+pub fn annotation(counts: &ReactionCounts) -> Option<String> {
+    if !is_notable(counts) {
+        return None;
+    }
+    let mut sorted: Vec<(&String, &u32)> = counts.iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(a.1));
+    let rendered: Vec<String> = sorted
+        .into_iter()
+        .map(|(emoji, count)| format!("{}x{}", emoji, count))
+        .collect();
+    Some(format!("[reactions: {}]", rendered.join(" ")))
+}
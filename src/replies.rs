@@ -0,0 +1,40 @@
+use crate::store::SavedMessage;
+use std::collections::{HashMap, HashSet};
+use teloxide::types::MessageId;
+
+// Positions of the messages that reply to `root`, directly or through other
+// replies, in arrival order. A reply whose parent isn't among `messages` (sent
+// before the bot saw it, or evicted since) can't be traced back and is left
+// out along with its own replies. The visited set keeps malformed reply
+// chains that loop back on themselves from being walked forever.
+pub fn descendants<'a>(
+    messages: impl IntoIterator<Item = &'a SavedMessage>,
+    root: MessageId,
+) -> Vec<usize> {
+    let mut children: HashMap<MessageId, Vec<(usize, MessageId)>> = HashMap::new();
+    for (position, message) in messages.into_iter().enumerate() {
+        if message.synthetic {
+            continue;
+        }
+        if let Some(parent) = message.reply_to_message_id {
+            children
+                .entry(parent)
+                .or_default()
+                .push((position, message.message_id));
+        }
+    }
+
+    let mut visited = HashSet::from([root]);
+    let mut pending = vec![root];
+    let mut found = Vec::new();
+    while let Some(parent) = pending.pop() {
+        for &(position, id) in children.get(&parent).into_iter().flatten() {
+            if visited.insert(id) {
+                found.push(position);
+                pending.push(id);
+            }
+        }
+    }
+    found.sort_unstable();
+    found
+}
@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+
+// Summaries longer than this, in characters, are regenerated without a note
+// on what changed
+const MAX_DIFF_CHARS: usize = 8_000;
+// Share of its words a replaced sentence has to keep to count as reworded
+// rather than as a different point
+const REWORDED_MIN_OVERLAP_PERCENT: usize = 40;
+
+// How a regenerated summary differs from the one it replaces, by sentence
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Changes {
+    pub reworded: usize,
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+}
+
+// Sentences and list items, as lowercase words so casing, punctuation and
+// list markers don't count as changes
+fn sentences(text: &str) -> Vec<Vec<String>> {
+    text.split(['.', '!', '?', '\n'])
+        .map(|sentence| {
+            sentence
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>()
+        })
+        .filter(|words| !words.is_empty())
+        .collect()
+}
+
+fn overlap_percent(a: &[String], b: &[String]) -> usize {
+    let a: HashSet<&String> = a.iter().collect();
+    let b: HashSet<&String> = b.iter().collect();
+    a.intersection(&b).count() * 100 / a.len().max(b.len())
+}
+
+// Sentences removed from and added to one stretch between unchanged ones.
+// Each added sentence is paired with the closest removed one, if it kept
+// enough of its words.
+fn classify(removed: &[&Vec<String>], added: &[&Vec<String>], changes: &mut Changes) {
+    let mut unpaired: Vec<&Vec<String>> = removed.to_vec();
+    for sentence in added {
+        let closest = unpaired
+            .iter()
+            .enumerate()
+            .map(|(i, old)| (i, overlap_percent(old, sentence)))
+            .filter(|&(_, overlap)| overlap >= REWORDED_MIN_OVERLAP_PERCENT)
+            .max_by_key(|&(_, overlap)| overlap);
+        match closest {
+            Some((i, _)) => {
+                unpaired.remove(i);
+                changes.reworded += 1;
+            }
+            None => changes.added += 1,
+        }
+    }
+    changes.removed += unpaired.len();
+}
+
+// Compare two summaries sentence by sentence, keeping the longest common
+// subsequence as unchanged. None when either is too long to bother.
+pub fn compare(old: &str, new: &str) -> Option<Changes> {
+    if old.chars().count() > MAX_DIFF_CHARS || new.chars().count() > MAX_DIFF_CHARS {
+        return None;
+    }
+    let old = sentences(old);
+    let new = sentences(new);
+
+    // lengths[i][j]: longest common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Changes::default();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            classify(&removed, &added, &mut changes);
+            removed.clear();
+            added.clear();
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            removed.push(&old[i]);
+            i += 1;
+        } else {
+            added.push(&new[j]);
+            j += 1;
+        }
+    }
+    classify(&removed, &added, &mut changes);
+    Some(changes)
+}
+
+fn count(n: usize, singular: &str, plural: &str) -> String {
+    format!("{} {}", n, if n == 1 { singular } else { plural })
+}
+
+// "(Changed: 2 sentences reworded, 1 new point)", appended to a regenerated
+// summary
+pub fn note(old: &str, new: &str) -> Option<String> {
+    let changes = compare(old, new)?;
+    if changes.is_empty() {
+        return Some("(Changed: nothing, same points as before)".to_string());
+    }
+    let mut parts = Vec::new();
+    if changes.reworded > 0 {
+        parts.push(count(
+            changes.reworded,
+            "sentence reworded",
+            "sentences reworded",
+        ));
+    }
+    if changes.added > 0 {
+        parts.push(count(changes.added, "new point", "new points"));
+    }
+    if changes.removed > 0 {
+        parts.push(count(changes.removed, "point dropped", "points dropped"));
+    }
+    Some(format!("(Changed: {})", parts.join(", ")))
+}
@@ -0,0 +1,201 @@
+use crate::{
+    citations, dayslice,
+    destination::{ChatDestination, SendOptions},
+    digest::{self, DigestOutcome},
+    media::MessageKind,
+    pipeline::{run_llm_task, summary_chunks},
+    prompt,
+    settings::ChatSettings,
+    state::{AppState, budget_month, charge_budget, notify_model_change},
+    store::{ChatThreadId, MAX_MESSAGES, MessageSelector, SavedMessage},
+    task::LlmTask,
+};
+use chrono::Utc;
+use log::{debug, info, warn};
+use std::sync::Arc;
+use teloxide::{
+    prelude::*,
+    types::{ChatId, MessageId, ParseMode},
+};
+
+// How often the scheduler looks for digests that are due
+pub const DIGEST_TICK: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Summarize everything the chat or thread said since its last digest and post
+// it there, or to `dry_run_to` without moving the watermark. A key without a
+// thread covers every thread of the chat. Every digest run, forced or
+// scheduled, goes through here.
+pub async fn run_digest(
+    bot: &Bot,
+    state: &AppState,
+    key: ChatThreadId,
+    dry_run_to: Option<ChatId>,
+) -> Result<DigestOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let chat_id = key.chat_id;
+    let (snapshot, previous) = {
+        let store = state.store.lock().await;
+        let watermark = store.digest_watermark(&key);
+        let selector = match (key.thread_id, watermark) {
+            (None, Some(seq)) => MessageSelector::AllThreadsAfter(seq, MAX_MESSAGES),
+            (None, None) => MessageSelector::AllThreads(MAX_MESSAGES),
+            (Some(_), Some(seq)) => MessageSelector::AfterSeq(seq, MAX_MESSAGES),
+            (Some(_), None) => MessageSelector::Last(MAX_MESSAGES),
+        };
+        (
+            store.snapshot(chat_id, key.thread_id, selector),
+            store.last_digest_text(&key).map(str::to_string),
+        )
+    };
+    let messages = &snapshot.messages;
+    if messages.iter().all(|m| m.synthetic) {
+        return Ok(DigestOutcome::NothingNew);
+    }
+    // Later digests see the previous one, so they can skip what it covered
+    let (task, prompt_messages) = match &previous {
+        Some(previous) => {
+            let context = SavedMessage {
+                seq: 0,
+                message_id: MessageId(0),
+                from_user: None,
+                username: None,
+                reply_to_message_id: None,
+                reply_to_user: None,
+                text: format!("{} {}", digest::PREVIOUS_DIGEST_LABEL, previous),
+                kind: MessageKind::Text,
+                timestamp: messages[0].timestamp,
+                lang: None,
+                synthetic: true,
+                edited: false,
+                reactions: Default::default(),
+            };
+            let with_previous: Arc<[SavedMessage]> = std::iter::once(context)
+                .chain(messages.iter().cloned())
+                .collect();
+            (LlmTask::DigestUpdate, with_previous)
+        }
+        None => (LlmTask::Summarize, messages.clone()),
+    };
+    let month = budget_month(state).await;
+    if !state.budget.lock().await.allows_llm(month) {
+        return Ok(DigestOutcome::OverBudget);
+    }
+
+    let chat_settings = state.store.lock().await.chat_settings(chat_id);
+    let model = state.settings.lock().await.model.clone();
+    let (completion, _, citations, exclusions) = run_llm_task(
+        &state.task_context(),
+        prompt_messages,
+        task,
+        None,
+        chat_id,
+        &chat_settings,
+        model.as_deref(),
+        None,
+    )
+    .await?;
+    charge_budget(bot, state, &completion).await;
+    notify_model_change(bot, state, &completion).await;
+
+    let destination = match dry_run_to {
+        Some(target) => ChatDestination::new(target, None),
+        None => ChatDestination::new(chat_id, key.thread_id),
+    };
+    let options = SendOptions {
+        parse_mode: Some(ParseMode::MarkdownV2),
+        ..SendOptions::default()
+    };
+    let mut trailer = vec![digest::trailer(messages.len(), chat_settings.locale())];
+    if exclusions.by_policy() && !chat_settings.hide_exclusion_note {
+        trailer.push(prompt::EXCLUSION_NOTE.to_string());
+    }
+    for chunk in summary_chunks(&completion.text, &trailer, &citations) {
+        destination.send(bot, chunk, options).await?;
+    }
+
+    // Only a digest that was posted moves the pipeline on
+    if dry_run_to.is_none() {
+        let mut store = state.store.lock().await;
+        if let Some(watermark) = snapshot.watermark {
+            store.set_digest_watermark(key.clone(), watermark);
+        }
+        store.set_last_digest_text(key.clone(), citations::strip_tags(&completion.text));
+    }
+    info!(target: "digest", "Posted a digest of {} messages from chat {} thread {:?}{}", messages.len(), chat_id, key.thread_id,
+        if dry_run_to.is_some() { " (dry run)" } else { "" });
+    Ok(DigestOutcome::Sent(messages.len()))
+}
+
+// Scheduled digests follow the chat's timezone, then DIGEST_TZ, then UTC
+pub fn digest_timezone(state: &AppState, chat_settings: &ChatSettings) -> chrono_tz::Tz {
+    dayslice::timezone(
+        chat_settings.timezone.as_deref(),
+        state.config.digest_timezone.as_deref(),
+    )
+}
+
+// Run a digest and move its schedule on to the next day, as the scheduler
+// does; a dry run leaves the schedule alone. A failed or skipped digest
+// still moves on, so a chat the bot can't post in isn't retried every minute.
+pub async fn run_scheduled_digest(
+    bot: &Bot,
+    state: &AppState,
+    key: ChatThreadId,
+    dry_run_to: Option<ChatId>,
+) -> Result<DigestOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let dry_run = dry_run_to.is_some();
+    let outcome = run_digest(bot, state, key.clone(), dry_run_to).await;
+    if dry_run {
+        return outcome;
+    }
+
+    let chat_settings = state.store.lock().await.chat_settings(key.chat_id);
+    let tz = digest_timezone(state, &chat_settings);
+    let now = Utc::now();
+    let mut store = state.store.lock().await;
+    // Not scheduled, or turned off while the digest was being made
+    if let Some(schedule) = store.digest_schedules.get_mut(&key) {
+        schedule.next_due = dayslice::next_occurrence(tz, schedule.time, now);
+        if matches!(outcome, Ok(DigestOutcome::Sent(_))) {
+            schedule.last_digest_at = Some(now);
+        }
+        store.save_digest_schedules();
+    }
+    outcome
+}
+
+// Post scheduled digests as they come due, for as long as the bot runs
+pub async fn run_digest_scheduler(bot: Bot, state: AppState) {
+    let mut ticker = tokio::time::interval(DIGEST_TICK);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let due = state.store.lock().await.due_digests(Utc::now());
+        let due_count = due.len();
+        for (done, key) in due.into_iter().enumerate() {
+            // One heartbeat per digest, so a round of slow ones isn't taken for
+            // a hung scheduler
+            state.tasks.beat(
+                "digest scheduler",
+                format!("{} of {} due digests done", done, due_count),
+                Utc::now(),
+            );
+            match run_scheduled_digest(&bot, &state, key.clone(), None).await {
+                Ok(DigestOutcome::Sent(_)) => {}
+                Ok(DigestOutcome::NothingNew) => {
+                    debug!(target: "digest", "Nothing new for the scheduled digest of chat {} thread {:?}", key.chat_id, key.thread_id)
+                }
+                Ok(DigestOutcome::OverBudget) => {
+                    warn!(target: "digest", "Skipped the scheduled digest of chat {} thread {:?}: budget used up", key.chat_id, key.thread_id)
+                }
+                Err(e) => {
+                    warn!(target: "digest", "Scheduled digest of chat {} thread {:?} failed: {}", key.chat_id, key.thread_id, e)
+                }
+            }
+        }
+        state.tasks.beat(
+            "digest scheduler",
+            format!("{} digests due", due_count),
+            Utc::now(),
+        );
+    }
+}
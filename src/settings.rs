@@ -0,0 +1,257 @@
+use crate::locale::Locale;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+
+// Operator-level defaults chosen through the setup wizard. Secrets never live
+// here; API keys stay in the environment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BotSettings {
+    // Overrides the primary provider's model
+    pub model: Option<String>,
+    // IANA timezone name used when a chat hasn't set its own
+    pub default_timezone: Option<String>,
+    // Default "HH:MM" time for daily digests
+    pub default_digest_time: Option<String>,
+}
+
+impl BotSettings {
+    pub fn is_incomplete(&self) -> bool {
+        self.model.is_none()
+            || self.default_timezone.is_none()
+            || self.default_digest_time.is_none()
+    }
+
+    pub fn describe(&self) -> String {
+        fn or_default(value: &Option<String>) -> &str {
+            value.as_deref().unwrap_or("default")
+        }
+        format!(
+            "Model: {}\nDefault timezone: {}\nDefault digest time: {}",
+            or_default(&self.model),
+            or_default(&self.default_timezone),
+            or_default(&self.default_digest_time)
+        )
+    }
+}
+
+// Settings the owner chose in one go, e.g. a finished wizard. Only these are
+// written, so changes made to other fields in the meantime survive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BotSettingsChanges {
+    pub model: Option<String>,
+    pub default_timezone: Option<String>,
+    pub default_digest_time: Option<String>,
+}
+
+impl BotSettingsChanges {
+    pub fn apply(&self, settings: &mut BotSettings) {
+        if let Some(model) = &self.model {
+            settings.model = Some(model.clone());
+        }
+        if let Some(timezone) = &self.default_timezone {
+            settings.default_timezone = Some(timezone.clone());
+        }
+        if let Some(time) = &self.default_digest_time {
+            settings.default_digest_time = Some(time.clone());
+        }
+    }
+}
+
+pub type BotSettingsType = Arc<Mutex<BotSettings>>;
+
+// Apply a change under the lock and return the settings as they are after it,
+// so concurrent changes to different fields don't overwrite each other
+pub async fn update_bot_settings(
+    settings: &BotSettingsType,
+    update: impl FnOnce(&mut BotSettings),
+) -> BotSettings {
+    let mut settings = settings.lock().await;
+    update(&mut settings);
+    settings.clone()
+}
+
+// How the bot signals that a summary is being generated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaceholderMode {
+    // Post "Summarizing N messages..." and edit it into the summary
+    #[default]
+    Edit,
+    // No placeholder; the summary is sent once, without a notification
+    Silent,
+    // React 👀 to the command while working, then send the summary
+    Reaction,
+}
+
+impl PlaceholderMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "edit" => Some(PlaceholderMode::Edit),
+            "silent" => Some(PlaceholderMode::Silent),
+            "reaction" => Some(PlaceholderMode::Reaction),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PlaceholderMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaceholderMode::Edit => write!(f, "edit"),
+            PlaceholderMode::Silent => write!(f, "silent"),
+            PlaceholderMode::Reaction => write!(f, "reaction"),
+        }
+    }
+}
+
+// What the summary replies to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyAnchor {
+    // The /summarize command
+    #[default]
+    Command,
+    // The first summarized message, so tapping the reply jumps to where the
+    // conversation begins
+    RangeStart,
+}
+
+impl ReplyAnchor {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "command" => Some(ReplyAnchor::Command),
+            "start" => Some(ReplyAnchor::RangeStart),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ReplyAnchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplyAnchor::Command => write!(f, "command"),
+            ReplyAnchor::RangeStart => write!(f, "start"),
+        }
+    }
+}
+
+// Per-chat preferences changed with /settings. Persisted as JSON, so fields
+// missing from older rows fall back to their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatSettings {
+    pub placeholder_mode: PlaceholderMode,
+    pub reply_anchor: ReplyAnchor,
+    // Admin opt-in to send messages of a content-protected chat to the provider
+    pub allow_protected: bool,
+    // Most messages one summary may cover, below the global limit
+    pub max_summarize: Option<usize>,
+    // Whether chat admins may go past max_summarize
+    pub admins_exempt: bool,
+    // IANA timezone for day slices like "yesterday"; the bot default if unset
+    pub timezone: Option<String>,
+    // Language code summaries are written in; the conversation's own if unset
+    pub language: Option<String>,
+    // Send pasted logs and code to the provider in full instead of condensed
+    pub keep_pastes: bool,
+    // Lowercase @usernames (without the @) left out of summaries
+    pub ignored_users: BTreeSet<String>,
+    // Chat-specific terms and their meanings, given to the model with every
+    // conversation
+    pub glossary: BTreeMap<String, String>,
+    // Terms whose messages are left out of summaries and which are redacted
+    // from summaries, as admins typed them
+    pub blocked_terms: BTreeSet<String>,
+    // Leave out the note saying chat settings excluded some messages
+    pub hide_exclusion_note: bool,
+    // Only administrators may run commands that reveal the conversation
+    pub restricted: bool,
+}
+
+impl ChatSettings {
+    // Replies format numbers and dates for the chat's summary language
+    pub fn locale(&self) -> Locale {
+        Locale::from_code(self.language.as_deref())
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "Placeholder mode: {}\nReply anchor: {}\nSummaries in content-protected chat: {}\n\
+            Max messages per summary: {}{}\nTimezone: {}\nSummary language: {}\nPasted logs and code: {}\n\
+            Ignored users: {}\nGlossary entries: {}\nBlocked terms: {}\nExclusion note: {}\nSummaries: {}",
+            self.placeholder_mode,
+            self.reply_anchor,
+            if self.allow_protected {
+                "allowed"
+            } else {
+                "not allowed"
+            },
+            self.max_summarize
+                .map(|max| max.to_string())
+                .unwrap_or_else(|| "no chat limit".to_string()),
+            if self.max_summarize.is_some() && self.admins_exempt {
+                " (admins exempt)"
+            } else {
+                ""
+            },
+            self.timezone.as_deref().unwrap_or("bot default"),
+            self.language
+                .as_deref()
+                .unwrap_or("same as the conversation"),
+            if self.keep_pastes {
+                "summarized in full"
+            } else {
+                "condensed"
+            },
+            if self.ignored_users.is_empty() {
+                "none".to_string()
+            } else {
+                format_usernames(&self.ignored_users)
+            },
+            self.glossary.len(),
+            self.blocked_terms.len(),
+            if self.hide_exclusion_note {
+                "hidden"
+            } else {
+                "shown"
+            },
+            if self.restricted {
+                "admins only"
+            } else {
+                "everyone"
+            }
+        )
+    }
+
+    // Whether messages from this sender are left out of summaries
+    pub fn ignores(&self, username: Option<&str>) -> bool {
+        username.is_some_and(|username| self.ignored_users.contains(&username.to_lowercase()))
+    }
+}
+
+// "@Name" or "name" as stored in ignored_users; None if it can't be a username
+pub fn normalize_username(value: &str) -> Option<String> {
+    let name = value.trim().trim_start_matches('@');
+    (!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        .then(|| name.to_lowercase())
+}
+
+pub fn format_usernames(usernames: &BTreeSet<String>) -> String {
+    usernames
+        .iter()
+        .map(|name| format!("@{}", name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn parse_toggle(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "on" | "true" | "yes" => Some(true),
+        "off" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,42 @@
+// Incremental parser for the server-sent events of a streaming chat
+// completion. Network chunks can end anywhere, even inside a UTF-8 sequence,
+// so bytes are buffered until a full line is available.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SseEvent {
+    // The payload of a `data:` line, usually a JSON chunk
+    Data(String),
+    // The `data: [DONE]` sentinel that ends an OpenAI-style stream
+    Done,
+}
+
+impl SseParser {
+    // Feed a chunk of the response body, returning the events it completed.
+    // Comments, blank lines and fields other than `data` are skipped.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.strip_prefix(' ').unwrap_or(data);
+            if data == "[DONE]" {
+                events.push(SseEvent::Done);
+            } else if !data.is_empty() {
+                events.push(SseEvent::Data(data.to_string()));
+            }
+        }
+
+        events
+    }
+}
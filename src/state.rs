@@ -0,0 +1,156 @@
+use crate::{
+    access::BlocklistType,
+    admins::{AdminCacheType, is_chat_admin},
+    budget::{self, BudgetEvent, BudgetType},
+    chatinfo::ChatInfoCacheType,
+    config::Config,
+    dayslice,
+    destination::ChatDestination,
+    events::EventSink,
+    forwards::PendingForwardsType,
+    inline::RecentChatsType,
+    limits::{self, ChatKind},
+    llm::{Completion, LlmProviders},
+    metrics::MetricsType,
+    persist::Database,
+    pipeline::TaskContext,
+    ratelimit::{InFlightType, RateLimiterType},
+    settings::{BotSettingsType, ChatSettings},
+    stats::StatsType,
+    store::MessageStoreType,
+    tasks::TaskRegistryType,
+    wizard::WizardSessionsType,
+};
+use chrono::Utc;
+use log::warn;
+use std::sync::Arc;
+use teloxide::{prelude::*, types::Message};
+
+pub const BUDGET_META_KEY: &str = "budget";
+
+// Shared handles injected into every handler
+#[derive(Clone)]
+pub struct AppState {
+    pub store: MessageStoreType,
+    pub config: Arc<Config>,
+    pub stats: StatsType,
+    pub blocklist: BlocklistType,
+    pub llm: Arc<LlmProviders>,
+    pub settings: BotSettingsType,
+    pub wizards: WizardSessionsType,
+    pub chat_info: ChatInfoCacheType,
+    pub admins: AdminCacheType,
+    pub budget: BudgetType,
+    pub rate_limiter: RateLimiterType,
+    pub in_flight: InFlightType,
+    pub recent_chats: RecentChatsType,
+    pub forwards: PendingForwardsType,
+    // From get_me at startup; None if Telegram couldn't be asked
+    pub bot_username: Option<Arc<str>>,
+    pub events: Option<EventSink>,
+    pub database: Option<Arc<Database>>,
+    pub metrics: MetricsType,
+    pub tasks: TaskRegistryType,
+}
+
+impl AppState {
+    // The parts a provider round needs, answered by the configured providers
+    pub fn task_context(&self) -> TaskContext<'_, LlmProviders> {
+        TaskContext {
+            config: &self.config,
+            stats: &self.stats,
+            metrics: &self.metrics,
+            llm: &self.llm,
+        }
+    }
+}
+
+// The most messages this sender may summarize in this chat
+pub async fn summarize_limit(
+    bot: &Bot,
+    msg: &Message,
+    chat_settings: &ChatSettings,
+    state: &AppState,
+) -> ResponseResult<usize> {
+    let limits =
+        limits::resolve_effective_limits(chat_settings, &state.config, ChatKind::of(&msg.chat));
+    // Only ask Telegram about admin status when it can make a difference
+    let is_admin = limits.admins_exempt && is_chat_admin(bot, &state.admins, msg).await?;
+    Ok(limits.summarize_limit(is_admin))
+}
+
+// The budget month follows the configured default timezone
+pub async fn budget_month(state: &AppState) -> budget::BudgetMonth {
+    let timezone = state.settings.lock().await.default_timezone.clone();
+    budget::current_month(timezone.as_deref(), Utc::now())
+}
+
+pub async fn chat_timezone(state: &AppState, chat_settings: &ChatSettings) -> chrono_tz::Tz {
+    let default = state.settings.lock().await.default_timezone.clone();
+    dayslice::timezone(chat_settings.timezone.as_deref(), default.as_deref())
+}
+
+// Count a provider call against the monthly budget and tell the owner about
+// thresholds it crossed
+pub async fn charge_budget(bot: &Bot, state: &AppState, completion: &Completion) {
+    let month = budget_month(state).await;
+    let event = {
+        let mut budget = state.budget.lock().await;
+        let rates = state.config.cost_rates.unwrap_or(budget::DEFAULT_RATES);
+        let cost = budget::call_cost(completion.usage, completion.chars, rates);
+        let event = budget.record(month, cost);
+        if let Some(database) = &state.database {
+            database.save_meta(BUDGET_META_KEY, &budget.state());
+        }
+        event
+    };
+    let Some(event) = event else {
+        return;
+    };
+
+    let text = match event {
+        BudgetEvent::WarningReached => {
+            warn!(target: "budget", "Monthly budget warning threshold reached");
+            "The monthly summarization budget is almost used up."
+        }
+        BudgetEvent::CapReached => {
+            warn!(target: "budget", "Monthly budget reached, falling back to extracts");
+            "The monthly summarization budget is used up. Summaries show extracts until the \
+            month ends; use /admin budget lift to override."
+        }
+    };
+    let Some(owner) = state.config.owner_user_id else {
+        return;
+    };
+    let status = state.budget.lock().await.status_line(month);
+    if let Err(e) = ChatDestination::new(owner.into(), None)
+        .message(bot, format!("{}\n{}", text, status))
+        .await
+    {
+        warn!(target: "budget", "Couldn't notify the owner about the budget: {}", e);
+    }
+}
+
+// Tell the owner, once a day per model, when the provider starts serving a
+// different model than earlier that day for the same request
+pub async fn notify_model_change(bot: &Bot, state: &AppState, completion: &Completion) {
+    let (Some((before, now)), Some(owner)) =
+        (&completion.served_model_changed, state.config.owner_user_id)
+    else {
+        return;
+    };
+    warn!(target: "api", "{} switched from serving {} to {} for {}", completion.provider, before, now, completion.requested_model);
+    if let Err(e) = ChatDestination::new(owner.into(), None)
+        .message(
+            bot,
+            format!(
+                "{} is now answering requests for {} with {} (earlier today: {}). Summary \
+                quality may change.",
+                completion.provider, completion.requested_model, now, before
+            ),
+        )
+        .await
+    {
+        warn!(target: "api", "Couldn't notify the owner about the model change: {}", e);
+    }
+}
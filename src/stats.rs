@@ -0,0 +1,196 @@
+use crate::{
+    config::PromptVariant,
+    llm::Usage,
+    timing::{StageStats, StageTimings},
+    usage::UsageStats,
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use teloxide::types::ChatId;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VariantStats {
+    pub summaries: u64,
+    pub total_length: u64,
+}
+
+impl VariantStats {
+    pub fn average_length(&self) -> u64 {
+        self.total_length.checked_div(self.summaries).unwrap_or(0)
+    }
+}
+
+// Counters reported to the bot owner via /admin stats
+#[derive(Debug, Default)]
+pub struct BotStats {
+    variants: BTreeMap<PromptVariant, VariantStats>,
+    preparation: PreparationStats,
+    stages: StageStats,
+    panics: u64,
+    last_panic_notice: Option<DateTime<Utc>>,
+    usage: UsageStats,
+    // Classes of the latest failed requests, oldest first
+    recent_errors: VecDeque<(DateTime<Utc>, &'static str)>,
+}
+
+// The owner hears about at most one panic per this many minutes
+const PANIC_NOTICE_INTERVAL_MINUTES: i64 = 10;
+// Failures kept for /admin dump
+const RECENT_ERRORS: usize = 20;
+
+#[derive(Debug, Serialize)]
+pub struct StatsDebug {
+    pub summaries_per_variant: BTreeMap<String, u64>,
+    pub preparation_runs: u64,
+    pub preparation_max_ms: u64,
+    pub preparation_degraded: u64,
+    pub messages_blocked: u64,
+    pub panics: u64,
+    pub recent_errors: Vec<ErrorDebug>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorDebug {
+    pub at: DateTime<Utc>,
+    pub class: &'static str,
+}
+
+// Time spent turning snapshots into prompts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PreparationStats {
+    pub runs: u64,
+    pub total: Duration,
+    pub max: Duration,
+    // Runs that hit the soft cap and skipped optional passes
+    pub degraded: u64,
+    // Messages left out for containing a chat's blocked terms
+    pub blocked: u64,
+}
+
+impl BotStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_summary(&mut self, variant: PromptVariant, summary: &str) {
+        let entry = self.variants.entry(variant).or_default();
+        entry.summaries += 1;
+        entry.total_length += summary.chars().count() as u64;
+    }
+
+    pub fn variant_stats(&self) -> &BTreeMap<PromptVariant, VariantStats> {
+        &self.variants
+    }
+
+    pub fn record_preparation(&mut self, elapsed: Duration, degraded: bool, blocked: usize) {
+        let stats = &mut self.preparation;
+        stats.runs += 1;
+        stats.blocked += blocked as u64;
+        stats.total += elapsed;
+        stats.max = stats.max.max(elapsed);
+        if degraded {
+            stats.degraded += 1;
+        }
+    }
+
+    pub fn record_stages(&mut self, timings: &StageTimings) {
+        self.stages.record(timings);
+    }
+
+    pub fn stages(&self) -> &StageStats {
+        &self.stages
+    }
+
+    // Count a handler panic; returns whether the owner should be told
+    pub fn record_panic(&mut self, now: DateTime<Utc>) -> bool {
+        self.panics += 1;
+        let due = self.last_panic_notice.is_none_or(|last| {
+            now - last >= ChronoDuration::minutes(PANIC_NOTICE_INTERVAL_MINUTES)
+        });
+        if due {
+            self.last_panic_notice = Some(now);
+        }
+        due
+    }
+
+    pub fn record_usage(&mut self, chat_id: ChatId, usage: Option<Usage>) {
+        self.usage.record(chat_id, usage);
+    }
+
+    pub fn usage(&self) -> &UsageStats {
+        &self.usage
+    }
+
+    // Remember how a request failed, e.g. "provider_rate_limited"
+    pub fn record_error(&mut self, class: &'static str, now: DateTime<Utc>) {
+        if self.recent_errors.len() == RECENT_ERRORS {
+            self.recent_errors.pop_front();
+        }
+        self.recent_errors.push_back((now, class));
+    }
+
+    pub fn debug_snapshot(&self) -> StatsDebug {
+        StatsDebug {
+            summaries_per_variant: self
+                .variants
+                .iter()
+                .map(|(variant, stats)| (format!("{:?}", variant), stats.summaries))
+                .collect(),
+            preparation_runs: self.preparation.runs,
+            preparation_max_ms: self.preparation.max.as_millis() as u64,
+            preparation_degraded: self.preparation.degraded,
+            messages_blocked: self.preparation.blocked,
+            panics: self.panics,
+            recent_errors: self
+                .recent_errors
+                .iter()
+                .map(|(at, class)| ErrorDebug { at: *at, class })
+                .collect(),
+        }
+    }
+
+    pub fn panics(&self) -> u64 {
+        self.panics
+    }
+
+    pub fn preparation_report(&self) -> String {
+        let stats = &self.preparation;
+        if stats.runs == 0 {
+            return "Prompt preparation: no runs yet".to_string();
+        }
+        format!(
+            "Prompt preparation: {} runs, average {} ms, max {} ms, {} over the soft cap, \
+            {} messages left out for blocked terms",
+            stats.runs,
+            (stats.total / stats.runs as u32).as_millis(),
+            stats.max.as_millis(),
+            stats.degraded,
+            stats.blocked
+        )
+    }
+}
+
+pub type StatsType = Arc<Mutex<BotStats>>;
+
+pub fn format_variant_report(variants: &BTreeMap<PromptVariant, VariantStats>) -> String {
+    if variants.is_empty() {
+        return "No summaries generated with prompt variants yet.".to_string();
+    }
+
+    let mut report = String::from("Prompt variants:");
+    for (variant, stats) in variants {
+        report.push_str(&format!(
+            "\n{}: {} summaries, average length {} chars",
+            variant,
+            stats.summaries,
+            stats.average_length()
+        ));
+    }
+    report
+}
@@ -0,0 +1,1210 @@
+use crate::{
+    access, aggregate,
+    compaction::CompactionConfig,
+    digest::{self, DigestSchedule},
+    dump, footprint, lang,
+    locale::Locale,
+    media::MessageKind,
+    persist::{self, Database, StoreSnapshot},
+    reactions, replies,
+    settings::ChatSettings,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+use teloxide::types::{ChatId, MessageId, ThreadId};
+use tokio::sync::Mutex;
+
+pub const MAX_MESSAGES: usize = 1000;
+// Sequence numbers start here, leaving room below for imported history
+pub const SEQ_BASE: u64 = 1 << 40;
+// Once MAX_TRACKED_CHATS is reached, chats without messages for this long are
+// dropped to make room for new ones
+pub const CAPACITY_IDLE_HOURS: i64 = 24;
+// Minimum time between those sweeps while chats keep being turned away
+const CAPACITY_SWEEP_INTERVAL_SECS: i64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChatThreadId {
+    pub chat_id: ChatId,
+    pub thread_id: Option<ThreadId>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedMessage {
+    // Position in arrival order, assigned by the store. Telegram message ids can
+    // arrive out of order (forwarded bursts, basic groups share a global counter),
+    // so ordering and ranges use this and ids are only used for lookups and links.
+    pub seq: u64,
+    pub message_id: MessageId,
+    pub from_user: Option<String>, // Username or first_name
+    // The sender's @username without the @, for /ignore; None if they have none
+    #[serde(default)]
+    pub username: Option<String>,
+    pub reply_to_message_id: Option<MessageId>,
+    // Author of the replied-to message, kept in case that message is deleted
+    // or falls outside the summarized range
+    pub reply_to_user: Option<String>,
+    // The text, caption, forwarded text or poll question; may be empty for media
+    pub text: String,
+    pub kind: MessageKind,
+    pub timestamp: DateTime<Utc>,
+    // Detected at ingest; None for short or unrecognized texts
+    #[serde(deserialize_with = "lang::deserialize_code")]
+    pub lang: Option<lang::LangCode>,
+    // A compacted summary of earlier messages rather than something a user sent
+    pub synthetic: bool,
+    // The text was changed after it was sent
+    pub edited: bool,
+    // Reactions to the message, by emoji
+    #[serde(default)]
+    pub reactions: reactions::ReactionCounts,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageStore {
+    // Map of chat_id+thread_id to message queue for that chat/thread
+    pub chats: HashMap<ChatThreadId, VecDeque<SavedMessage>>,
+    // Messages across all queues, kept in step with `chats` so /memory doesn't
+    // have to walk every queue
+    pub total_messages: usize,
+    // Estimated bytes held by all queues, kept in step the same way
+    pub total_bytes: usize,
+    // Oldest messages of the largest queues are evicted beyond this; None for
+    // no limit
+    pub max_store_bytes: Option<usize>,
+    // Full queues wait for compaction instead of evicting while this is set
+    // and the chat/thread has compactions left today
+    pub compaction: Option<CompactionConfig>,
+    // Chats with at least one queue in `chats`, kept in step with it
+    pub tracked_chats: HashSet<ChatId>,
+    // New chats aren't stored beyond this many; None for no limit
+    pub max_tracked_chats: Option<usize>,
+    // When idle chats were last dropped to make room
+    pub last_capacity_sweep: Option<DateTime<Utc>>,
+    // Set once the owner has been told the limit was reached, until there is
+    // room again
+    pub capacity_reported: bool,
+    // When the first message of each chat/thread was stored
+    pub first_seen: HashMap<ChatThreadId, DateTime<Utc>>,
+    // Next insertion sequence number handed out by add_message
+    pub next_seq: u64,
+    // Per-chat preferences; chats without an entry use the defaults
+    pub settings: HashMap<ChatId, ChatSettings>,
+    // Messages dropped from each chat/thread because its queue was full
+    pub evictions: HashMap<ChatThreadId, u64>,
+    // Compactions started per chat/thread on the given UTC day
+    pub compactions: HashMap<ChatThreadId, (NaiveDate, u32)>,
+    // Chats/threads with a compaction waiting on the provider
+    pub compacting: HashSet<ChatThreadId>,
+    // Newest sequence number each chat's or thread's last digest covered. A
+    // key without a thread covers every thread of the chat.
+    pub digest_watermarks: HashMap<ChatThreadId, u64>,
+    // Daily digests set up with /digest on
+    pub digest_schedules: HashMap<ChatThreadId, DigestSchedule>,
+    // Text of the last digest posted in each chat/thread
+    pub digest_texts: HashMap<ChatThreadId, String>,
+    // Last summary made in each chat/thread, for /regenerate. Only kept in
+    // memory; it names the summarized messages by sequence number rather than
+    // holding on to them.
+    pub last_summaries: HashMap<ChatThreadId, LastSummary>,
+    // Chats allowed or blocked with /allowchat and /blockchat
+    pub chat_access: access::ChatOverrides,
+    // Start of the time range the store can cover; carried over from a snapshot
+    pub startup_time: DateTime<Utc>,
+    // When this process started, for uptime
+    pub launched_at: DateTime<Utc>,
+    // When the snapshot this store was restored from was taken
+    pub restored_from: Option<DateTime<Utc>>,
+    // Write-through copy on disk when DATABASE_PATH is set
+    pub database: Option<Arc<Database>>,
+}
+
+impl Default for MessageStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageStore {
+    pub fn new() -> Self {
+        Self {
+            chats: HashMap::new(),
+            total_messages: 0,
+            total_bytes: 0,
+            max_store_bytes: None,
+            compaction: None,
+            tracked_chats: HashSet::new(),
+            max_tracked_chats: None,
+            last_capacity_sweep: None,
+            capacity_reported: false,
+            first_seen: HashMap::new(),
+            next_seq: SEQ_BASE,
+            settings: HashMap::new(),
+            evictions: HashMap::new(),
+            compactions: HashMap::new(),
+            compacting: HashSet::new(),
+            digest_watermarks: HashMap::new(),
+            digest_schedules: HashMap::new(),
+            digest_texts: HashMap::new(),
+            last_summaries: HashMap::new(),
+            chat_access: access::ChatOverrides::default(),
+            startup_time: Utc::now(),
+            launched_at: Utc::now(),
+            restored_from: None,
+            database: None,
+        }
+    }
+
+    // A store backed by the database, starting from what it holds
+    pub fn with_database(database: Arc<Database>) -> rusqlite::Result<Self> {
+        let loaded = database.load(MAX_MESSAGES)?;
+        let mut store = Self::new();
+
+        for (key, messages) in loaded.chats {
+            if let Some(oldest) = messages.iter().find(|m| !m.synthetic) {
+                store.first_seen.insert(key.clone(), oldest.timestamp);
+            }
+            if let Some(last) = messages.last() {
+                store.next_seq = store.next_seq.max(last.seq + 1);
+            }
+            store.total_messages += messages.len();
+            store.total_bytes += footprint::queue_bytes(&messages);
+            store.tracked_chats.insert(key.chat_id);
+            store.chats.insert(key, messages.into());
+        }
+        store.settings = loaded.settings;
+        // Watermarks used to be kept per chat only
+        store.digest_watermarks = database
+            .load_meta::<Vec<(ChatThreadId, u64)>>(digest::WATERMARKS_META_KEY)
+            .map(|watermarks| watermarks.into_iter().collect())
+            .or_else(|| {
+                database
+                    .load_meta::<HashMap<ChatId, u64>>(digest::WATERMARKS_META_KEY)
+                    .map(|watermarks| {
+                        watermarks
+                            .into_iter()
+                            .map(|(chat_id, seq)| {
+                                let key = ChatThreadId {
+                                    chat_id,
+                                    thread_id: None,
+                                };
+                                (key, seq)
+                            })
+                            .collect()
+                    })
+            })
+            .unwrap_or_default();
+        store.digest_schedules = database
+            .load_meta::<Vec<(ChatThreadId, DigestSchedule)>>(digest::SCHEDULES_META_KEY)
+            .map(|schedules| schedules.into_iter().collect())
+            .unwrap_or_default();
+        store.digest_texts = database
+            .load_meta::<Vec<(ChatThreadId, String)>>(digest::TEXTS_META_KEY)
+            .map(|texts| texts.into_iter().collect())
+            .unwrap_or_default();
+        store.chat_access = database
+            .load_meta(access::CHAT_ACCESS_META_KEY)
+            .unwrap_or_default();
+        info!(target: "persist", "Loaded {} messages in {} chats/threads",
+            store.total_messages, store.chats.len());
+
+        store.database = Some(database);
+        store.make_seq_headroom();
+        Ok(store)
+    }
+
+    // Whether a chat can be stored without going over MAX_TRACKED_CHATS
+    pub fn has_room_for(&self, chat_id: ChatId) -> bool {
+        self.tracked_chats.contains(&chat_id)
+            || self
+                .max_tracked_chats
+                .is_none_or(|max| self.tracked_chats.len() < max)
+    }
+
+    // Decide whether messages of a chat may be stored. At the limit, chats
+    // idle for CAPACITY_IDLE_HOURS are dropped to make room, at most once per
+    // CAPACITY_SWEEP_INTERVAL_SECS.
+    fn admit(&mut self, chat_id: ChatId, now: DateTime<Utc>) -> Admission {
+        if self.has_room_for(chat_id) {
+            return Admission::Admitted;
+        }
+        let swept_recently = self.last_capacity_sweep.is_some_and(|at| {
+            now.signed_duration_since(at) < chrono::Duration::seconds(CAPACITY_SWEEP_INTERVAL_SECS)
+        });
+        if !swept_recently {
+            self.last_capacity_sweep = Some(now);
+            let dropped = self.drop_idle_chats(now - chrono::Duration::hours(CAPACITY_IDLE_HOURS));
+            if dropped > 0 {
+                info!(target: "store", "Dropped {} idle chats to make room for new ones", dropped);
+            }
+            if self.has_room_for(chat_id) {
+                self.capacity_reported = false;
+                return Admission::Admitted;
+            }
+        }
+        Admission::Denied {
+            first: !std::mem::replace(&mut self.capacity_reported, true),
+        }
+    }
+
+    // Forget every chat whose newest message is older than `cutoff`. Chat
+    // settings and digest schedules stay. Returns how many chats were dropped.
+    fn drop_idle_chats(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let mut newest: HashMap<ChatId, DateTime<Utc>> = HashMap::new();
+        for (key, queue) in &self.chats {
+            if let Some(last) = queue.back() {
+                let entry = newest.entry(key.chat_id).or_insert(last.timestamp);
+                *entry = (*entry).max(last.timestamp);
+            }
+        }
+        let idle: Vec<ChatId> = self
+            .tracked_chats
+            .iter()
+            .filter(|chat_id| newest.get(chat_id).is_none_or(|last| *last < cutoff))
+            .copied()
+            .collect();
+        for chat_id in &idle {
+            self.remove_chat(*chat_id);
+        }
+        idle.len()
+    }
+
+    // Drop the stored history of every thread of a chat
+    pub fn remove_chat(&mut self, chat_id: ChatId) {
+        let keys: Vec<ChatThreadId> = self
+            .chats
+            .keys()
+            .filter(|key| key.chat_id == chat_id)
+            .cloned()
+            .collect();
+        for key in keys {
+            if let Some(queue) = self.chats.remove(&key) {
+                self.total_messages -= queue.len();
+                self.total_bytes -= footprint::queue_bytes(&queue);
+            }
+            self.first_seen.remove(&key);
+            self.evictions.remove(&key);
+            self.compactions.remove(&key);
+            if let Some(database) = &self.database {
+                database.delete_chat_thread(&key);
+            }
+        }
+        self.last_summaries.retain(|key, _| key.chat_id != chat_id);
+        self.tracked_chats.remove(&chat_id);
+        debug!(target: "store", "Dropped the stored history of chat {}", chat_id);
+    }
+
+    pub fn add_message(
+        &mut self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        mut message: SavedMessage,
+    ) -> Admission {
+        let admission = self.admit(chat_id, Utc::now());
+        if admission != Admission::Admitted {
+            return admission;
+        }
+        self.tracked_chats.insert(chat_id);
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+
+        message.seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.first_seen
+            .entry(chat_thread_id.clone())
+            .or_insert(message.timestamp);
+
+        let queued = self
+            .chats
+            .entry(chat_thread_id.clone())
+            .or_insert_with(|| VecDeque::with_capacity(MAX_MESSAGES))
+            .len();
+
+        // A queue left over the limit by a failed compaction is brought back
+        // down once eviction takes over again
+        if queued >= MAX_MESSAGES
+            && !self.awaits_compaction(&chat_thread_id, Utc::now().date_naive())
+        {
+            for _ in MAX_MESSAGES - 1..queued {
+                if !self.evict_oldest(&chat_thread_id) {
+                    break;
+                }
+                *self.evictions.entry(chat_thread_id.clone()).or_default() += 1;
+            }
+        }
+        if let Some(database) = &self.database {
+            database.insert_message(&chat_thread_id, &message);
+        }
+        self.total_messages += 1;
+        self.total_bytes += footprint::message_bytes(&message);
+        self.chats
+            .entry(chat_thread_id)
+            .or_default()
+            .push_back(message);
+        self.enforce_byte_limit();
+        Admission::Admitted
+    }
+
+    // Whether a full queue can keep its oldest messages until a compaction
+    // replaces them: one is running already, or there's one left for today
+    fn awaits_compaction(&self, key: &ChatThreadId, today: NaiveDate) -> bool {
+        let Some(config) = &self.compaction else {
+            return false;
+        };
+        self.compacting.contains(key)
+            || self
+                .compactions
+                .get(key)
+                .is_none_or(|(day, count)| *day != today || *count < config.max_per_day)
+    }
+
+    // Drop the oldest message of a queue, keeping a compacted summary at the
+    // front. Returns false if there was nothing to drop.
+    fn evict_oldest(&mut self, key: &ChatThreadId) -> bool {
+        let Some(queue) = self.chats.get_mut(key) else {
+            return false;
+        };
+        let Some(evicted) = footprint::evictable_position(queue).and_then(|at| queue.remove(at))
+        else {
+            return false;
+        };
+        self.total_messages -= 1;
+        self.total_bytes -= footprint::message_bytes(&evicted);
+        if let Some(database) = &self.database {
+            database.delete_message(key, evicted.message_id);
+        }
+        true
+    }
+
+    // Evict from the largest queues until the store fits MAX_STORE_BYTES
+    pub fn enforce_byte_limit(&mut self) {
+        let Some(max) = self.max_store_bytes else {
+            return;
+        };
+        let mut evicted = 0;
+        while self.total_bytes > max {
+            let Some(victim) = footprint::pick_victim(&self.chats) else {
+                break;
+            };
+            if !self.evict_oldest(&victim) {
+                break;
+            }
+            evicted += 1;
+        }
+        if evicted > 0 {
+            debug!(target: "store", "Evicted {} messages to stay under MAX_STORE_BYTES ({} bytes)", evicted, max);
+        }
+    }
+
+    // Replace the text of an edited message. Returns false if the message isn't
+    // stored, e.g. because it was evicted or compacted already.
+    pub fn update_message(
+        &mut self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        message_id: MessageId,
+        new_text: String,
+    ) -> bool {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+        let Some(message) = self.chats.get_mut(&chat_thread_id).and_then(|queue| {
+            queue
+                .iter_mut()
+                .find(|message| !message.synthetic && message.message_id == message_id)
+        }) else {
+            return false;
+        };
+
+        message.lang = lang::detect_language(&new_text);
+        self.total_bytes = self.total_bytes - message.text.len() + new_text.len();
+        message.text = new_text;
+        message.edited = true;
+        if let Some(database) = &self.database {
+            database.insert_message(&chat_thread_id, message);
+        }
+        // A longer edit can take the store past MAX_STORE_BYTES
+        self.enforce_byte_limit();
+        true
+    }
+
+    // Update the reactions of a stored message with `update`. Reaction updates
+    // don't say which thread the message is in, so every thread of the chat is
+    // searched. Messages that were never stored or have been evicted are
+    // skipped; returns whether one was found.
+    pub fn apply_reaction(
+        &mut self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        update: impl FnOnce(&mut reactions::ReactionCounts),
+    ) -> bool {
+        let found = self
+            .chats
+            .iter_mut()
+            .filter(|(key, _)| key.chat_id == chat_id)
+            .find_map(|(key, queue)| {
+                queue
+                    .iter_mut()
+                    .find(|message| !message.synthetic && message.message_id == message_id)
+                    .map(|message| (key, message))
+            });
+        let Some((key, message)) = found else {
+            return false;
+        };
+        update(&mut message.reactions);
+        if let Some(database) = &self.database {
+            database.insert_message(key, message);
+        }
+        true
+    }
+
+    pub fn digest_watermark(&self, key: &ChatThreadId) -> Option<u64> {
+        self.digest_watermarks.get(key).copied()
+    }
+
+    pub fn set_digest_watermark(&mut self, key: ChatThreadId, seq: u64) {
+        self.digest_watermarks.insert(key, seq);
+        self.save_digest_watermarks();
+    }
+
+    fn save_digest_watermarks(&self) {
+        if let Some(database) = &self.database {
+            let watermarks: Vec<_> = self.digest_watermarks.iter().collect();
+            database.save_meta(digest::WATERMARKS_META_KEY, &watermarks);
+        }
+    }
+
+    pub fn last_digest_text(&self, key: &ChatThreadId) -> Option<&str> {
+        self.digest_texts.get(key).map(String::as_str)
+    }
+
+    pub fn set_last_digest_text(&mut self, key: ChatThreadId, text: String) {
+        self.digest_texts.insert(key, text);
+        if let Some(database) = &self.database {
+            let texts: Vec<_> = self.digest_texts.iter().collect();
+            database.save_meta(digest::TEXTS_META_KEY, &texts);
+        }
+    }
+
+    pub fn last_summary(&self, key: &ChatThreadId) -> Option<LastSummary> {
+        self.last_summaries.get(key).cloned()
+    }
+
+    pub fn set_last_summary(&mut self, key: ChatThreadId, mut summary: LastSummary) {
+        summary.seqs.sort_unstable();
+        self.last_summaries.insert(key, summary);
+    }
+
+    // The messages of a chat's/thread's last summary that are still stored.
+    // Cross-thread summaries take everything in the covered range and collapse
+    // cross-posts again, since only the first copy of each was kept.
+    pub fn regenerate_snapshot(&self, key: &ChatThreadId, last: &LastSummary) -> ChatSnapshot {
+        let messages = if last.selector.is_cross_thread() {
+            let (Some(first), Some(newest)) = (last.seqs.first(), last.seqs.last()) else {
+                return self.snapshot_of(key, last.selector, Vec::new());
+            };
+            let mut tagged: Vec<(Option<ThreadId>, SavedMessage)> = self
+                .chats
+                .iter()
+                .filter(|(chat, _)| chat.chat_id == key.chat_id)
+                .flat_map(|(chat, queue)| {
+                    queue
+                        .iter()
+                        .filter(|message| (*first..=*newest).contains(&message.seq))
+                        .map(|message| (chat.thread_id, message.clone()))
+                })
+                .collect();
+            tagged.sort_by_key(|(_, message)| message.seq);
+            aggregate::collapse_cross_posts(
+                tagged,
+                chrono::Duration::minutes(aggregate::CROSS_POST_WINDOW_MINUTES),
+            )
+        } else {
+            self.chats
+                .get(key)
+                .map(|queue| {
+                    queue
+                        .iter()
+                        .filter(|message| last.seqs.binary_search(&message.seq).is_ok())
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        self.snapshot_of(key, last.selector, messages)
+    }
+
+    // Add, replace or (with None) remove the daily digest of a chat/thread
+    pub fn set_digest_schedule(&mut self, key: ChatThreadId, schedule: Option<DigestSchedule>) {
+        match schedule {
+            Some(schedule) => self.digest_schedules.insert(key, schedule),
+            None => self.digest_schedules.remove(&key),
+        };
+        self.save_digest_schedules();
+    }
+
+    pub fn save_digest_schedules(&self) {
+        if let Some(database) = &self.database {
+            let schedules: Vec<_> = self.digest_schedules.iter().collect();
+            database.save_meta(digest::SCHEDULES_META_KEY, &schedules);
+        }
+    }
+
+    // Allow (true) or block (false) a chat at runtime. Returns false if it
+    // already was.
+    pub fn set_chat_access(&mut self, chat_id: ChatId, allowed: bool) -> bool {
+        let changed = if allowed {
+            self.chat_access.allow(chat_id)
+        } else {
+            self.chat_access.block(chat_id)
+        };
+        if changed && let Some(database) = &self.database {
+            database.save_meta(access::CHAT_ACCESS_META_KEY, &self.chat_access);
+        }
+        changed
+    }
+
+    pub fn due_digests(&self, now: DateTime<Utc>) -> Vec<ChatThreadId> {
+        self.digest_schedules
+            .iter()
+            .filter(|(_, schedule)| schedule.is_due(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    // Claim the oldest messages of a full queue for compaction. Returns None if
+    // the queue isn't full, a compaction is already running, or the chat used up
+    // its daily budget.
+    pub fn begin_compaction(
+        &mut self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        config: &CompactionConfig,
+        today: NaiveDate,
+    ) -> Option<Vec<SavedMessage>> {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+        let queue = self.chats.get(&chat_thread_id)?;
+        if queue.len() < MAX_MESSAGES || self.compacting.contains(&chat_thread_id) {
+            return None;
+        }
+
+        let (day, count) = self
+            .compactions
+            .entry(chat_thread_id.clone())
+            .or_insert((today, 0));
+        if *day != today {
+            *day = today;
+            *count = 0;
+        }
+        if *count >= config.max_per_day {
+            return None;
+        }
+        *count += 1;
+
+        self.compacting.insert(chat_thread_id);
+        Some(queue.iter().take(config.batch_size).cloned().collect())
+    }
+
+    // Replace the compacted batch (everything up to `last_seq`) with its summary.
+    // Without a summary the batch stays and the claim is simply released.
+    pub fn finish_compaction(
+        &mut self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        last_seq: u64,
+        summary: Option<SavedMessage>,
+    ) {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+        self.compacting.remove(&chat_thread_id);
+
+        let (Some(summary), Some(queue)) = (summary, self.chats.get_mut(&chat_thread_id)) else {
+            return;
+        };
+        // Part of the batch may have been evicted while the provider was busy
+        while let Some(compacted) = queue.pop_front_if(|m| m.seq <= last_seq) {
+            self.total_messages -= 1;
+            self.total_bytes -= footprint::message_bytes(&compacted);
+        }
+        if let Some(database) = &self.database {
+            database.delete_through(&chat_thread_id, last_seq);
+            database.insert_message(&chat_thread_id, &summary);
+        }
+        self.total_bytes += footprint::message_bytes(&summary);
+        queue.push_front(summary);
+        self.total_messages += 1;
+    }
+
+    // Merge imported history into a chat/thread. Only messages from before the
+    // oldest stored one are taken, numbered down from the lowest sequence number
+    // in the store, so what the bot saw live keeps its numbers and digest
+    // watermarks stay valid. History a compacted summary already covers is
+    // left out. Returns how many messages were added.
+    pub fn seed(
+        &mut self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        imported: Vec<SavedMessage>,
+    ) -> usize {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+        if self.compacting.contains(&chat_thread_id) {
+            warn!(target: "store", "Not seeding chat {} thread {:?} while it's being compacted", chat_id, thread_id);
+            return 0;
+        }
+        let lowest = self
+            .chats
+            .values()
+            .filter_map(|queue| queue.front())
+            .map(|m| m.seq)
+            .min()
+            .unwrap_or(self.next_seq);
+        self.tracked_chats.insert(chat_id);
+        let queue = self.chats.entry(chat_thread_id.clone()).or_default();
+        if queue.front().is_some_and(|m| m.synthetic) {
+            return 0;
+        }
+
+        let oldest = queue.front().map(|m| m.timestamp);
+        let known: HashSet<MessageId> = queue.iter().map(|m| m.message_id).collect();
+        let mut imported: Vec<SavedMessage> = imported
+            .into_iter()
+            .filter(|m| !known.contains(&m.message_id))
+            .filter(|m| oldest.is_none_or(|oldest| m.timestamp < oldest))
+            .collect();
+        imported.sort_by_key(|m| m.timestamp);
+        let room = MAX_MESSAGES
+            .saturating_sub(queue.len())
+            .min(usize::try_from(lowest).unwrap_or(usize::MAX));
+        let skip = imported.len().saturating_sub(room);
+        imported.drain(..skip);
+
+        for (below, message) in (1..).zip(imported.iter_mut().rev()) {
+            message.seq = lowest - below;
+        }
+        if let Some(oldest) = imported.first() {
+            let first_seen = self
+                .first_seen
+                .entry(chat_thread_id.clone())
+                .or_insert(oldest.timestamp);
+            *first_seen = (*first_seen).min(oldest.timestamp);
+        }
+        if let Some(database) = &self.database {
+            for message in &imported {
+                database.insert_message(&chat_thread_id, message);
+            }
+        }
+
+        let added = imported.len();
+        self.total_messages += added;
+        self.total_bytes += footprint::queue_bytes(&imported);
+        for message in imported.into_iter().rev() {
+            queue.push_front(message);
+        }
+        self.enforce_byte_limit();
+        added
+    }
+
+    // Stores from before SEQ_BASE numbered their messages from 0. Move every
+    // sequence number up once, so seed has room below; order and watermarks
+    // stay as they were.
+    fn make_seq_headroom(&mut self) {
+        let lowest = self
+            .chats
+            .values()
+            .filter_map(|queue| queue.front())
+            .map(|m| m.seq)
+            .min();
+        if lowest.is_none_or(|lowest| lowest >= SEQ_BASE / 2) {
+            return;
+        }
+        for message in self.chats.values_mut().flatten() {
+            message.seq += SEQ_BASE;
+        }
+        for watermark in self.digest_watermarks.values_mut() {
+            *watermark += SEQ_BASE;
+        }
+        self.next_seq += SEQ_BASE;
+        if let Some(database) = &self.database {
+            database.shift_seqs(SEQ_BASE);
+            self.save_digest_watermarks();
+        }
+        info!(target: "store", "Renumbered stored messages to leave room for imported history");
+    }
+
+    pub fn get_last_n_messages(
+        &self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        n: usize,
+    ) -> Vec<SavedMessage> {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+
+        match self.chats.get(&chat_thread_id) {
+            Some(messages) => {
+                let count = n.min(messages.len());
+                messages.iter().rev().take(count).rev().cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    // Messages sent in [start, end)
+    pub fn get_messages_between(
+        &self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Vec<SavedMessage> {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+
+        match self.chats.get(&chat_thread_id) {
+            Some(messages) => messages
+                .iter()
+                .filter(|message| message.timestamp >= start && message.timestamp < end)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // The newest n messages across every thread of the chat (newer than `after`,
+    // if given), with cross-posts collapsed
+    pub fn get_all_threads(
+        &self,
+        chat_id: ChatId,
+        after: Option<u64>,
+        n: usize,
+    ) -> Vec<SavedMessage> {
+        let mut tagged: Vec<(Option<ThreadId>, SavedMessage)> = self
+            .chats
+            .iter()
+            .filter(|(key, _)| key.chat_id == chat_id)
+            .flat_map(|(key, queue)| {
+                queue
+                    .iter()
+                    .filter(|message| after.is_none_or(|after| message.seq > after))
+                    .map(|message| (key.thread_id, message.clone()))
+            })
+            .collect();
+        tagged.sort_by_key(|(_, message)| message.seq);
+        let skip = tagged.len().saturating_sub(n);
+        tagged.drain(..skip);
+        aggregate::collapse_cross_posts(
+            tagged,
+            chrono::Duration::minutes(aggregate::CROSS_POST_WINDOW_MINUTES),
+        )
+    }
+
+    // Everything stored after the given message, or None if that message isn't stored
+    pub fn get_messages_after(
+        &self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        message_id: MessageId,
+    ) -> Option<Vec<SavedMessage>> {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+        let messages = self.chats.get(&chat_thread_id)?;
+        let position = messages
+            .iter()
+            .position(|message| !message.synthetic && message.message_id == message_id)?;
+        Some(messages.iter().skip(position + 1).cloned().collect())
+    }
+
+    // The root message, if still stored, followed by its reply tree in
+    // arrival order. Only the newest n replies are kept.
+    pub fn get_replies(
+        &self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        root: MessageId,
+        n: usize,
+    ) -> Vec<SavedMessage> {
+        let Some(queue) = self.chats.get(&ChatThreadId { chat_id, thread_id }) else {
+            return Vec::new();
+        };
+        let mut positions = replies::descendants(queue, root);
+        let skip = positions.len().saturating_sub(n);
+        positions.drain(..skip);
+
+        queue
+            .iter()
+            .find(|message| !message.synthetic && message.message_id == root)
+            .into_iter()
+            .chain(positions.into_iter().map(|position| &queue[position]))
+            .cloned()
+            .collect()
+    }
+
+    pub fn snapshot(
+        &self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        selector: MessageSelector,
+    ) -> ChatSnapshot {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+
+        let messages = match selector {
+            MessageSelector::Last(n) => self.get_last_n_messages(chat_id, thread_id, n),
+            MessageSelector::After(message_id, n) => {
+                let mut messages = self
+                    .get_messages_after(chat_id, thread_id, message_id)
+                    .unwrap_or_default();
+                let skip = messages.len().saturating_sub(n);
+                messages.drain(..skip);
+                messages
+            }
+            MessageSelector::Since(since, n) => {
+                let mut messages =
+                    self.get_messages_between(chat_id, thread_id, since, DateTime::<Utc>::MAX_UTC);
+                let skip = messages.len().saturating_sub(n);
+                messages.drain(..skip);
+                messages
+            }
+            MessageSelector::Between(start, end, n) => {
+                let mut messages = self.get_messages_between(chat_id, thread_id, start, end);
+                let skip = messages.len().saturating_sub(n);
+                messages.drain(..skip);
+                messages
+            }
+            MessageSelector::Replies(root, n) => self.get_replies(chat_id, thread_id, root, n),
+            MessageSelector::AllThreads(n) => self.get_all_threads(chat_id, None, n),
+            MessageSelector::AllThreadsAfter(seq, n) => self.get_all_threads(chat_id, Some(seq), n),
+            MessageSelector::AfterSeq(seq, n) => {
+                let mut messages: Vec<SavedMessage> = self
+                    .chats
+                    .get(&chat_thread_id)
+                    .map(|queue| queue.iter().filter(|m| m.seq > seq).cloned().collect())
+                    .unwrap_or_default();
+                let skip = messages.len().saturating_sub(n);
+                messages.drain(..skip);
+                messages
+            }
+        };
+
+        self.snapshot_of(&chat_thread_id, selector, messages)
+    }
+
+    fn snapshot_of(
+        &self,
+        chat_thread_id: &ChatThreadId,
+        selector: MessageSelector,
+        messages: Vec<SavedMessage>,
+    ) -> ChatSnapshot {
+        let (chat_id, thread_id) = (chat_thread_id.chat_id, chat_thread_id.thread_id);
+        let (watermark, first_seen) = if !selector.is_cross_thread() {
+            (
+                self.chats
+                    .get(chat_thread_id)
+                    .and_then(|queue| queue.back())
+                    .map(|message| message.seq),
+                self.get_first_seen(chat_id, thread_id),
+            )
+        } else {
+            (
+                self.chats
+                    .iter()
+                    .filter(|(key, _)| key.chat_id == chat_id)
+                    .filter_map(|(_, queue)| queue.back().map(|message| message.seq))
+                    .max(),
+                self.first_seen
+                    .iter()
+                    .filter(|(key, _)| key.chat_id == chat_id)
+                    .map(|(_, seen)| *seen)
+                    .min(),
+            )
+        };
+
+        ChatSnapshot {
+            selector,
+            messages: messages.into(),
+            watermark,
+            first_seen,
+            taken_at: Utc::now(),
+            source: SnapshotSource::Chat,
+            previous_summary: None,
+        }
+    }
+
+    pub fn get_first_seen(
+        &self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+    ) -> Option<DateTime<Utc>> {
+        let chat_thread_id = ChatThreadId { chat_id, thread_id };
+        self.first_seen.get(&chat_thread_id).copied()
+    }
+
+    // Timestamps of the oldest and newest stored message of a chat/thread
+    pub fn time_range(
+        &self,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let queue = self.chats.get(&ChatThreadId { chat_id, thread_id })?;
+        Some((queue.front()?.timestamp, queue.back()?.timestamp))
+    }
+
+    // Same as time_range, across every chat in the store
+    pub fn global_time_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let oldest = self
+            .chats
+            .values()
+            .filter_map(|queue| queue.front())
+            .map(|m| m.timestamp)
+            .min()?;
+        let newest = self
+            .chats
+            .values()
+            .filter_map(|queue| queue.back())
+            .map(|m| m.timestamp)
+            .max()?;
+        Some((oldest, newest))
+    }
+
+    // Sizes and times of every queue for /admin dump, without their contents
+    pub fn debug_snapshot(&self) -> dump::StoreDebug {
+        let thread = |key: &ChatThreadId| key.thread_id.map(|thread| thread.0.0);
+        let mut chats: Vec<dump::ChatDebug> = self
+            .chats
+            .iter()
+            .map(|(key, queue)| dump::ChatDebug {
+                chat_id: key.chat_id.0,
+                thread_id: thread(key),
+                messages: queue.len(),
+                bytes: footprint::queue_bytes(queue),
+                oldest: queue.front().map(|m| m.timestamp),
+                newest: queue.back().map(|m| m.timestamp),
+                evictions: self.evictions.get(key).copied().unwrap_or(0),
+            })
+            .collect();
+        chats.sort_by_key(|chat| (chat.chat_id, chat.thread_id));
+        let mut digests: Vec<dump::DigestDebug> = self
+            .digest_schedules
+            .iter()
+            .map(|(key, schedule)| dump::DigestDebug {
+                chat_id: key.chat_id.0,
+                thread_id: thread(key),
+                time: schedule.time.format("%H:%M").to_string(),
+                next_due: schedule.next_due,
+                last_digest_at: schedule.last_digest_at,
+                watermark: self.digest_watermarks.get(key).copied(),
+            })
+            .collect();
+        digests.sort_by_key(|digest| (digest.chat_id, digest.thread_id));
+
+        dump::StoreDebug {
+            total_messages: self.total_messages,
+            total_bytes: self.total_bytes,
+            tracked_chats: self.tracked_chats.len(),
+            chats_with_settings: self.settings.len(),
+            startup_time: self.startup_time,
+            launched_at: self.launched_at,
+            restored_from: self.restored_from,
+            compactions_running: self.compacting.len(),
+            allowed_chats: self.chat_access.allowed.iter().copied().collect(),
+            blocked_chats: self.chat_access.blocked.iter().copied().collect(),
+            chats,
+            digests,
+        }
+    }
+
+    pub fn eviction_count(&self, chat_id: ChatId, thread_id: Option<ThreadId>) -> u64 {
+        self.evictions
+            .get(&ChatThreadId { chat_id, thread_id })
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn chat_settings(&self, chat_id: ChatId) -> ChatSettings {
+        self.settings.get(&chat_id).cloned().unwrap_or_default()
+    }
+
+    // Apply a change to a chat's settings and return the result. Every change
+    // goes through here under the store lock, so concurrent changes to
+    // different fields don't overwrite each other.
+    pub fn update_chat_settings(
+        &mut self,
+        chat_id: ChatId,
+        update: impl FnOnce(&mut ChatSettings),
+    ) -> ChatSettings {
+        let settings = self.settings.entry(chat_id).or_default();
+        update(settings);
+        if let Some(database) = &self.database {
+            database.save_chat_settings(chat_id, settings);
+        }
+        settings.clone()
+    }
+
+    pub fn get_uptime(&self, locale: Locale) -> String {
+        locale.duration(Utc::now().signed_duration_since(self.launched_at))
+    }
+
+    pub fn to_snapshot(&self) -> StoreSnapshot {
+        StoreSnapshot {
+            version: persist::SNAPSHOT_VERSION,
+            taken_at: Utc::now(),
+            startup_time: self.startup_time,
+            chats: self
+                .chats
+                .iter()
+                .map(|(key, queue)| (key.clone(), queue.iter().cloned().collect()))
+                .collect(),
+            first_seen: self
+                .first_seen
+                .iter()
+                .map(|(key, seen)| (key.clone(), *seen))
+                .collect(),
+            settings: self
+                .settings
+                .iter()
+                .map(|(chat_id, settings)| (*chat_id, settings.clone()))
+                .collect(),
+            digest_watermarks: self
+                .digest_watermarks
+                .iter()
+                .map(|(key, seq)| (key.clone(), *seq))
+                .collect(),
+            digest_schedules: self
+                .digest_schedules
+                .iter()
+                .map(|(key, schedule)| (key.clone(), schedule.clone()))
+                .collect(),
+            chat_access: self.chat_access.clone(),
+            digest_texts: self
+                .digest_texts
+                .iter()
+                .map(|(key, text)| (key.clone(), text.clone()))
+                .collect(),
+        }
+    }
+
+    // Take over the history of a snapshot made by a previous run
+    pub fn restore(&mut self, snapshot: StoreSnapshot) {
+        for (key, messages) in snapshot.chats {
+            if let Some(last) = messages.last() {
+                self.next_seq = self.next_seq.max(last.seq + 1);
+            }
+            self.total_messages += messages.len();
+            self.total_bytes += footprint::queue_bytes(&messages);
+            self.tracked_chats.insert(key.chat_id);
+            if let Some(replaced) = self.chats.insert(key, messages.into()) {
+                self.total_messages -= replaced.len();
+                self.total_bytes -= footprint::queue_bytes(&replaced);
+            }
+        }
+        self.first_seen.extend(snapshot.first_seen);
+        self.settings.extend(snapshot.settings);
+        self.digest_watermarks.extend(snapshot.digest_watermarks);
+        self.digest_schedules.extend(snapshot.digest_schedules);
+        self.chat_access = snapshot.chat_access;
+        self.digest_texts.extend(snapshot.digest_texts);
+        self.startup_time = snapshot.startup_time;
+        self.restored_from = Some(snapshot.taken_at);
+        self.make_seq_headroom();
+        info!(target: "persist", "Restored {} messages in {} chats/threads from a snapshot taken {}",
+            self.total_messages, self.chats.len(), snapshot.taken_at);
+    }
+
+    // Recount every queue and correct the running total. Returns the total
+    // before and after, which only differ if a code path forgot to update it.
+    pub fn recount(&mut self) -> (usize, usize) {
+        self.tracked_chats = self.chats.keys().map(|key| key.chat_id).collect();
+        let counted = self.chats.values().map(|queue| queue.len()).sum();
+        let tracked = std::mem::replace(&mut self.total_messages, counted);
+        if tracked != counted {
+            warn!(target: "store", "Message counter drifted: tracked {}, counted {}", tracked, counted);
+        }
+        let bytes = self.chats.values().map(footprint::queue_bytes).sum();
+        let tracked_bytes = std::mem::replace(&mut self.total_bytes, bytes);
+        if tracked_bytes != bytes {
+            warn!(target: "store", "Byte counter drifted: tracked {}, counted {}", tracked_bytes, bytes);
+        }
+        (tracked, counted)
+    }
+}
+
+// Whether add_message stored a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    // MAX_TRACKED_CHATS is reached and no idle chat could make room. `first`
+    // is set on the first refusal since there was last room.
+    Denied { first: bool },
+}
+
+// Which stored messages a snapshot should contain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSelector {
+    Last(usize),
+    // Stored messages of the chat/thread sent at or after the given time, at
+    // most the newest n of them
+    Since(DateTime<Utc>, usize),
+    // Stored messages sent in [start, end), at most the newest n of them
+    Between(DateTime<Utc>, DateTime<Utc>, usize),
+    // Stored messages after the given one, at most the newest n of them
+    After(MessageId, usize),
+    // The given message followed by the stored replies to it, direct or
+    // transitive, at most the newest n of those replies
+    Replies(MessageId, usize),
+    // The last n messages across every thread of the chat, with cross-posts collapsed
+    AllThreads(usize),
+    // Like AllThreads, limited to messages newer than the given sequence number
+    AllThreadsAfter(u64, usize),
+    // Stored messages of the chat/thread newer than the given sequence number,
+    // at most the newest n of them
+    AfterSeq(u64, usize),
+}
+
+impl MessageSelector {
+    // Spans every topic of the chat rather than the one the command came from
+    pub fn is_cross_thread(self) -> bool {
+        matches!(
+            self,
+            MessageSelector::AllThreads(_) | MessageSelector::AllThreadsAfter(..)
+        )
+    }
+}
+
+// Immutable view of a chat/thread taken under the store lock in one go.
+// Cloning is cheap, so pipeline stages can pass it around freely.
+#[derive(Debug, Clone)]
+pub struct ChatSnapshot {
+    pub selector: MessageSelector,
+    pub messages: Arc<[SavedMessage]>,
+    // Sequence number of the newest message stored for the chat when the
+    // snapshot was taken, regardless of which messages were selected
+    pub watermark: Option<u64>,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub taken_at: DateTime<Utc>,
+    pub source: SnapshotSource,
+    // The summary a regenerated one replaces, to say what changed
+    pub previous_summary: Option<String>,
+}
+
+// Where the messages of a snapshot came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotSource {
+    // The chat's stored history
+    Chat,
+    // An uploaded transcript: nothing to reply to, and not kept past the reply
+    Upload,
+    // Messages forwarded privately, which are let go once summarized
+    Forwards,
+}
+
+// What the last summary of a chat/thread covered and said, so /regenerate can
+// write it again from the same messages. Only their sequence numbers are
+// kept; messages evicted or dropped since are gone from the regeneration too.
+#[derive(Debug, Clone)]
+pub struct LastSummary {
+    pub selector: MessageSelector,
+    pub seqs: Vec<u64>,
+    pub requested: usize,
+    pub focus: Option<String>,
+    pub text: String,
+}
+
+pub type MessageStoreType = Arc<Mutex<MessageStore>>;
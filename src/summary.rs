@@ -0,0 +1,485 @@
+use crate::{
+    admins::is_chat_admin,
+    blockterms, budget, chatinfo, citations, context, dayslice,
+    destination::reply_retrying,
+    events::SummaryEvent,
+    limits::{self, ChatKind},
+    locale::Locale,
+    metrics::Metrics,
+    pipeline::{extractive_summary, request_headroom, run_llm_task, summary_chunks},
+    progress::SummaryReply,
+    prompt, revision,
+    settings::ReplyAnchor,
+    state::{AppState, budget_month, charge_budget, chat_timezone, notify_model_change},
+    store::{
+        ChatSnapshot, ChatThreadId, LastSummary, MessageSelector, SavedMessage, SnapshotSource,
+    },
+    task::LlmTask,
+    timing::{Stage, StageTimings},
+    usage,
+};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use std::time::Instant;
+use teloxide::{
+    prelude::*,
+    types::{Message, MessageId, ParseMode},
+};
+use tokio::sync::watch;
+
+// A chat first seen more recently than this is considered "new" to the bot
+pub const NEW_CHAT_WINDOW_HOURS: i64 = 24;
+
+// Explain a short result when the bot simply hasn't been in the chat for long.
+// Chats that are quiet but were first seen long ago don't get the note.
+pub fn new_chat_note(
+    requested: usize,
+    resolved: usize,
+    first_seen: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    locale: Locale,
+) -> Option<String> {
+    let first_seen = first_seen?;
+    if resolved * 2 > requested {
+        return None;
+    }
+    let age = now.signed_duration_since(first_seen);
+    if age > chrono::Duration::hours(NEW_CHAT_WINDOW_HOURS) {
+        return None;
+    }
+    Some(format!(
+        "Note: I can only summarize messages sent after I was added (first seen {} ago).",
+        locale.duration(age)
+    ))
+}
+
+// Time-based summaries can't reach further back than the bot has been running
+pub fn startup_note(
+    since: DateTime<Utc>,
+    startup_time: DateTime<Utc>,
+    now: DateTime<Utc>,
+    locale: Locale,
+) -> Option<String> {
+    if since >= startup_time {
+        return None;
+    }
+    Some(format!(
+        "Note: I've only been running for {}, so only messages since then are available.",
+        locale.duration(now.signed_duration_since(startup_time))
+    ))
+}
+
+// Names the message a replies-only summary hangs off, e.g. `Anna's "Meeting
+// moved to…"`. The first selected message is the root unless it was evicted.
+pub fn describe_root(first: &SavedMessage, root: MessageId) -> String {
+    if first.message_id != root {
+        return "a message I no longer have".to_string();
+    }
+    let excerpt: String = first.text.chars().take(40).collect();
+    let ellipsis = if excerpt.len() < first.text.len() {
+        "…"
+    } else {
+        ""
+    };
+    format!(
+        "{}'s \"{}{}\"",
+        first.from_user.as_deref().unwrap_or("someone"),
+        excerpt.replace('\n', " "),
+        ellipsis
+    )
+}
+
+// Tell the requester privately why nothing happened. Users who never started
+// the bot can't be messaged, which is only logged.
+pub async fn explain_cant_send(bot: &Bot, msg: &Message) {
+    let Some(user) = &msg.from else {
+        return;
+    };
+    let text = format!(
+        "I can't post in {} right now, so I didn't make the summary you asked for. I may be \
+        muted there, or members may not be allowed to send messages. An admin can let me post \
+        again.",
+        msg.chat.title().unwrap_or("that chat")
+    );
+    if let Err(e) = bot.send_message(user.id, text).await {
+        debug!(target: "command", "Couldn't tell {} about chat {} privately: {}", user.id, msg.chat.id, e);
+    }
+}
+
+// Shared tail of the summarize commands: placeholder, provider call and final edit
+// `timings` arrives with the argument and snapshot stages filled in
+#[allow(clippy::too_many_arguments)]
+pub async fn summarize_snapshot(
+    bot: &Bot,
+    msg: &Message,
+    snapshot: &ChatSnapshot,
+    requested: usize,
+    state: &AppState,
+    task: LlmTask,
+    focus: Option<&str>,
+    display_name: &str,
+    mut timings: StageTimings,
+) -> ResponseResult<()> {
+    let started = Instant::now();
+    let chat_id = msg.chat.id;
+    let thread_id = msg.thread_id;
+    let messages = &snapshot.messages;
+    let chat_settings = state.store.lock().await.chat_settings(chat_id);
+    Metrics::increment(&state.metrics.summaries_requested);
+    // Reported to the event webhook, if any, once the outcome is known
+    let emit = |fill: &dyn Fn(&mut SummaryEvent)| {
+        if let Some(events) = &state.events {
+            let command = if snapshot.selector.is_cross_thread() {
+                "summarizeall"
+            } else {
+                task.command()
+            };
+            let mut event = SummaryEvent::new(command, chat_id, thread_id, events.secret());
+            event.requested = requested;
+            event.actual = messages.len();
+            event.latency_ms = started.elapsed().as_millis() as u64;
+            fill(&mut event);
+            events.emit(event);
+        }
+    };
+
+    if !state.chat_info.lock().await.can_send(chat_id, Utc::now()) {
+        info!(target: "command", "Not summarizing in chat {}, where I can't post", chat_id);
+        explain_cant_send(bot, msg).await;
+        emit(&|event| event.error = Some("cant_send"));
+        return Ok(());
+    }
+
+    // Chats with content protection need an explicit opt-in before their
+    // messages are sent to a third-party provider
+    if !chat_settings.allow_protected
+        && chatinfo::has_protected_content(bot, &state.chat_info, chat_id).await
+    {
+        info!(target: "command", "Refusing to summarize content-protected chat {} without opt-in", chat_id);
+        reply_retrying(
+            bot,
+            msg,
+            "This chat has content protection enabled, so I won't send its messages to the \
+            summarization provider. An admin can allow it with /settings allow_protected on."
+                .to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // Day slices are shown in the chat's timezone so users see what was covered
+    let slice_range = match snapshot.selector {
+        MessageSelector::Between(start, end, _) => {
+            let tz = chat_timezone(state, &chat_settings).await;
+            Some((
+                end,
+                dayslice::format_range(start, end, tz, chat_settings.locale()),
+                tz,
+            ))
+        }
+        _ => None,
+    };
+
+    if messages.is_empty() {
+        info!(target: "command", "No messages found to summarize in chat {} thread {:?} for user {}", chat_id, thread_id, display_name);
+        let oldest = state
+            .store
+            .lock()
+            .await
+            .time_range(chat_id, thread_id)
+            .map(|(oldest, _)| oldest);
+        let text = match (&slice_range, oldest) {
+            (Some((end, range, tz)), Some(oldest)) if oldest >= *end => format!(
+                "I only have messages since {}, so there's nothing stored for {}.",
+                chat_settings.locale().datetime(&oldest.with_timezone(tz)),
+                range
+            ),
+            (Some((_, range, _)), _) => format!("No messages to summarize for {}.", range),
+            _ => "No messages to summarize.".to_string(),
+        };
+        reply_retrying(bot, msg, text).await?;
+        return Ok(());
+    }
+
+    // Held until the final edit, so a request arriving in the meantime gets
+    // turned away instead of paying for an identical summary
+    let Some(_in_flight) = state
+        .in_flight
+        .try_start(ChatThreadId { chat_id, thread_id })
+    else {
+        info!(target: "command", "Summary already in progress in chat {} thread {:?}, ignoring the one from {}", chat_id, thread_id, display_name);
+        reply_retrying(
+            bot,
+            msg,
+            "A summary is already being generated in this chat.".to_string(),
+        )
+        .await?;
+        emit(&|event| event.error = Some("in_flight"));
+        return Ok(());
+    };
+
+    let limits =
+        limits::resolve_effective_limits(&chat_settings, &state.config, ChatKind::of(&msg.chat));
+    // Only ask Telegram about admin status when it can make a difference
+    let is_admin = limits.cooldown_admins_exempt
+        && !limits.cooldown.is_zero()
+        && is_chat_admin(bot, &state.admins, msg).await?;
+    if let Some(cooldown) = limits.cooldown_for(is_admin) {
+        let key = ChatThreadId { chat_id, thread_id };
+        let acquired = state
+            .rate_limiter
+            .lock()
+            .await
+            .try_acquire(key, cooldown, Utc::now());
+        if let Err(remaining) = acquired {
+            let seconds = (remaining.num_milliseconds() as f64 / 1000.0).ceil() as i64;
+            info!(target: "command", "Summary in chat {} thread {:?} is on cooldown for {}s", chat_id, thread_id, seconds);
+            reply_retrying(
+                bot,
+                msg,
+                format!(
+                    "A summary was just made here. Please wait {} more second{}.",
+                    seconds,
+                    if seconds == 1 { "" } else { "s" }
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+
+    debug!(target: "command", "Summarizing {} messages (seq {}..={}, watermark {:?}) in chat {} thread {:?} for user {}",
+        messages.len(), messages[0].seq, messages[messages.len() - 1].seq, snapshot.watermark, chat_id, thread_id, display_name);
+    let note = match snapshot.selector {
+        MessageSelector::Since(since, _) | MessageSelector::Between(since, _, _) => {
+            let startup_time = state.store.lock().await.startup_time;
+            startup_note(
+                since,
+                startup_time,
+                snapshot.taken_at,
+                chat_settings.locale(),
+            )
+        }
+        _ => new_chat_note(
+            requested,
+            messages.len(),
+            snapshot.first_seen,
+            snapshot.taken_at,
+            chat_settings.locale(),
+        ),
+    };
+
+    // Use actual number of messages retrieved in the summary message
+    let mut placeholder = match (&slice_range, snapshot.selector) {
+        (Some((_, range, _)), _) => {
+            format!("{} from {}...", task.progress(messages.len()), range)
+        }
+        (None, MessageSelector::Replies(root, _)) => format!(
+            "{} in the discussion under {}...",
+            task.progress(messages.len()),
+            describe_root(&messages[0], root)
+        ),
+        (None, _) => format!("{}...", task.progress(messages.len())),
+    };
+    let model = state.settings.lock().await.model.clone();
+    let headroom = request_headroom(
+        state,
+        messages,
+        task,
+        chat_id,
+        &chat_settings,
+        model.as_deref().unwrap_or(state.llm.primary_model()),
+    );
+    if let Some(topic) = focus {
+        placeholder.insert_str(
+            placeholder.len() - "...".len(),
+            &format!(" about \"{}\"", topic),
+        );
+    }
+    if let Some(headroom) = headroom.filter(context::Headroom::is_large) {
+        debug!(target: "summarization", "Request in chat {} takes about {}% of the model's context", chat_id, headroom.percent());
+        placeholder.push(' ');
+        placeholder.push_str(context::LARGE_REQUEST_NOTE);
+    }
+    if let Some(note) = &note {
+        placeholder.push_str(&format!("\n\n{}", note));
+    }
+    // Cross-thread summaries keep replying to the command, since the range may
+    // start in another topic
+    let anchor = if chat_settings.reply_anchor == ReplyAnchor::RangeStart
+        && !snapshot.selector.is_cross_thread()
+        && snapshot.source != SnapshotSource::Upload
+    {
+        messages.iter().find(|m| !m.synthetic).map(|m| m.message_id)
+    } else {
+        None
+    };
+    let placeholder_sent = Instant::now();
+    let reply = SummaryReply::start(bot, msg, limits.placeholder_mode, anchor, placeholder).await?;
+    timings.lap(Stage::Telegram, placeholder_sent);
+    if reply.has_placeholder() {
+        state
+            .chat_info
+            .lock()
+            .await
+            .set_can_send(chat_id, true, Utc::now());
+    }
+
+    let (_, variant) = task.system_prompt(&state.config, chat_id);
+
+    let month = budget_month(state).await;
+    if !state.budget.lock().await.allows_llm(month) {
+        // Extracts stand in for a summary, but not for a mood or topic list
+        if task != LlmTask::Summarize {
+            info!(target: "summarization", "Monthly budget reached, skipping /{} in chat {} thread {:?}", task.command(), chat_id, thread_id);
+            reply
+                .finish(
+                    "The monthly budget has been reached, so I can't do this until next month."
+                        .to_string(),
+                    None,
+                )
+                .await?;
+            emit(&|event| event.source = "over_budget");
+            return Ok(());
+        }
+        info!(target: "summarization", "Monthly budget reached, sending extracts in chat {} thread {:?}", chat_id, thread_id);
+        let mut text = format!(
+            "The monthly summarization budget has been reached, so here are the longest \
+            messages instead of a summary:\n\n{}",
+            extractive_summary(
+                messages,
+                &blockterms::Matcher::new(&chat_settings.blocked_terms)
+            )
+        );
+        if let Some(note) = &note {
+            text.push_str(&format!("\n\n{}", note));
+        }
+        reply.finish(text, None).await?;
+        emit(&|event| event.source = "extracts");
+        return Ok(());
+    }
+
+    // Stream into the placeholder when there is one, so long summaries show
+    // progress instead of sitting on "Summarizing...". Conversations summarized
+    // in parts report each part there too.
+    let (partial, partial_updates) = watch::channel(String::new());
+    let show_progress = reply.has_placeholder();
+    let locale = chat_settings.locale();
+    let hide_exclusion_note = chat_settings.hide_exclusion_note;
+    let summarize = async move {
+        run_llm_task(
+            &state.task_context(),
+            messages.clone(),
+            task,
+            focus,
+            chat_id,
+            &chat_settings,
+            model.as_deref(),
+            show_progress.then_some(&partial),
+        )
+        .await
+        // Dropping the sender here ends stream_progress
+    };
+    let (result, ()) = tokio::join!(summarize, reply.stream_progress(partial_updates));
+
+    match result {
+        Ok((completion, llm_timings, citations, exclusions)) => {
+            timings.merge(&llm_timings);
+            charge_budget(bot, state, &completion).await;
+            notify_model_change(bot, state, &completion).await;
+            info!(target: "summarization", "Successfully ran /{} in chat {} thread {:?} for user {} (provider {}, prompt variant {:?})", task.command(), chat_id, thread_id, display_name, completion.provider, variant);
+            if let Some(variant) = variant {
+                state
+                    .stats
+                    .lock()
+                    .await
+                    .record_summary(variant, &completion.text);
+            }
+            let mut trailer: Vec<String> = note.iter().cloned().collect();
+            if completion.failed_over {
+                trailer.push(format!(
+                    "(generated by {} while the main provider is unavailable)",
+                    completion.provider
+                ));
+            }
+            if completion.model_mismatch()
+                && let Some(served) = &completion.served_model
+            {
+                trailer.push(format!(
+                    "(answered by {} instead of {})",
+                    served, completion.requested_model
+                ));
+            }
+            if state.config.show_usage
+                && let Some(usage) = completion.usage
+            {
+                trailer.push(usage::footer(usage, locale));
+            }
+            if exclusions.by_policy() && !hide_exclusion_note {
+                trailer.push(prompt::EXCLUSION_NOTE.to_string());
+            }
+            if task == LlmTask::Summarize {
+                let text = citations::strip_tags(&completion.text);
+                if let Some(note) = snapshot
+                    .previous_summary
+                    .as_deref()
+                    .and_then(|previous| revision::note(previous, &text))
+                {
+                    trailer.push(note);
+                }
+                // Uploads and forward batches aren't kept for /regenerate, and
+                // mustn't replace the chat's own last summary
+                if snapshot.source == SnapshotSource::Chat {
+                    state.store.lock().await.set_last_summary(
+                        ChatThreadId { chat_id, thread_id },
+                        LastSummary {
+                            selector: snapshot.selector,
+                            seqs: messages.iter().map(|m| m.seq).collect(),
+                            requested,
+                            focus: focus.map(str::to_string),
+                            text,
+                        },
+                    );
+                }
+            }
+            let formatting = Instant::now();
+            let chunks = summary_chunks(&completion.text, &trailer, &citations);
+            let sending = timings.lap(Stage::Formatting, formatting);
+            reply
+                .finish_chunks(chunks, Some(ParseMode::MarkdownV2))
+                .await?;
+            timings.lap(Stage::Telegram, sending);
+            Metrics::increment(&state.metrics.summaries_succeeded);
+            state
+                .chat_info
+                .lock()
+                .await
+                .set_can_send(chat_id, true, Utc::now());
+            emit(&|event| {
+                event.source = if completion.failed_over {
+                    "failover"
+                } else {
+                    "provider"
+                };
+                event.provider = Some(completion.provider.clone());
+                event.estimated_tokens = Some(budget::estimate_tokens(completion.chars));
+                event.prompt_tokens = completion.usage.map(|usage| usage.prompt_tokens);
+                event.completion_tokens = completion.usage.map(|usage| usage.completion_tokens);
+            });
+        }
+        Err(e) => {
+            error!(target: "summarization", "Failed to run /{} in chat {} thread {:?} for user {}: {}", task.command(), chat_id, thread_id, display_name, e);
+            Metrics::increment(&state.metrics.summaries_failed);
+            let sending = Instant::now();
+            reply.finish(e.user_message(task).to_string(), None).await?;
+            timings.lap(Stage::Telegram, sending);
+            let class = e.class();
+            state.stats.lock().await.record_error(class, Utc::now());
+            emit(&|event| event.error = Some(class));
+        }
+    }
+
+    info!(target: "timing", "/{} in chat {} thread {:?}: {}", task.command(), chat_id, thread_id, timings.log_line());
+    state.stats.lock().await.record_stages(&timings);
+    Ok(())
+}
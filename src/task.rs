@@ -0,0 +1,88 @@
+use crate::{
+    compaction,
+    config::{Config, PromptVariant},
+};
+use teloxide::types::ChatId;
+
+const MOOD_PROMPT: &str = "You are reading the mood of a Telegram conversation. Describe its overall tone in a few sentences: whether it's friendly, tense, playful or heated, how that changed over time, and who is arguing or agreeing with whom. Quote nobody at length and don't take sides. Don't use markdown.";
+// Added to the summary prompt when a digest follows an earlier one
+const DIGEST_UPDATE_INSTRUCTION: &str = "The conversation opens with the previous digest of this chat, given as context. Cover only what happened since it. When a topic from the previous digest continues, start that point with \"ongoing: \" and say only what's new about it.";
+const TOPICS_PROMPT: &str = "You are listing the topics of a Telegram conversation. Write one short line per topic, most discussed first, each starting with \"- \" and ending with the names of the people who talked about it in parentheses. Leave out greetings and small talk. Don't use markdown.";
+
+// What the provider is asked to do with a conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmTask {
+    Summarize,
+    Mood,
+    Topics,
+    // A digest that follows an earlier one, whose text leads the conversation
+    DigestUpdate,
+    // Condensing old history into notes for later summaries
+    Compact,
+}
+
+impl LlmTask {
+    // The command name, also used for usage events
+    pub fn command(&self) -> &'static str {
+        match self {
+            LlmTask::Summarize => "summarize",
+            LlmTask::Mood => "mood",
+            LlmTask::Topics => "topics",
+            LlmTask::DigestUpdate => "digest",
+            LlmTask::Compact => "compact",
+        }
+    }
+
+    // Only summaries, digests included, take part in prompt A/B testing
+    pub fn system_prompt<'a>(
+        &self,
+        config: &'a Config,
+        chat_id: ChatId,
+    ) -> (&'a str, Option<PromptVariant>) {
+        match self {
+            LlmTask::Summarize | LlmTask::DigestUpdate => config.system_prompt(chat_id),
+            LlmTask::Mood => (MOOD_PROMPT, None),
+            LlmTask::Topics => (TOPICS_PROMPT, None),
+            LlmTask::Compact => (compaction::COMPACTION_PROMPT, None),
+        }
+    }
+
+    // Appended to the system prompt, on top of the language instruction
+    pub fn extra_instruction(&self) -> Option<&'static str> {
+        match self {
+            LlmTask::DigestUpdate => Some(DIGEST_UPDATE_INSTRUCTION),
+            _ => None,
+        }
+    }
+
+    // Appended to the summary prompt when /summarize names a topic
+    pub fn focus_instruction(topic: &str) -> String {
+        format!(
+            "Summarize only the parts of the conversation about this topic: \"{}\". Leave \
+            out everything unrelated. If the topic wasn't discussed, say so in one sentence \
+            instead of summarizing anything else.",
+            topic
+        )
+    }
+
+    // Shown in the placeholder while the task runs
+    pub fn progress(&self, count: usize) -> String {
+        match self {
+            LlmTask::Summarize | LlmTask::DigestUpdate | LlmTask::Compact => {
+                format!("Summarizing {} messages", count)
+            }
+            LlmTask::Mood => format!("Reading the mood of {} messages", count),
+            LlmTask::Topics => format!("Listing the topics of {} messages", count),
+        }
+    }
+
+    pub fn failure(&self) -> &'static str {
+        match self {
+            LlmTask::Summarize | LlmTask::DigestUpdate | LlmTask::Compact => {
+                "Failed to summarize the conversation."
+            }
+            LlmTask::Mood => "Failed to read the mood of the conversation.",
+            LlmTask::Topics => "Failed to list the topics of the conversation.",
+        }
+    }
+}
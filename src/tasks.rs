@@ -0,0 +1,298 @@
+use crate::{backoff::Backoff, locale::Locale};
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use log::{error, info};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// A task is stale once it has missed this many heartbeats
+pub const STALE_AFTER_BEATS: u32 = 3;
+// How often the monitor looks for stale and panicked tasks
+pub const MONITOR_INTERVAL: Duration = Duration::from_secs(60);
+// Wait before restarting a task that panicked, doubling for each panic in a
+// row. A run that outlasts the longest wait starts the count over.
+pub const RESTART_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_secs(1),
+    max_delay: Duration::from_secs(300),
+    window: Duration::MAX,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    // Panicked and waiting out the backoff before the next run
+    Restarting,
+    // The task returned, e.g. a server at shutdown
+    Stopped,
+    // A job started when needed, e.g. compaction, rather than a loop
+    OnDemand,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskStatus {
+    // How often the task promised to send a heartbeat. None for tasks that
+    // wait on something else, like connections, and so can't go stale.
+    pub interval: Option<Duration>,
+    pub state: TaskState,
+    pub started_at: DateTime<Utc>,
+    pub last_beat: DateTime<Utc>,
+    // What the last round did, e.g. "2 digests due"
+    pub last_result: Option<String>,
+    pub restarts: u32,
+    pub last_panic: Option<(DateTime<Utc>, String)>,
+    // Staleness already reported; cleared by the next heartbeat
+    stale_reported: bool,
+    panic_reported: bool,
+}
+
+// Something the owner should hear about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    Stale {
+        name: &'static str,
+        silent_for: chrono::Duration,
+    },
+    Panicked {
+        name: &'static str,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alert::Stale { name, silent_for } => write!(
+                f,
+                "The {} background task hasn't reported in {}; it may be stuck.",
+                name,
+                Locale::English.duration(*silent_for)
+            ),
+            Alert::Panicked { name, reason } => write!(
+                f,
+                "The {} background task panicked: {}\nSee /admin tasks for its state.",
+                name, reason
+            ),
+        }
+    }
+}
+
+// Background loops by name, with their heartbeats, so one that panicked or
+// hangs is noticed. Tasks run under `supervise`, which registers them.
+#[derive(Debug, Default)]
+pub struct TaskRegistry {
+    tasks: Mutex<BTreeMap<&'static str, TaskStatus>>,
+}
+
+pub type TaskRegistryType = Arc<TaskRegistry>;
+
+impl TaskRegistry {
+    // A task that panicked mid-update leaves nothing worse than a stale entry
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<&'static str, TaskStatus>> {
+        self.tasks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn register(&self, name: &'static str, interval: Option<Duration>, now: DateTime<Utc>) {
+        self.insert(name, interval, TaskState::Running, now);
+    }
+
+    fn insert(
+        &self,
+        name: &'static str,
+        interval: Option<Duration>,
+        state: TaskState,
+        now: DateTime<Utc>,
+    ) {
+        self.lock().insert(
+            name,
+            TaskStatus {
+                interval,
+                state,
+                started_at: now,
+                last_beat: now,
+                last_result: None,
+                restarts: 0,
+                last_panic: None,
+                stale_reported: false,
+                panic_reported: false,
+            },
+        );
+    }
+
+    // Called by a task after each round of work
+    pub fn beat(&self, name: &'static str, result: impl Into<String>, now: DateTime<Utc>) {
+        if let Some(task) = self.lock().get_mut(name) {
+            if task.stale_reported {
+                info!(target: "tasks", "Background task {} is reporting again", name);
+            }
+            task.last_beat = now;
+            task.last_result = Some(result.into());
+            task.stale_reported = false;
+        }
+    }
+
+    fn panicked(&self, name: &'static str, reason: String, now: DateTime<Utc>) {
+        if let Some(task) = self.lock().get_mut(name) {
+            if task.state != TaskState::OnDemand {
+                task.state = TaskState::Restarting;
+            }
+            task.last_panic = Some((now, reason));
+            task.panic_reported = false;
+        }
+    }
+
+    fn restarted(&self, name: &'static str, now: DateTime<Utc>) {
+        if let Some(task) = self.lock().get_mut(name) {
+            task.state = TaskState::Running;
+            task.started_at = now;
+            // The new run gets a full interval before it counts as stale
+            task.last_beat = now;
+            task.restarts += 1;
+        }
+    }
+
+    fn stopped(&self, name: &'static str) {
+        if let Some(task) = self.lock().get_mut(name) {
+            task.state = TaskState::Stopped;
+        }
+    }
+
+    pub fn status(&self, name: &str) -> Option<TaskStatus> {
+        self.lock().get(name).cloned()
+    }
+
+    // Panics not reported yet, and running tasks whose last heartbeat is more
+    // than STALE_AFTER_BEATS intervals old. Each is returned once.
+    pub fn check(&self, now: DateTime<Utc>) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for (name, task) in self.lock().iter_mut() {
+            if let Some((_, reason)) = &task.last_panic
+                && !task.panic_reported
+            {
+                task.panic_reported = true;
+                alerts.push(Alert::Panicked {
+                    name,
+                    reason: reason.clone(),
+                });
+            }
+            let silent_for = now.signed_duration_since(task.last_beat);
+            let Some(interval) = task.interval else {
+                continue;
+            };
+            let allowed = chrono::Duration::from_std(interval * STALE_AFTER_BEATS)
+                .unwrap_or(chrono::Duration::MAX);
+            if task.state == TaskState::Running && !task.stale_reported && silent_for > allowed {
+                task.stale_reported = true;
+                alerts.push(Alert::Stale { name, silent_for });
+            }
+        }
+        alerts
+    }
+
+    // One line per task for /admin tasks
+    pub fn describe(&self, now: DateTime<Utc>) -> String {
+        let tasks = self.lock();
+        if tasks.is_empty() {
+            return "No background tasks are registered.".to_string();
+        }
+        let ago = |at: DateTime<Utc>| Locale::English.duration(now.signed_duration_since(at));
+        tasks
+            .iter()
+            .map(|(name, task)| {
+                let state = match task.state {
+                    TaskState::Running if task.stale_reported => "stale",
+                    TaskState::Running => "running",
+                    TaskState::Restarting => "restarting",
+                    TaskState::Stopped => "stopped",
+                    TaskState::OnDemand => "on demand",
+                };
+                let mut line = format!(
+                    "{}: {}, last heartbeat {} ago",
+                    name,
+                    state,
+                    ago(task.last_beat)
+                );
+                if let Some(result) = &task.last_result {
+                    line.push_str(&format!(" ({})", result));
+                }
+                if task.restarts > 0 {
+                    line.push_str(&format!(", restarted {}x", task.restarts));
+                }
+                if let Some((at, reason)) = &task.last_panic {
+                    line.push_str(&format!(", last panic {} ago: {}", ago(*at), reason));
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+pub fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|reason| reason.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+// Run a background loop under `name`, expecting a heartbeat every `interval`.
+// A panic is recorded and the loop started again after a backoff; a loop that
+// returns is marked stopped.
+pub async fn supervise<Fut>(
+    registry: TaskRegistryType,
+    name: &'static str,
+    interval: Option<Duration>,
+    restart: Backoff,
+    mut run: impl FnMut() -> Fut,
+) where
+    Fut: Future<Output = ()>,
+{
+    registry.register(name, interval, Utc::now());
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let payload = match AssertUnwindSafe(run()).catch_unwind().await {
+            Ok(()) => {
+                info!(target: "tasks", "Background task {} stopped", name);
+                registry.stopped(name);
+                return;
+            }
+            Err(payload) => payload,
+        };
+        if started.elapsed() > restart.max_delay {
+            attempt = 0;
+        }
+        attempt += 1;
+        let delay = restart.delay(attempt);
+        let reason = panic_reason(&*payload);
+        error!(target: "tasks", "Background task {} panicked, restarting in {}s: {}", name, delay.as_secs(), reason);
+        registry.panicked(name, reason, Utc::now());
+        tokio::time::sleep(delay).await;
+        registry.restarted(name, Utc::now());
+    }
+}
+
+// Run one job of the kind `name`, e.g. one compaction. A panic is recorded
+// like a loop's, but the job isn't started again; returns false if it panicked.
+pub async fn run_job(
+    registry: &TaskRegistry,
+    name: &'static str,
+    job: impl Future<Output = ()>,
+) -> bool {
+    if registry.status(name).is_none() {
+        registry.insert(name, None, TaskState::OnDemand, Utc::now());
+    }
+    let Err(payload) = AssertUnwindSafe(job).catch_unwind().await else {
+        return true;
+    };
+    let reason = panic_reason(&*payload);
+    error!(target: "tasks", "A {} job panicked: {}", name, reason);
+    registry.panicked(name, reason, Utc::now());
+    false
+}
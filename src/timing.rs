@@ -0,0 +1,202 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+// The parts of a summary request that time is spent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    // Parsing the command and resolving limits, including admin checks
+    Arguments,
+    // Waiting for the store and copying the messages out
+    Snapshot,
+    Prompt,
+    // The answering provider attempt
+    Provider,
+    // Failed attempts, retry waits and failover before that
+    Retries,
+    Formatting,
+    // Placeholder, progress edits and the final reply
+    Telegram,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 7] = [
+        Stage::Arguments,
+        Stage::Snapshot,
+        Stage::Prompt,
+        Stage::Provider,
+        Stage::Retries,
+        Stage::Formatting,
+        Stage::Telegram,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Arguments => "arguments",
+            Stage::Snapshot => "snapshot",
+            Stage::Prompt => "prompt",
+            Stage::Provider => "provider",
+            Stage::Retries => "retries",
+            Stage::Formatting => "formatting",
+            Stage::Telegram => "telegram",
+        }
+    }
+}
+
+// Where one request's time went. Stages that run more than once, like the
+// provider for a conversation summarized in parts, add up.
+#[derive(Debug, Clone)]
+pub struct StageTimings {
+    started: Instant,
+    stages: BTreeMap<Stage, Duration>,
+}
+
+impl StageTimings {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            stages: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, stage: Stage, duration: Duration) {
+        *self.stages.entry(stage).or_default() += duration;
+    }
+
+    // Record the time since `since` and return the current instant, so
+    // consecutive stages can be chained
+    pub fn lap(&mut self, stage: Stage, since: Instant) -> Instant {
+        let now = Instant::now();
+        self.add(stage, now - since);
+        now
+    }
+
+    // A provider call that took `elapsed`, of which `retry_time` went to
+    // attempts that failed
+    pub fn provider_call(&mut self, elapsed: Duration, retry_time: Duration) {
+        self.add(Stage::Provider, elapsed.saturating_sub(retry_time));
+        self.add(Stage::Retries, retry_time);
+    }
+
+    // Add the stages timed elsewhere, e.g. inside the LLM task
+    pub fn merge(&mut self, other: &StageTimings) {
+        for (stage, duration) in &other.stages {
+            self.add(*stage, *duration);
+        }
+    }
+
+    pub fn get(&self, stage: Stage) -> Option<Duration> {
+        self.stages.get(&stage).copied()
+    }
+
+    pub fn total(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // "arguments=2ms snapshot=0ms prompt=14ms ... total=2531ms", with every
+    // stage listed so lines can be compared column by column
+    pub fn log_line(&self) -> String {
+        Stage::ALL
+            .iter()
+            .map(|stage| {
+                format!(
+                    "{}={}ms",
+                    stage.name(),
+                    self.get(*stage).unwrap_or_default().as_millis()
+                )
+            })
+            .chain(std::iter::once(format!(
+                "total={}ms",
+                self.total().as_millis()
+            )))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+// Upper bounds of the histogram buckets, in milliseconds; anything slower
+// lands in a final overflow bucket
+pub const BUCKET_BOUNDS_MS: [u64; 10] = [10, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    // One count per bound plus the overflow bucket
+    pub counts: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.counts[bucket] += 1;
+        self.sum += duration;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // The bucket bound at or below which `quantile` of the samples fall, e.g.
+    // "≤250ms"; ">30000ms" if it's in the overflow bucket
+    pub fn quantile(&self, quantile: f64) -> String {
+        let target = (self.count() as f64 * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return match BUCKET_BOUNDS_MS.get(bucket) {
+                    Some(bound) => format!("≤{}ms", bound),
+                    None => format!(">{}ms", BUCKET_BOUNDS_MS[BUCKET_BOUNDS_MS.len() - 1]),
+                };
+            }
+        }
+        "-".to_string()
+    }
+}
+
+// Histograms per stage over every timed request since startup
+#[derive(Debug, Clone, Default)]
+pub struct StageStats {
+    pub stages: BTreeMap<Stage, Histogram>,
+    pub total: Histogram,
+}
+
+impl StageStats {
+    pub fn record(&mut self, timings: &StageTimings) {
+        for (stage, duration) in &timings.stages {
+            self.stages.entry(*stage).or_default().record(*duration);
+        }
+        self.total.record(timings.total());
+    }
+
+    pub fn report(&self) -> String {
+        if self.total.count() == 0 {
+            return "Request stages: no timed requests yet".to_string();
+        }
+        let mut lines = vec![format!(
+            "Request stages ({} requests, median / p95):",
+            self.total.count()
+        )];
+        for stage in Stage::ALL {
+            if let Some(histogram) = self.stages.get(&stage) {
+                lines.push(format!(
+                    "  {}: {} / {}",
+                    stage.name(),
+                    histogram.quantile(0.5),
+                    histogram.quantile(0.95)
+                ));
+            }
+        }
+        lines.push(format!(
+            "  total: {} / {}",
+            self.total.quantile(0.5),
+            self.total.quantile(0.95)
+        ));
+        lines.join("\n")
+    }
+}
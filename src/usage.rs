@@ -0,0 +1,120 @@
+use crate::{llm::Usage, locale::Locale};
+use log::{info, warn};
+use std::{collections::HashMap, env};
+use teloxide::types::ChatId;
+
+// Tokens used by provider requests since startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageTotals {
+    pub summaries: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // Summaries the server reported no usage for; their tokens aren't counted
+    pub unreported: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, usage: Option<Usage>) {
+        self.summaries += 1;
+        match usage {
+            Some(usage) => {
+                self.prompt_tokens += usage.prompt_tokens;
+                self.completion_tokens += usage.completion_tokens;
+            }
+            None => self.unreported += 1,
+        }
+    }
+}
+
+// Usage per chat and across all of them
+#[derive(Debug, Default)]
+pub struct UsageStats {
+    global: UsageTotals,
+    chats: HashMap<ChatId, UsageTotals>,
+}
+
+impl UsageStats {
+    pub fn record(&mut self, chat_id: ChatId, usage: Option<Usage>) {
+        self.global.add(usage);
+        self.chats.entry(chat_id).or_default().add(usage);
+    }
+
+    pub fn global(&self) -> UsageTotals {
+        self.global
+    }
+
+    pub fn chat(&self, chat_id: ChatId) -> UsageTotals {
+        self.chats.get(&chat_id).copied().unwrap_or_default()
+    }
+}
+
+// USD per million tokens, from COST_PER_MTOK_INPUT and COST_PER_MTOK_OUTPUT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostRates {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl CostRates {
+    // Enabled when either price is set; the other then counts as free
+    pub fn from_env() -> Option<Self> {
+        let parse = |name: &str| {
+            let value = env::var(name).ok()?;
+            match value.trim().parse::<f64>() {
+                Ok(price) if price >= 0.0 => Some(price),
+                _ => {
+                    warn!(target: "config", "Ignoring invalid {} '{}'", name, value);
+                    None
+                }
+            }
+        };
+        let (input, output) = (parse("COST_PER_MTOK_INPUT"), parse("COST_PER_MTOK_OUTPUT"));
+        if input.is_none() && output.is_none() {
+            return None;
+        }
+        let rates = Self {
+            input: input.unwrap_or(0.0),
+            output: output.unwrap_or(0.0),
+        };
+        info!(target: "config", "Estimating costs at ${} input and ${} output per million tokens", rates.input, rates.output);
+        Some(rates)
+    }
+
+    pub fn cost(&self, totals: &UsageTotals) -> f64 {
+        (totals.prompt_tokens as f64 * self.input + totals.completion_tokens as f64 * self.output)
+            / 1_000_000.0
+    }
+}
+
+// Shown under a summary with SHOW_USAGE, e.g. "(1,234 tokens)"
+pub fn footer(usage: Usage, locale: Locale) -> String {
+    format!("({} tokens)", locale.integer(usage.total()))
+}
+
+// Lines for /usage, headed by `label`
+pub fn report(
+    label: &str,
+    totals: &UsageTotals,
+    rates: Option<CostRates>,
+    locale: Locale,
+) -> String {
+    let mut lines = vec![
+        format!("{}:", label),
+        format!("Summaries: {}", locale.integer(totals.summaries)),
+        format!("Prompt tokens: {}", locale.integer(totals.prompt_tokens)),
+        format!(
+            "Completion tokens: {}",
+            locale.integer(totals.completion_tokens)
+        ),
+    ];
+    if totals.unreported > 0 {
+        lines.push(format!(
+            "Summaries without token counts from the provider: {}",
+            locale.integer(totals.unreported)
+        ));
+    }
+    if let Some(rates) = rates {
+        lines.push(format!("Estimated cost: ${:.4}", rates.cost(totals)));
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,163 @@
+use crate::settings::BotSettingsChanges;
+use chrono::{DateTime, Duration, Utc};
+use std::{collections::HashMap, sync::Arc};
+use teloxide::types::UserId;
+use tokio::sync::Mutex;
+
+// Sessions left untouched for longer than this are discarded
+pub const WIZARD_TIMEOUT_MINUTES: i64 = 10;
+pub const CALLBACK_PREFIX: &str = "wiz:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WizardStep {
+    Model,
+    Timezone,
+    DigestTime,
+}
+
+impl WizardStep {
+    fn next(self) -> Option<WizardStep> {
+        match self {
+            WizardStep::Model => Some(WizardStep::Timezone),
+            WizardStep::Timezone => Some(WizardStep::DigestTime),
+            WizardStep::DigestTime => None,
+        }
+    }
+
+    pub fn question(self) -> &'static str {
+        match self {
+            WizardStep::Model => "Which model should summaries use?",
+            WizardStep::Timezone => "Which timezone should chats use by default?",
+            WizardStep::DigestTime => "When should daily digests be posted by default?",
+        }
+    }
+
+    // Values offered as buttons for this step
+    pub fn options(self) -> &'static [&'static str] {
+        match self {
+            WizardStep::Model => &["llama-3.3-70b-versatile", "llama-3.1-8b-instant"],
+            WizardStep::Timezone => &["UTC", "Europe/London", "Europe/Warsaw", "America/New_York"],
+            WizardStep::DigestTime => &["18:00", "20:00", "22:00"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WizardAction {
+    Choose(String),
+    Skip,
+    Cancel,
+}
+
+impl WizardAction {
+    pub fn to_callback_data(&self) -> String {
+        match self {
+            WizardAction::Choose(value) => format!("{}set:{}", CALLBACK_PREFIX, value),
+            WizardAction::Skip => format!("{}skip", CALLBACK_PREFIX),
+            WizardAction::Cancel => format!("{}cancel", CALLBACK_PREFIX),
+        }
+    }
+
+    pub fn from_callback_data(data: &str) -> Option<WizardAction> {
+        let action = data.strip_prefix(CALLBACK_PREFIX)?;
+        match action {
+            "skip" => Some(WizardAction::Skip),
+            "cancel" => Some(WizardAction::Cancel),
+            _ => action
+                .strip_prefix("set:")
+                .map(|value| WizardAction::Choose(value.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WizardSession {
+    pub step: WizardStep,
+    // Choices collected so far, applied to the settings only when finished
+    pub changes: BotSettingsChanges,
+    pub last_activity: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transition {
+    // Ask the next question
+    Continue(WizardStep),
+    // All steps answered; the choices should be applied
+    Finished(BotSettingsChanges),
+    Cancelled,
+    // The choice isn't one of the offered options for the current step
+    Invalid,
+}
+
+impl WizardSession {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            step: WizardStep::Model,
+            changes: BotSettingsChanges::default(),
+            last_activity: now,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now - self.last_activity > Duration::minutes(WIZARD_TIMEOUT_MINUTES)
+    }
+
+    pub fn apply(&mut self, action: WizardAction, now: DateTime<Utc>) -> Transition {
+        self.last_activity = now;
+
+        match action {
+            WizardAction::Cancel => return Transition::Cancelled,
+            WizardAction::Skip => {}
+            WizardAction::Choose(value) => {
+                if !self.step.options().contains(&value.as_str()) {
+                    return Transition::Invalid;
+                }
+                match self.step {
+                    WizardStep::Model => self.changes.model = Some(value),
+                    WizardStep::Timezone => self.changes.default_timezone = Some(value),
+                    WizardStep::DigestTime => self.changes.default_digest_time = Some(value),
+                }
+            }
+        }
+
+        match self.step.next() {
+            Some(step) => {
+                self.step = step;
+                Transition::Continue(step)
+            }
+            None => Transition::Finished(self.changes.clone()),
+        }
+    }
+}
+
+// In-progress wizards, one per user
+#[derive(Debug, Default)]
+pub struct WizardSessions {
+    sessions: HashMap<UserId, WizardSession>,
+}
+
+impl WizardSessions {
+    pub fn start(&mut self, user_id: UserId, now: DateTime<Utc>) {
+        self.sessions.insert(user_id, WizardSession::new(now));
+    }
+
+    // Feed an action to the user's session, dropping it once it's over.
+    // Returns None when the user has no live session.
+    pub fn apply(
+        &mut self,
+        user_id: UserId,
+        action: WizardAction,
+        now: DateTime<Utc>,
+    ) -> Option<Transition> {
+        self.sessions.retain(|_, session| !session.is_expired(now));
+
+        let session = self.sessions.get_mut(&user_id)?;
+        let transition = session.apply(action, now);
+        if matches!(transition, Transition::Finished(_) | Transition::Cancelled) {
+            self.sessions.remove(&user_id);
+        }
+        Some(transition)
+    }
+}
+
+pub type WizardSessionsType = Arc<Mutex<WizardSessions>>;
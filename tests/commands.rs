@@ -0,0 +1,66 @@
+use duck_summarizer::{
+    commands::{SummaryRange, parse_count, parse_range, parse_seed_target, split_focus},
+    limits::ChatKind,
+};
+use teloxide::types::{ChatId, MessageId, ThreadId};
+
+#[test]
+fn parses_counts_within_limit() {
+    assert_eq!(parse_count("50", 100, ChatKind::Group), Some(50));
+    assert_eq!(parse_count(" 100 ", 100, ChatKind::Group), Some(100));
+    assert_eq!(parse_count("101", 100, ChatKind::Group), None);
+    assert_eq!(parse_count("0", 100, ChatKind::Group), None);
+    assert_eq!(parse_count("ten", 100, ChatKind::Group), None);
+    assert!(parse_count("", 100, ChatKind::Group).is_some());
+}
+
+#[test]
+fn parses_ranges() {
+    assert_eq!(
+        parse_range("30", 100, ChatKind::Group),
+        Some(SummaryRange::Count(30))
+    );
+    assert_eq!(
+        parse_range("2h", 100, ChatKind::Group),
+        Some(SummaryRange::Window(chrono::Duration::hours(2)))
+    );
+    assert_eq!(
+        parse_range("3D", 100, ChatKind::Group),
+        Some(SummaryRange::Window(chrono::Duration::days(3)))
+    );
+    assert!(matches!(
+        parse_range("yesterday", 100, ChatKind::Group),
+        Some(SummaryRange::Slice(_))
+    ));
+    assert_eq!(parse_range("0h", 100, ChatKind::Group), None);
+    assert_eq!(parse_range("5w", 100, ChatKind::Group), None);
+}
+
+#[test]
+fn splits_focus_from_range() {
+    assert_eq!(
+        split_focus("300 about the hackathon"),
+        ("300", Some("the hackathon".to_string()))
+    );
+    assert_eq!(split_focus("2h"), ("2h", None));
+    assert_eq!(
+        split_focus("the hackathon"),
+        ("", Some("the hackathon".to_string()))
+    );
+    assert_eq!(split_focus("50 about"), ("50", None));
+    assert_eq!(split_focus(""), ("", None));
+}
+
+#[test]
+fn parses_seed_targets() {
+    assert_eq!(
+        parse_seed_target(&["-100123"]),
+        Some((ChatId(-100123), None))
+    );
+    assert_eq!(
+        parse_seed_target(&["-100123", "7"]),
+        Some((ChatId(-100123), Some(ThreadId(MessageId(7)))))
+    );
+    assert_eq!(parse_seed_target(&["-100123", "x"]), None);
+    assert_eq!(parse_seed_target(&[]), None);
+}
@@ -0,0 +1,57 @@
+// Shared by the integration tests; not every test file uses all of it
+#![allow(dead_code)]
+
+use chrono::{TimeZone, Utc};
+use duck_summarizer::{
+    config::Config, context::ContextLimits, media::MessageKind, prompt, reactions::ReactionCounts,
+    store::SavedMessage,
+};
+use std::time::Duration;
+use teloxide::types::MessageId;
+
+// A plain text message, numbered and timed by its id
+pub fn message(id: i32, from: &str, text: &str) -> SavedMessage {
+    SavedMessage {
+        seq: u64::try_from(id).unwrap(),
+        message_id: MessageId(id),
+        from_user: Some(from.to_string()),
+        username: None,
+        reply_to_message_id: None,
+        reply_to_user: None,
+        text: text.to_string(),
+        kind: MessageKind::Text,
+        timestamp: Utc.timestamp_opt(1_700_000_000 + i64::from(id), 0).unwrap(),
+        lang: None,
+        synthetic: false,
+        edited: false,
+        reactions: ReactionCounts::new(),
+    }
+}
+
+// Every optional feature off, so tests don't depend on the environment
+pub fn config() -> Config {
+    Config {
+        owner_user_id: None,
+        prompt_variants: None,
+        compaction: None,
+        show_usage: false,
+        cost_rates: None,
+        prompt_soft_cap: Duration::from_millis(prompt::DEFAULT_SOFT_CAP_MS),
+        prompt_token_budget: 0,
+        context_limits: ContextLimits::default(),
+        summarize_cooldown: chrono::Duration::zero(),
+        cooldown_admins_exempt: false,
+        admin_cache_ttl: chrono::Duration::zero(),
+        snapshot_path: None,
+        snapshot_max_age: chrono::Duration::zero(),
+        ignore_bots: false,
+        digest_timezone: None,
+        max_tracked_chats: None,
+        max_store_bytes: None,
+        metrics_addr: None,
+        startup_retry_window: Duration::ZERO,
+        allowed_chat_ids: None,
+        leave_unallowed_chats: false,
+        exit_on_conflict: false,
+    }
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use async_trait::async_trait;
+use common::message;
+use duck_summarizer::{
+    config::Config,
+    error::SummarizeError,
+    llm::{Completer, Completion},
+    metrics::Metrics,
+    pipeline::{TaskContext, run_llm_task},
+    settings::ChatSettings,
+    stats::BotStats,
+    store::SavedMessage,
+    task::LlmTask,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use teloxide::types::ChatId;
+use tokio::sync::watch;
+
+// Answers every request with the same text and keeps the system prompts
+struct Canned {
+    reply: &'static str,
+    prompts: Mutex<Vec<String>>,
+}
+
+impl Canned {
+    fn new(reply: &'static str) -> Self {
+        Canned {
+            reply,
+            prompts: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn prompts(&self) -> Vec<String> {
+        self.prompts.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Completer for Canned {
+    async fn complete(
+        &self,
+        system_prompt: &str,
+        user_content: &str,
+        model_override: Option<&str>,
+        _progress: Option<&watch::Sender<String>>,
+    ) -> Result<Completion, SummarizeError> {
+        self.prompts.lock().unwrap().push(system_prompt.to_string());
+        Ok(Completion {
+            text: self.reply.to_string(),
+            provider: "canned".to_string(),
+            failed_over: false,
+            chars: user_content.len(),
+            usage: None,
+            requested_model: model_override.unwrap_or("default").to_string(),
+            served_model: None,
+            served_model_changed: None,
+            retry_time: Duration::ZERO,
+        })
+    }
+}
+
+async fn summarize(
+    llm: &Canned,
+    config: &Config,
+    messages: Vec<SavedMessage>,
+    chat_settings: &ChatSettings,
+) -> Completion {
+    let stats = Arc::new(tokio::sync::Mutex::new(BotStats::new()));
+    let metrics = Metrics::default();
+    let ctx = TaskContext {
+        config,
+        stats: &stats,
+        metrics: &metrics,
+        llm,
+    };
+    let (completion, ..) = run_llm_task(
+        &ctx,
+        messages.into(),
+        LlmTask::Summarize,
+        None,
+        ChatId(-100),
+        chat_settings,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    completion
+}
+
+#[tokio::test]
+async fn sends_a_short_conversation_in_one_request() {
+    let llm = Canned::new("They said hello.");
+    let config = common::config();
+    let messages = vec![
+        message(1, "alice", "hello"),
+        message(2, "alice", "hi there"),
+    ];
+
+    let completion = summarize(&llm, &config, messages, &ChatSettings::default()).await;
+
+    assert_eq!(completion.text, "They said hello.");
+    assert_eq!(llm.prompts().len(), 1);
+}
+
+#[tokio::test]
+async fn splits_a_long_conversation_and_merges_the_parts() {
+    let llm = Canned::new("partial");
+    let config = Config {
+        prompt_token_budget: 50,
+        ..common::config()
+    };
+    let messages = (1..=20)
+        .map(|id| message(id, "alice", &"a fairly long line of chat ".repeat(4)))
+        .collect();
+
+    summarize(&llm, &config, messages, &ChatSettings::default()).await;
+
+    let prompts = llm.prompts();
+    assert!(prompts.len() > 2);
+    assert!(prompts[0].contains(&format!("This is part 1 of {}", prompts.len() - 1)));
+    assert!(
+        prompts
+            .last()
+            .unwrap()
+            .contains("Combine them into a single result")
+    );
+}
+
+#[tokio::test]
+async fn redacts_blocked_terms_from_the_answer() {
+    let llm = Canned::new("They discussed the secret plan.");
+    let config = common::config();
+    let chat_settings = ChatSettings {
+        blocked_terms: ["secret".to_string()].into(),
+        ..ChatSettings::default()
+    };
+
+    let completion = summarize(
+        &llm,
+        &config,
+        vec![message(1, "alice", "hello")],
+        &chat_settings,
+    )
+    .await;
+
+    assert!(!completion.text.contains("secret"));
+}
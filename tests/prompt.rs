@@ -0,0 +1,97 @@
+mod common;
+
+use common::message;
+use duck_summarizer::{
+    blockterms, compaction, format::format_conversation, prompt, store::SavedMessage,
+};
+use std::time::Duration;
+use teloxide::types::MessageId;
+
+fn reply(id: i32, from: &str, text: &str, to: i32, to_user: Option<&str>) -> SavedMessage {
+    SavedMessage {
+        reply_to_message_id: Some(MessageId(to)),
+        reply_to_user: to_user.map(str::to_string),
+        ..message(id, from, text)
+    }
+}
+
+#[test]
+fn numbers_messages_and_names_authors() {
+    let messages = [message(1, "alice", "hi"), message(2, "bob", "hello")];
+    assert_eq!(
+        format_conversation(&messages),
+        "[#1] alice: hi\n[#2] bob: hello\n"
+    );
+}
+
+#[test]
+fn escapes_newlines() {
+    let messages = [message(1, "alice", "first line\nsecond line\n")];
+    assert_eq!(
+        format_conversation(&messages),
+        "[#1] alice: first line\\nsecond line\\n\n"
+    );
+}
+
+#[test]
+fn resolves_reply_within_messages() {
+    let messages = [
+        message(1, "alice", "lunch?"),
+        reply(2, "bob", "sure", 1, None),
+    ];
+    assert_eq!(
+        format_conversation(&messages),
+        "[#1] alice: lunch?\n[#2] bob (replying to alice): sure\n"
+    );
+}
+
+#[test]
+fn resolves_reply_to_evicted_message_from_saved_author() {
+    // Message 1 is gone, e.g. evicted, but its author was kept with the reply
+    let messages = [reply(2, "bob", "sure", 1, Some("alice"))];
+    assert_eq!(
+        format_conversation(&messages),
+        "[#1] bob (replying to alice): sure\n"
+    );
+}
+
+#[test]
+fn renders_reply_to_unknown_message_as_plain() {
+    let messages = [reply(2, "bob", "sure", 1, None)];
+    assert_eq!(format_conversation(&messages), "[#1] bob: sure\n");
+}
+
+#[test]
+fn falls_back_to_unknown_author() {
+    let messages = [SavedMessage {
+        from_user: None,
+        ..message(1, "", "anonymous")
+    }];
+    assert_eq!(format_conversation(&messages), "[#1] Unknown: anonymous\n");
+}
+
+#[test]
+fn counts_compacted_summaries_made_without_some_messages() {
+    let summary = |id, text: String| SavedMessage {
+        from_user: None,
+        synthetic: true,
+        text,
+        ..message(id, "", "")
+    };
+    let messages = [
+        summary(
+            1,
+            format!("They planned a trip.\n{}", compaction::EXCLUSIONS_MARK),
+        ),
+        summary(2, "They discussed books.".to_string()),
+        message(3, "alice", "hi"),
+    ];
+
+    let prepared = prompt::build(
+        &messages,
+        Duration::MAX,
+        false,
+        &blockterms::Matcher::default(),
+    );
+    assert_eq!(prepared.compacted_exclusions, 1);
+}
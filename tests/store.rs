@@ -0,0 +1,145 @@
+mod common;
+
+use chrono::Utc;
+use duck_summarizer::{
+    compaction::CompactionConfig,
+    store::{
+        Admission, ChatThreadId, LastSummary, MAX_MESSAGES, MessageSelector, MessageStore,
+        SavedMessage,
+    },
+};
+use teloxide::types::{ChatId, MessageId};
+
+const CHAT: ChatId = ChatId(-100);
+
+fn message(id: i32) -> SavedMessage {
+    common::message(id, &format!("user{}", id % 3), &format!("message {}", id))
+}
+
+fn ids(messages: &[SavedMessage]) -> Vec<i32> {
+    messages.iter().map(|m| m.message_id.0).collect()
+}
+
+#[test]
+fn evicts_oldest_past_max_messages() {
+    let mut store = MessageStore::new();
+    let total = MAX_MESSAGES as i32 + 5;
+    for id in 1..=total {
+        assert_eq!(
+            store.add_message(CHAT, None, message(id)),
+            Admission::Admitted
+        );
+    }
+
+    let stored = store.get_last_n_messages(CHAT, None, usize::MAX);
+    assert_eq!(stored.len(), MAX_MESSAGES);
+    assert_eq!(stored.first().unwrap().message_id, MessageId(6));
+    assert_eq!(stored.last().unwrap().message_id, MessageId(total));
+    assert_eq!(store.total_messages, MAX_MESSAGES);
+    assert_eq!(store.eviction_count(CHAT, None), 5);
+}
+
+#[test]
+fn last_n_is_oldest_first_when_n_exceeds_stored() {
+    let mut store = MessageStore::new();
+    for id in 1..=3 {
+        store.add_message(CHAT, None, message(id));
+    }
+
+    assert_eq!(ids(&store.get_last_n_messages(CHAT, None, 10)), [1, 2, 3]);
+    assert_eq!(ids(&store.get_last_n_messages(CHAT, None, 2)), [2, 3]);
+    assert!(store.get_last_n_messages(ChatId(-200), None, 10).is_empty());
+}
+
+#[test]
+fn assigns_sequence_in_arrival_order() {
+    let mut store = MessageStore::new();
+    // Telegram ids can arrive out of order; the store keeps arrival order
+    for id in [5, 3, 4] {
+        store.add_message(CHAT, None, message(id));
+    }
+
+    let stored = store.get_last_n_messages(CHAT, None, 3);
+    assert_eq!(ids(&stored), [5, 3, 4]);
+    assert!(stored.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+}
+
+#[test]
+fn seed_keeps_live_sequence_numbers() {
+    let mut store = MessageStore::new();
+    for id in 10..=12 {
+        store.add_message(CHAT, None, message(id));
+    }
+    let live = store.get_last_n_messages(CHAT, None, 3);
+
+    // 11 is already stored and 13 is newer than what the bot saw live
+    let imported = [5, 6, 11, 13].into_iter().map(message).collect();
+    assert_eq!(store.seed(CHAT, None, imported), 2);
+
+    let stored = store.get_last_n_messages(CHAT, None, usize::MAX);
+    assert_eq!(ids(&stored), [5, 6, 10, 11, 12]);
+    assert!(stored.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+    let live_after: Vec<u64> = stored[2..].iter().map(|m| m.seq).collect();
+    assert_eq!(live_after, live.iter().map(|m| m.seq).collect::<Vec<_>>());
+    assert_eq!(store.total_messages, 5);
+}
+
+#[test]
+fn regenerates_only_what_is_still_stored() {
+    let mut store = MessageStore::new();
+    for id in 1..=3 {
+        store.add_message(CHAT, None, message(id));
+    }
+    let key = ChatThreadId {
+        chat_id: CHAT,
+        thread_id: None,
+    };
+    let snapshot = store.snapshot(CHAT, None, MessageSelector::Last(2));
+    store.set_last_summary(
+        key.clone(),
+        LastSummary {
+            selector: snapshot.selector,
+            seqs: snapshot.messages.iter().map(|m| m.seq).collect(),
+            requested: 2,
+            focus: None,
+            text: "summary".to_string(),
+        },
+    );
+    let last = store.last_summary(&key).unwrap();
+    assert_eq!(
+        ids(&store.regenerate_snapshot(&key, &last).messages),
+        [2, 3]
+    );
+
+    store.remove_chat(CHAT);
+    assert!(store.last_summary(&key).is_none());
+    assert!(store.regenerate_snapshot(&key, &last).messages.is_empty());
+}
+
+#[test]
+fn full_queue_waits_for_compaction_then_falls_back_to_eviction() {
+    let mut store = MessageStore::new();
+    let config = CompactionConfig {
+        batch_size: 10,
+        max_per_day: 1,
+    };
+    store.compaction = Some(config);
+    let total = MAX_MESSAGES as i32 + 3;
+    for id in 1..=total {
+        store.add_message(CHAT, None, message(id));
+    }
+    assert_eq!(store.total_messages, MAX_MESSAGES + 3);
+    assert_eq!(store.eviction_count(CHAT, None), 0);
+
+    let today = Utc::now().date_naive();
+    let batch = store.begin_compaction(CHAT, None, &config, today).unwrap();
+    assert_eq!(ids(&batch[..2]), [1, 2]);
+    // The provider failed and today's compaction is used up
+    store.finish_compaction(CHAT, None, 0, None);
+    store.add_message(CHAT, None, message(total + 1));
+
+    let stored = store.get_last_n_messages(CHAT, None, usize::MAX);
+    assert_eq!(stored.len(), MAX_MESSAGES);
+    assert_eq!(stored.first().unwrap().message_id, MessageId(5));
+    assert_eq!(store.eviction_count(CHAT, None), 4);
+}
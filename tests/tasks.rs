@@ -0,0 +1,140 @@
+use chrono::{TimeZone, Utc};
+use duck_summarizer::{
+    backoff::Backoff,
+    tasks::{self, Alert, TaskRegistry, TaskState},
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+#[test]
+fn flags_task_after_missed_heartbeats() {
+    let registry = TaskRegistry::default();
+    let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+    registry.register("ticker", Some(MINUTE), start);
+
+    // Two missed beats are tolerated
+    assert!(
+        registry
+            .check(start + chrono::Duration::minutes(3))
+            .is_empty()
+    );
+
+    let late = start + chrono::Duration::minutes(4);
+    assert_eq!(
+        registry.check(late),
+        [Alert::Stale {
+            name: "ticker",
+            silent_for: chrono::Duration::minutes(4),
+        }]
+    );
+    // Reported once, not on every check
+    assert!(
+        registry
+            .check(late + chrono::Duration::minutes(1))
+            .is_empty()
+    );
+
+    // A heartbeat clears it, so the next stall is reported again
+    registry.beat("ticker", "ok", late + chrono::Duration::minutes(2));
+    assert!(
+        registry
+            .check(late + chrono::Duration::minutes(3))
+            .is_empty()
+    );
+    assert_eq!(registry.check(late + chrono::Duration::minutes(6)).len(), 1);
+}
+
+#[test]
+fn heartbeat_keeps_task_fresh() {
+    let registry = TaskRegistry::default();
+    let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+    registry.register("ticker", Some(MINUTE), start);
+    for minute in 1..=10 {
+        let now = start + chrono::Duration::minutes(minute);
+        registry.beat("ticker", format!("round {}", minute), now);
+        assert!(registry.check(now).is_empty());
+    }
+    let status = registry.status("ticker").unwrap();
+    assert_eq!(status.last_result.as_deref(), Some("round 10"));
+}
+
+#[tokio::test]
+async fn restarts_task_after_panic() {
+    let registry = Arc::new(TaskRegistry::default());
+    let runs = Arc::new(AtomicU32::new(0));
+    let restart = Backoff {
+        initial: Duration::from_millis(10),
+        max_delay: Duration::from_millis(10),
+        window: Duration::MAX,
+    };
+
+    let task_runs = runs.clone();
+    let task_registry = registry.clone();
+    tasks::supervise(
+        registry.clone(),
+        "flaky",
+        Some(MINUTE),
+        restart,
+        move || {
+            let run = task_runs.fetch_add(1, Ordering::SeqCst);
+            let registry = task_registry.clone();
+            async move {
+                if run == 0 {
+                    panic!("deliberate failure");
+                }
+                registry.beat("flaky", "recovered", Utc::now());
+            }
+        },
+    )
+    .await;
+
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    let status = registry.status("flaky").unwrap();
+    assert_eq!(status.restarts, 1);
+    assert_eq!(status.state, TaskState::Stopped);
+    assert_eq!(status.last_result.as_deref(), Some("recovered"));
+    assert_eq!(
+        status.last_panic.map(|(_, reason)| reason).as_deref(),
+        Some("deliberate failure")
+    );
+    assert_eq!(
+        registry.check(Utc::now()),
+        [Alert::Panicked {
+            name: "flaky",
+            reason: "deliberate failure".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn tasks_without_interval_never_go_stale() {
+    let registry = TaskRegistry::default();
+    let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+    registry.register("server", None, start);
+    assert!(registry.check(start + chrono::Duration::days(7)).is_empty());
+}
+
+#[tokio::test]
+async fn records_panicking_job_without_restarting() {
+    let registry = TaskRegistry::default();
+    assert!(tasks::run_job(&registry, "job", async {}).await);
+    assert!(!tasks::run_job(&registry, "job", async { panic!("job failure") }).await);
+
+    let status = registry.status("job").unwrap();
+    assert_eq!(status.state, TaskState::OnDemand);
+    assert_eq!(status.restarts, 0);
+    assert_eq!(
+        registry.check(Utc::now()),
+        [Alert::Panicked {
+            name: "job",
+            reason: "job failure".to_string(),
+        }]
+    );
+}